@@ -82,6 +82,7 @@ cargo zigbuild --release --target aarch64-unknown-linux-musl --features lambda |
 mkdir -p bin || exit 1
 cp ./target/aarch64-unknown-linux-musl/release/api ./bin/bootstrap || exit 1
 cp ./aws/collector-config.yaml ./bin/ || exit 1
+cp -r ./config ./bin/ || exit 1
 `;
 
 const apiBuildCommand = new local.Command(`${apiLambdaRemoteId}-build`, {
@@ -92,6 +93,7 @@ const apiBuildCommand = new local.Command(`${apiLambdaRemoteId}-build`, {
     new pulumi.asset.FileAsset(`./${API_DIR}/Cargo.toml`),
     new pulumi.asset.FileAsset(`./${API_DIR}/build.rs`),
     new pulumi.asset.FileAsset(`./${API_DIR}/aws/collector-config.yaml`),
+    new pulumi.asset.FileArchive(`./${API_DIR}/config`),
   ],
   environment: {
     PULUMI_STACK: pulumi.getStack(),
@@ -105,6 +107,8 @@ export const apiLambdaRemote = new aws.lambda.Function(apiLambdaRemoteId, {
       TZ: "Asia/Tokyo",
       OPENTELEMETRY_COLLECTOR_CONFIG_URI: "/var/task/collector-config.yaml",
       RUST_LOG: "info",
+      PULUMI_STACK: pulumi.getStack(),
+      CONFIG_DIR: "/var/task/config",
     },
   },
   code: fs.existsSync(BIN_PATH) ? apiBuildCommand.stdout.apply((_) => {
@@ -80,6 +80,7 @@ cargo zigbuild --release --target aarch64-unknown-linux-musl --features lambda |
 mkdir -p bin || exit 1
 cp ./target/aarch64-unknown-linux-musl/release/api ./bin/bootstrap || exit 1
 cp ./aws/collector-config.yaml ./bin/ || exit 1
+cp -r ./config ./bin/ || exit 1
 `;
 
 const selfStack = new pulumi.StackReference(
@@ -94,12 +95,12 @@ const apiBuildCommand = new local.Command(`${apiLambdaId}-build`, {
     new pulumi.asset.FileAsset(`./${API_DIR}/Cargo.toml`),
     new pulumi.asset.FileAsset(`./${API_DIR}/build.rs`),
     new pulumi.asset.FileAsset(`./${API_DIR}/aws/collector-config.yaml`),
+    new pulumi.asset.FileArchive(`./${API_DIR}/config`),
   ],
   environment: {
     PULUMI_STACK: pulumi.getStack(),
     API_LAMBDA_ARN: selfStack.getOutput("API_LAMBDA_ARN"),
     PROJECT_NAME: pulumi.getProject(),
-    REMOTE_ENDPOINT: selfStack.getOutput("API_LAMBDA_REMOTE_FUNCTION_URL"),
   }
 });
 
@@ -110,6 +111,9 @@ export const apiLambda = new aws.lambda.Function(apiLambdaId, {
       TZ: "Asia/Tokyo",
       OPENTELEMETRY_COLLECTOR_CONFIG_URI: "/var/task/collector-config.yaml",
       RUST_LOG: "info",
+      PULUMI_STACK: pulumi.getStack(),
+      CONFIG_DIR: "/var/task/config",
+      DOWNSTREAM_REMOTE_BASE_URL: selfStack.getOutput("API_LAMBDA_REMOTE_FUNCTION_URL"),
     },
   },
   code: fs.existsSync(BIN_PATH) ? apiBuildCommand.stdout.apply((_) => {
@@ -121,7 +125,6 @@ export const apiLambda = new aws.lambda.Function(apiLambdaId, {
       PULUMI_STACK: pulumi.getStack(),
       API_LAMBDA_ARN: selfStack.getOutput("API_LAMBDA_ARN"),
       PROJECT_NAME: pulumi.getProject(),
-      REMOTE_ENDPOINT: selfStack.getOutput("API_LAMBDA_REMOTE_FUNCTION_URL"),
     }
   }).apply(_ => {
     return new pulumi.asset.FileArchive(BIN_PATH);
@@ -100,13 +100,6 @@ fn api_lambda_arn() {
     println!("cargo:rustc-env={}={}", API_LAMBDA_ARN, api_lambda_arn.trim());
 }
 
-fn remote_endpoint() {
-    const REMOTE_ENDPOINT: &str = "REMOTE_ENDPOINT";
-    let remote_endpoint: String =
-        std::env::var(REMOTE_ENDPOINT).unwrap_or("http://localhost:3030".to_string());
-    println!("cargo:rustc-env={}={}", REMOTE_ENDPOINT, remote_endpoint.trim());
-}
-
 fn main() {
     vcs_ref_head_name();
     vcs_ref_head_revision();
@@ -116,5 +109,4 @@ fn main() {
     telemetry_sdk_version();
     api_base_path();
     api_lambda_arn();
-    remote_endpoint();
 }
@@ -0,0 +1,232 @@
+//! api crate の derive macro
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{
+    Attribute, Data, DeriveInput, Expr, Fields, LitStr, Result, parse::ParseStream,
+    parse_macro_input, spanned::Spanned,
+};
+
+/// utoipa の `#[schema(min_length, max_length, pattern)]` の制約から `crate::validation::Validate` を実装する
+///
+/// OpenAPI の schema と実行時の検証が同じ属性から作られるので、制約はフィールドに 1 回だけ書く。
+/// すべてのフィールドに制約か `#[validate(skip)]` が必要で、付け忘れはコンパイルエラーになる。
+/// 違反のフィールド名は serde の `rename_all` と `rename` を反映した JSON の名前を使う
+///
+/// ```ignore
+/// #[derive(Deserialize, ToSchema, Validate)]
+/// #[serde(rename_all = "camelCase")]
+/// struct GreetContent {
+///     #[schema(min_length = 1, max_length = 20)]
+///     person: String,
+/// }
+/// ```
+#[proc_macro_derive(Validate, attributes(validate))]
+pub fn derive_validate(input: TokenStream) -> TokenStream {
+    let input: DeriveInput = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: &DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let ident = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            input.generics.span(),
+            "Validate cannot be derived for generic types",
+        ));
+    }
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(
+            Span::call_site(),
+            "Validate can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new(
+            data.fields.span(),
+            "Validate can only be derived for structs with named fields",
+        ));
+    };
+    let rename_all: Option<String> = serde_rename(&input.attrs, "rename_all")?;
+
+    let mut checks: Vec<proc_macro2::TokenStream> = vec![];
+    for field in &fields.named {
+        let field_ident = field.ident.as_ref().expect("named field");
+        let constraint: Option<proc_macro2::TokenStream> = field_constraint(field)?;
+        let Some(constraint) = constraint else {
+            continue;
+        };
+        let name: String = match serde_rename(&field.attrs, "rename")? {
+            Some(name) => name,
+            None => rename_field(
+                field_ident.to_string().trim_start_matches("r#"),
+                rename_all.as_deref(),
+                field.span(),
+            )?,
+        };
+        checks.push(quote! {
+            {
+                static CONSTRAINT: ::std::sync::LazyLock<crate::validation::CompiledConstraint> =
+                    ::std::sync::LazyLock::new(|| (#constraint).compile());
+                crate::validation::ValidateField::check_field(
+                    &self.#field_ident,
+                    #name,
+                    &CONSTRAINT,
+                    &mut violations,
+                );
+            }
+        });
+    }
+
+    Ok(quote! {
+        impl crate::validation::Validate for #ident {
+            fn validate(&self) -> ::std::result::Result<(), ::std::vec::Vec<crate::error::Violation>> {
+                let mut violations: ::std::vec::Vec<crate::error::Violation> = ::std::vec::Vec::new();
+                #(#checks)*
+                if violations.is_empty() {
+                    ::std::result::Result::Ok(())
+                } else {
+                    ::std::result::Result::Err(violations)
+                }
+            }
+        }
+    })
+}
+
+/// `#[schema(...)]` の制約から組み立てる `StringConstraint` の式。`#[validate(skip)]` なら `None`
+fn field_constraint(field: &syn::Field) -> Result<Option<proc_macro2::TokenStream>> {
+    let mut skip: bool = false;
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("validate")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                skip = true;
+                Ok(())
+            } else {
+                Err(meta.error(
+                    "#[validate] only accepts `skip`; declare constraints in #[schema(...)]",
+                ))
+            }
+        })?;
+    }
+
+    let mut setters: Vec<proc_macro2::TokenStream> = vec![];
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("schema")) {
+        attr.parse_nested_meta(|meta| {
+            for key in ["min_length", "max_length", "pattern"] {
+                if meta.path.is_ident(key) {
+                    let value: Expr = meta.value()?.parse()?;
+                    let setter = syn::Ident::new(key, meta.path.span());
+                    setters.push(quote! { .#setter(#value) });
+                    return Ok(());
+                }
+            }
+            // 他の utoipa の属性は読み飛ばす
+            skip_value(meta.input)
+        })?;
+    }
+
+    match (skip, setters.is_empty()) {
+        (true, true) => Ok(None),
+        (true, false) => Err(syn::Error::new(
+            field.span(),
+            "#[validate(skip)] fields must not declare schema constraints",
+        )),
+        (false, true) => Err(syn::Error::new(
+            field.span(),
+            "every field needs #[schema(min_length, max_length or pattern)] or #[validate(skip)]",
+        )),
+        (false, false) => Ok(Some(quote! {
+            crate::validation::StringConstraint::new() #(#setters)*
+        })),
+    }
+}
+
+/// `key = value` や `key(...)` の、次の `,` までを読み飛ばす。`value_type = HashMap<K, V>` の `,` では止まらない
+fn skip_value(input: ParseStream) -> Result<()> {
+    let mut depth: usize = 0;
+    while !input.is_empty() && (depth > 0 || !input.peek(syn::Token![,])) {
+        match input.parse::<proc_macro2::TokenTree>()? {
+            proc_macro2::TokenTree::Punct(punct) if punct.as_char() == '<' => depth += 1,
+            proc_macro2::TokenTree::Punct(punct) if punct.as_char() == '>' => {
+                depth = depth.saturating_sub(1)
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// `#[serde(key = "...")]` か `#[serde(key(deserialize = "..."))]` の値
+fn serde_rename(attrs: &[Attribute], key: &str) -> Result<Option<String>> {
+    let mut value: Option<String> = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        attr.parse_nested_meta(|meta| {
+            if !meta.path.is_ident(key) {
+                // 他の serde の属性は読み飛ばす
+                if meta.input.peek(syn::Token![=]) {
+                    meta.value()?.parse::<Expr>()?;
+                } else if meta.input.peek(syn::token::Paren) {
+                    meta.parse_nested_meta(|nested| {
+                        if nested.input.peek(syn::Token![=]) {
+                            nested.value()?.parse::<Expr>()?;
+                        }
+                        Ok(())
+                    })?;
+                }
+                return Ok(());
+            }
+            if meta.input.peek(syn::Token![=]) {
+                value = Some(meta.value()?.parse::<LitStr>()?.value());
+                return Ok(());
+            }
+            meta.parse_nested_meta(|nested| {
+                let lit: LitStr = nested.value()?.parse()?;
+                if nested.path.is_ident("deserialize") {
+                    value = Some(lit.value());
+                }
+                Ok(())
+            })
+        })?;
+    }
+    Ok(value)
+}
+
+/// serde の `rename_all` と同じ規則で snake_case のフィールド名を変換する
+fn rename_field(field: &str, rename_all: Option<&str>, span: Span) -> Result<String> {
+    let words = field.split('_').filter(|word| !word.is_empty());
+    let capitalize = |word: &str| -> String {
+        let mut chars = word.chars();
+        chars
+            .next()
+            .map(|first| first.to_uppercase().chain(chars).collect())
+            .unwrap_or_default()
+    };
+    Ok(match rename_all {
+        None | Some("snake_case") => field.to_string(),
+        Some("lowercase") => field.to_lowercase(),
+        Some("UPPERCASE") => field.to_uppercase(),
+        Some("PascalCase") => words.map(capitalize).collect(),
+        Some("camelCase") => words
+            .enumerate()
+            .map(|(i, word)| {
+                if i == 0 {
+                    word.to_string()
+                } else {
+                    capitalize(word)
+                }
+            })
+            .collect(),
+        Some("SCREAMING_SNAKE_CASE") => field.to_uppercase(),
+        Some("kebab-case") => field.replace('_', "-"),
+        Some("SCREAMING-KEBAB-CASE") => field.replace('_', "-").to_uppercase(),
+        Some(other) => {
+            return Err(syn::Error::new(
+                span,
+                format!("unsupported serde rename_all rule `{}`", other),
+            ));
+        }
+    })
+}
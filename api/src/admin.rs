@@ -0,0 +1,425 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{FromRef, FromRequestParts, Request, State},
+    http::{StatusCode, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::filter::ParseError;
+use utoipa::ToSchema;
+use utoipa_axum::router::OpenApiRouter;
+
+use crate::auth::{Authenticated, Authenticator, Principal};
+use crate::config::Config;
+use crate::error::{ApiError, PROBLEM_JSON_CONTENT_TYPE, ProblemDetails, Violation};
+use crate::logging::{LogFilterHandle, LogFilters};
+use crate::state::AppState;
+use crate::validation::{ApiJson, Validate};
+
+const ADMIN_TAG: &str = "admin";
+/// 管理者が付けると、そのリクエストの中では `debug` のログまで出力する
+pub const DEBUG_LOG_HEADER: &str = "x-debug-log";
+
+/// `auth.admins` に含まれる利用者だけが呼べる handler の引数
+#[derive(Debug, Clone)]
+pub struct AdminAuthenticated(pub Principal);
+
+impl<S> FromRequestParts<S> for AdminAuthenticated
+where
+    Authenticator: FromRef<S>,
+    Arc<Config>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Authenticated(principal) = Authenticated::from_request_parts(parts, state).await?;
+        if !Arc::<Config>::from_ref(state)
+            .auth
+            .admins
+            .contains(&principal.id)
+        {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "Forbidden",
+                "admin privileges are required",
+            ));
+        }
+        Ok(Self(principal))
+    }
+}
+
+/// 出力先ごとの `EnvFilter` の directive。更新では省略した出力先を変えない
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct LogLevel {
+    /// 標準出力のログ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "info,api=debug")]
+    directives: Option<String>,
+    /// OTLP で送るログ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "info,opentelemetry=off,tonic=off,hyper=off,h2=off,reqwest=off")]
+    otlp_directives: Option<String>,
+    /// OTLP で送る span
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "info")]
+    span_directives: Option<String>,
+}
+
+impl LogLevel {
+    fn fields(&self) -> [(&'static str, &Option<String>); 3] {
+        [
+            ("directives", &self.directives),
+            ("otlpDirectives", &self.otlp_directives),
+            ("spanDirectives", &self.span_directives),
+        ]
+    }
+}
+
+impl Validate for LogLevel {
+    fn validate(&self) -> Result<(), Vec<Violation>> {
+        let fields = self.fields();
+        if fields.iter().all(|(_, directives)| directives.is_none()) {
+            return Err(vec![Violation::new(
+                "directives",
+                "at least one of directives, otlpDirectives and spanDirectives is required",
+            )]);
+        }
+        let violations: Vec<Violation> = fields
+            .into_iter()
+            .filter_map(|(name, directives)| {
+                let err: ParseError = LogFilterHandle::new(directives.as_deref()?).err()?;
+                Some(Violation::new(name, err.to_string()))
+            })
+            .collect();
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+impl From<&LogFilters> for LogLevel {
+    fn from(filters: &LogFilters) -> Self {
+        Self {
+            directives: Some(filters.stdout.directives()),
+            otlp_directives: Some(filters.otlp.directives()),
+            span_directives: Some(filters.spans.directives()),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/log-level",
+    responses(
+        (status = StatusCode::OK, body = LogLevel),
+        (
+            status = StatusCode::UNAUTHORIZED,
+            description = "missing or invalid credentials",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
+        ),
+        (
+            status = StatusCode::FORBIDDEN,
+            description = "caller is not an admin",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
+        ),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+    tags = [ ADMIN_TAG ]
+)]
+async fn get_log_level(
+    AdminAuthenticated(_): AdminAuthenticated,
+    State(log_filters): State<LogFilters>,
+) -> ApiJson<LogLevel> {
+    ApiJson(LogLevel::from(&log_filters))
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/log-level",
+    request_body(content = LogLevel, content_type = "application/json"),
+    responses(
+        (status = StatusCode::OK, body = LogLevel),
+        (
+            status = StatusCode::BAD_REQUEST,
+            description = "invalid directives",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
+        ),
+        (
+            status = StatusCode::UNAUTHORIZED,
+            description = "missing or invalid credentials",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
+        ),
+        (
+            status = StatusCode::FORBIDDEN,
+            description = "caller is not an admin",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
+        ),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+    tags = [ ADMIN_TAG ]
+)]
+async fn put_log_level(
+    AdminAuthenticated(principal): AdminAuthenticated,
+    State(log_filters): State<LogFilters>,
+    ApiJson(payload): ApiJson<LogLevel>,
+) -> Result<ApiJson<LogLevel>, ApiError> {
+    for ((name, directives), handle) in payload.fields().into_iter().zip([
+        &log_filters.stdout,
+        &log_filters.otlp,
+        &log_filters.spans,
+    ]) {
+        let Some(directives) = directives else {
+            continue;
+        };
+        handle.reload(directives).map_err(|err| {
+            ApiError::validation(vec![Violation::new(name, err.to_string())])
+        })?;
+        tracing::warn!(
+            "Log filter `{}` changed to `{}` by {}",
+            name,
+            directives,
+            principal.id
+        );
+    }
+    Ok(ApiJson(LogLevel::from(&log_filters)))
+}
+
+/// 管理者のリクエストに `X-Debug-Log` があれば、そのリクエストの処理中は `debug` まで出力する
+///
+/// server span より外側に置くので、SigV4 の呼び出し元ではなく API キーか JWT で判定する。管理者でなければヘッダーを無視する
+pub async fn debug_log_override(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !request.headers().contains_key(DEBUG_LOG_HEADER) {
+        return next.run(request).await;
+    }
+    let (mut parts, body) = request.into_parts();
+    let request: Request = match AdminAuthenticated::from_request_parts(&mut parts, &state).await {
+        Ok(AdminAuthenticated(principal)) => {
+            tracing::info!("Debug logging enabled for a request by {}", principal.id);
+            Request::from_parts(parts, body)
+        }
+        Err(err) => {
+            tracing::warn!("Ignoring {} header: {}", DEBUG_LOG_HEADER, err);
+            return next.run(Request::from_parts(parts, body)).await;
+        }
+    };
+    crate::logging::with_debug(next.run(request)).await
+}
+
+pub fn create_admin_router() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new().routes(utoipa_axum::routes!(get_log_level, put_log_level))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::{Router, body::Body, http::header, routing::get};
+    use tower::ServiceExt;
+    use tracing_subscriber::{Layer, layer::SubscriberExt};
+
+    use super::*;
+    use crate::auth::{API_KEY_HEADER, ApiKeyConfig, AuthConfig};
+    use crate::hello::RemoteHelloRepository;
+
+    const ADMIN_KEY: &str = "admin-key";
+    const USER_KEY: &str = "user-key";
+
+    /// `debug` のイベントを数える
+    #[derive(Clone, Default)]
+    struct CountDebugEvents(Arc<AtomicUsize>);
+
+    impl<S: tracing::Subscriber> Layer<S> for CountDebugEvents {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if *event.metadata().level() == tracing::Level::DEBUG {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    async fn state(log_filters: LogFilters) -> AppState {
+        let authenticator: Authenticator = Authenticator::load(&AuthConfig {
+            api_keys: vec![
+                ApiKeyConfig {
+                    id: "admin".to_string(),
+                    key: ADMIN_KEY.to_string(),
+                },
+                ApiKeyConfig {
+                    id: "user".to_string(),
+                    key: USER_KEY.to_string(),
+                },
+            ],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        AppState::builder()
+            .config(Arc::new(Config {
+                auth: AuthConfig {
+                    admins: vec!["admin".to_string()],
+                    ..Default::default()
+                },
+                ..Default::default()
+            }))
+            .authenticator(authenticator)
+            .log_filters(log_filters)
+            .hello_repository(Arc::new(RemoteHelloRepository::new(None)))
+            .build()
+            .unwrap()
+    }
+
+    async fn send(
+        log_filters: &LogFilters,
+        request: axum::http::request::Builder,
+        body: Option<serde_json::Value>,
+    ) -> (StatusCode, serde_json::Value) {
+        let (router, _) = create_admin_router().split_for_parts();
+        let request = match body {
+            Some(body) => request
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        };
+        let response = router
+            .with_state(state(log_filters.clone()).await)
+            .oneshot(request.unwrap())
+            .await
+            .unwrap();
+        let status: StatusCode = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn log_level(method: &str, api_key: &str) -> axum::http::request::Builder {
+        axum::http::Request::builder()
+            .method(method)
+            .uri("/admin/log-level")
+            .header(API_KEY_HEADER, api_key)
+    }
+
+    #[tokio::test]
+    async fn admins_can_read_and_change_log_levels() {
+        let log_filters: LogFilters = LogFilters::default();
+        let (status, body) = send(&log_filters, log_level("GET", ADMIN_KEY), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["directives"], "info");
+
+        let (status, body) = send(
+            &log_filters,
+            log_level("PUT", ADMIN_KEY),
+            Some(serde_json::json!({ "directives": "info,api=debug" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["directives"], "info,api=debug");
+        assert_eq!(log_filters.stdout.directives(), "info,api=debug");
+        // 省略した出力先は変えない
+        assert_eq!(body["spanDirectives"], "info");
+    }
+
+    #[tokio::test]
+    async fn non_admins_are_forbidden() {
+        let log_filters: LogFilters = LogFilters::default();
+        for request in [
+            (log_level("GET", USER_KEY), None),
+            (
+                log_level("PUT", USER_KEY),
+                Some(serde_json::json!({ "directives": "trace" })),
+            ),
+        ] {
+            let (status, body) = send(&log_filters, request.0, request.1).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            assert_eq!(body["detail"], "admin privileges are required");
+        }
+        assert_eq!(log_filters.stdout.directives(), "info");
+    }
+
+    #[tokio::test]
+    async fn invalid_directives_are_rejected() {
+        let log_filters: LogFilters = LogFilters::default();
+        let (status, body) = send(
+            &log_filters,
+            log_level("PUT", ADMIN_KEY),
+            Some(serde_json::json!({ "directives": "info", "spanDirectives": "api=loud" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["violations"][0]["field"], "spanDirectives");
+        // 1 つでも不正なら何も変えない
+        assert_eq!(log_filters.spans.directives(), "info");
+        assert_eq!(log_filters.stdout.directives(), "info");
+    }
+
+    #[tokio::test]
+    async fn debug_log_header_enables_debug_only_for_admins() {
+        let log_filters: LogFilters = LogFilters::default();
+        let events: CountDebugEvents = CountDebugEvents::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(events.clone().with_filter(log_filters.stdout.filter()));
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let state: AppState = state(log_filters).await;
+        let router: Router = Router::new()
+            .route(
+                "/probe",
+                get(|| async {
+                    tracing::debug!("probe");
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                debug_log_override,
+            ))
+            .with_state(state);
+
+        for (api_key, debug_header, expected) in [
+            (Some(ADMIN_KEY), true, 1),
+            (Some(ADMIN_KEY), false, 0),
+            (Some(USER_KEY), true, 0),
+            (None, true, 0),
+        ] {
+            events.0.store(0, Ordering::Relaxed);
+            let mut request = axum::http::Request::get("/probe");
+            if let Some(api_key) = api_key {
+                request = request.header(API_KEY_HEADER, api_key);
+            }
+            if debug_header {
+                request = request.header(DEBUG_LOG_HEADER, "1");
+            }
+            let response = router
+                .clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                events.0.load(Ordering::Relaxed),
+                expected,
+                "{:?} {}",
+                api_key,
+                debug_header
+            );
+        }
+    }
+}
@@ -0,0 +1,620 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::{HeaderMap, HeaderValue, StatusCode, header, request::Parts},
+};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation,
+    jwk::{JwkSet, KeyAlgorithm},
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{
+    Modify,
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+};
+
+use crate::config::Env;
+use crate::error::ApiError;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+/// OpenAPI の security scheme の名前
+///
+/// 認証が必要な operation には `#[utoipa::path(security(("api_key" = []), ("bearer" = [])))]` と書く
+pub const API_KEY_SCHEME: &str = "api_key";
+pub const BEARER_SCHEME: &str = "bearer";
+// 起動時に取得するので、JWKS の host が応答しなくても起動が止まらないようにする
+const JWKS_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub api_keys: Vec<ApiKeyConfig>,
+    pub jwt: Option<JwtConfig>,
+    pub sigv4: SigV4Config,
+    /// 管理用の endpoint を呼べる利用者の識別子 (`enduser.id`)
+    pub admins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// `enduser.id` に記録する利用者の識別子
+    pub id: String,
+    pub key: String,
+}
+
+/// JWKS は `jwks_path` (ファイル) か `jwks_url` のどちらかから読む
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    pub jwks_path: Option<String>,
+    pub jwks_url: Option<String>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+/// `lambda` feature なしで起動したときの SigV4 署名の検証
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigV4Config {
+    pub enabled: bool,
+    pub region: String,
+    pub service: String,
+    /// `x-amz-date` と現在時刻のずれの許容範囲
+    pub max_clock_skew_secs: u64,
+    pub credentials: Vec<SigV4CredentialConfig>,
+}
+
+impl Default for SigV4Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            region: "ap-northeast-1".to_string(),
+            // function URL の署名と同じ service 名
+            service: "lambda".to_string(),
+            max_clock_skew_secs: 300,
+            credentials: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigV4CredentialConfig {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// `enduser.id` に記録する呼び出し元 (IAM の ARN など)
+    pub principal: String,
+}
+
+impl AuthConfig {
+    /// `AUTH_API_KEYS` (`<id>:<key>` のカンマ区切り)、`AUTH_JWKS_PATH`、`AUTH_JWKS_URL`、
+    /// `AUTH_SIGV4_ENABLED`、`AUTH_SIGV4_REGION`、
+    /// `AUTH_SIGV4_CREDENTIALS` (`<access_key_id>:<secret_access_key>:<principal>` のカンマ区切り)、
+    /// `AUTH_ADMINS` (カンマ区切り) で上書きする
+    pub fn apply_env_overrides(&mut self, env: &Env) -> anyhow::Result<()> {
+        if let Some(api_keys) = env.get("AUTH_API_KEYS") {
+            self.api_keys = api_keys
+                .split(',')
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| match entry.split_once(':') {
+                    Some((id, key)) if !id.is_empty() && !key.is_empty() => Ok(ApiKeyConfig {
+                        id: id.to_string(),
+                        key: key.to_string(),
+                    }),
+                    _ => bail!("invalid value in `AUTH_API_KEYS`: expected `<id>:<key>`"),
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        if let Some(jwks_path) = env.get("AUTH_JWKS_PATH") {
+            self.jwt.get_or_insert_with(JwtConfig::default).jwks_path = Some(jwks_path.to_string());
+        }
+        if let Some(jwks_url) = env.get("AUTH_JWKS_URL") {
+            self.jwt.get_or_insert_with(JwtConfig::default).jwks_url = Some(jwks_url.to_string());
+        }
+        if let Some(enabled) = env.get("AUTH_SIGV4_ENABLED") {
+            self.sigv4.enabled = enabled
+                .parse()
+                .context("invalid value in `AUTH_SIGV4_ENABLED`: expected `true` or `false`")?;
+        }
+        if let Some(region) = env.get("AUTH_SIGV4_REGION") {
+            self.sigv4.region = region.to_string();
+        }
+        if let Some(credentials) = env.get("AUTH_SIGV4_CREDENTIALS") {
+            self.sigv4.credentials = credentials
+                .split(',')
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| match entry.splitn(3, ':').collect::<Vec<_>>()[..] {
+                    [access_key_id, secret_access_key, principal]
+                        if !access_key_id.is_empty()
+                            && !secret_access_key.is_empty()
+                            && !principal.is_empty() =>
+                    {
+                        Ok(SigV4CredentialConfig {
+                            access_key_id: access_key_id.to_string(),
+                            secret_access_key: secret_access_key.to_string(),
+                            principal: principal.to_string(),
+                        })
+                    }
+                    _ => bail!(
+                        "invalid value in `AUTH_SIGV4_CREDENTIALS`: expected `<access_key_id>:<secret_access_key>:<principal>`"
+                    ),
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        if let Some(admins) = env.get("AUTH_ADMINS") {
+            self.admins = admins
+                .split(',')
+                .filter(|admin| !admin.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    ApiKey,
+    Jwt,
+    #[cfg(not(feature = "lambda"))]
+    SigV4,
+}
+
+/// 認証済みの利用者
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub id: String,
+    pub method: AuthMethod,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+struct JwtVerifier {
+    keys: HashMap<String, (DecodingKey, Algorithm)>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl JwtVerifier {
+    async fn load(config: &JwtConfig) -> anyhow::Result<Self> {
+        let jwks: JwkSet = match (&config.jwks_path, &config.jwks_url) {
+            (Some(path), None) => {
+                let content: String = tokio::fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("failed to read JWKS `{}`", path))?;
+                serde_json::from_str(&content)
+                    .with_context(|| format!("failed to parse JWKS `{}`", path))?
+            }
+            (None, Some(url)) => reqwest::Client::builder()
+                .connect_timeout(JWKS_CONNECT_TIMEOUT)
+                .timeout(JWKS_TIMEOUT)
+                .build()
+                .context("failed to create JWKS HTTP client")?
+                .get(url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .with_context(|| format!("failed to fetch JWKS `{}`", url))?
+                .json()
+                .await
+                .with_context(|| format!("failed to parse JWKS `{}`", url))?,
+            _ => bail!("auth.jwt needs exactly one of `jwks_path` or `jwks_url`"),
+        };
+
+        let mut keys: HashMap<String, (DecodingKey, Algorithm)> = HashMap::new();
+        for jwk in &jwks.keys {
+            let kid: String = jwk
+                .common
+                .key_id
+                .clone()
+                .context("JWK without `kid` is not supported")?;
+            let algorithm: Algorithm = match jwk.common.key_algorithm {
+                Some(KeyAlgorithm::RS256) => Algorithm::RS256,
+                Some(KeyAlgorithm::ES256) => Algorithm::ES256,
+                other => bail!("JWK `{}` has unsupported alg {:?} (RS256 or ES256)", kid, other),
+            };
+            let key: DecodingKey = DecodingKey::from_jwk(jwk)
+                .with_context(|| format!("invalid JWK `{}`", kid))?;
+            keys.insert(kid, (key, algorithm));
+        }
+        Ok(Self {
+            keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+        })
+    }
+
+    fn verify(&self, token: &str) -> Result<Principal, &'static str> {
+        let header = jsonwebtoken::decode_header(token).map_err(|_| "malformed token")?;
+        let kid: String = header.kid.ok_or("token has no kid")?;
+        let (key, algorithm) = self.keys.get(&kid).ok_or("unknown signing key")?;
+        if header.alg != *algorithm {
+            return Err("unexpected signing algorithm");
+        }
+        let mut validation: Validation = Validation::new(*algorithm);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let claims: Claims = jsonwebtoken::decode::<Claims>(token, key, &validation)
+            .map_err(|_| "invalid token")?
+            .claims;
+        Ok(Principal {
+            id: claims.sub,
+            method: AuthMethod::Jwt,
+        })
+    }
+}
+
+#[derive(Default)]
+struct AuthenticatorInner {
+    api_keys: Vec<ApiKeyConfig>,
+    jwt: Option<JwtVerifier>,
+}
+
+/// API キーと JWT を検証する
+///
+/// 何も設定されていない場合は、認証が必要なルートをすべて拒否する
+#[derive(Clone, Default)]
+pub struct Authenticator(Arc<AuthenticatorInner>);
+
+impl Authenticator {
+    pub async fn load(config: &AuthConfig) -> anyhow::Result<Self> {
+        let jwt: Option<JwtVerifier> = match &config.jwt {
+            Some(jwt_config) => Some(JwtVerifier::load(jwt_config).await?),
+            None => None,
+        };
+        if config.api_keys.is_empty() && jwt.is_none() {
+            tracing::warn!("No API keys or JWKS configured; authenticated routes will reject every request");
+        }
+        Ok(Self(Arc::new(AuthenticatorInner {
+            api_keys: config.api_keys.clone(),
+            jwt,
+        })))
+    }
+
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, ApiError> {
+        if let Some(api_key) = headers.get(API_KEY_HEADER) {
+            let api_key: &[u8] = api_key.as_bytes();
+            // 一致したかどうかで処理時間が変わらないよう、すべてのキーと比較する
+            let matched: Option<&ApiKeyConfig> = self
+                .0
+                .api_keys
+                .iter()
+                .fold(None, |matched, config| {
+                    if constant_time_eq(config.key.as_bytes(), api_key) {
+                        Some(config)
+                    } else {
+                        matched
+                    }
+                });
+            return matched
+                .map(|config| Principal {
+                    id: config.id.clone(),
+                    method: AuthMethod::ApiKey,
+                })
+                .ok_or_else(|| unauthorized("invalid API key"));
+        }
+
+        let token: Option<&str> = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match (token, &self.0.jwt) {
+            (Some(token), Some(verifier)) => verifier.verify(token).map_err(unauthorized),
+            (Some(_), None) => Err(unauthorized("bearer tokens are not accepted")),
+            (None, _) => Err(unauthorized("missing credentials")),
+        }
+    }
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unauthorized(detail: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::UNAUTHORIZED,
+        "unauthorized",
+        "Unauthorized",
+        detail,
+    )
+    .with_header(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))
+}
+
+/// 認証が必要な handler の引数
+///
+/// `#[utoipa::path(...)]` には [`API_KEY_SCHEME`] と [`BEARER_SCHEME`] の security と 401 を書く
+///
+/// 利用者の識別子を server span の `enduser.id` に記録する
+///
+/// SigV4 の middleware で検証済みのリクエストでは、その呼び出し元をそのまま使う
+#[derive(Debug, Clone)]
+pub struct Authenticated(pub Principal);
+
+impl<S> FromRequestParts<S> for Authenticated
+where
+    Authenticator: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(principal) = parts.extensions.get::<Principal>() {
+            return Ok(Self(principal.clone()));
+        }
+        let principal: Principal = Authenticator::from_ref(state).authenticate(&parts.headers)?;
+        tracing::Span::current().record(
+            opentelemetry_semantic_conventions::attribute::ENDUSER_ID,
+            principal.id.as_str(),
+        );
+        Ok(Self(principal))
+    }
+}
+
+/// `ApiDocs` に security scheme を追加する
+pub struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            API_KEY_SCHEME,
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))),
+        );
+        components.add_security_scheme(
+            BEARER_SCHEME,
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, body::Body, http::Request, routing::get};
+    use jsonwebtoken::{EncodingKey, Header};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const TESTDATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/auth");
+
+    async fn authenticator() -> Authenticator {
+        Authenticator::load(&AuthConfig {
+            api_keys: vec![
+                ApiKeyConfig {
+                    id: "alice".to_string(),
+                    key: "alice-key".to_string(),
+                },
+                ApiKeyConfig {
+                    id: "bob".to_string(),
+                    key: "bob-key".to_string(),
+                },
+            ],
+            jwt: Some(JwtConfig {
+                jwks_path: Some(format!("{}/jwks.json", TESTDATA)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+        HeaderMap::from_iter([(name, HeaderValue::from_str(value).unwrap())])
+    }
+
+    fn api_key(key: &str) -> HeaderMap {
+        headers(header::HeaderName::from_static(API_KEY_HEADER), key)
+    }
+
+    /// `testdata/auth` の秘密鍵で署名した bearer token の header
+    fn bearer(pem: &str, algorithm: Algorithm, kid: Option<&str>, exp_offset: i64) -> HeaderMap {
+        let pem: Vec<u8> = std::fs::read(format!("{}/{}", TESTDATA, pem)).unwrap();
+        let key: EncodingKey = match algorithm {
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(&pem).unwrap(),
+            _ => EncodingKey::from_ec_pem(&pem).unwrap(),
+        };
+        let header: Header = Header {
+            kid: kid.map(str::to_string),
+            ..Header::new(algorithm)
+        };
+        let exp: i64 = jsonwebtoken::get_current_timestamp() as i64 + exp_offset;
+        let claims = serde_json::json!({ "sub": "carol", "exp": exp });
+        let token: String = jsonwebtoken::encode(&header, &claims, &key).unwrap();
+        headers(header::AUTHORIZATION, &format!("Bearer {}", token))
+    }
+
+    #[test]
+    fn constant_time_eq_compares_length_and_every_byte() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret-longer"));
+        assert!(!constant_time_eq(b"secret", b"secre"));
+    }
+
+    #[tokio::test]
+    async fn api_keys_identify_their_owner() {
+        let authenticator: Authenticator = authenticator().await;
+        for (key, id) in [("alice-key", "alice"), ("bob-key", "bob")] {
+            let principal: Principal = authenticator.authenticate(&api_key(key)).unwrap();
+            assert_eq!(principal.id, id);
+            assert_eq!(principal.method, AuthMethod::ApiKey);
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_api_keys_are_rejected() {
+        let authenticator: Authenticator = authenticator().await;
+        for key in ["carol-key", "alice-ke", "alice-key2", ""] {
+            let err: ApiError = authenticator.authenticate(&api_key(key)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "unauthorized: invalid API key",
+                "{:?}",
+                key
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn jwts_signed_with_a_known_key_are_accepted() {
+        let authenticator: Authenticator = authenticator().await;
+        for headers in [
+            bearer("rsa.pem", Algorithm::RS256, Some("rsa"), 60),
+            bearer("ec.pem", Algorithm::ES256, Some("ec"), 60),
+        ] {
+            let principal: Principal = authenticator.authenticate(&headers).unwrap();
+            assert_eq!(principal.id, "carol");
+            assert_eq!(principal.method, AuthMethod::Jwt);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_jwts_are_rejected() {
+        let authenticator: Authenticator = authenticator().await;
+        for (headers, detail) in [
+            (
+                bearer("rsa_other.pem", Algorithm::RS256, Some("rsa"), 60),
+                "invalid token",
+            ),
+            (
+                bearer("rsa.pem", Algorithm::RS256, Some("rsa"), -3600),
+                "invalid token",
+            ),
+            (
+                bearer("rsa.pem", Algorithm::RS256, None, 60),
+                "token has no kid",
+            ),
+            (
+                bearer("rsa.pem", Algorithm::RS256, Some("other"), 60),
+                "unknown signing key",
+            ),
+            (
+                bearer("ec.pem", Algorithm::ES256, Some("rsa"), 60),
+                "unexpected signing algorithm",
+            ),
+            (
+                bearer("rsa.pem", Algorithm::RS256, Some("ec"), 60),
+                "unexpected signing algorithm",
+            ),
+            (
+                headers(header::AUTHORIZATION, "Bearer not-a-jwt"),
+                "malformed token",
+            ),
+        ] {
+            let err: ApiError = authenticator.authenticate(&headers).unwrap_err();
+            assert_eq!(err.to_string(), format!("unauthorized: {}", detail));
+        }
+    }
+
+    #[tokio::test]
+    async fn bearer_tokens_need_a_jwks() {
+        let authenticator: Authenticator =
+            Authenticator::load(&AuthConfig::default()).await.unwrap();
+        let headers: HeaderMap = bearer("rsa.pem", Algorithm::RS256, Some("rsa"), 60);
+        let err: ApiError = authenticator.authenticate(&headers).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unauthorized: bearer tokens are not accepted"
+        );
+    }
+
+    #[tokio::test]
+    async fn authenticated_rejects_missing_credentials_with_problem_json() {
+        let router: Router = Router::new()
+            .route(
+                "/",
+                get(|Authenticated(principal): Authenticated| async move { principal.id }),
+            )
+            .with_state(authenticator().await);
+        let response = router
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            crate::error::PROBLEM_JSON_CONTENT_TYPE
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], 401);
+        assert_eq!(body["title"], "Unauthorized");
+        assert_eq!(body["detail"], "missing credentials");
+    }
+
+    #[tokio::test]
+    async fn authenticated_reuses_a_principal_verified_by_middleware() {
+        let router: Router = Router::new()
+            .route(
+                "/",
+                get(|Authenticated(principal): Authenticated| async move { principal.id }),
+            )
+            .with_state(authenticator().await);
+        let mut request: Request<Body> = Request::get("/").body(Body::empty()).unwrap();
+        request.extensions_mut().insert(Principal {
+            id: "dave".to_string(),
+            method: AuthMethod::ApiKey,
+        });
+        let response = router.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"dave");
+    }
+
+    #[test]
+    fn operations_that_return_401_declare_security() {
+        let (_, openapi) = crate::hello::create_hello_router()
+            .merge(crate::admin::create_admin_router())
+            .merge(crate::receiver::create_receiver_router(&Default::default()))
+            .split_for_parts();
+        let unauthorized: String = StatusCode::UNAUTHORIZED.as_u16().to_string();
+        let mut secured: Vec<String> = Vec::new();
+        for (path, item) in &openapi.paths.paths {
+            for operation in [&item.get, &item.put, &item.post].into_iter().flatten() {
+                let security = serde_json::to_value(&operation.security).unwrap();
+                if operation.responses.responses.contains_key(&unauthorized) {
+                    assert_eq!(
+                        security,
+                        serde_json::json!([{ API_KEY_SCHEME: [] }, { BEARER_SCHEME: [] }]),
+                        "{}",
+                        path
+                    );
+                    secured.push(path.clone());
+                } else {
+                    assert_eq!(security, serde_json::Value::Null, "{}", path);
+                }
+            }
+        }
+        assert!(secured.contains(&"/hello/remote".to_string()));
+        assert!(!secured.contains(&"/hello".to_string()));
+    }
+}
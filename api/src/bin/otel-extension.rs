@@ -0,0 +1,17 @@
+//! collector layer の代わりに、function の span とログを X-Ray と CloudWatch Logs に送る Lambda extension
+//!
+//! layer の `extensions/otel-extension` に置くと、Lambda が function と並べて起動する
+
+use tracing_subscriber::{Layer, layer::SubscriberExt};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let config: api::config::Config = api::config::Config::load()?;
+    // 自分のログは OTLP で送らず、標準出力から CloudWatch Logs に書く
+    let filter: tracing_subscriber::EnvFilter = config.log.filter.parse()?;
+    let subscriber = tracing_subscriber::registry()
+        .with(api::logging::fmt_layer(&config.log, false).with_filter(filter));
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    api::extension::run(&config).await
+}
@@ -0,0 +1,555 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::AuthConfig;
+use crate::cors::CorsConfig;
+use crate::downstream::PartialDownstreamConfig;
+use crate::emf::EmfConfig;
+use crate::export_queue::ExportQueuesConfig;
+use crate::extension::ExtensionConfig;
+use crate::flush::FlushConfig;
+use crate::receiver::ReceiverConfig;
+use crate::logging::LogConfig;
+use crate::otlp_aws::AwsExportConfig;
+use crate::otlp_retry::RetryConfig;
+use crate::ratelimit::RateLimitConfig;
+use crate::tail_sampling::TailSamplingConfig;
+
+const CONFIG_DIR: &str = "CONFIG_DIR";
+const DEFAULT_CONFIG_DIR: &str = "config";
+const MASKED: &str = "********";
+// --print-config でこれらを含むキーの値を伏せる
+const SECRET_KEY_MARKERS: [&str; 4] = ["secret", "token", "password", "key"];
+
+/// アプリケーションの設定
+///
+/// 既定値 (ビルド時の環境変数を含む) → `{CONFIG_DIR}/{stack}.toml` → 環境変数 の順に上書きする
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// 設定ファイルの選択に使うので、ファイルからは変更できない
+    #[serde(skip_deserializing)]
+    pub stack: String,
+    pub project_name: String,
+    pub server: ServerConfig,
+    pub otel: OtelConfig,
+    pub log: LogConfig,
+    pub auth: AuthConfig,
+    pub cors: CorsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub downstream: BTreeMap<String, PartialDownstreamConfig>,
+    /// クライアントから OTLP を受け取る
+    pub receiver: ReceiverConfig,
+    /// `otel-extension` だけが使う
+    pub extension: ExtensionConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            stack: env!("PULUMI_STACK").to_string(),
+            project_name: env!("PROJECT_NAME").to_string(),
+            server: ServerConfig::default(),
+            otel: OtelConfig::default(),
+            log: LogConfig::default(),
+            auth: AuthConfig::default(),
+            cors: CorsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            downstream: BTreeMap::new(),
+            receiver: ReceiverConfig::default(),
+            extension: ExtensionConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `lambda` feature なしで起動したときの待ち受けアドレス
+    pub bind_address: String,
+    pub api_base_path: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "localhost:3030".to_string(),
+            api_base_path: env!("API_BASE_PATH").to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtelConfig {
+    pub endpoint: String,
+    pub timeout_ms: u64,
+    pub propagators: Vec<Propagator>,
+    pub metrics_exporter: MetricsExporter,
+    /// `metrics_exporter` が `emf` のときの設定
+    pub emf: EmfConfig,
+    /// span とログの送り先
+    pub export_target: ExportTarget,
+    /// `export_target` が `aws` のときの設定
+    pub aws: AwsExportConfig,
+    /// Lambda で invocation の後に flush する方法
+    pub flush: FlushConfig,
+    /// span とログの batch processor の queue
+    pub queues: ExportQueuesConfig,
+    /// span とログの export の再送
+    pub retry: RetryConfig,
+    /// trace の終わりに残すかどうかを決める sampling
+    pub tail_sampling: TailSamplingConfig,
+}
+
+impl Default for OtelConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://localhost:4317".to_string(),
+            timeout_ms: 3000,
+            propagators: vec![Propagator::TraceContext],
+            // collector layer は metrics を受け付けないので、Lambda では EMF で書く
+            metrics_exporter: if cfg!(feature = "lambda") {
+                MetricsExporter::Emf
+            } else {
+                MetricsExporter::Otlp
+            },
+            emf: EmfConfig::default(),
+            export_target: ExportTarget::Collector,
+            aws: AwsExportConfig::default(),
+            flush: FlushConfig::default(),
+            queues: ExportQueuesConfig::default(),
+            retry: RetryConfig::default(),
+            tail_sampling: TailSamplingConfig::default(),
+        }
+    }
+}
+
+impl OtelConfig {
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.timeout_ms)
+    }
+
+    /// X-Ray の propagator を使うときは、trace ID も X-Ray の形式で生成してログに出す
+    pub fn xray_enabled(&self) -> bool {
+        self.propagators.contains(&Propagator::Xray)
+    }
+}
+
+/// `OTEL_PROPAGATORS` と同じ名前で指定する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Propagator {
+    /// W3C Trace Context (`traceparent`)
+    TraceContext,
+    /// AWS X-Ray (`X-Amzn-Trace-Id`)
+    Xray,
+}
+
+impl std::str::FromStr for Propagator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tracecontext" => Ok(Propagator::TraceContext),
+            "xray" => Ok(Propagator::Xray),
+            _ => anyhow::bail!("unsupported propagator `{}` (tracecontext or xray)", s),
+        }
+    }
+}
+
+/// `OTEL_METRICS_EXPORTER` と同じ名前で指定する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsExporter {
+    /// collector に OTLP で送る
+    Otlp,
+    /// CloudWatch Embedded Metric Format で標準出力に書く
+    Emf,
+}
+
+impl std::str::FromStr for MetricsExporter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "otlp" => Ok(MetricsExporter::Otlp),
+            "emf" => Ok(MetricsExporter::Emf),
+            _ => anyhow::bail!("unsupported metrics exporter `{}` (otlp or emf)", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportTarget {
+    /// `endpoint` の collector に OTLP/gRPC で送る
+    Collector,
+    /// X-Ray と CloudWatch Logs の OTLP endpoint に SigV4 で署名して直接送る
+    Aws,
+}
+
+impl std::str::FromStr for ExportTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "collector" => Ok(ExportTarget::Collector),
+            "aws" => Ok(ExportTarget::Aws),
+            _ => anyhow::bail!("unsupported export target `{}` (collector or aws)", s),
+        }
+    }
+}
+
+/// 設定を上書きする環境変数
+///
+/// テストでは process の環境変数を変えずに `Env::from_iter` で値を渡す
+#[derive(Debug, Clone, Default)]
+pub struct Env(BTreeMap<String, String>);
+
+impl Env {
+    /// process の環境変数。UTF-8 でない変数は無視する
+    pub fn from_process() -> Self {
+        std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Env {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(vars: I) -> Self {
+        Self(
+            vars.into_iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+        )
+    }
+}
+
+impl Config {
+    pub fn load() -> anyhow::Result<Self> {
+        Self::load_from(&Env::from_process())
+    }
+
+    fn load_from(env: &Env) -> anyhow::Result<Self> {
+        let stack: String = env
+            .get("PULUMI_STACK")
+            .filter(|stack| !stack.is_empty())
+            .unwrap_or(env!("PULUMI_STACK"))
+            .to_string();
+
+        let mut config: Config = Self::read_stack_file(&Self::stack_file_path(env, &stack))?
+            .unwrap_or_default();
+        config.stack = stack;
+        config.apply_env_overrides(env)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn stack_file_path(env: &Env, stack: &str) -> PathBuf {
+        let config_dir: &str = env.get(CONFIG_DIR).unwrap_or(DEFAULT_CONFIG_DIR);
+        PathBuf::from(config_dir).join(format!("{}.toml", stack))
+    }
+
+    fn read_stack_file(path: &Path) -> anyhow::Result<Option<Config>> {
+        let content: String = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to read config `{}`", path.display()));
+            }
+        };
+        let config: Config = toml::from_str(&content)
+            .with_context(|| format!("failed to parse config `{}`", path.display()))?;
+        Ok(Some(config))
+    }
+
+    fn apply_env_overrides(&mut self, env: &Env) -> anyhow::Result<()> {
+        if let Some(project_name) = env.get("PROJECT_NAME")
+            && !project_name.is_empty()
+        {
+            self.project_name = project_name.to_string();
+        }
+        if let Some(bind_address) = env.get("BIND_ADDRESS") {
+            self.server.bind_address = bind_address.to_string();
+        }
+        if let Some(api_base_path) = env.get("API_BASE_PATH") {
+            self.server.api_base_path = api_base_path.to_string();
+        }
+        if let Some(endpoint) = env.get("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            self.otel.endpoint = endpoint.to_string();
+        }
+        if let Some(timeout_ms) = env.get("OTEL_EXPORTER_OTLP_TIMEOUT") {
+            self.otel.timeout_ms = timeout_ms
+                .parse()
+                .context("invalid value in `OTEL_EXPORTER_OTLP_TIMEOUT`")?;
+        }
+        if let Some(propagators) = env.get("OTEL_PROPAGATORS") {
+            self.otel.propagators = propagators
+                .split(',')
+                .map(str::trim)
+                .filter(|propagator| !propagator.is_empty())
+                .map(str::parse)
+                .collect::<anyhow::Result<_>>()
+                .context("invalid value in `OTEL_PROPAGATORS`")?;
+        }
+        if let Some(metrics_exporter) = env.get("OTEL_METRICS_EXPORTER") {
+            self.otel.metrics_exporter = metrics_exporter
+                .trim()
+                .parse()
+                .context("invalid value in `OTEL_METRICS_EXPORTER`")?;
+        }
+        if let Some(export_target) = env.get("OTEL_EXPORT_TARGET") {
+            self.otel.export_target = export_target
+                .trim()
+                .parse()
+                .context("invalid value in `OTEL_EXPORT_TARGET`")?;
+        }
+        if let Some(strategy) = env.get("OTEL_LAMBDA_FLUSH_STRATEGY") {
+            self.otel.flush.strategy = strategy
+                .trim()
+                .parse()
+                .context("invalid value in `OTEL_LAMBDA_FLUSH_STRATEGY`")?;
+        }
+        self.otel.aws.apply_env_overrides(env);
+        self.otel.queues.apply_env_overrides(env)?;
+        self.log.apply_env_overrides(env)?;
+        self.auth.apply_env_overrides(env)?;
+        crate::downstream::apply_env_overrides(&mut self.downstream, env)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !self.server.api_base_path.starts_with('/') {
+            anyhow::bail!(
+                "server.api_base_path must start with `/`, got `{}`",
+                self.server.api_base_path
+            );
+        }
+        if self.otel.timeout_ms == 0 {
+            anyhow::bail!("otel.timeout_ms must be positive");
+        }
+        if self.auth.sigv4.enabled
+            && (self.auth.sigv4.region.is_empty() || self.auth.sigv4.service.is_empty())
+        {
+            anyhow::bail!("auth.sigv4.region and auth.sigv4.service are required when SigV4 is enabled");
+        }
+        if self.otel.export_target == ExportTarget::Aws {
+            self.otel.aws.validate()?;
+            // AWS には metrics の OTLP endpoint がない
+            if self.otel.metrics_exporter != MetricsExporter::Emf {
+                anyhow::bail!("otel.metrics_exporter must be `emf` when otel.export_target is `aws`");
+            }
+        }
+        self.otel.flush.validate()?;
+        self.otel.queues.validate()?;
+        self.otel.retry.validate()?;
+        self.otel.tail_sampling.validate()?;
+        self.otel.emf.validate()?;
+        self.receiver.validate()?;
+        self.rate_limit.validate()?;
+        self.log.validate()?;
+        Ok(())
+    }
+
+    /// `--print-config` 用に、秘密情報を伏せた TOML を返す
+    pub fn to_masked_toml(&self) -> anyhow::Result<String> {
+        let mut value: toml::Value = toml::Value::try_from(self)?;
+        mask_secrets(&mut value);
+        Ok(toml::to_string_pretty(&value)?)
+    }
+}
+
+fn mask_secrets(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                let key: String = key.to_lowercase();
+                if SECRET_KEY_MARKERS.iter().any(|marker| key.contains(marker))
+                    && !value.is_table()
+                    && !value.is_array()
+                {
+                    *value = toml::Value::String(MASKED.to_string());
+                } else {
+                    mask_secrets(value);
+                }
+            }
+        }
+        toml::Value::Array(array) => array.iter_mut().for_each(mask_secrets),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `{name}` の一時ディレクトリに `test.toml` を書く
+    fn config_dir(name: &str, content: Option<&str>) -> PathBuf {
+        let directory: PathBuf =
+            std::env::temp_dir().join(format!("config-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        if let Some(content) = content {
+            std::fs::write(directory.join("test.toml"), content).unwrap();
+        }
+        directory
+    }
+
+    fn load(directory: &Path, vars: &[(&str, &str)]) -> anyhow::Result<Config> {
+        let directory: &str = directory.to_str().unwrap();
+        Config::load_from(&Env::from_iter(
+            [(CONFIG_DIR, directory), ("PULUMI_STACK", "test")]
+                .into_iter()
+                .chain(vars.iter().copied()),
+        ))
+    }
+
+    fn load_err(vars: &[(&str, &str)]) -> String {
+        let directory: PathBuf = config_dir("invalid", None);
+        format!("{:#}", load(&directory, vars).unwrap_err())
+    }
+
+    #[test]
+    fn stack_file_overrides_defaults_and_env_overrides_the_file() {
+        let directory: PathBuf = config_dir(
+            "layers",
+            Some(
+                r#"
+                [server]
+                bind_address = "0.0.0.0:8080"
+
+                [otel]
+                timeout_ms = 5000
+
+                [log]
+                filter = "debug"
+                "#,
+            ),
+        );
+        let config: Config = load(
+            &directory,
+            &[
+                ("OTEL_EXPORTER_OTLP_TIMEOUT", "7000"),
+                ("LOG_OTLP_FILTER", "warn"),
+                ("OTEL_BSP_MAX_QUEUE_SIZE", "4096"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.stack, "test");
+        // 既定値
+        assert_eq!(config.server.api_base_path, env!("API_BASE_PATH"));
+        assert_eq!(config.otel.endpoint, "http://localhost:4317");
+        // `test.toml`
+        assert_eq!(config.server.bind_address, "0.0.0.0:8080");
+        assert_eq!(config.log.filter, "debug");
+        // 環境変数
+        assert_eq!(config.otel.timeout_ms, 7000);
+        assert_eq!(config.log.otlp_filter, "warn");
+        assert_eq!(config.otel.queues.spans.max_queue_size, 4096);
+    }
+
+    #[test]
+    fn missing_stack_file_uses_defaults() {
+        let config: Config = load(&config_dir("missing", None), &[]).unwrap();
+        assert_eq!(config.stack, "test");
+        assert_eq!(config.server.bind_address, "localhost:3030");
+        assert_eq!(config.otel.timeout_ms, 3000);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let directory: PathBuf = config_dir(
+            "unknown",
+            Some("[server]\nbind_adress = \"0.0.0.0:8080\"\n"),
+        );
+        let err: String = format!("{:#}", load(&directory, &[]).unwrap_err());
+        assert!(err.contains("test.toml"), "{}", err);
+        assert!(err.contains("unknown field `bind_adress`"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        for (vars, expected) in [
+            (
+                &[("API_BASE_PATH", "api")][..],
+                "server.api_base_path must start with `/`",
+            ),
+            (
+                &[("OTEL_EXPORTER_OTLP_TIMEOUT", "0")],
+                "otel.timeout_ms must be positive",
+            ),
+            (
+                &[("OTEL_EXPORTER_OTLP_TIMEOUT", "soon")],
+                "invalid value in `OTEL_EXPORTER_OTLP_TIMEOUT`",
+            ),
+            (
+                &[
+                    ("OTEL_EXPORT_TARGET", "aws"),
+                    ("OTEL_METRICS_EXPORTER", "otlp"),
+                    ("AWS_REGION", "ap-northeast-1"),
+                    ("AWS_LAMBDA_LOG_GROUP_NAME", "/aws/lambda/api"),
+                ],
+                "otel.metrics_exporter must be `emf`",
+            ),
+            (&[("OTEL_BSP_MAX_QUEUE_SIZE", "0")], "must be positive"),
+            (&[("LOG_FORMAT", "xml")], "invalid value in `LOG_FORMAT`"),
+        ] {
+            let err: String = load_err(vars);
+            assert!(err.contains(expected), "{:?}: {}", vars, err);
+        }
+    }
+
+    #[test]
+    fn masked_toml_hides_secrets() {
+        let directory: PathBuf = config_dir(
+            "masked",
+            Some(
+                r#"
+                [[auth.api_keys]]
+                id = "web"
+                key = "api-key-value"
+
+                [auth.sigv4]
+                [[auth.sigv4.credentials]]
+                access_key_id = "AKIDEXAMPLE"
+                secret_access_key = "secret-access-key-value"
+                principal = "arn:aws:iam::123456789012:role/web"
+                "#,
+            ),
+        );
+        let toml: String = load(&directory, &[]).unwrap().to_masked_toml().unwrap();
+        assert!(!toml.contains("api-key-value"), "{}", toml);
+        assert!(!toml.contains("secret-access-key-value"), "{}", toml);
+        assert!(toml.contains(MASKED), "{}", toml);
+        assert!(toml.contains("id = \"web\""), "{}", toml);
+        assert!(
+            toml.contains("arn:aws:iam::123456789012:role/web"),
+            "{}",
+            toml
+        );
+    }
+
+    #[test]
+    fn dev_config_is_valid() {
+        let config: Config = Config::load_from(&Env::from_iter([
+            (CONFIG_DIR, concat!(env!("CARGO_MANIFEST_DIR"), "/config")),
+            ("PULUMI_STACK", "dev"),
+        ]))
+        .unwrap();
+        assert_eq!(config.cors.api.allowed_methods, ["GET", "POST", "PUT"]);
+    }
+}
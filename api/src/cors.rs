@@ -0,0 +1,326 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use axum::{
+    Router,
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, header},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{KeyValue, metrics::Counter};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// ルーターごとの CORS ポリシー
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub api: CorsPolicyConfig,
+    /// Scalar のドキュメント。既定では同一 origin からのみ読める
+    pub docs: CorsPolicyConfig,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            api: CorsPolicyConfig {
+                allowed_origins: vec!["*".to_string()],
+                ..CorsPolicyConfig::default()
+            },
+            docs: CorsPolicyConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsPolicyConfig {
+    /// `*` はホスト名やポートの一部に一致する (例: `https://*.example.com`、`http://localhost:*`)
+    ///
+    /// `"*"` だけを書くとすべての origin を許可する
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// ブラウザのスクリプトに読ませるレスポンスヘッダー
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsPolicyConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec![
+                header::CONTENT_TYPE.to_string(),
+                header::AUTHORIZATION.to_string(),
+                crate::auth::API_KEY_HEADER.to_string(),
+                crate::request_id::REQUEST_ID_HEADER.to_string(),
+                crate::admin::DEBUG_LOG_HEADER.to_string(),
+            ],
+            exposed_headers: vec![
+                crate::request_id::REQUEST_ID_HEADER.to_string(),
+                header::RETRY_AFTER.to_string(),
+            ],
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum OriginPattern {
+    Any,
+    Exact(String),
+    Wildcard(Regex),
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> anyhow::Result<Self> {
+        if pattern == "*" {
+            return Ok(OriginPattern::Any);
+        }
+        if !pattern.contains('*') {
+            return Ok(OriginPattern::Exact(pattern.to_string()));
+        }
+        let regex: String = format!(
+            "^{}$",
+            regex::escape(pattern).replace(r"\*", "[A-Za-z0-9.-]+")
+        );
+        Ok(OriginPattern::Wildcard(Regex::new(&regex)?))
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(exact) => exact == origin,
+            OriginPattern::Wildcard(regex) => regex.is_match(origin),
+        }
+    }
+}
+
+/// 設定から作った CORS ポリシー
+///
+/// [`CorsPolicy::apply`] でルーターに `CorsLayer` と、拒否した preflight を記録する middleware を追加する
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    name: &'static str,
+    origins: Arc<Vec<OriginPattern>>,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    exposed_headers: Vec<HeaderName>,
+    allow_credentials: bool,
+    max_age: Duration,
+    rejected_preflights: Counter<u64>,
+}
+
+impl CorsPolicy {
+    pub fn new(name: &'static str, config: &CorsPolicyConfig) -> anyhow::Result<Self> {
+        Self::parse(name, config).with_context(|| format!("invalid cors.{}", name))
+    }
+
+    fn parse(name: &'static str, config: &CorsPolicyConfig) -> anyhow::Result<Self> {
+        let origins: Vec<OriginPattern> = config
+            .allowed_origins
+            .iter()
+            .map(|pattern| {
+                OriginPattern::parse(pattern)
+                    .with_context(|| format!("invalid origin pattern `{}`", pattern))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        if config.allow_credentials
+            && origins
+                .iter()
+                .any(|origin| matches!(origin, OriginPattern::Any))
+        {
+            bail!("allow_credentials cannot be combined with allowed_origins = [\"*\"]");
+        }
+        let methods: Vec<Method> = config
+            .allowed_methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.to_uppercase().as_bytes())
+                    .with_context(|| format!("invalid method `{}`", method))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let header_names = |names: &[String]| -> anyhow::Result<Vec<HeaderName>> {
+            names
+                .iter()
+                .map(|name| {
+                    HeaderName::from_bytes(name.as_bytes())
+                        .with_context(|| format!("invalid header name `{}`", name))
+                })
+                .collect()
+        };
+        let headers: Vec<HeaderName> = header_names(&config.allowed_headers)?;
+        let exposed_headers: Vec<HeaderName> = header_names(&config.exposed_headers)?;
+        let rejected_preflights: Counter<u64> =
+            opentelemetry::global::meter_with_scope(crate::otel::init_scope())
+                .u64_counter("http.server.cors.rejected_preflights")
+                .with_unit("{request}")
+                .with_description("Number of CORS preflight requests the browser will reject.")
+                .build();
+        Ok(Self {
+            name,
+            origins: Arc::new(origins),
+            methods,
+            headers,
+            exposed_headers,
+            allow_credentials: config.allow_credentials,
+            max_age: Duration::from_secs(config.max_age_secs),
+            rejected_preflights,
+        })
+    }
+
+    fn layer(&self) -> CorsLayer {
+        let origins: Arc<Vec<OriginPattern>> = self.origins.clone();
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::predicate(move |origin, _| {
+                allows_origin(&origins, origin)
+            }))
+            .allow_methods(self.methods.clone())
+            .allow_headers(self.headers.clone())
+            .expose_headers(self.exposed_headers.clone())
+            .allow_credentials(self.allow_credentials)
+            .max_age(self.max_age)
+    }
+
+    /// preflight をブラウザが拒否する理由。許可される場合は `None`
+    fn preflight_rejection(
+        &self,
+        headers: &HeaderMap,
+        origin: &HeaderValue,
+    ) -> Option<&'static str> {
+        if !allows_origin(&self.origins, origin) {
+            return Some("origin");
+        }
+        let method_allowed: bool = headers
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+            .is_some_and(|method| self.methods.contains(&method));
+        if !method_allowed {
+            return Some("method");
+        }
+        let headers_allowed: bool = headers
+            .get_all(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .all(|name| {
+                self.headers
+                    .iter()
+                    .any(|allowed| allowed.as_str().eq_ignore_ascii_case(name))
+            });
+        if !headers_allowed {
+            return Some("headers");
+        }
+        None
+    }
+
+    pub fn apply<S>(&self, router: Router<S>) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        // preflight には CorsLayer が応答するので、記録する middleware をその外側に置く
+        router
+            .layer(self.layer())
+            .layer(axum::middleware::from_fn_with_state(
+                self.clone(),
+                record_rejected_preflight,
+            ))
+    }
+}
+
+fn allows_origin(origins: &[OriginPattern], origin: &HeaderValue) -> bool {
+    origin
+        .to_str()
+        .is_ok_and(|origin| origins.iter().any(|pattern| pattern.matches(origin)))
+}
+
+async fn record_rejected_preflight(
+    State(policy): State<CorsPolicy>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        && let Some(origin) = request.headers().get(header::ORIGIN)
+        && let Some(reason) = policy.preflight_rejection(request.headers(), origin)
+    {
+        tracing::warn!(
+            cors.policy = policy.name,
+            cors.origin = String::from_utf8_lossy(origin.as_bytes()).as_ref(),
+            cors.rejection_reason = reason,
+            "Rejected CORS preflight"
+        );
+        policy.rejected_preflights.add(
+            1,
+            &[
+                KeyValue::new("cors.policy", policy.name),
+                KeyValue::new("cors.rejection_reason", reason),
+            ],
+        );
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn send(request: axum::http::Request<Body>) -> Response {
+        let policy: CorsPolicy = CorsPolicy::new("api", &CorsConfig::default().api).unwrap();
+        policy
+            .apply(Router::new().route("/hello", get(|| async { "hello" })))
+            .oneshot(request)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn default_policy_allows_request_id_and_debug_log_headers() {
+        let response: Response = send(
+            axum::http::Request::options("/hello")
+                .header(header::ORIGIN, "https://app.example.com")
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                .header(
+                    header::ACCESS_CONTROL_REQUEST_HEADERS,
+                    "x-request-id,x-debug-log",
+                )
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        let allowed: &str = response.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap();
+        assert!(allowed.contains("x-request-id"), "{}", allowed);
+        assert!(allowed.contains("x-debug-log"), "{}", allowed);
+    }
+
+    #[tokio::test]
+    async fn default_policy_exposes_request_id() {
+        let response: Response = send(
+            axum::http::Request::get("/hello")
+                .header(header::ORIGIN, "https://app.example.com")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        let exposed: &str = response.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+            .to_str()
+            .unwrap();
+        assert!(exposed.contains("x-request-id"), "{}", exposed);
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::{Context, anyhow, bail};
+use reqwest::{
+    Url,
+    header::{AUTHORIZATION, HeaderMap, HeaderValue},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Env;
+use crate::outbound::{Deadline, OutboundClient, OutboundError};
+
+const DOWNSTREAM_ENV_PREFIX: &str = "DOWNSTREAM_";
+
+const DEFAULT_BASE_PATH: &str = "/api";
+const DEFAULT_API_VERSION: u32 = 0;
+const DEFAULT_TIMEOUT_MS: u64 = 3000;
+const MAX_TIMEOUT_MS: u64 = 30_000;
+
+/// 呼び出し先の認証方式
+///
+/// 設定では `none` または `bearer:<トークンを持つ環境変数名>` と書く
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum AuthMode {
+    #[default]
+    None,
+    Bearer { token_env: String },
+}
+
+impl std::str::FromStr for AuthMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "none" => Ok(AuthMode::None),
+            Some(("bearer", token_env)) if !token_env.is_empty() => Ok(AuthMode::Bearer {
+                token_env: token_env.to_string(),
+            }),
+            _ => bail!("auth must be `none` or `bearer:<ENV_NAME>`, got `{}`", s),
+        }
+    }
+}
+
+impl std::fmt::Display for AuthMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthMode::None => write!(f, "none"),
+            AuthMode::Bearer { token_env } => write!(f, "bearer:{}", token_env),
+        }
+    }
+}
+
+impl Serialize for AuthMode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for AuthMode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value: String = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// 設定ファイルと環境変数から読んだ、検証前の呼び出し先の設定
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartialDownstreamConfig {
+    base_url: Option<String>,
+    base_path: Option<String>,
+    api_version: Option<u32>,
+    timeout_ms: Option<u64>,
+    auth: Option<AuthMode>,
+}
+
+impl PartialDownstreamConfig {
+    fn merge(&mut self, other: PartialDownstreamConfig) {
+        self.base_url = other.base_url.or(self.base_url.take());
+        self.base_path = other.base_path.or(self.base_path.take());
+        self.api_version = other.api_version.or(self.api_version);
+        self.timeout_ms = other.timeout_ms.or(self.timeout_ms);
+        self.auth = other.auth.or(self.auth.take());
+    }
+
+    const ENV_FIELDS: [&'static str; 5] =
+        ["BASE_URL", "BASE_PATH", "API_VERSION", "TIMEOUT_MS", "AUTH"];
+
+    fn set_from_env(&mut self, field: &str, value: String) -> anyhow::Result<()> {
+        match field {
+            "BASE_URL" => self.base_url = Some(value),
+            "BASE_PATH" => self.base_path = Some(value),
+            "API_VERSION" => self.api_version = Some(value.parse()?),
+            "TIMEOUT_MS" => self.timeout_ms = Some(value.parse()?),
+            "AUTH" => self.auth = Some(value.parse()?),
+            _ => unreachable!("unknown downstream env field `{}`", field),
+        }
+        Ok(())
+    }
+
+    fn validate(self, name: &str) -> anyhow::Result<DownstreamConfig> {
+        let base_url: String = self
+            .base_url
+            .ok_or_else(|| anyhow!("base_url is required"))?;
+        let base_url: Url = Url::parse(base_url.trim_end_matches('/'))
+            .with_context(|| format!("invalid base_url `{}`", base_url))?;
+        if !matches!(base_url.scheme(), "http" | "https") {
+            bail!("base_url must be http or https, got `{}`", base_url);
+        }
+        let base_path: String = self
+            .base_path
+            .unwrap_or_else(|| DEFAULT_BASE_PATH.to_string());
+        if !base_path.starts_with('/') || base_path.ends_with('/') {
+            bail!("base_path must start with `/` and must not end with `/`, got `{}`", base_path);
+        }
+        let timeout_ms: u64 = self.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
+        if timeout_ms == 0 || timeout_ms > MAX_TIMEOUT_MS {
+            bail!("timeout_ms must be between 1 and {}, got {}", MAX_TIMEOUT_MS, timeout_ms);
+        }
+        let auth: AuthMode = self.auth.unwrap_or_default();
+        if let AuthMode::Bearer { token_env } = &auth
+            && std::env::var(token_env).unwrap_or_default().is_empty()
+        {
+            bail!("environment variable `{}` for bearer token is not set", token_env);
+        }
+        Ok(DownstreamConfig {
+            name: name.to_string(),
+            base_url,
+            base_path,
+            api_version: self.api_version.unwrap_or(DEFAULT_API_VERSION),
+            timeout: Duration::from_millis(timeout_ms),
+            auth,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DownstreamConfig {
+    pub name: String,
+    pub base_url: Url,
+    pub base_path: String,
+    pub api_version: u32,
+    pub timeout: Duration,
+    pub auth: AuthMode,
+}
+
+impl DownstreamConfig {
+    /// `{base_url}{base_path}/v{api_version}{path}`
+    pub fn url(&self, path: &str) -> String {
+        format!(
+            "{}{}/v{}{}",
+            self.base_url.as_str().trim_end_matches('/'),
+            self.base_path,
+            self.api_version,
+            path
+        )
+    }
+
+    pub fn validate_all(
+        partials: &BTreeMap<String, PartialDownstreamConfig>,
+    ) -> anyhow::Result<BTreeMap<String, DownstreamConfig>> {
+        partials
+            .iter()
+            .map(|(name, partial)| {
+                let config: DownstreamConfig = partial
+                    .clone()
+                    .validate(name)
+                    .with_context(|| format!("invalid downstream service `{}`", name))?;
+                Ok((name.clone(), config))
+            })
+            .collect()
+    }
+}
+
+/// `DOWNSTREAM_<NAME>_<FIELD>` 環境変数で呼び出し先 `<name>` の設定を上書きする
+pub fn apply_env_overrides(
+    partials: &mut BTreeMap<String, PartialDownstreamConfig>,
+    env: &Env,
+) -> anyhow::Result<()> {
+    for (key, value) in env.iter() {
+        let Some(rest) = key.strip_prefix(DOWNSTREAM_ENV_PREFIX) else {
+            continue;
+        };
+        let Some((name, field)) = PartialDownstreamConfig::ENV_FIELDS.iter().find_map(|field| {
+            rest.strip_suffix(field)
+                .and_then(|name| name.strip_suffix('_'))
+                .map(|name| (name.to_lowercase(), *field))
+        }) else {
+            continue;
+        };
+        let mut partial: PartialDownstreamConfig = PartialDownstreamConfig::default();
+        partial
+            .set_from_env(field, value.to_string())
+            .with_context(|| format!("invalid value in `{}`", key))?;
+        partials.entry(name).or_default().merge(partial);
+    }
+    Ok(())
+}
+
+/// 設定済みの呼び出し先と、その呼び出し先専用のクライアント
+#[derive(Debug, Clone)]
+pub struct DownstreamService {
+    config: DownstreamConfig,
+    client: OutboundClient,
+    auth_header: Option<HeaderValue>,
+}
+
+impl DownstreamService {
+    pub fn new(config: DownstreamConfig, client: &OutboundClient) -> anyhow::Result<Self> {
+        let auth_header: Option<HeaderValue> = match &config.auth {
+            AuthMode::None => None,
+            AuthMode::Bearer { token_env } => {
+                let token: String = std::env::var(token_env)
+                    .with_context(|| format!("environment variable `{}` is not set", token_env))?;
+                let mut value: HeaderValue = HeaderValue::from_str(&format!("Bearer {}", token))
+                    .context("bearer token is not a valid header value")?;
+                value.set_sensitive(true);
+                Some(value)
+            }
+        };
+        Ok(Self {
+            client: client.for_service(config.timeout),
+            config,
+            auth_header,
+        })
+    }
+
+    pub async fn get(
+        &self,
+        path: &str,
+        deadline: Deadline,
+    ) -> Result<reqwest::Response, OutboundError> {
+        let mut headers: HeaderMap = HeaderMap::new();
+        if let Some(auth_header) = &self.auth_header {
+            headers.insert(AUTHORIZATION, auth_header.clone());
+        }
+        self.client
+            .get(&self.config.url(path), headers, deadline)
+            .await
+    }
+}
+
+/// 起動時に検証した呼び出し先の一覧
+#[derive(Debug, Clone, Default)]
+pub struct DownstreamServices(BTreeMap<String, DownstreamService>);
+
+impl DownstreamServices {
+    pub fn load(
+        partials: &BTreeMap<String, PartialDownstreamConfig>,
+        client: &OutboundClient,
+    ) -> anyhow::Result<Self> {
+        let services: BTreeMap<String, DownstreamService> = DownstreamConfig::validate_all(partials)?
+            .into_iter()
+            .map(|(name, config)| Ok((name, DownstreamService::new(config, client)?)))
+            .collect::<anyhow::Result<_>>()?;
+        for service in services.values() {
+            tracing::info!(
+                downstream.name = service.config.name,
+                downstream.url = service.config.url(""),
+                "Configured downstream service"
+            );
+        }
+        Ok(Self(services))
+    }
+
+    pub fn require(&self, name: &str) -> anyhow::Result<DownstreamService> {
+        self.0.get(name).cloned().ok_or_else(|| {
+            anyhow!(
+                "downstream service `{}` is not configured (set {}{}_BASE_URL or add [downstream.{}] to the config file)",
+                name,
+                DOWNSTREAM_ENV_PREFIX,
+                name.to_uppercase(),
+                name,
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(base_url: &str) -> PartialDownstreamConfig {
+        PartialDownstreamConfig {
+            base_url: Some(base_url.to_string()),
+            ..PartialDownstreamConfig::default()
+        }
+    }
+
+    fn validate_err(partial: PartialDownstreamConfig) -> String {
+        format!("{:#}", partial.validate("remote").unwrap_err())
+    }
+
+    #[test]
+    fn env_overrides_replace_fields_of_the_named_service() {
+        let mut partials: BTreeMap<String, PartialDownstreamConfig> = BTreeMap::from([(
+            "remote".to_string(),
+            PartialDownstreamConfig {
+                timeout_ms: Some(1000),
+                ..partial("http://localhost:3030")
+            },
+        )]);
+        apply_env_overrides(
+            &mut partials,
+            &Env::from_iter([
+                ("DOWNSTREAM_REMOTE_BASE_URL", "https://remote.example.com/"),
+                ("DOWNSTREAM_REMOTE_API_VERSION", "2"),
+                ("DOWNSTREAM_USER_PROFILE_BASE_PATH", "/profile"),
+                ("DOWNSTREAM_USER_PROFILE_BASE_URL", "http://profile:8080"),
+                ("UNRELATED_BASE_URL", "http://ignored"),
+                ("DOWNSTREAM_REMOTE_UNKNOWN", "ignored"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            partials.keys().collect::<Vec<_>>(),
+            ["remote", "user_profile"]
+        );
+
+        let configs: BTreeMap<String, DownstreamConfig> =
+            DownstreamConfig::validate_all(&partials).unwrap();
+        let remote: &DownstreamConfig = &configs["remote"];
+        assert_eq!(
+            remote.url("/hello"),
+            "https://remote.example.com/api/v2/hello"
+        );
+        assert_eq!(remote.timeout, Duration::from_millis(1000));
+        assert_eq!(
+            configs["user_profile"].url("/me"),
+            "http://profile:8080/profile/v0/me"
+        );
+    }
+
+    #[test]
+    fn invalid_env_overrides_name_the_variable() {
+        let mut partials: BTreeMap<String, PartialDownstreamConfig> = BTreeMap::new();
+        let err: anyhow::Error = apply_env_overrides(
+            &mut partials,
+            &Env::from_iter([("DOWNSTREAM_REMOTE_TIMEOUT_MS", "soon")]),
+        )
+        .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("DOWNSTREAM_REMOTE_TIMEOUT_MS"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn validate_rejects_invalid_fields() {
+        assert!(validate_err(PartialDownstreamConfig::default()).contains("base_url is required"));
+        assert!(validate_err(partial("not a url")).contains("invalid base_url"));
+        assert!(validate_err(partial("ftp://remote")).contains("http or https"));
+        assert!(
+            validate_err(PartialDownstreamConfig {
+                base_path: Some("api/".to_string()),
+                ..partial("http://remote")
+            })
+            .contains("base_path")
+        );
+        for timeout_ms in [0, MAX_TIMEOUT_MS + 1] {
+            assert!(
+                validate_err(PartialDownstreamConfig {
+                    timeout_ms: Some(timeout_ms),
+                    ..partial("http://remote")
+                })
+                .contains("timeout_ms")
+            );
+        }
+        assert!(
+            validate_err(PartialDownstreamConfig {
+                auth: Some(
+                    "bearer:DOWNSTREAM_TEST_TOKEN_THAT_IS_NOT_SET"
+                        .parse()
+                        .unwrap()
+                ),
+                ..partial("http://remote")
+            })
+            .contains("DOWNSTREAM_TEST_TOKEN_THAT_IS_NOT_SET")
+        );
+    }
+
+    #[test]
+    fn validate_fills_in_defaults() {
+        let config: DownstreamConfig = partial("http://remote/").validate("remote").unwrap();
+        assert_eq!(config.url("/hello"), "http://remote/api/v0/hello");
+        assert_eq!(config.timeout, Duration::from_millis(DEFAULT_TIMEOUT_MS));
+        assert_eq!(config.auth, AuthMode::None);
+    }
+
+    #[test]
+    fn auth_modes_round_trip() {
+        for auth in ["none", "bearer:REMOTE_TOKEN"] {
+            assert_eq!(auth.parse::<AuthMode>().unwrap().to_string(), auth);
+        }
+        for auth in ["bearer:", "basic:user", ""] {
+            assert!(auth.parse::<AuthMode>().is_err(), "{}", auth);
+        }
+    }
+
+    #[test]
+    fn require_names_the_missing_service_and_how_to_configure_it() {
+        let services: DownstreamServices = DownstreamServices::load(
+            &BTreeMap::from([("other".to_string(), partial("http://other"))]),
+            &OutboundClient::new(),
+        )
+        .unwrap();
+        assert!(services.require("other").is_ok());
+        let err: String = services.require("remote").unwrap_err().to_string();
+        assert!(err.contains("DOWNSTREAM_REMOTE_BASE_URL"), "{}", err);
+        assert!(err.contains("[downstream.remote]"), "{}", err);
+    }
+}
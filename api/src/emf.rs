@@ -0,0 +1,780 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use opentelemetry::{Key, KeyValue};
+use opentelemetry_proto::tonic::{
+    collector::metrics::v1::ExportMetricsServiceRequest, common::v1 as proto_common,
+    metrics::v1 as proto,
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::{OTelSdkError, OTelSdkResult},
+    metrics::{
+        Temporality,
+        data::{AggregatedMetrics, Metric, MetricData, ResourceMetrics},
+        exporter::PushMetricExporter,
+    },
+};
+use serde::{Deserialize, Serialize};
+use opentelemetry_semantic_conventions::attribute;
+use serde_json::{Map, Value, json};
+
+// CloudWatch が 1 つの document で受け付ける上限
+const MAX_DIMENSIONS: usize = 30;
+const MAX_METRICS_PER_DOCUMENT: usize = 100;
+// EMF のメタデータを入れる document のキー
+const METADATA_KEY: &str = "_aws";
+
+/// CloudWatch Embedded Metric Format で標準出力に書く metrics exporter の設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmfConfig {
+    /// CloudWatch の namespace。空なら resource の `service.namespace`
+    pub namespace: String,
+    /// dimension にする resource の属性
+    pub dimensions: Vec<String>,
+    /// dimension にする data point の属性。それ以外の属性は捨て、同じ dimension の data point は足し合わせる
+    ///
+    /// 属性の値ごとに CloudWatch の metric が増えるので、値の種類が限られた属性だけを並べる
+    pub attribute_dimensions: Vec<String>,
+}
+
+impl Default for EmfConfig {
+    fn default() -> Self {
+        Self {
+            namespace: String::new(),
+            dimensions: vec![
+                opentelemetry_semantic_conventions::resource::FAAS_NAME.to_string(),
+                opentelemetry_semantic_conventions::resource::DEPLOYMENT_ENVIRONMENT_NAME
+                    .to_string(),
+            ],
+            attribute_dimensions: [
+                attribute::HTTP_REQUEST_METHOD,
+                attribute::HTTP_ROUTE,
+                attribute::HTTP_RESPONSE_STATUS_CODE,
+                attribute::ERROR_TYPE,
+                attribute::SERVER_ADDRESS,
+                attribute::OTEL_COMPONENT_TYPE,
+                "throttle.reason",
+                "cors.policy",
+                "cors.rejection_reason",
+                "telemetry.flush.strategy",
+                "telemetry.flush.result",
+                "telemetry.tail_sampling.decision",
+                "telemetry.tail_sampling.reason",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+impl EmfConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let count: usize = self.dimensions.len() + self.attribute_dimensions.len();
+        if count > MAX_DIMENSIONS {
+            anyhow::bail!(
+                "otel.emf.dimensions and otel.emf.attribute_dimensions allow at most {} dimensions, got {}",
+                MAX_DIMENSIONS,
+                count
+            );
+        }
+        if let Some(name) = self
+            .dimensions
+            .iter()
+            .chain(&self.attribute_dimensions)
+            .find(|name| name.is_empty() || name.as_str() == METADATA_KEY)
+        {
+            anyhow::bail!("`{}` cannot be an EMF dimension", name);
+        }
+        Ok(())
+    }
+
+    fn is_attribute_dimension(&self, key: &str) -> bool {
+        self.attribute_dimensions.iter().any(|name| name == key)
+    }
+
+    /// document のメタデータや dimension と同じキーになるので、metric の名前に使えない
+    pub fn is_reserved_name(&self, name: &str) -> bool {
+        name == METADATA_KEY
+            || self
+                .dimensions
+                .iter()
+                .chain(&self.attribute_dimensions)
+                .any(|dimension| dimension == name)
+    }
+}
+
+/// metrics を EMF の JSON 1 行ずつにして標準出力に書く
+///
+/// Lambda では CloudWatch Logs に書いた行から metrics が作られるので、collector を経由しない。
+/// delta で集計し、invocation の終わりの `force_flush` で書き出す
+#[derive(Debug)]
+pub struct EmfExporter {
+    config: EmfConfig,
+}
+
+impl EmfExporter {
+    pub fn new(config: &EmfConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    fn namespace(&self, service_namespace: Option<String>) -> String {
+        if !self.config.namespace.is_empty() {
+            return self.config.namespace.clone();
+        }
+        service_namespace
+            .filter(|namespace| !namespace.is_empty())
+            .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string())
+    }
+
+    /// `timestamp` 時点の metrics を EMF の document にする
+    ///
+    /// 同じ属性の data point を 1 つの document にまとめる
+    pub fn documents(&self, metrics: &ResourceMetrics, timestamp: SystemTime) -> Vec<Value> {
+        let resource: &Resource = metrics.resource();
+        let resource_dimensions: BTreeMap<String, String> = self
+            .config
+            .dimensions
+            .iter()
+            .filter_map(|name| {
+                let value = resource.get(&Key::new(name.clone()))?;
+                Some((name.clone(), value.to_string()))
+            })
+            .collect();
+
+        let mut groups: Groups = BTreeMap::new();
+        let metrics = metrics
+            .scope_metrics()
+            .flat_map(|scope| scope.metrics())
+            .filter(|metric| !self.config.is_reserved_name(metric.name()));
+        for metric in metrics {
+            for (attributes, value) in data_points(metric) {
+                let mut dimensions: BTreeMap<String, String> = resource_dimensions.clone();
+                dimensions.extend(
+                    attributes
+                        .into_iter()
+                        .filter(|attribute| {
+                            self.config.is_attribute_dimension(attribute.key.as_str())
+                        })
+                        .map(|attribute| (attribute.key.to_string(), attribute.value.to_string())),
+                );
+                add(&mut groups, dimensions, metric.name(), metric.unit(), value);
+            }
+        }
+
+        let namespace: String = self.namespace(
+            resource
+                .get(&Key::from_static_str(
+                    opentelemetry_semantic_conventions::resource::SERVICE_NAMESPACE,
+                ))
+                .map(|value| value.to_string()),
+        );
+        group_documents(&namespace, timestamp, &groups)
+    }
+
+    /// OTLP で受け取った metrics を EMF の document にする
+    ///
+    /// resource ごとに namespace と dimension を決める。summary は変換しない
+    pub fn proto_documents(&self, request: &ExportMetricsServiceRequest, timestamp: SystemTime) -> Vec<Value> {
+        let mut documents: Vec<Value> = Vec::new();
+        for resource_metrics in &request.resource_metrics {
+            let attributes: BTreeMap<String, String> = resource_metrics
+                .resource
+                .iter()
+                .flat_map(|resource| proto_attributes(&resource.attributes))
+                .collect();
+            let resource_dimensions: BTreeMap<String, String> = self
+                .config
+                .dimensions
+                .iter()
+                .filter_map(|name| Some((name.clone(), attributes.get(name)?.clone())))
+                .collect();
+
+            let mut groups: Groups = BTreeMap::new();
+            let metrics = resource_metrics
+                .scope_metrics
+                .iter()
+                .flat_map(|scope| &scope.metrics)
+                .filter(|metric| !self.config.is_reserved_name(&metric.name));
+            for metric in metrics {
+                for (point_attributes, value) in proto_data_points(metric) {
+                    let mut dimensions: BTreeMap<String, String> = resource_dimensions.clone();
+                    dimensions.extend(
+                        proto_attributes(point_attributes)
+                            .filter(|(key, _)| self.config.is_attribute_dimension(key)),
+                    );
+                    add(&mut groups, dimensions, &metric.name, &metric.unit, value);
+                }
+            }
+
+            let namespace: String = self.namespace(
+                attributes
+                    .get(opentelemetry_semantic_conventions::resource::SERVICE_NAMESPACE)
+                    .cloned(),
+            );
+            documents.extend(group_documents(&namespace, timestamp, &groups));
+        }
+        documents
+    }
+
+    /// document を 1 行ずつ標準出力に書く
+    pub fn write(documents: &[Value]) -> OTelSdkResult {
+        let mut buffer: Vec<u8> = Vec::new();
+        for document in documents {
+            serde_json::to_writer(&mut buffer, document)
+                .map_err(|err| OTelSdkError::InternalFailure(err.to_string()))?;
+            buffer.push(b'\n');
+        }
+        // ログの行と混ざらないよう、まとめて書く
+        std::io::stdout()
+            .lock()
+            .write_all(&buffer)
+            .map_err(|err| OTelSdkError::InternalFailure(err.to_string()))
+    }
+}
+
+/// dimension の組ごとの、metric の名前ごとの単位と値
+type Groups<'a> = BTreeMap<BTreeMap<String, String>, BTreeMap<&'a str, (&'a str, Value)>>;
+
+/// 同じ dimension の組と名前の値は、捨てた属性の分を足し合わせる
+fn add<'a>(
+    groups: &mut Groups<'a>,
+    dimensions: BTreeMap<String, String>,
+    name: &'a str,
+    unit: &'a str,
+    value: Value,
+) {
+    let metrics = groups.entry(dimensions).or_default();
+    match metrics.get_mut(name) {
+        Some((_, merged)) => merge(merged, value),
+        None => {
+            metrics.insert(name, (unit, value));
+        }
+    }
+}
+
+/// 数値は和にし、histogram の統計値の組は `Max`、`Min` を取り `Count`、`Sum` を足す
+fn merge(merged: &mut Value, value: Value) {
+    let (Value::Object(merged), Value::Object(value)) = (&mut *merged, &value) else {
+        *merged = add_numbers(merged, &value);
+        return;
+    };
+    for (key, value) in value {
+        let number = |value: &Value| value.as_f64().unwrap_or_default();
+        match merged.get_mut(key) {
+            Some(current) if key == "Max" => {
+                if number(value) > number(current) {
+                    *current = value.clone();
+                }
+            }
+            Some(current) if key == "Min" => {
+                if number(value) < number(current) {
+                    *current = value.clone();
+                }
+            }
+            Some(current) => *current = add_numbers(current, value),
+            None => {
+                merged.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn add_numbers(a: &Value, b: &Value) -> Value {
+    if let (Some(a), Some(b)) = (a.as_u64(), b.as_u64()) {
+        json!(a.saturating_add(b))
+    } else if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        json!(a.saturating_add(b))
+    } else {
+        json!(a.as_f64().unwrap_or_default() + b.as_f64().unwrap_or_default())
+    }
+}
+
+fn group_documents(namespace: &str, timestamp: SystemTime, groups: &Groups) -> Vec<Value> {
+    let timestamp: u128 = timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    groups
+        .iter()
+        .flat_map(|(dimensions, metrics)| {
+            let values: Vec<(&str, &str, &Value)> = metrics
+                .iter()
+                .map(|(name, (unit, value))| (*name, *unit, value))
+                .collect();
+            values
+                .chunks(MAX_METRICS_PER_DOCUMENT)
+                .map(|values| document(namespace, timestamp, dimensions, values))
+                .collect::<Vec<Value>>()
+        })
+        .collect()
+}
+
+fn document(
+    namespace: &str,
+    timestamp: u128,
+    dimensions: &BTreeMap<String, String>,
+    values: &[(&str, &str, &Value)],
+) -> Value {
+    // dimension の数は EmfConfig::validate で MAX_DIMENSIONS 以下にしている
+    let dimension_names: Vec<&String> = dimensions.keys().collect();
+    let definitions: Vec<Value> = values
+        .iter()
+        .map(|(name, unit_name, _)| json!({ "Name": name, "Unit": unit(unit_name) }))
+        .collect();
+    let mut document: Map<String, Value> = Map::new();
+    document.insert(
+        METADATA_KEY.to_string(),
+        json!({
+            "Timestamp": timestamp,
+            "CloudWatchMetrics": [{
+                "Namespace": namespace,
+                "Dimensions": [dimension_names],
+                "Metrics": definitions,
+            }],
+        }),
+    );
+    for (name, value) in dimensions {
+        document.insert(name.clone(), Value::from(value.as_str()));
+    }
+    for (name, _, value) in values {
+        document.insert(name.to_string(), (*value).clone());
+    }
+    Value::Object(document)
+}
+
+/// data point ごとの属性と EMF の値。histogram は統計値の組にする
+fn data_points(metric: &Metric) -> Vec<(Vec<KeyValue>, Value)> {
+    match metric.data() {
+        AggregatedMetrics::F64(data) => metric_values(data, |value| json!(value)),
+        AggregatedMetrics::U64(data) => metric_values(data, |value| json!(value)),
+        AggregatedMetrics::I64(data) => metric_values(data, |value| json!(value)),
+    }
+}
+
+fn metric_values<T: Copy>(
+    data: &MetricData<T>,
+    to_json: impl Fn(T) -> Value,
+) -> Vec<(Vec<KeyValue>, Value)> {
+    match data {
+        MetricData::Gauge(gauge) => gauge
+            .data_points()
+            .map(|point| (point.attributes().cloned().collect(), to_json(point.value())))
+            .collect(),
+        MetricData::Sum(sum) => sum
+            .data_points()
+            .map(|point| (point.attributes().cloned().collect(), to_json(point.value())))
+            .collect(),
+        MetricData::Histogram(histogram) => histogram
+            .data_points()
+            // 値のない区間は CloudWatch が受け付けない
+            .filter(|point| point.count() > 0)
+            .filter_map(|point| {
+                let statistics: Value = json!({
+                    "Max": to_json(point.max()?),
+                    "Min": to_json(point.min()?),
+                    "Count": point.count(),
+                    "Sum": to_json(point.sum()),
+                });
+                Some((point.attributes().cloned().collect(), statistics))
+            })
+            .collect(),
+        MetricData::ExponentialHistogram(histogram) => histogram
+            .data_points()
+            .filter(|point| point.count() > 0)
+            .filter_map(|point| {
+                let statistics: Value = json!({
+                    "Max": to_json(point.max()?),
+                    "Min": to_json(point.min()?),
+                    "Count": point.count(),
+                    "Sum": to_json(point.sum()),
+                });
+                Some((point.attributes().cloned().collect(), statistics))
+            })
+            .collect(),
+    }
+}
+
+fn proto_data_points(metric: &proto::Metric) -> Vec<(&[proto_common::KeyValue], Value)> {
+    let number = |point: &proto::NumberDataPoint| match point.value? {
+        proto::number_data_point::Value::AsDouble(value) => Some(json!(value)),
+        proto::number_data_point::Value::AsInt(value) => Some(json!(value)),
+    };
+    match &metric.data {
+        Some(proto::metric::Data::Gauge(gauge)) => gauge
+            .data_points
+            .iter()
+            .filter_map(|point| Some((point.attributes.as_slice(), number(point)?)))
+            .collect(),
+        Some(proto::metric::Data::Sum(sum)) => sum
+            .data_points
+            .iter()
+            .filter_map(|point| Some((point.attributes.as_slice(), number(point)?)))
+            .collect(),
+        Some(proto::metric::Data::Histogram(histogram)) => histogram
+            .data_points
+            .iter()
+            .filter(|point| point.count > 0)
+            .filter_map(|point| {
+                let statistics: Value = json!({
+                    "Max": point.max?,
+                    "Min": point.min?,
+                    "Count": point.count,
+                    "Sum": point.sum?,
+                });
+                Some((point.attributes.as_slice(), statistics))
+            })
+            .collect(),
+        Some(proto::metric::Data::ExponentialHistogram(histogram)) => histogram
+            .data_points
+            .iter()
+            .filter(|point| point.count > 0)
+            .filter_map(|point| {
+                let statistics: Value = json!({
+                    "Max": point.max?,
+                    "Min": point.min?,
+                    "Count": point.count,
+                    "Sum": point.sum?,
+                });
+                Some((point.attributes.as_slice(), statistics))
+            })
+            .collect(),
+        Some(proto::metric::Data::Summary(_)) | None => vec![],
+    }
+}
+
+/// 文字列にできる属性だけを取り出す
+fn proto_attributes(
+    attributes: &[proto_common::KeyValue],
+) -> impl Iterator<Item = (String, String)> + '_ {
+    attributes.iter().filter_map(|attribute| {
+        let value: String = match attribute.value.as_ref()?.value.as_ref()? {
+            proto_common::any_value::Value::StringValue(value) => value.clone(),
+            proto_common::any_value::Value::BoolValue(value) => value.to_string(),
+            proto_common::any_value::Value::IntValue(value) => value.to_string(),
+            proto_common::any_value::Value::DoubleValue(value) => value.to_string(),
+            _ => return None,
+        };
+        Some((attribute.key.clone(), value))
+    })
+}
+
+/// UCUM の単位を CloudWatch の単位にする
+fn unit(unit: &str) -> &'static str {
+    match unit {
+        "s" => "Seconds",
+        "ms" => "Milliseconds",
+        "us" => "Microseconds",
+        "By" => "Bytes",
+        "KiBy" => "Kilobytes",
+        "MiBy" => "Megabytes",
+        "%" => "Percent",
+        // `{request}` のような annotation は数
+        unit if unit.starts_with('{') && unit.ends_with('}') => "Count",
+        _ => "None",
+    }
+}
+
+impl PushMetricExporter for EmfExporter {
+    async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+        Self::write(&self.documents(metrics, SystemTime::now()))
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        std::io::stdout()
+            .flush()
+            .map_err(|err| OTelSdkError::InternalFailure(err.to_string()))
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn temporality(&self) -> Temporality {
+        // CloudWatch が期間ごとに集計するので、書き出した後の増分だけを送る
+        Temporality::Delta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry_proto::tonic::{
+        common::v1::{AnyValue, KeyValue as ProtoKeyValue, any_value},
+        metrics::v1::{
+            Gauge, Histogram, HistogramDataPoint, Metric as ProtoMetric, NumberDataPoint,
+            ResourceMetrics as ProtoResourceMetrics, ScopeMetrics, Sum, Summary, metric,
+            number_data_point,
+        },
+        resource::v1::Resource as ProtoResource,
+    };
+    use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+
+    use super::*;
+    use crate::testing::assert_golden;
+
+    fn timestamp() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1_767_225_600_000)
+    }
+
+    fn snapshot(documents: &[Value]) -> String {
+        format!("{}\n", serde_json::to_string_pretty(documents).unwrap())
+    }
+
+    fn string(key: &str, value: &str) -> ProtoKeyValue {
+        ProtoKeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_string())),
+            }),
+        }
+    }
+
+    fn int(key: &str, value: i64) -> ProtoKeyValue {
+        ProtoKeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::IntValue(value)),
+            }),
+        }
+    }
+
+    fn number(attributes: Vec<ProtoKeyValue>, value: i64) -> NumberDataPoint {
+        NumberDataPoint {
+            attributes,
+            value: Some(number_data_point::Value::AsInt(value)),
+            ..Default::default()
+        }
+    }
+
+    fn histogram(
+        attributes: Vec<ProtoKeyValue>,
+        count: u64,
+        sum: f64,
+        min: f64,
+        max: f64,
+    ) -> HistogramDataPoint {
+        HistogramDataPoint {
+            attributes,
+            count,
+            sum: Some(sum),
+            min: Some(min),
+            max: Some(max),
+            ..Default::default()
+        }
+    }
+
+    fn request() -> ExportMetricsServiceRequest {
+        let route = |route: &str, client: &str| {
+            vec![
+                string(attribute::HTTP_ROUTE, route),
+                int(attribute::HTTP_RESPONSE_STATUS_CODE, 200),
+                // 許可していない属性は dimension にしない
+                string("client.id", client),
+            ]
+        };
+        let metrics: Vec<ProtoMetric> = vec![
+            ProtoMetric {
+                name: "http.server.request.duration".to_string(),
+                unit: "s".to_string(),
+                data: Some(metric::Data::Histogram(Histogram {
+                    data_points: vec![
+                        histogram(route("/hello", "a"), 2, 0.3, 0.1, 0.2),
+                        histogram(route("/hello", "b"), 1, 0.5, 0.5, 0.5),
+                        histogram(route("/greet", "a"), 1, 0.05, 0.05, 0.05),
+                        // 値のない区間は書かない
+                        histogram(route("/admin", "a"), 0, 0.0, 0.0, 0.0),
+                    ],
+                    ..Default::default()
+                })),
+                ..Default::default()
+            },
+            ProtoMetric {
+                name: "http.server.throttled_requests".to_string(),
+                unit: "{request}".to_string(),
+                data: Some(metric::Data::Sum(Sum {
+                    data_points: vec![
+                        number(
+                            vec![
+                                string("throttle.reason", "rate_limited"),
+                                string("client.id", "a"),
+                            ],
+                            3,
+                        ),
+                        number(
+                            vec![
+                                string("throttle.reason", "rate_limited"),
+                                string("client.id", "b"),
+                            ],
+                            4,
+                        ),
+                    ],
+                    ..Default::default()
+                })),
+                ..Default::default()
+            },
+            ProtoMetric {
+                name: "process.memory.usage".to_string(),
+                unit: "By".to_string(),
+                data: Some(metric::Data::Gauge(Gauge {
+                    data_points: vec![number(vec![], 1_048_576)],
+                })),
+                ..Default::default()
+            },
+            ProtoMetric {
+                name: "ignored.summary".to_string(),
+                data: Some(metric::Data::Summary(Summary::default())),
+                ..Default::default()
+            },
+        ];
+        // メタデータや dimension を上書きする名前の metric は書かない
+        let reserved = [METADATA_KEY, attribute::HTTP_ROUTE].map(|name| ProtoMetric {
+            name: name.to_string(),
+            data: Some(metric::Data::Gauge(Gauge {
+                data_points: vec![number(vec![], 1)],
+            })),
+            ..Default::default()
+        });
+        let metrics: Vec<ProtoMetric> = metrics.into_iter().chain(reserved).collect();
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ProtoResourceMetrics {
+                resource: Some(ProtoResource {
+                    attributes: vec![
+                        string(
+                            opentelemetry_semantic_conventions::resource::SERVICE_NAMESPACE,
+                            "sample",
+                        ),
+                        string(
+                            opentelemetry_semantic_conventions::resource::FAAS_NAME,
+                            "api",
+                        ),
+                        string(
+                            opentelemetry_semantic_conventions::resource::SERVICE_NAME,
+                            "api",
+                        ),
+                    ],
+                    ..Default::default()
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    metrics,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[test]
+    fn proto_documents_match_snapshot() {
+        let exporter: EmfExporter = EmfExporter::new(&EmfConfig::default());
+        let documents: Vec<Value> = exporter.proto_documents(&request(), timestamp());
+        assert_golden("emf/proto_documents.json", &snapshot(&documents));
+    }
+
+    #[derive(Debug)]
+    struct Capture {
+        emf: EmfExporter,
+        documents: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl PushMetricExporter for Capture {
+        async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+            self.documents
+                .lock()
+                .unwrap()
+                .extend(self.emf.documents(metrics, timestamp()));
+            Ok(())
+        }
+
+        fn force_flush(&self) -> OTelSdkResult {
+            Ok(())
+        }
+
+        fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+            Ok(())
+        }
+
+        fn temporality(&self) -> Temporality {
+            self.emf.temporality()
+        }
+    }
+
+    #[test]
+    fn documents_match_snapshot() {
+        let documents: Arc<Mutex<Vec<Value>>> = Arc::default();
+        let exporter: Capture = Capture {
+            emf: EmfExporter::new(&EmfConfig {
+                namespace: "pinned".to_string(),
+                ..EmfConfig::default()
+            }),
+            documents: documents.clone(),
+        };
+        let provider: SdkMeterProvider = SdkMeterProvider::builder()
+            .with_resource(
+                Resource::builder_empty()
+                    .with_attribute(KeyValue::new(
+                        opentelemetry_semantic_conventions::resource::FAAS_NAME,
+                        "api",
+                    ))
+                    .build(),
+            )
+            .with_reader(PeriodicReader::builder(exporter).build())
+            .build();
+        let meter = provider.meter("test");
+        let requests = meter
+            .u64_counter("http.server.throttled_requests")
+            .with_unit("{request}")
+            .build();
+        requests.add(
+            1,
+            &[
+                KeyValue::new("throttle.reason", "rate_limited"),
+                KeyValue::new("client.id", "a"),
+            ],
+        );
+        requests.add(
+            2,
+            &[
+                KeyValue::new("throttle.reason", "rate_limited"),
+                KeyValue::new("client.id", "b"),
+            ],
+        );
+        requests.add(
+            5,
+            &[KeyValue::new("throttle.reason", "concurrency_limited")],
+        );
+        let duration = meter
+            .f64_histogram("telemetry.flush.duration")
+            .with_unit("s")
+            .build();
+        duration.record(0.25, &[KeyValue::new("telemetry.flush.result", "success")]);
+        duration.record(0.75, &[KeyValue::new("telemetry.flush.result", "success")]);
+        provider.force_flush().unwrap();
+
+        let documents: Vec<Value> = documents.lock().unwrap().clone();
+        assert_golden("emf/documents.json", &snapshot(&documents));
+    }
+
+    #[test]
+    fn config_rejects_too_many_or_reserved_dimensions() {
+        assert!(EmfConfig::default().validate().is_ok());
+        let too_many: EmfConfig = EmfConfig {
+            attribute_dimensions: (0..MAX_DIMENSIONS)
+                .map(|i| format!("attribute.{}", i))
+                .collect(),
+            ..EmfConfig::default()
+        };
+        assert!(too_many.validate().is_err());
+        let reserved: EmfConfig = EmfConfig {
+            attribute_dimensions: vec![METADATA_KEY.to_string()],
+            ..EmfConfig::default()
+        };
+        assert!(reserved.validate().is_err());
+    }
+}
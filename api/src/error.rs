@@ -0,0 +1,280 @@
+use axum::{
+    extract::rejection::JsonRejection,
+    http::{HeaderName, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+pub const PROBLEM_JSON_CONTENT_TYPE: &str = "application/problem+json";
+
+/// RFC 7807 の problem details
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<Violation>,
+}
+
+/// フィールド単位の検証エラー
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Violation {
+    pub field: String,
+    pub message: String,
+}
+
+impl Violation {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// server span の `error.type` に記録するためにレスポンスの extensions に載せる値
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorType(pub &'static str);
+
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    status: StatusCode,
+    error_type: &'static str,
+    title: &'static str,
+    detail: String,
+    violations: Vec<Violation>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl ApiError {
+    pub fn new(
+        status: StatusCode,
+        error_type: &'static str,
+        title: &'static str,
+        detail: impl Into<String>,
+    ) -> Self {
+        Self {
+            status,
+            error_type,
+            title,
+            detail: detail.into(),
+            violations: vec![],
+            headers: vec![],
+        }
+    }
+
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    pub fn validation(violations: Vec<Violation>) -> Self {
+        Self {
+            violations,
+            ..Self::new(
+                StatusCode::BAD_REQUEST,
+                "validation_failed",
+                "Validation failed",
+                "request body has invalid fields",
+            )
+        }
+    }
+
+    fn to_problem_details(&self) -> ProblemDetails {
+        ProblemDetails {
+            problem_type: format!("urn:problem-type:{}", self.error_type.replace('_', "-")),
+            title: self.title.to_string(),
+            status: self.status.as_u16(),
+            detail: self.detail.clone(),
+            violations: self.violations.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.error_type, self.detail)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        let error_type: &'static str = match rejection {
+            JsonRejection::JsonDataError(_) => "json_data_error",
+            JsonRejection::JsonSyntaxError(_) => "json_syntax_error",
+            JsonRejection::MissingJsonContentType(_) => "missing_json_content_type",
+            JsonRejection::BytesRejection(_) => "bytes_rejection",
+            _ => "json_rejection",
+        };
+        Self::new(
+            rejection.status(),
+            error_type,
+            "Malformed request body",
+            rejection.body_text(),
+        )
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        tracing::warn!(
+            error.type = self.error_type,
+            status = self.status.as_u16(),
+            violations = ?self.violations,
+            "{}",
+            self.detail
+        );
+        let mut response: Response = (
+            self.status,
+            [(header::CONTENT_TYPE, PROBLEM_JSON_CONTENT_TYPE)],
+            crate::validation::ApiJson(self.to_problem_details()),
+        )
+            .into_response();
+        response.headers_mut().extend(self.headers);
+        response.extensions_mut().insert(ErrorType(self.error_type));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        extract::{FromRequest, Request},
+    };
+    use serde_json::Value;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Payload {
+        count: u32,
+    }
+
+    /// `axum::Json` が返す rejection
+    #[allow(clippy::disallowed_types)]
+    async fn rejection(content_type: Option<&str>, body: &str) -> JsonRejection {
+        let mut request = Request::post("/");
+        if let Some(content_type) = content_type {
+            request = request.header(header::CONTENT_TYPE, content_type);
+        }
+        let request: Request = request.body(Body::from(body.to_string())).unwrap();
+        axum::Json::<Payload>::from_request(request, &())
+            .await
+            .unwrap_err()
+    }
+
+    async fn problem(error: ApiError) -> (Response, Value) {
+        let response: Response = error.into_response();
+        let (parts, body) = response.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let problem: Value = serde_json::from_slice(&body).unwrap();
+        (Response::from_parts(parts, Body::empty()), problem)
+    }
+
+    #[tokio::test]
+    async fn json_rejections_keep_their_status_and_name_their_type() {
+        let json: Option<&str> = Some("application/json");
+        for (content_type, body, status, problem_type) in [
+            (json, "{", StatusCode::BAD_REQUEST, "json-syntax-error"),
+            (
+                json,
+                r#"{"count": "many"}"#,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "json-data-error",
+            ),
+            (
+                None,
+                r#"{"count": 1}"#,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "missing-json-content-type",
+            ),
+        ] {
+            let (response, problem) =
+                problem(ApiError::from(rejection(content_type, body).await)).await;
+            assert_eq!(response.status(), status);
+            assert_eq!(
+                problem["type"],
+                format!("urn:problem-type:{}", problem_type)
+            );
+            assert_eq!(problem["title"], "Malformed request body");
+            assert_eq!(problem["status"], status.as_u16());
+            assert!(!problem["detail"].as_str().unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn errors_are_problem_json() {
+        let (response, problem) = problem(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "greeting_not_found",
+            "Greeting not found",
+            "no greeting for 42",
+        ))
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            PROBLEM_JSON_CONTENT_TYPE
+        );
+        assert_eq!(
+            problem,
+            serde_json::json!({
+                "type": "urn:problem-type:greeting-not-found",
+                "title": "Greeting not found",
+                "status": 404,
+                "detail": "no greeting for 42",
+            })
+        );
+        assert_eq!(
+            response.extensions().get::<ErrorType>().unwrap().0,
+            "greeting_not_found"
+        );
+    }
+
+    #[tokio::test]
+    async fn validation_errors_list_every_violation() {
+        let (response, problem) = problem(ApiError::validation(vec![
+            Violation::new("person", "must be at least 1 characters"),
+            Violation::new("message", "must be at most 32 characters"),
+        ]))
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(problem["type"], "urn:problem-type:validation-failed");
+        assert_eq!(
+            problem["violations"],
+            serde_json::json!([
+                { "field": "person", "message": "must be at least 1 characters" },
+                { "field": "message", "message": "must be at most 32 characters" },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn custom_headers_are_added_to_the_response() {
+        let (response, _) = problem(
+            ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                "Too many requests",
+                "slow down",
+            )
+            .with_header(header::RETRY_AFTER, HeaderValue::from_static("3")),
+        )
+        .await;
+        assert_eq!(response.headers()[header::RETRY_AFTER], "3");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            PROBLEM_JSON_CONTENT_TYPE
+        );
+    }
+}
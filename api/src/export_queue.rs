@@ -0,0 +1,644 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use opentelemetry::{
+    Context, InstrumentationScope, KeyValue,
+    metrics::{Counter, Meter},
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    logs::{BatchLogProcessor, LogBatch, LogExporter, LogProcessor, SdkLogRecord},
+    trace::{BatchSpanProcessor, Span, SpanData, SpanExporter, SpanProcessor},
+};
+use opentelemetry_semantic_conventions::{attribute, metric};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Env;
+
+/// 送っていない span とログの上限を超えたときの扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// 新しいものを捨てる
+    Drop,
+    /// 空くまで `block_timeout_ms` だけ呼び出し元を待たせ、空かなければ捨てる
+    ///
+    /// tokio の runtime の中では worker を止めないよう、待たずに捨てる
+    Block,
+}
+
+/// batch processor の queue の設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportQueueConfig {
+    pub max_queue_size: usize,
+    pub max_export_batch_size: usize,
+    pub scheduled_delay_ms: u64,
+    pub overflow: OverflowPolicy,
+    pub block_timeout_ms: u64,
+}
+
+impl Default for ExportQueueConfig {
+    fn default() -> Self {
+        Self {
+            max_queue_size: 2048,
+            max_export_batch_size: 512,
+            scheduled_delay_ms: 5000,
+            overflow: OverflowPolicy::Drop,
+            block_timeout_ms: 100,
+        }
+    }
+}
+
+impl ExportQueueConfig {
+    /// `OTEL_BSP_MAX_QUEUE_SIZE` のような `{prefix}_*` の環境変数で上書きする
+    fn apply_env_overrides(&mut self, env: &Env, prefix: &str) -> anyhow::Result<()> {
+        for (suffix, value) in [
+            ("MAX_QUEUE_SIZE", &mut self.max_queue_size),
+            ("MAX_EXPORT_BATCH_SIZE", &mut self.max_export_batch_size),
+        ] {
+            let name: String = format!("{}_{}", prefix, suffix);
+            if let Some(env) = env.get(&name) {
+                *value = env
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid value in `{}`", name))?;
+            }
+        }
+        let name: String = format!("{}_SCHEDULE_DELAY", prefix);
+        if let Some(env) = env.get(&name) {
+            self.scheduled_delay_ms = env
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid value in `{}`", name))?;
+        }
+        Ok(())
+    }
+
+    fn validate(&self, name: &str) -> anyhow::Result<()> {
+        if self.max_queue_size == 0 || self.max_export_batch_size == 0 {
+            anyhow::bail!(
+                "otel.queues.{}.max_queue_size and max_export_batch_size must be positive",
+                name
+            );
+        }
+        if self.max_export_batch_size > self.max_queue_size {
+            anyhow::bail!(
+                "otel.queues.{}.max_export_batch_size must not exceed max_queue_size",
+                name
+            );
+        }
+        Ok(())
+    }
+}
+
+/// span とログの batch processor の queue
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportQueuesConfig {
+    /// `OTEL_BSP_*` で上書きする
+    pub spans: ExportQueueConfig,
+    /// `OTEL_BLRP_*` で上書きする
+    pub logs: ExportQueueConfig,
+}
+
+impl ExportQueuesConfig {
+    pub fn apply_env_overrides(&mut self, env: &Env) -> anyhow::Result<()> {
+        self.spans.apply_env_overrides(env, "OTEL_BSP")?;
+        self.logs.apply_env_overrides(env, "OTEL_BLRP")
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.spans.validate("spans")?;
+        self.logs.validate("logs")
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Signal {
+    Span,
+    Log,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    signal: Signal,
+    config: ExportQueueConfig,
+    /// processor が受け取り、export が終わっていない件数
+    pending: Mutex<usize>,
+    released: Condvar,
+    exporting: AtomicUsize,
+    dropped_once: AtomicBool,
+    /// meter provider を作った後に `register_metrics` で設定する
+    counters: OnceLock<QueueCounters>,
+}
+
+#[derive(Debug)]
+struct QueueCounters {
+    processed: Counter<u64>,
+    exported: Counter<u64>,
+    succeeded: [KeyValue; 1],
+    failed: [KeyValue; 2],
+    dropped: [KeyValue; 2],
+}
+
+/// batch processor の前で送っていない件数を数え、上限を超えた分を数えて捨てる
+///
+/// SDK の batch processor は捨てた件数を公開しないので、SDK の queue があふれる前にここで止める
+#[derive(Debug, Clone)]
+pub struct ExportQueue(Arc<QueueState>);
+
+impl ExportQueue {
+    fn new(signal: Signal, config: &ExportQueueConfig) -> Self {
+        Self(Arc::new(QueueState {
+            signal,
+            config: config.clone(),
+            pending: Mutex::new(0),
+            released: Condvar::new(),
+            exporting: AtomicUsize::new(0),
+            dropped_once: AtomicBool::new(false),
+            counters: OnceLock::new(),
+        }))
+    }
+
+    pub fn spans(config: &ExportQueueConfig) -> Self {
+        Self::new(Signal::Span, config)
+    }
+
+    pub fn logs(config: &ExportQueueConfig) -> Self {
+        Self::new(Signal::Log, config)
+    }
+
+    fn try_enqueue(&self) -> bool {
+        let state: &QueueState = &self.0;
+        let capacity: usize = state.config.max_queue_size;
+        let mut pending = state.pending.lock().unwrap();
+        if *pending >= capacity
+            && state.config.overflow == OverflowPolicy::Block
+            && tokio::runtime::Handle::try_current().is_err()
+        {
+            let timeout: Duration = Duration::from_millis(state.config.block_timeout_ms);
+            pending = state
+                .released
+                .wait_timeout_while(pending, timeout, |pending| *pending >= capacity)
+                .unwrap()
+                .0;
+        }
+        if *pending >= capacity {
+            drop(pending);
+            if let Some(counters) = state.counters.get() {
+                counters.processed.add(1, &counters.dropped);
+            }
+            if !state.dropped_once.swap(true, Ordering::Relaxed) {
+                tracing::warn!(
+                    "Dropped {} because the export queue is full; further drops are only counted",
+                    self.item()
+                );
+            }
+            return false;
+        }
+        *pending += 1;
+        true
+    }
+
+    fn begin_export(&self, count: usize) -> ExportGuard {
+        self.0.exporting.fetch_add(count, Ordering::Relaxed);
+        ExportGuard {
+            queue: self.clone(),
+            count,
+            succeeded: false,
+        }
+    }
+
+    fn item(&self) -> &'static str {
+        match self.0.signal {
+            Signal::Span => "a span",
+            Signal::Log => "a log record",
+        }
+    }
+
+    /// queue の長さ、捨てた件数、export の成否を `otel.sdk.*` の instrument で記録する
+    pub fn register_metrics(&self, meter: &Meter) {
+        let (component, queue_size, capacity, processed, exported, inflight) = match self.0.signal {
+            Signal::Span => (
+                "batching_span_processor",
+                metric::OTEL_SDK_PROCESSOR_SPAN_QUEUE_SIZE,
+                metric::OTEL_SDK_PROCESSOR_SPAN_QUEUE_CAPACITY,
+                metric::OTEL_SDK_PROCESSOR_SPAN_PROCESSED,
+                metric::OTEL_SDK_EXPORTER_SPAN_EXPORTED,
+                metric::OTEL_SDK_EXPORTER_SPAN_INFLIGHT,
+            ),
+            Signal::Log => (
+                "batching_log_processor",
+                metric::OTEL_SDK_PROCESSOR_LOG_QUEUE_SIZE,
+                metric::OTEL_SDK_PROCESSOR_LOG_QUEUE_CAPACITY,
+                metric::OTEL_SDK_PROCESSOR_LOG_PROCESSED,
+                metric::OTEL_SDK_EXPORTER_LOG_EXPORTED,
+                metric::OTEL_SDK_EXPORTER_LOG_INFLIGHT,
+            ),
+        };
+        let component: KeyValue = KeyValue::new(attribute::OTEL_COMPONENT_TYPE, component);
+
+        let state: ExportQueue = self.clone();
+        let attributes: [KeyValue; 1] = [component.clone()];
+        meter
+            .i64_observable_up_down_counter(queue_size)
+            .with_description("Items waiting in the export queue")
+            .with_callback(move |observer| {
+                let pending: usize = *state.0.pending.lock().unwrap();
+                let exporting: usize = state.0.exporting.load(Ordering::Relaxed);
+                observer.observe(pending.saturating_sub(exporting) as i64, &attributes);
+            })
+            .build();
+
+        let state: ExportQueue = self.clone();
+        let attributes: [KeyValue; 1] = [component.clone()];
+        meter
+            .i64_observable_up_down_counter(capacity)
+            .with_description("Maximum number of items in the export queue")
+            .with_callback(move |observer| {
+                observer.observe(state.0.config.max_queue_size as i64, &attributes);
+            })
+            .build();
+
+        let state: ExportQueue = self.clone();
+        let attributes: [KeyValue; 1] = [component.clone()];
+        meter
+            .i64_observable_up_down_counter(inflight)
+            .with_description("Items being exported")
+            .with_callback(move |observer| {
+                observer.observe(
+                    state.0.exporting.load(Ordering::Relaxed) as i64,
+                    &attributes,
+                );
+            })
+            .build();
+
+        // 一度しか呼ばない
+        let _ = self.0.counters.set(QueueCounters {
+            processed: meter
+                .u64_counter(processed)
+                .with_description("Items that left the export queue, including dropped ones")
+                .build(),
+            exported: meter
+                .u64_counter(exported)
+                .with_description("Items passed to the exporter")
+                .build(),
+            succeeded: [component.clone()],
+            failed: [
+                component.clone(),
+                KeyValue::new(attribute::ERROR_TYPE, "export_failed"),
+            ],
+            dropped: [
+                component,
+                KeyValue::new(attribute::ERROR_TYPE, "queue_full"),
+            ],
+        });
+    }
+}
+
+/// export が終わるか、future が捨てられたときに queue を空ける
+struct ExportGuard {
+    queue: ExportQueue,
+    count: usize,
+    succeeded: bool,
+}
+
+impl Drop for ExportGuard {
+    fn drop(&mut self) {
+        let state: &QueueState = &self.queue.0;
+        state.exporting.fetch_sub(self.count, Ordering::Relaxed);
+        if let Some(counters) = state.counters.get() {
+            let attributes: &[KeyValue] = if self.succeeded {
+                &counters.succeeded
+            } else {
+                &counters.failed
+            };
+            counters.processed.add(self.count as u64, attributes);
+            counters.exported.add(self.count as u64, attributes);
+        }
+        let mut pending = state.pending.lock().unwrap();
+        *pending = pending.saturating_sub(self.count);
+        state.released.notify_all();
+    }
+}
+
+/// 件数を数える exporter
+#[derive(Debug)]
+struct CountingExporter<E> {
+    inner: E,
+    queue: ExportQueue,
+}
+
+impl<E: SpanExporter> SpanExporter for CountingExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let mut guard: ExportGuard = self.queue.begin_export(batch.len());
+        let result: OTelSdkResult = self.inner.export(batch).await;
+        guard.succeeded = result.is_ok();
+        result
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+impl<E: LogExporter> LogExporter for CountingExporter<E> {
+    async fn export(&self, batch: LogBatch<'_>) -> OTelSdkResult {
+        let mut guard: ExportGuard = self.queue.begin_export(batch.iter().count());
+        let result: OTelSdkResult = self.inner.export(batch).await;
+        guard.succeeded = result.is_ok();
+        result
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// `queue` の上限を超えない分だけを batch processor に渡す
+#[derive(Debug)]
+pub struct QueuedProcessor<P> {
+    inner: P,
+    queue: ExportQueue,
+}
+
+impl SpanProcessor for QueuedProcessor<BatchSpanProcessor> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        if self.queue.try_enqueue() {
+            self.inner.on_end(span);
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+impl LogProcessor for QueuedProcessor<BatchLogProcessor> {
+    fn emit(&self, data: &mut SdkLogRecord, instrumentation: &InstrumentationScope) {
+        if self.queue.try_enqueue() {
+            self.inner.emit(data, instrumentation);
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// `queue` の設定で作った batch span processor
+pub fn span_processor<E>(exporter: E, queue: &ExportQueue) -> QueuedProcessor<BatchSpanProcessor>
+where
+    E: SpanExporter + 'static,
+{
+    let config: &ExportQueueConfig = &queue.0.config;
+    let batch_config = opentelemetry_sdk::trace::BatchConfigBuilder::default()
+        .with_max_queue_size(config.max_queue_size)
+        .with_max_export_batch_size(config.max_export_batch_size)
+        .with_scheduled_delay(Duration::from_millis(config.scheduled_delay_ms))
+        .build();
+    QueuedProcessor {
+        inner: BatchSpanProcessor::builder(CountingExporter {
+            inner: exporter,
+            queue: queue.clone(),
+        })
+        .with_batch_config(batch_config)
+        .build(),
+        queue: queue.clone(),
+    }
+}
+
+/// `queue` の設定で作った batch log processor
+pub fn log_processor<E>(exporter: E, queue: &ExportQueue) -> QueuedProcessor<BatchLogProcessor>
+where
+    E: LogExporter + 'static,
+{
+    let config: &ExportQueueConfig = &queue.0.config;
+    let batch_config = opentelemetry_sdk::logs::BatchConfigBuilder::default()
+        .with_max_queue_size(config.max_queue_size)
+        .with_max_export_batch_size(config.max_export_batch_size)
+        .with_scheduled_delay(Duration::from_millis(config.scheduled_delay_ms))
+        .build();
+    QueuedProcessor {
+        inner: BatchLogProcessor::builder(CountingExporter {
+            inner: exporter,
+            queue: queue.clone(),
+        })
+        .with_batch_config(batch_config)
+        .build(),
+        queue: queue.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::testing::TestMeter;
+
+    const SPANS: &str = "otel.component.type=batching_span_processor";
+    const DROPPED: &str = "error.type=queue_full,otel.component.type=batching_span_processor";
+    const FAILED: &str = "error.type=export_failed,otel.component.type=batching_span_processor";
+
+    fn queue_with_metrics(max_queue_size: usize) -> (ExportQueue, TestMeter) {
+        let queue: ExportQueue = ExportQueue::spans(&ExportQueueConfig {
+            max_queue_size,
+            ..Default::default()
+        });
+        let meter: TestMeter = TestMeter::new();
+        queue.register_metrics(&meter.meter());
+        (queue, meter)
+    }
+
+    fn values(pairs: &[(&str, f64)]) -> BTreeMap<String, f64> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), *value))
+            .collect()
+    }
+
+    fn full_queue() -> ExportQueue {
+        let queue: ExportQueue = ExportQueue::spans(&ExportQueueConfig {
+            max_queue_size: 1,
+            overflow: OverflowPolicy::Block,
+            block_timeout_ms: 10_000,
+            ..Default::default()
+        });
+        assert!(queue.try_enqueue());
+        queue
+    }
+
+    #[test]
+    fn block_waits_outside_the_runtime() {
+        let queue: ExportQueue = full_queue();
+        let exporting: ExportQueue = queue.clone();
+        let export = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(exporting.begin_export(1));
+        });
+        assert!(queue.try_enqueue());
+        export.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn block_does_not_wait_on_the_runtime() {
+        let queue: ExportQueue = full_queue();
+        let start: Instant = Instant::now();
+        assert!(!queue.try_enqueue());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn env_overrides_replace_queue_settings() {
+        let mut config: ExportQueuesConfig = ExportQueuesConfig::default();
+        config
+            .apply_env_overrides(&Env::from_iter([
+                ("OTEL_BSP_MAX_QUEUE_SIZE", "100"),
+                ("OTEL_BSP_MAX_EXPORT_BATCH_SIZE", " 10 "),
+                ("OTEL_BSP_SCHEDULE_DELAY", "200"),
+                ("OTEL_BLRP_MAX_QUEUE_SIZE", "50"),
+            ]))
+            .unwrap();
+        assert_eq!(config.spans.max_queue_size, 100);
+        assert_eq!(config.spans.max_export_batch_size, 10);
+        assert_eq!(config.spans.scheduled_delay_ms, 200);
+        assert_eq!(config.logs.max_queue_size, 50);
+        assert_eq!(config.logs.max_export_batch_size, 512);
+        assert_eq!(config.logs.scheduled_delay_ms, 5000);
+
+        let err: anyhow::Error = config
+            .apply_env_overrides(&Env::from_iter([("OTEL_BLRP_SCHEDULE_DELAY", "5s")]))
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("OTEL_BLRP_SCHEDULE_DELAY"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn validate_rejects_empty_queues_and_oversized_batches() {
+        assert!(ExportQueuesConfig::default().validate().is_ok());
+        for (spans, expected) in [
+            (
+                ExportQueueConfig {
+                    max_queue_size: 0,
+                    ..Default::default()
+                },
+                "otel.queues.spans.max_queue_size and max_export_batch_size must be positive",
+            ),
+            (
+                ExportQueueConfig {
+                    max_queue_size: 10,
+                    max_export_batch_size: 11,
+                    ..Default::default()
+                },
+                "otel.queues.spans.max_export_batch_size must not exceed max_queue_size",
+            ),
+        ] {
+            let config: ExportQueuesConfig = ExportQueuesConfig {
+                spans,
+                ..Default::default()
+            };
+            assert_eq!(config.validate().unwrap_err().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn drops_are_counted_as_processed_with_queue_full() {
+        let (queue, meter) = queue_with_metrics(2);
+        assert!(queue.try_enqueue());
+        assert!(queue.try_enqueue());
+        assert!(!queue.try_enqueue());
+        assert!(!queue.try_enqueue());
+
+        assert_eq!(
+            meter.values(metric::OTEL_SDK_PROCESSOR_SPAN_PROCESSED),
+            values(&[(DROPPED, 2.0)])
+        );
+        assert_eq!(
+            meter.values(metric::OTEL_SDK_PROCESSOR_SPAN_QUEUE_SIZE),
+            values(&[(SPANS, 2.0)])
+        );
+        assert_eq!(
+            meter.values(metric::OTEL_SDK_PROCESSOR_SPAN_QUEUE_CAPACITY),
+            values(&[(SPANS, 2.0)])
+        );
+    }
+
+    #[test]
+    fn export_guards_record_the_result_and_release_the_queue() {
+        let (queue, meter) = queue_with_metrics(3);
+        for _ in 0..3 {
+            assert!(queue.try_enqueue());
+        }
+
+        let mut succeeded: ExportGuard = queue.begin_export(2);
+        succeeded.succeeded = true;
+        let failed: ExportGuard = queue.begin_export(1);
+        assert_eq!(
+            meter.values(metric::OTEL_SDK_EXPORTER_SPAN_INFLIGHT),
+            values(&[(SPANS, 3.0)])
+        );
+        assert_eq!(
+            meter.values(metric::OTEL_SDK_PROCESSOR_SPAN_QUEUE_SIZE),
+            values(&[(SPANS, 0.0)])
+        );
+        assert!(!queue.try_enqueue());
+
+        drop(succeeded);
+        drop(failed);
+        assert_eq!(*queue.0.pending.lock().unwrap(), 0);
+        assert_eq!(
+            meter.values(metric::OTEL_SDK_EXPORTER_SPAN_EXPORTED),
+            values(&[(SPANS, 2.0), (FAILED, 1.0)])
+        );
+        assert_eq!(
+            meter.values(metric::OTEL_SDK_PROCESSOR_SPAN_PROCESSED),
+            values(&[(SPANS, 2.0), (FAILED, 1.0), (DROPPED, 1.0)])
+        );
+        assert_eq!(
+            meter.values(metric::OTEL_SDK_EXPORTER_SPAN_INFLIGHT),
+            values(&[(SPANS, 0.0)])
+        );
+        assert!(queue.try_enqueue());
+    }
+}
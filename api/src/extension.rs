@@ -0,0 +1,1095 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context as _;
+use axum::{
+    Router,
+    body::Bytes,
+    extract::{DefaultBodyLimit, State},
+    http::HeaderMap,
+    response::Response,
+    routing::post,
+};
+use opentelemetry_proto::tonic::{
+    collector::{
+        metrics::v1::ExportMetricsServiceRequest,
+        logs::v1::{
+            ExportLogsPartialSuccess, ExportLogsServiceRequest, ExportLogsServiceResponse,
+            logs_service_server::{LogsService, LogsServiceServer},
+        },
+        trace::v1::{
+            ExportTracePartialSuccess, ExportTraceServiceRequest, ExportTraceServiceResponse,
+            trace_service_server::{TraceService, TraceServiceServer},
+        },
+    },
+    logs::v1::ResourceLogs,
+    trace::v1::ResourceSpans,
+};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+
+use crate::config::Config;
+use crate::emf::EmfExporter;
+use crate::error::ApiError;
+use crate::otlp;
+use crate::otlp_aws::{AwsOtlpEndpoint, RequestLimits};
+use crate::sigv4::AwsCredentialsProvider;
+use crate::telemetry_api::{self, PlatformTelemetry, TelemetryEvent};
+
+const DEFAULT_EXTENSION_NAME: &str = "otel-extension";
+const EXTENSION_NAME_HEADER: &str = "Lambda-Extension-Name";
+const EXTENSION_ID_HEADER: &str = "Lambda-Extension-Identifier";
+const FUNCTION_ERROR_TYPE_HEADER: &str = "Lambda-Extension-Function-Error-Type";
+// SHUTDOWN の期限までに送り終えるための余裕
+const DEADLINE_MARGIN: Duration = Duration::from_millis(200);
+// Telemetry API は event を sandbox の中のこの名前の address に送る
+const TELEMETRY_HOST: &str = "sandbox.localdomain";
+// timeoutMs は Lambda が受け付ける最小値で、invocation の終わりまでに届くようにする。
+// platform event は 1 回の invocation で数件なので、maxItems と maxBytes に先に達することはない
+const TELEMETRY_BUFFER_MAX_ITEMS: u32 = 1000;
+const TELEMETRY_BUFFER_MAX_BYTES: u32 = 256 * 1024;
+const TELEMETRY_BUFFER_TIMEOUT_MS: u64 = 25;
+// SHUTDOWN の前の invocation の `platform.report` を待つ
+const TELEMETRY_SHUTDOWN_WAIT: Duration = Duration::from_millis(4 * TELEMETRY_BUFFER_TIMEOUT_MS);
+
+/// collector layer の代わりに動かす Lambda extension の設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExtensionConfig {
+    /// OTLP/gRPC を受ける address
+    pub grpc_address: String,
+    /// OTLP/HTTP を受ける address
+    pub http_address: String,
+    /// 1 回の export request の上限 (展開後)
+    pub max_request_bytes: usize,
+    /// これを超えて溜まったら、次の event を待たずに送る
+    pub max_buffered_bytes: usize,
+    /// 溜めておける上限。送れない間に超えた分は捨て、OTLP の partial success で client に伝える
+    pub max_pending_bytes: usize,
+    /// Telemetry API の platform event を span と metrics にする
+    pub telemetry_api: bool,
+    /// Telemetry API から event を受ける address
+    pub telemetry_address: String,
+}
+
+impl Default for ExtensionConfig {
+    fn default() -> Self {
+        Self {
+            grpc_address: "127.0.0.1:4317".to_string(),
+            http_address: "127.0.0.1:4318".to_string(),
+            max_request_bytes: 6 * 1024 * 1024,
+            max_buffered_bytes: 4 * 1024 * 1024,
+            max_pending_bytes: 16 * 1024 * 1024,
+            telemetry_api: false,
+            telemetry_address: "0.0.0.0:4319".to_string(),
+        }
+    }
+}
+
+/// Extensions API の `event/next` の応答
+#[derive(Debug, Deserialize)]
+#[serde(tag = "eventType", rename_all = "SCREAMING_SNAKE_CASE")]
+enum NextEvent {
+    #[serde(rename_all = "camelCase")]
+    Invoke { request_id: String, deadline_ms: u64 },
+    #[serde(rename_all = "camelCase")]
+    Shutdown {
+        shutdown_reason: String,
+        deadline_ms: u64,
+    },
+}
+
+/// `AWS_LAMBDA_RUNTIME_API` の Extensions API
+struct ExtensionsApi {
+    client: reqwest::Client,
+    runtime_api: String,
+    base_url: String,
+    id: String,
+}
+
+impl ExtensionsApi {
+    /// Lambda は実行ファイルの名前で extension を見分ける
+    async fn register(runtime_api: &str, name: &str) -> anyhow::Result<Self> {
+        let base_url: String = format!("http://{}/2020-01-01/extension", runtime_api);
+        // `event/next` は次の invocation まで返らないので timeout を付けない
+        let client: reqwest::Client = reqwest::Client::new();
+        let response: reqwest::Response = client
+            .post(format!("{}/register", base_url))
+            .header(EXTENSION_NAME_HEADER, name)
+            .json(&serde_json::json!({ "events": ["INVOKE", "SHUTDOWN"] }))
+            .send()
+            .await?
+            .error_for_status()?;
+        let id: String = response
+            .headers()
+            .get(EXTENSION_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .context("register response has no extension identifier")?
+            .to_string();
+        tracing::info!("Registered extension `{}`", name);
+        Ok(Self {
+            client,
+            runtime_api: runtime_api.to_string(),
+            base_url,
+            id,
+        })
+    }
+
+    /// `port` で待ち受けている listener に platform event を送らせる
+    async fn subscribe_telemetry(&self, port: u16) -> anyhow::Result<()> {
+        self.client
+            .put(format!("http://{}/2022-07-01/telemetry", self.runtime_api))
+            .header(EXTENSION_ID_HEADER, &self.id)
+            .json(&serde_json::json!({
+                "schemaVersion": telemetry_api::SCHEMA_VERSION,
+                "types": ["platform"],
+                "buffering": {
+                    "maxItems": TELEMETRY_BUFFER_MAX_ITEMS,
+                    "maxBytes": TELEMETRY_BUFFER_MAX_BYTES,
+                    "timeoutMs": TELEMETRY_BUFFER_TIMEOUT_MS,
+                },
+                "destination": {
+                    "protocol": "HTTP",
+                    "URI": format!("http://{}:{}", TELEMETRY_HOST, port),
+                },
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+        tracing::info!("Subscribed to platform telemetry");
+        Ok(())
+    }
+
+    async fn next_event(&self) -> anyhow::Result<NextEvent> {
+        Ok(self
+            .client
+            .get(format!("{}/event/next", self.base_url))
+            .header(EXTENSION_ID_HEADER, &self.id)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// 初期化に失敗したことを伝える。Lambda は function の初期化も失敗させる
+    async fn init_error(&self, error_type: &str, err: &anyhow::Error) {
+        let result = self
+            .client
+            .post(format!("{}/init/error", self.base_url))
+            .header(EXTENSION_ID_HEADER, &self.id)
+            .header(FUNCTION_ERROR_TYPE_HEADER, error_type)
+            .json(&serde_json::json!({
+                "errorMessage": format!("{:#}", err),
+                "errorType": error_type,
+            }))
+            .send()
+            .await;
+        if let Err(err) = result {
+            tracing::error!("Failed to report init error: {}", err);
+        }
+    }
+}
+
+/// resource と scope の入れ子を保ったまま、span やログを件数で分けられる OTLP のデータ
+trait ResourceItems: Message + Sized {
+    type Request: Message;
+
+    fn item_count(&self) -> usize;
+
+    /// 先頭の `at` 件を残し、残りを同じ resource と scope に入れて返す
+    fn split_off(&mut self, at: usize) -> Self;
+
+    fn request(resources: Vec<Self>) -> Self::Request;
+
+    fn into_resources(request: Self::Request) -> Vec<Self>;
+}
+
+impl ResourceItems for ResourceSpans {
+    type Request = ExportTraceServiceRequest;
+
+    fn item_count(&self) -> usize {
+        self.scope_spans.iter().map(|scope| scope.spans.len()).sum()
+    }
+
+    fn split_off(&mut self, at: usize) -> Self {
+        Self {
+            resource: self.resource.clone(),
+            scope_spans: split_scopes(&mut self.scope_spans, at, |scope| &mut scope.spans),
+            schema_url: self.schema_url.clone(),
+        }
+    }
+
+    fn request(resource_spans: Vec<Self>) -> Self::Request {
+        ExportTraceServiceRequest { resource_spans }
+    }
+
+    fn into_resources(request: Self::Request) -> Vec<Self> {
+        request.resource_spans
+    }
+}
+
+impl ResourceItems for ResourceLogs {
+    type Request = ExportLogsServiceRequest;
+
+    fn item_count(&self) -> usize {
+        self.scope_logs.iter().map(|scope| scope.log_records.len()).sum()
+    }
+
+    fn split_off(&mut self, at: usize) -> Self {
+        Self {
+            resource: self.resource.clone(),
+            scope_logs: split_scopes(&mut self.scope_logs, at, |scope| &mut scope.log_records),
+            schema_url: self.schema_url.clone(),
+        }
+    }
+
+    fn request(resource_logs: Vec<Self>) -> Self::Request {
+        ExportLogsServiceRequest { resource_logs }
+    }
+
+    fn into_resources(request: Self::Request) -> Vec<Self> {
+        request.resource_logs
+    }
+}
+
+/// scope の列を先頭から `at` 件目で分け、後ろを返す。途中で分ける scope は両方に残す
+fn split_scopes<S: Clone, I>(
+    scopes: &mut Vec<S>,
+    at: usize,
+    items: fn(&mut S) -> &mut Vec<I>,
+) -> Vec<S> {
+    let mut remaining: usize = at;
+    for index in 0..scopes.len() {
+        let len: usize = items(&mut scopes[index]).len();
+        if remaining >= len {
+            remaining -= len;
+            continue;
+        }
+        let mut tail: Vec<S> = scopes.split_off(index + 1);
+        if remaining == 0 {
+            tail.insert(0, scopes.pop().expect("scope at index"));
+            return tail;
+        }
+        let moved: Vec<I> = items(&mut scopes[index]).split_off(remaining);
+        let kept: Vec<I> = std::mem::take(items(&mut scopes[index]));
+        let mut scope: S = scopes[index].clone();
+        *items(&mut scopes[index]) = kept;
+        *items(&mut scope) = moved;
+        tail.insert(0, scope);
+        return tail;
+    }
+    vec![]
+}
+
+/// 繰り返しフィールドの 1 要素として encode したときの大きさ
+fn field_len(len: usize) -> usize {
+    1 + prost::length_delimiter_len(len) + len
+}
+
+#[derive(Default)]
+struct Pending {
+    spans: VecDeque<ResourceSpans>,
+    logs: VecDeque<ResourceLogs>,
+    /// 溜まっている span とログを encode した大きさの和
+    bytes: usize,
+}
+
+impl Pending {
+    /// `limits` に収まるだけ先頭から取り出す。1 件で上限を超えるものは捨て、その件数を返す
+    fn take_batch<T: ResourceItems>(
+        &mut self,
+        queue: fn(&mut Pending) -> &mut VecDeque<T>,
+        limits: RequestLimits,
+    ) -> (Vec<T>, usize) {
+        let mut batch: Vec<T> = Vec::new();
+        let (mut batch_bytes, mut batch_items, mut dropped) = (0, 0, 0);
+        while let Some(mut resource) = queue(self).pop_front() {
+            let len: usize = resource.encoded_len();
+            let count: usize = resource.item_count();
+            if batch_bytes + field_len(len) <= limits.max_bytes
+                && batch_items + count <= limits.max_items
+            {
+                self.bytes = self.bytes.saturating_sub(len);
+                batch_bytes += field_len(len);
+                batch_items += count;
+                batch.push(resource);
+                continue;
+            }
+            if !batch.is_empty() {
+                queue(self).push_front(resource);
+                break;
+            }
+            if count > 1 {
+                // 1 つの resource が上限を超えるので、半分ずつに分けて詰め直す
+                let tail: T = resource.split_off(count / 2);
+                self.bytes = (self.bytes + resource.encoded_len() + tail.encoded_len())
+                    .saturating_sub(len);
+                queue(self).push_front(tail);
+                queue(self).push_front(resource);
+                continue;
+            }
+            self.bytes = self.bytes.saturating_sub(len);
+            dropped += count;
+        }
+        (batch, dropped)
+    }
+
+    /// 送れなかった batch を先頭に戻す
+    fn requeue<T: ResourceItems>(
+        &mut self,
+        queue: fn(&mut Pending) -> &mut VecDeque<T>,
+        batch: Vec<T>,
+    ) {
+        self.bytes += batch.iter().map(Message::encoded_len).sum::<usize>();
+        for resource in batch.into_iter().rev() {
+            queue(self).push_front(resource);
+        }
+    }
+}
+
+/// 溜められずに捨てた span とログの数
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Rejected {
+    spans: usize,
+    log_records: usize,
+}
+
+/// 受け取った span とログを溜め、X-Ray と CloudWatch Logs にまとめて送る
+#[derive(Clone)]
+struct Forwarder {
+    pending: Arc<Mutex<Pending>>,
+    flush_requested: Arc<Notify>,
+    traces: Arc<AwsOtlpEndpoint>,
+    logs: Arc<AwsOtlpEndpoint>,
+    max_request_bytes: usize,
+    max_buffered_bytes: usize,
+    max_pending_bytes: usize,
+    dropped_spans: Arc<AtomicUsize>,
+    dropped_log_records: Arc<AtomicUsize>,
+}
+
+impl Forwarder {
+    fn new(config: &Config, credentials: AwsCredentialsProvider) -> anyhow::Result<Self> {
+        config.otel.aws.validate()?;
+        Ok(Self {
+            pending: Arc::default(),
+            flush_requested: Arc::default(),
+            traces: Arc::new(AwsOtlpEndpoint::traces(&config.otel, credentials.clone())?),
+            logs: Arc::new(AwsOtlpEndpoint::logs(&config.otel, credentials)?),
+            max_request_bytes: config.extension.max_request_bytes,
+            max_buffered_bytes: config.extension.max_buffered_bytes,
+            max_pending_bytes: config.extension.max_pending_bytes,
+            dropped_spans: Arc::default(),
+            dropped_log_records: Arc::default(),
+        })
+    }
+
+    /// `max_pending_bytes` に収まるものだけ溜め、収まらなかった件数を返す
+    fn push(&self, spans: Vec<ResourceSpans>, logs: Vec<ResourceLogs>) -> Rejected {
+        let mut rejected: Rejected = Rejected::default();
+        let mut pending = self.pending.lock().unwrap();
+        for resource in spans {
+            let len: usize = resource.encoded_len();
+            if pending.bytes + len > self.max_pending_bytes {
+                rejected.spans += resource.item_count();
+                continue;
+            }
+            pending.bytes += len;
+            pending.spans.push_back(resource);
+        }
+        for resource in logs {
+            let len: usize = resource.encoded_len();
+            if pending.bytes + len > self.max_pending_bytes {
+                rejected.log_records += resource.item_count();
+                continue;
+            }
+            pending.bytes += len;
+            pending.logs.push_back(resource);
+        }
+        if pending.bytes > self.max_buffered_bytes {
+            self.flush_requested.notify_one();
+        }
+        drop(pending);
+        if rejected != Rejected::default() {
+            self.dropped_spans.fetch_add(rejected.spans, Ordering::Relaxed);
+            self.dropped_log_records
+                .fetch_add(rejected.log_records, Ordering::Relaxed);
+            tracing::warn!(
+                rejected.spans,
+                rejected.log_records,
+                "Dropped telemetry because the forwarding buffer is full"
+            );
+        }
+        rejected
+    }
+
+    /// 溜まっているものを endpoint の上限に収まる request に分けて送る
+    async fn flush(&self) {
+        tokio::join!(
+            self.flush_queue(
+                &self.traces,
+                |pending| &mut pending.spans,
+                &self.dropped_spans,
+                "spans",
+            ),
+            self.flush_queue(
+                &self.logs,
+                |pending| &mut pending.logs,
+                &self.dropped_log_records,
+                "log records",
+            ),
+        );
+    }
+
+    /// 再送できる失敗なら送れなかった分を戻して次の flush に回し、それ以外の失敗は捨てる
+    async fn flush_queue<T: ResourceItems>(
+        &self,
+        endpoint: &AwsOtlpEndpoint,
+        queue: fn(&mut Pending) -> &mut VecDeque<T>,
+        dropped: &AtomicUsize,
+        signal: &str,
+    ) {
+        let limits: RequestLimits = endpoint.limits().min(RequestLimits {
+            max_bytes: self.max_request_bytes,
+            max_items: usize::MAX,
+        });
+        loop {
+            let (batch, too_large) = self.pending.lock().unwrap().take_batch(queue, limits);
+            if too_large > 0 {
+                dropped.fetch_add(too_large, Ordering::Relaxed);
+                tracing::warn!("Dropped {} {} larger than the request limit", too_large, signal);
+            }
+            if batch.is_empty() {
+                return;
+            }
+            let count: usize = batch.iter().map(ResourceItems::item_count).sum();
+            let request: T::Request = T::request(batch);
+            match endpoint.export(&request).await {
+                Ok(()) => {}
+                Err(err) if err.is_retryable() => {
+                    tracing::warn!(
+                        "Failed to forward {} {}, retrying on the next flush: {:#}",
+                        count,
+                        signal,
+                        err
+                    );
+                    self.pending
+                        .lock()
+                        .unwrap()
+                        .requeue(queue, T::into_resources(request));
+                    return;
+                }
+                Err(err) => {
+                    dropped.fetch_add(count, Ordering::Relaxed);
+                    tracing::error!(
+                        "Dropped {} {} rejected by the endpoint: {:#}",
+                        count,
+                        signal,
+                        err
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl TraceService for Forwarder {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportTraceServiceRequest>,
+    ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+        let rejected: Rejected = self.push(request.into_inner().resource_spans, vec![]);
+        Ok(tonic::Response::new(trace_response(rejected)))
+    }
+}
+
+#[tonic::async_trait]
+impl LogsService for Forwarder {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportLogsServiceRequest>,
+    ) -> Result<tonic::Response<ExportLogsServiceResponse>, tonic::Status> {
+        let rejected: Rejected = self.push(vec![], request.into_inner().resource_logs);
+        Ok(tonic::Response::new(logs_response(rejected)))
+    }
+}
+
+// buffer に収まらなかった分は OTLP の partial success で client に伝える
+const BUFFER_FULL_MESSAGE: &str = "the extension's forwarding buffer is full";
+
+fn trace_response(rejected: Rejected) -> ExportTraceServiceResponse {
+    ExportTraceServiceResponse {
+        partial_success: (rejected.spans > 0).then(|| ExportTracePartialSuccess {
+            rejected_spans: rejected.spans as i64,
+            error_message: BUFFER_FULL_MESSAGE.to_string(),
+        }),
+    }
+}
+
+fn logs_response(rejected: Rejected) -> ExportLogsServiceResponse {
+    ExportLogsServiceResponse {
+        partial_success: (rejected.log_records > 0).then(|| ExportLogsPartialSuccess {
+            rejected_log_records: rejected.log_records as i64,
+            error_message: BUFFER_FULL_MESSAGE.to_string(),
+        }),
+    }
+}
+
+async fn export_traces(
+    State(forwarder): State<Forwarder>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let (request, encoding): (ExportTraceServiceRequest, _) =
+        otlp::decode(&headers, &body, forwarder.max_request_bytes)?;
+    let rejected: Rejected = forwarder.push(request.resource_spans, vec![]);
+    Ok(otlp::encode_response(&trace_response(rejected), encoding))
+}
+
+async fn export_logs(
+    State(forwarder): State<Forwarder>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let (request, encoding): (ExportLogsServiceRequest, _) =
+        otlp::decode(&headers, &body, forwarder.max_request_bytes)?;
+    let rejected: Rejected = forwarder.push(vec![], request.resource_logs);
+    Ok(otlp::encode_response(&logs_response(rejected), encoding))
+}
+
+#[derive(Clone)]
+struct TelemetryListener {
+    forwarder: Forwarder,
+    telemetry: Arc<PlatformTelemetry>,
+    emf: Arc<EmfExporter>,
+}
+
+// Lambda が送る Telemetry API のイベントは OpenAPI に載らないので検証しない
+#[allow(clippy::disallowed_types)]
+async fn receive_telemetry(
+    State(listener): State<TelemetryListener>,
+    axum::Json(events): axum::Json<Vec<TelemetryEvent>>,
+) {
+    let (spans, metrics) = listener.telemetry.record(events);
+    listener.forwarder.push(spans, vec![]);
+    if metrics.is_empty() {
+        return;
+    }
+    let request = ExportMetricsServiceRequest {
+        resource_metrics: metrics,
+    };
+    if let Err(err) = EmfExporter::write(&listener.emf.proto_documents(&request, SystemTime::now())) {
+        tracing::error!("Failed to write platform metrics: {}", err);
+    }
+}
+
+fn grpc_router(forwarder: &Forwarder) -> Router {
+    let max_bytes: usize = forwarder.max_request_bytes;
+    tonic::service::Routes::new(
+        TraceServiceServer::new(forwarder.clone())
+            .accept_compressed(tonic::codec::CompressionEncoding::Gzip)
+            .max_decoding_message_size(max_bytes),
+    )
+    .add_service(
+        LogsServiceServer::new(forwarder.clone())
+            .accept_compressed(tonic::codec::CompressionEncoding::Gzip)
+            .max_decoding_message_size(max_bytes),
+    )
+    .into_axum_router()
+}
+
+fn http_router(forwarder: &Forwarder) -> Router {
+    // gzip の展開後の大きさは decode で制限する
+    Router::new()
+        .route("/v1/traces", post(export_traces))
+        .route("/v1/logs", post(export_logs))
+        .layer(DefaultBodyLimit::max(forwarder.max_request_bytes))
+        .with_state(forwarder.clone())
+}
+
+/// 実行ファイルの名前。`/opt/extensions/<name>` に置く
+fn extension_name() -> String {
+    std::env::args()
+        .next()
+        .as_deref()
+        .map(std::path::Path::new)
+        .and_then(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| DEFAULT_EXTENSION_NAME.to_string())
+}
+
+/// `deadline_ms` (epoch ミリ秒) まで、余裕を残して待つ
+fn until_deadline(deadline_ms: u64) -> Duration {
+    let now: Duration = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    Duration::from_millis(deadline_ms)
+        .saturating_sub(now)
+        .saturating_sub(DEADLINE_MARGIN)
+}
+
+/// Extensions API に登録して OTLP の receiver を起動し、`INVOKE` と `SHUTDOWN` のたびに溜まったものを送る
+///
+/// function の span とログは invocation の終わりに届くので、`INVOKE` では前の invocation の分を送ることになる
+pub async fn run(config: &Config) -> anyhow::Result<()> {
+    let runtime_api: String = std::env::var("AWS_LAMBDA_RUNTIME_API")
+        .context("`AWS_LAMBDA_RUNTIME_API` is not set")?;
+    let api: ExtensionsApi = ExtensionsApi::register(&runtime_api, &extension_name()).await?;
+    let forwarder: anyhow::Result<Forwarder> = AwsCredentialsProvider::from_env()
+        .and_then(|credentials| Forwarder::new(config, credentials));
+    serve(config, &api, forwarder).await
+}
+
+/// receiver を起動して `event/next` を待つ。起動に失敗したら `init/error` で Lambda に伝える
+async fn serve(
+    config: &Config,
+    api: &ExtensionsApi,
+    forwarder: anyhow::Result<Forwarder>,
+) -> anyhow::Result<()> {
+    let started: anyhow::Result<Forwarder> = async {
+        let forwarder: Forwarder = forwarder?;
+        let grpc: TcpListener = TcpListener::bind(&config.extension.grpc_address)
+            .await
+            .with_context(|| format!("failed to bind {}", config.extension.grpc_address))?;
+        let http: TcpListener = TcpListener::bind(&config.extension.http_address)
+            .await
+            .with_context(|| format!("failed to bind {}", config.extension.http_address))?;
+        tokio::spawn(axum::serve(grpc, grpc_router(&forwarder)).into_future());
+        tokio::spawn(axum::serve(http, http_router(&forwarder)).into_future());
+        if config.extension.telemetry_api {
+            let telemetry: TcpListener = TcpListener::bind(&config.extension.telemetry_address)
+                .await
+                .with_context(|| format!("failed to bind {}", config.extension.telemetry_address))?;
+            let port: u16 = telemetry.local_addr()?.port();
+            let listener = TelemetryListener {
+                forwarder: forwarder.clone(),
+                telemetry: Arc::new(PlatformTelemetry::new(&crate::otel::init_resource(config))),
+                emf: Arc::new(EmfExporter::new(&config.otel.emf)),
+            };
+            let router: Router = Router::new()
+                .route("/", post(receive_telemetry))
+                .with_state(listener);
+            tokio::spawn(axum::serve(telemetry, router).into_future());
+            api.subscribe_telemetry(port).await?;
+        }
+        Ok(forwarder)
+    }
+    .await;
+    let forwarder: Forwarder = match started {
+        Ok(forwarder) => forwarder,
+        Err(err) => {
+            api.init_error("Extension.InitFailed", &err).await;
+            return Err(err);
+        }
+    };
+
+    let early_flush: Forwarder = forwarder.clone();
+    tokio::spawn(async move {
+        loop {
+            early_flush.flush_requested.notified().await;
+            early_flush.flush().await;
+        }
+    });
+
+    loop {
+        match api.next_event().await? {
+            NextEvent::Invoke {
+                request_id,
+                deadline_ms,
+            } => {
+                tracing::debug!(request_id, "Forwarding telemetry on INVOKE");
+                if tokio::time::timeout(until_deadline(deadline_ms), forwarder.flush())
+                    .await
+                    .is_err()
+                {
+                    tracing::warn!("Forwarding telemetry did not finish before the deadline");
+                }
+            }
+            NextEvent::Shutdown {
+                shutdown_reason,
+                deadline_ms,
+            } => {
+                tracing::info!(shutdown_reason, "Forwarding telemetry on SHUTDOWN");
+                let flush = async {
+                    if config.extension.telemetry_api {
+                        tokio::time::sleep(TELEMETRY_SHUTDOWN_WAIT).await;
+                    }
+                    forwarder.flush().await
+                };
+                if tokio::time::timeout(until_deadline(deadline_ms), flush)
+                    .await
+                    .is_err()
+                {
+                    tracing::warn!("Dropped telemetry that was not forwarded before shutdown");
+                }
+                let dropped_spans: usize = forwarder.dropped_spans.load(Ordering::Relaxed);
+                let dropped_log_records: usize =
+                    forwarder.dropped_log_records.load(Ordering::Relaxed);
+                if dropped_spans > 0 || dropped_log_records > 0 {
+                    tracing::warn!(
+                        dropped_spans,
+                        dropped_log_records,
+                        "Telemetry was dropped during this execution environment"
+                    );
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use axum::{
+        extract::Path,
+        http::{HeaderValue, StatusCode},
+        response::IntoResponse,
+        routing::get,
+    };
+    use opentelemetry_proto::tonic::{
+        logs::v1::{LogRecord, ScopeLogs},
+        trace::v1::{ScopeSpans, Span},
+    };
+
+    use super::*;
+    use crate::sigv4::AwsCredentials;
+    use crate::validation::ApiJson;
+
+    fn resource_spans(scopes: &[usize]) -> ResourceSpans {
+        ResourceSpans {
+            scope_spans: scopes
+                .iter()
+                .enumerate()
+                .map(|(scope, &count)| ScopeSpans {
+                    schema_url: format!("scope-{}", scope),
+                    spans: (0..count)
+                        .map(|span| Span {
+                            name: format!("span-{}-{}", scope, span),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn resource_logs(count: usize) -> ResourceLogs {
+        ResourceLogs {
+            scope_logs: vec![ScopeLogs {
+                log_records: vec![LogRecord::default(); count],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn span_names(resources: &[ResourceSpans]) -> Vec<String> {
+        resources
+            .iter()
+            .flat_map(|resource| &resource.scope_spans)
+            .flat_map(|scope| &scope.spans)
+            .map(|span| span.name.clone())
+            .collect()
+    }
+
+    /// X-Ray と CloudWatch Logs の代わり。受け取った request の件数を記録し、`statuses` の順に応答する
+    #[derive(Clone, Default)]
+    struct FakeAws {
+        spans: Arc<Mutex<Vec<usize>>>,
+        log_records: Arc<Mutex<Vec<usize>>>,
+        statuses: Arc<Mutex<VecDeque<StatusCode>>>,
+    }
+
+    async fn fake_aws_export(
+        State(aws): State<FakeAws>,
+        Path(signal): Path<String>,
+        body: Bytes,
+    ) -> StatusCode {
+        let status: StatusCode = aws
+            .statuses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or(StatusCode::OK);
+        if status != StatusCode::OK {
+            return status;
+        }
+        let mut decoded: Vec<u8> = Vec::new();
+        flate2::read::GzDecoder::new(body.as_ref())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        if signal == "traces" {
+            let request = ExportTraceServiceRequest::decode(decoded.as_slice()).unwrap();
+            let count: usize = request.resource_spans.iter().map(ResourceItems::item_count).sum();
+            aws.spans.lock().unwrap().push(count);
+        } else {
+            let request = ExportLogsServiceRequest::decode(decoded.as_slice()).unwrap();
+            let count: usize = request.resource_logs.iter().map(ResourceItems::item_count).sum();
+            aws.log_records.lock().unwrap().push(count);
+        }
+        status
+    }
+
+    async fn spawn(router: Router) -> String {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address: String = listener.local_addr().unwrap().to_string();
+        tokio::spawn(axum::serve(listener, router).into_future());
+        address
+    }
+
+    async fn config(aws: &FakeAws) -> Config {
+        let address: String = spawn(
+            Router::new()
+                .route("/v1/{signal}", post(fake_aws_export))
+                .with_state(aws.clone()),
+        )
+        .await;
+        let mut config: Config = Config::default();
+        config.otel.aws.region = "us-east-1".to_string();
+        config.otel.aws.log_group = "/aws/lambda/test".to_string();
+        config.otel.aws.traces_endpoint = Some(format!("http://{}/v1/traces", address));
+        config.otel.aws.logs_endpoint = Some(format!("http://{}/v1/logs", address));
+        config.extension.grpc_address = "127.0.0.1:0".to_string();
+        config.extension.http_address = "127.0.0.1:0".to_string();
+        config
+    }
+
+    fn forwarder(config: &Config) -> Forwarder {
+        let credentials: AwsCredentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "secret".to_string(),
+            session_token: None,
+            expiration: None,
+        };
+        Forwarder::new(config, AwsCredentialsProvider::Static(credentials)).unwrap()
+    }
+
+    /// Extensions API の代わり。`event/next` の 1 回目は INVOKE、2 回目は function のログを溜めてから SHUTDOWN を返す
+    #[derive(Clone)]
+    struct FakeExtensionsApi {
+        calls: Arc<Mutex<Vec<String>>>,
+        forwarder: Option<Forwarder>,
+    }
+
+    async fn fake_register(State(api): State<FakeExtensionsApi>, headers: HeaderMap) -> Response {
+        let name: &str = headers[EXTENSION_NAME_HEADER].to_str().unwrap();
+        api.calls.lock().unwrap().push(format!("register {}", name));
+        let mut response: Response = StatusCode::OK.into_response();
+        response
+            .headers_mut()
+            .insert(EXTENSION_ID_HEADER, HeaderValue::from_static("extension-id"));
+        response
+    }
+
+    async fn fake_next(State(api): State<FakeExtensionsApi>) -> ApiJson<serde_json::Value> {
+        let count: usize = {
+            let mut calls = api.calls.lock().unwrap();
+            calls.push("next".to_string());
+            calls.iter().filter(|call| *call == "next").count()
+        };
+        let deadline_ms: u128 = (SystemTime::now() + Duration::from_secs(10))
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        if count == 1 {
+            return ApiJson(serde_json::json!({
+                "eventType": "INVOKE",
+                "requestId": "request-1",
+                "deadlineMs": deadline_ms,
+            }));
+        }
+        if let Some(forwarder) = &api.forwarder {
+            forwarder.push(vec![], vec![resource_logs(3)]);
+        }
+        ApiJson(serde_json::json!({
+            "eventType": "SHUTDOWN",
+            "shutdownReason": "spindown",
+            "deadlineMs": deadline_ms,
+        }))
+    }
+
+    async fn fake_init_error(State(api): State<FakeExtensionsApi>, headers: HeaderMap) {
+        let error_type: &str = headers[FUNCTION_ERROR_TYPE_HEADER].to_str().unwrap();
+        api.calls
+            .lock()
+            .unwrap()
+            .push(format!("init/error {}", error_type));
+    }
+
+    async fn register(api: FakeExtensionsApi) -> ExtensionsApi {
+        let address: String = spawn(
+            Router::new()
+                .route("/2020-01-01/extension/register", post(fake_register))
+                .route("/2020-01-01/extension/event/next", get(fake_next))
+                .route("/2020-01-01/extension/init/error", post(fake_init_error))
+                .with_state(api),
+        )
+        .await;
+        ExtensionsApi::register(&address, "otel-extension").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn forwards_on_invoke_and_shutdown() {
+        let aws: FakeAws = FakeAws::default();
+        let config: Config = config(&aws).await;
+        let forwarder: Forwarder = forwarder(&config);
+        let calls: Arc<Mutex<Vec<String>>> = Arc::default();
+        let api: ExtensionsApi = register(FakeExtensionsApi {
+            calls: calls.clone(),
+            forwarder: Some(forwarder.clone()),
+        })
+        .await;
+        assert_eq!(api.id, "extension-id");
+
+        // 前の invocation の span は INVOKE で、function が最後に書いたログは SHUTDOWN で送る
+        forwarder.push(vec![resource_spans(&[2])], vec![]);
+        serve(&config, &api, Ok(forwarder)).await.unwrap();
+        assert_eq!(*aws.spans.lock().unwrap(), [2]);
+        assert_eq!(*aws.log_records.lock().unwrap(), [3]);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["register otel-extension", "next", "next"]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_init_errors() {
+        let aws: FakeAws = FakeAws::default();
+        let mut config: Config = config(&aws).await;
+        config.extension.grpc_address = "invalid address".to_string();
+        let calls: Arc<Mutex<Vec<String>>> = Arc::default();
+        let api: ExtensionsApi = register(FakeExtensionsApi {
+            calls: calls.clone(),
+            forwarder: None,
+        })
+        .await;
+
+        assert!(serve(&config, &api, Ok(forwarder(&config))).await.is_err());
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["register otel-extension", "init/error Extension.InitFailed"]
+        );
+    }
+
+    #[tokio::test]
+    async fn flush_splits_requests_and_keeps_retryable_failures() {
+        let aws: FakeAws = FakeAws::default();
+        let mut config: Config = config(&aws).await;
+        let span_bytes: usize = resource_spans(&[1]).encoded_len();
+        // resource 2 つ分に収まらない上限
+        config.extension.max_request_bytes = 2 * span_bytes;
+        let forwarder: Forwarder = forwarder(&config);
+        forwarder.push(
+            vec![resource_spans(&[1]), resource_spans(&[1]), resource_spans(&[1])],
+            vec![resource_logs(1)],
+        );
+
+        aws.statuses
+            .lock()
+            .unwrap()
+            .extend([StatusCode::SERVICE_UNAVAILABLE, StatusCode::SERVICE_UNAVAILABLE]);
+        forwarder.flush().await;
+        assert!(aws.spans.lock().unwrap().is_empty());
+        assert!(aws.log_records.lock().unwrap().is_empty());
+        assert_eq!(forwarder.pending.lock().unwrap().spans.len(), 3);
+
+        forwarder.flush().await;
+        assert_eq!(*aws.spans.lock().unwrap(), [1, 1, 1]);
+        assert_eq!(*aws.log_records.lock().unwrap(), [1]);
+        assert_eq!(forwarder.pending.lock().unwrap().bytes, 0);
+        assert_eq!(forwarder.dropped_spans.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn flush_drops_rejected_requests() {
+        let aws: FakeAws = FakeAws::default();
+        let config: Config = config(&aws).await;
+        let forwarder: Forwarder = forwarder(&config);
+        forwarder.push(vec![resource_spans(&[2])], vec![]);
+        aws.statuses.lock().unwrap().push_back(StatusCode::BAD_REQUEST);
+        forwarder.flush().await;
+        assert!(forwarder.pending.lock().unwrap().spans.is_empty());
+        assert_eq!(forwarder.dropped_spans.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn push_rejects_what_does_not_fit() {
+        let aws: FakeAws = FakeAws::default();
+        let mut config: Config = config(&aws).await;
+        config.extension.max_pending_bytes = resource_spans(&[2]).encoded_len();
+        let forwarder: Forwarder = forwarder(&config);
+
+        let rejected: Rejected = forwarder.push(
+            vec![resource_spans(&[2]), resource_spans(&[3])],
+            vec![resource_logs(4)],
+        );
+        assert_eq!(
+            rejected,
+            Rejected {
+                spans: 3,
+                log_records: 4
+            }
+        );
+        let response: ExportTraceServiceResponse = trace_response(rejected);
+        assert_eq!(response.partial_success.unwrap().rejected_spans, 3);
+        assert_eq!(forwarder.dropped_log_records.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn take_batch_splits_large_resources_within_scopes() {
+        let mut pending: Pending = Pending::default();
+        let resource: ResourceSpans = resource_spans(&[2, 3]);
+        pending.bytes = resource.encoded_len();
+        pending.spans.push_back(resource);
+        let limits: RequestLimits = RequestLimits {
+            max_bytes: usize::MAX,
+            max_items: 2,
+        };
+
+        let mut batches: Vec<Vec<ResourceSpans>> = vec![];
+        loop {
+            let (batch, dropped) = pending.take_batch(|pending| &mut pending.spans, limits);
+            assert_eq!(dropped, 0);
+            if batch.is_empty() {
+                break;
+            }
+            assert!(batch.iter().map(ResourceItems::item_count).sum::<usize>() <= 2);
+            batches.push(batch);
+        }
+        assert_eq!(
+            span_names(&batches.concat()),
+            ["span-0-0", "span-0-1", "span-1-0", "span-1-1", "span-1-2"]
+        );
+        // 分けた後も span は元の scope に入っている
+        for resource in batches.concat() {
+            for scope in &resource.scope_spans {
+                let prefix: String = format!("span-{}-", &scope.schema_url["scope-".len()..]);
+                assert!(scope.spans.iter().all(|span| span.name.starts_with(&prefix)));
+            }
+        }
+        assert_eq!(pending.bytes, 0);
+    }
+
+    #[test]
+    fn take_batch_drops_single_items_over_the_byte_limit() {
+        let mut pending: Pending = Pending::default();
+        pending.spans.push_back(resource_spans(&[1]));
+        let limits: RequestLimits = RequestLimits {
+            max_bytes: 1,
+            max_items: usize::MAX,
+        };
+        let (batch, dropped) = pending.take_batch(|pending| &mut pending.spans, limits);
+        assert!(batch.is_empty());
+        assert_eq!(dropped, 1);
+    }
+}
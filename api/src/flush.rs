@@ -0,0 +1,496 @@
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use opentelemetry::{
+    Context, InstrumentationScope, KeyValue,
+    metrics::{Histogram, Meter},
+};
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    logs::{LogProcessor, SdkLoggerProvider, SdkLogRecord},
+    metrics::SdkMeterProvider,
+    trace::{SdkTracerProvider, Span, SpanData, SpanProcessor},
+};
+use serde::{Deserialize, Serialize};
+
+/// Lambda で invocation の後に provider を flush する方法
+///
+/// `OTEL_LAMBDA_FLUSH_STRATEGY` と同じ名前で指定する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlushStrategy {
+    /// invocation ごとに flush し終えてから次の event を待つ
+    EveryInvocation,
+    /// `every_invocations` 回の invocation か `interval_ms` ごとに flush する
+    Periodic,
+    /// まだ送っていない span とログが `queue_threshold` 件を超えたら flush する
+    QueueThreshold,
+    /// invocation ごとに、終わりを待たずに別のスレッドで flush する
+    ///
+    /// 次の event を待つ間は実行環境が止まるので、送り終えるのは次の invocation の中になることがある
+    Async,
+}
+
+impl std::str::FromStr for FlushStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "every_invocation" => Ok(FlushStrategy::EveryInvocation),
+            "periodic" => Ok(FlushStrategy::Periodic),
+            "queue_threshold" => Ok(FlushStrategy::QueueThreshold),
+            "async" => Ok(FlushStrategy::Async),
+            _ => anyhow::bail!(
+                "unsupported flush strategy `{}` (every_invocation, periodic, queue_threshold or async)",
+                s
+            ),
+        }
+    }
+}
+
+impl FlushStrategy {
+    fn as_str(self) -> &'static str {
+        match self {
+            FlushStrategy::EveryInvocation => "every_invocation",
+            FlushStrategy::Periodic => "periodic",
+            FlushStrategy::QueueThreshold => "queue_threshold",
+            FlushStrategy::Async => "async",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlushConfig {
+    pub strategy: FlushStrategy,
+    /// `periodic` で flush する invocation の回数
+    pub every_invocations: u32,
+    /// `periodic` で flush する間隔
+    pub interval_ms: u64,
+    /// `queue_threshold` で flush する件数
+    pub queue_threshold: usize,
+}
+
+impl Default for FlushConfig {
+    fn default() -> Self {
+        Self {
+            strategy: FlushStrategy::EveryInvocation,
+            every_invocations: 10,
+            interval_ms: 10_000,
+            queue_threshold: 512,
+        }
+    }
+}
+
+impl FlushConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.every_invocations == 0 || self.interval_ms == 0 || self.queue_threshold == 0 {
+            anyhow::bail!(
+                "otel.flush.every_invocations, interval_ms and queue_threshold must be positive"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// 前の flush の後に終わった span と出力したログの件数
+///
+/// batch processor は待っている件数を公開しないので、同じ provider に processor として登録して数える
+#[derive(Debug, Clone, Default)]
+pub struct PendingTelemetry(Arc<AtomicUsize>);
+
+impl PendingTelemetry {
+    fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+impl SpanProcessor for PendingTelemetry {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, _span: SpanData) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        Ok(())
+    }
+}
+
+impl LogProcessor for PendingTelemetry {
+    fn emit(&self, _data: &mut SdkLogRecord, _instrumentation: &InstrumentationScope) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+}
+
+/// invocation の終わりに、`FlushStrategy` に従って provider を flush する
+#[derive(Clone)]
+pub struct Flusher(Arc<FlusherInner>);
+
+struct FlusherInner {
+    config: FlushConfig,
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+    logger_provider: SdkLoggerProvider,
+    pending: PendingTelemetry,
+    invocations: AtomicU32,
+    last_flush: Mutex<Instant>,
+    duration: Histogram<f64>,
+}
+
+impl Flusher {
+    pub fn new(
+        config: &FlushConfig,
+        tracer_provider: &SdkTracerProvider,
+        meter_provider: &SdkMeterProvider,
+        logger_provider: &SdkLoggerProvider,
+        pending: PendingTelemetry,
+        meter: &Meter,
+    ) -> Self {
+        Self(Arc::new(FlusherInner {
+            config: config.clone(),
+            tracer_provider: tracer_provider.clone(),
+            meter_provider: meter_provider.clone(),
+            logger_provider: logger_provider.clone(),
+            pending,
+            invocations: AtomicU32::new(0),
+            last_flush: Mutex::new(Instant::now()),
+            duration: meter
+                .f64_histogram("telemetry.flush.duration")
+                .with_unit("s")
+                .with_description("Duration of flushing the OpenTelemetry providers")
+                .build(),
+        }))
+    }
+
+    /// `OTelLayer` から invocation の終わりに呼ぶ
+    pub fn on_invocation_end(&self) {
+        let inner: &FlusherInner = &self.0;
+        match inner.config.strategy {
+            FlushStrategy::EveryInvocation => self.flush(),
+            FlushStrategy::Periodic => {
+                let invocations: u32 = inner.invocations.fetch_add(1, Ordering::Relaxed) + 1;
+                let interval: Duration = Duration::from_millis(inner.config.interval_ms);
+                if invocations >= inner.config.every_invocations
+                    || inner.last_flush.lock().unwrap().elapsed() >= interval
+                {
+                    self.flush();
+                }
+            }
+            FlushStrategy::QueueThreshold => {
+                if inner.pending.count() >= inner.config.queue_threshold {
+                    self.flush();
+                }
+            }
+            FlushStrategy::Async => {
+                let flusher: Flusher = self.clone();
+                // force_flush は export が終わるまでスレッドを止める
+                tokio::task::spawn_blocking(move || flusher.flush());
+            }
+        }
+    }
+
+    /// すべての provider を flush する。失敗してもログに出すだけで続ける
+    pub fn flush(&self) {
+        let inner: &FlusherInner = &self.0;
+        let started: Instant = Instant::now();
+        inner.pending.reset();
+        inner.invocations.store(0, Ordering::Relaxed);
+        *inner.last_flush.lock().unwrap() = started;
+
+        let mut failed: bool = false;
+        for (signal, result) in [
+            ("traces", inner.tracer_provider.force_flush()),
+            ("metrics", inner.meter_provider.force_flush()),
+            ("logs", inner.logger_provider.force_flush()),
+        ] {
+            if let Err(err) = result {
+                failed = true;
+                tracing::error!("Failed to flush OpenTelemetry {}: {}", signal, err);
+            }
+        }
+        // 記録した値は次の flush で送られる
+        inner.duration.record(
+            started.elapsed().as_secs_f64(),
+            &[
+                KeyValue::new("telemetry.flush.strategy", inner.config.strategy.as_str()),
+                KeyValue::new(
+                    "telemetry.flush.result",
+                    if failed { "failure" } else { "success" },
+                ),
+            ],
+        );
+    }
+}
+
+/// Lambda が実行環境を止める前に送る SIGTERM で、flush していない分を送る
+///
+/// Lambda は extension が登録されているときだけ SIGTERM を送る
+#[cfg(feature = "lambda")]
+pub async fn flush_on_sigterm(flusher: Flusher) {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(err) => {
+            tracing::warn!("Failed to listen for SIGTERM: {}", err);
+            return;
+        }
+    };
+    sigterm.recv().await;
+    tracing::info!("OpenTelemetry provider flush on SIGTERM");
+    let _ = tokio::task::spawn_blocking(move || flusher.flush()).await;
+    std::process::exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::{Span as _, Tracer, TracerProvider};
+    use opentelemetry_sdk::error::OTelSdkError;
+    use opentelemetry_sdk::logs::LoggerProviderBuilder;
+    use opentelemetry_sdk::trace::{BatchConfigBuilder, BatchSpanProcessor, InMemorySpanExporter};
+    use tracing_subscriber::Layer;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::testing::TestMeter;
+
+    /// `error` のイベントを数える
+    #[derive(Clone, Default)]
+    struct CountErrorEvents(Arc<AtomicUsize>);
+
+    impl<S: tracing::Subscriber> Layer<S> for CountErrorEvents {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if *event.metadata().level() == tracing::Level::ERROR {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// force_flush が必ず失敗する log processor
+    #[derive(Debug)]
+    struct FailingLogs;
+
+    impl LogProcessor for FailingLogs {
+        fn emit(&self, _data: &mut SdkLogRecord, _instrumentation: &InstrumentationScope) {}
+
+        fn force_flush(&self) -> OTelSdkResult {
+            Err(OTelSdkError::InternalFailure("export failed".to_string()))
+        }
+    }
+
+    struct Harness {
+        flusher: Flusher,
+        spans: InMemorySpanExporter,
+        tracer_provider: SdkTracerProvider,
+        meter: TestMeter,
+    }
+
+    impl Harness {
+        fn new(config: FlushConfig) -> Self {
+            Self::with_logger(config, SdkLoggerProvider::builder())
+        }
+
+        /// flush するまで span を送らない provider で `Flusher` を作る
+        fn with_logger(config: FlushConfig, logger: LoggerProviderBuilder) -> Self {
+            let spans: InMemorySpanExporter = InMemorySpanExporter::default();
+            let pending: PendingTelemetry = PendingTelemetry::default();
+            let batch = BatchSpanProcessor::builder(spans.clone())
+                .with_batch_config(
+                    BatchConfigBuilder::default()
+                        .with_scheduled_delay(Duration::from_secs(3600))
+                        .build(),
+                )
+                .build();
+            let tracer_provider: SdkTracerProvider = SdkTracerProvider::builder()
+                .with_span_processor(batch)
+                .with_span_processor(pending.clone())
+                .build();
+            let logger_provider: SdkLoggerProvider =
+                logger.with_log_processor(pending.clone()).build();
+            let meter: TestMeter = TestMeter::new();
+            let flusher: Flusher = Flusher::new(
+                &config,
+                &tracer_provider,
+                &SdkMeterProvider::builder().build(),
+                &logger_provider,
+                pending,
+                &meter.meter(),
+            );
+            Self {
+                flusher,
+                spans,
+                tracer_provider,
+                meter,
+            }
+        }
+
+        fn end_span(&self) {
+            self.tracer_provider.tracer("test").start("work").end();
+        }
+
+        fn exported_spans(&self) -> usize {
+            self.spans.get_finished_spans().unwrap().len()
+        }
+
+        /// `telemetry.flush.duration` の属性ごとの記録回数
+        fn flushes(&self) -> std::collections::BTreeMap<String, f64> {
+            self.meter.values("telemetry.flush.duration")
+        }
+
+        fn flush_count(&self) -> f64 {
+            self.flushes().values().sum()
+        }
+    }
+
+    #[test]
+    fn every_invocation_flushes_each_time() {
+        let harness: Harness = Harness::new(FlushConfig::default());
+        harness.end_span();
+        assert_eq!(harness.exported_spans(), 0);
+        harness.flusher.on_invocation_end();
+        assert_eq!(harness.exported_spans(), 1);
+        harness.flusher.on_invocation_end();
+        assert_eq!(
+            harness.flushes(),
+            [(
+                "telemetry.flush.result=success,telemetry.flush.strategy=every_invocation"
+                    .to_string(),
+                2.0
+            )]
+            .into()
+        );
+    }
+
+    #[test]
+    fn periodic_flushes_after_the_configured_invocations() {
+        let harness: Harness = Harness::new(FlushConfig {
+            strategy: FlushStrategy::Periodic,
+            every_invocations: 3,
+            interval_ms: 3_600_000,
+            ..Default::default()
+        });
+        harness.end_span();
+        for expected in [0.0, 0.0, 1.0, 1.0, 1.0, 2.0] {
+            harness.flusher.on_invocation_end();
+            assert_eq!(harness.flush_count(), expected);
+        }
+        assert_eq!(harness.exported_spans(), 1);
+    }
+
+    #[test]
+    fn periodic_flushes_after_the_interval() {
+        let harness: Harness = Harness::new(FlushConfig {
+            strategy: FlushStrategy::Periodic,
+            every_invocations: 1000,
+            interval_ms: 20,
+            ..Default::default()
+        });
+        harness.flusher.flush();
+        harness.flusher.on_invocation_end();
+        assert_eq!(harness.flush_count(), 1.0);
+        std::thread::sleep(Duration::from_millis(30));
+        harness.flusher.on_invocation_end();
+        assert_eq!(harness.flush_count(), 2.0);
+    }
+
+    #[test]
+    fn queue_threshold_flushes_only_at_the_threshold() {
+        let harness: Harness = Harness::new(FlushConfig {
+            strategy: FlushStrategy::QueueThreshold,
+            queue_threshold: 2,
+            ..Default::default()
+        });
+        harness.end_span();
+        harness.flusher.on_invocation_end();
+        assert_eq!(harness.exported_spans(), 0);
+
+        harness.end_span();
+        harness.flusher.on_invocation_end();
+        assert_eq!(harness.exported_spans(), 2);
+
+        // flush で数え直す
+        harness.end_span();
+        harness.flusher.on_invocation_end();
+        assert_eq!(harness.exported_spans(), 2);
+        assert_eq!(harness.flush_count(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn async_flushes_in_the_background() {
+        let harness: Harness = Harness::new(FlushConfig {
+            strategy: FlushStrategy::Async,
+            ..Default::default()
+        });
+        harness.end_span();
+        harness.flusher.on_invocation_end();
+        for _ in 0..100 {
+            if harness.exported_spans() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(harness.exported_spans(), 1);
+    }
+
+    #[test]
+    fn flush_failures_are_logged_without_panicking() {
+        let errors: CountErrorEvents = CountErrorEvents::default();
+        let _default =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(errors.clone()));
+        let harness: Harness = Harness::with_logger(
+            FlushConfig::default(),
+            SdkLoggerProvider::builder().with_log_processor(FailingLogs),
+        );
+        harness.end_span();
+        harness.flusher.flush();
+        assert_eq!(errors.0.load(Ordering::Relaxed), 1);
+        // 失敗した provider があっても、他の provider は flush する
+        assert_eq!(harness.exported_spans(), 1);
+        assert_eq!(
+            harness.flushes(),
+            [(
+                "telemetry.flush.result=failure,telemetry.flush.strategy=every_invocation"
+                    .to_string(),
+                1.0
+            )]
+            .into()
+        );
+    }
+
+    #[test]
+    fn strategies_round_trip_through_their_names() {
+        for strategy in [
+            FlushStrategy::EveryInvocation,
+            FlushStrategy::Periodic,
+            FlushStrategy::QueueThreshold,
+            FlushStrategy::Async,
+        ] {
+            assert_eq!(
+                strategy.as_str().parse::<FlushStrategy>().unwrap(),
+                strategy
+            );
+            assert_eq!(
+                serde_json::to_value(strategy).unwrap(),
+                serde_json::Value::from(strategy.as_str())
+            );
+        }
+        assert!("sometimes".parse::<FlushStrategy>().is_err());
+    }
+}
@@ -21,103 +21,100 @@ pub async fn hello() -> &'static str {
     path = "/hello/remote",
     responses(
         (status = 200, body = String),
+        (
+            status = 401,
+            description = "missing or invalid credentials",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
+        ),
+        (
+            status = 502,
+            description = "remote endpoint is unavailable",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
+        ),
+        (
+            status = 503,
+            description = "remote endpoint is not configured",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
+        ),
+        (
+            status = 504,
+            description = "remote endpoint timed out",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
+        ),
     ),
+    security(("api_key" = []), ("bearer" = [])),
     tags = [ HELLO_TAG ]
 )]
-async fn hello_remote() -> String {
-    use opentelemetry_http::HeaderInjector;
-    let span = tracing::info_span!("hello remote");
-    let remote_endpoint: String = env!("REMOTE_ENDPOINT").to_string();
-    let url = format!("{}/api/v0/hello", remote_endpoint);
-    let mut headers = reqwest::header::HeaderMap::new();
-    opentelemetry::global::get_text_map_propagator(|propagator| {
-        propagator.inject_context(&span.context(), &mut HeaderInjector(&mut headers))
-    });
-    let client = reqwest::Client::new();
-    let response = client.get(&url)
-        .headers(headers)
-        .send()
-        .await;
-    match response {
-        Ok(res) => {
-            let body = res.text().await.unwrap();
-            tracing::info!("Received response from remote: {}", body);
-            body
-        }
-        Err(err) => {
-            tracing::error!("Failed to call remote endpoint: {}", err);
-            format!("Error calling remote endpoint: {}", err)
-        }
+async fn hello_remote(
+    Authenticated(principal): Authenticated,
+    State(hello_repository): State<Arc<dyn HelloRepository>>,
+    deadline: Deadline,
+) -> Result<String, ApiError> {
+    tracing::info!(auth.method = ?principal.method, "Calling remote endpoint for {}", principal.id);
+    let body: String = hello_repository
+        .fetch_remote_hello(deadline)
+        .await
+        .inspect_err(|err| tracing::error!("Failed to call remote endpoint: {}", err))?;
+    tracing::info!("Received response from remote: {}", body);
+    Ok(body)
+}
+
+#[async_trait::async_trait]
+pub trait HelloRepository: Send + Sync {
+    async fn fetch_remote_hello(&self, deadline: Deadline) -> Result<String, OutboundError>;
+}
+
+/// 呼び出し先 `remote` の hello API から挨拶を取得する
+///
+/// `remote` が設定されていなければ、起動は止めずに呼び出しごとに 503 を返す
+pub struct RemoteHelloRepository {
+    remote: Option<DownstreamService>,
+}
+
+impl RemoteHelloRepository {
+    pub fn new(remote: Option<DownstreamService>) -> Self {
+        Self { remote }
+    }
+}
+
+#[async_trait::async_trait]
+impl HelloRepository for RemoteHelloRepository {
+    async fn fetch_remote_hello(&self, deadline: Deadline) -> Result<String, OutboundError> {
+        let Some(remote) = &self.remote else {
+            return Err(OutboundError::NotConfigured("remote"));
+        };
+        let response: reqwest::Response = remote.get("/hello", deadline).await?;
+        response.text().await.map_err(OutboundError::Transport)
     }
 }
 
 
+use crate::error::{ApiError, PROBLEM_JSON_CONTENT_TYPE, ProblemDetails};
+use crate::auth::Authenticated;
+use crate::downstream::DownstreamService;
+use crate::state::AppState;
+use std::sync::Arc;
+use crate::outbound::{Deadline, OutboundError};
+use crate::validation::{ApiJson, Validate};
 use serde::{Deserialize, Serialize};
-use tracing_opentelemetry::OpenTelemetrySpanExt;
-use utoipa::{
-    ToSchema,
-    openapi::{Object, ObjectBuilder},
-};
-#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+use utoipa::ToSchema;
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Validate)]
 #[serde(rename_all = "camelCase")]
 struct GreetContent {
-    #[schema(schema_with = Self::person_schema)]
+    /// 挨拶する人の名前
+    #[schema(min_length = 1, max_length = 20, example = "山田太郎")]
     pub person: String,
-    #[schema(schema_with = Self::message_schema)]
+    /// やぁ の挨拶に続く簡単なメッセージ
+    #[schema(min_length = 3, max_length = 32, example = "お元気ですか？")]
     pub message: String,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-enum GreetContentError {
-    InvalidPerson,
-    InvalidMessage,
-}
-
 impl GreetContent {
     const DESCRIPTION: &'static str = "挨拶の内容";
-
-    const PERSON_TITLE: &'static str = "名前";
-    const PERSON_DESCRIPTION: &'static str = "挨拶する人の名前";
-    const PERSON_EXAMPLE: &'static str = "山田太郎";
-    const PERSON_MIN_LENGTH: usize = 1;
-    const PERSON_MAX_LENGTH: usize = 20;
-
-    const MESSAGE_TITLE: &'static str = "メッセージ";
-    const MESSAGE_DESCRIPTION: &'static str = "やぁ の挨拶に続く簡単なメッセージ";
-    const MESSAGE_EXAMPLE: &'static str = "お元気ですか？";
-    const MESSAGE_MIN_LENGTH: usize = 3;
-    const MESSAGE_MAX_LENGTH: usize = 32;
-
-    #[tracing::instrument(ret)]
-    fn try_new(person: String, message: String) -> Result<Self, GreetContentError> {
-        if person.len() < Self::PERSON_MIN_LENGTH || person.len() > Self::PERSON_MAX_LENGTH {
-            return Err(GreetContentError::InvalidPerson);
-        }
-        if message.len() < Self::MESSAGE_MIN_LENGTH || message.len() > Self::MESSAGE_MAX_LENGTH {
-            return Err(GreetContentError::InvalidMessage);
-        }
-        Ok(Self { person, message })
-    }
-
-    fn person_schema() -> Object {
-        ObjectBuilder::new()
-            .title(Some(GreetContent::PERSON_TITLE))
-            .description(Some(GreetContent::PERSON_DESCRIPTION))
-            .examples(Some(GreetContent::PERSON_EXAMPLE))
-            .min_length(Some(GreetContent::PERSON_MIN_LENGTH))
-            .max_length(Some(GreetContent::PERSON_MAX_LENGTH))
-            .build()
-    }
-
-    fn message_schema() -> Object {
-        ObjectBuilder::new()
-            .title(Some(GreetContent::MESSAGE_TITLE))
-            .description(Some(GreetContent::MESSAGE_DESCRIPTION))
-            .examples(Some(GreetContent::MESSAGE_EXAMPLE))
-            .min_length(Some(GreetContent::MESSAGE_MIN_LENGTH))
-            .max_length(Some(GreetContent::MESSAGE_MAX_LENGTH))
-            .build()
-    }
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
@@ -138,7 +135,7 @@ impl GreetResponse {
     }
 }
 
-use axum::{Json, http::StatusCode};
+use axum::{extract::State, http::StatusCode};
 #[utoipa::path(
     post,
     path = "/greet",
@@ -150,52 +147,166 @@ use axum::{Json, http::StatusCode};
     responses(
         (
             status = StatusCode::OK,
-            body = GreetContent
+            body = GreetResponse
+        ),
+        (
+            status = StatusCode::UNAUTHORIZED,
+            description = "missing or invalid credentials",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
         ),
         (
             status = StatusCode::BAD_REQUEST,
-            body = String
+            description = "validation failed or malformed JSON",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
+        ),
+        (
+            status = StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            description = "missing JSON content type",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
+        ),
+        (
+            status = StatusCode::UNPROCESSABLE_ENTITY,
+            description = "JSON does not match the request schema",
+            body = ProblemDetails,
+            content_type = PROBLEM_JSON_CONTENT_TYPE
         )
     ),
+    security(("api_key" = []), ("bearer" = [])),
     tags = [ HELLO_TAG ]
 )]
 #[tracing::instrument(ret)]
 async fn greet(
-    Json(payload): Json<GreetContent>,
-) -> Result<(StatusCode, Json<GreetResponse>), (StatusCode, String)> {
-    match GreetContent::try_new(payload.person, payload.message) {
-        Err(GreetContentError::InvalidPerson) => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                format!(
-                    "person length must be between {} and {}",
-                    GreetContent::PERSON_MIN_LENGTH,
-                    GreetContent::PERSON_MAX_LENGTH
-                ),
-            ));
-        }
-        Err(GreetContentError::InvalidMessage) => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                format!(
-                    "message length must be between {} and {}",
-                    GreetContent::MESSAGE_MIN_LENGTH,
-                    GreetContent::MESSAGE_MAX_LENGTH
-                ),
-            ));
-        }
-        Ok(valid_payload) => Ok((
-            StatusCode::OK,
-            Json(GreetResponse::create_greeting(&valid_payload)),
-        )),
-    }
+    Authenticated(principal): Authenticated,
+    ApiJson(payload): ApiJson<GreetContent>,
+) -> Result<(StatusCode, ApiJson<GreetResponse>), ApiError> {
+    tracing::info!(auth.method = ?principal.method, "Greeting requested by {}", principal.id);
+    Ok((
+        StatusCode::OK,
+        ApiJson(GreetResponse::create_greeting(&payload)),
+    ))
 }
 
 use utoipa_axum::router::OpenApiRouter;
-pub fn create_hello_router() -> OpenApiRouter {
-    let hello_router: OpenApiRouter = OpenApiRouter::new()
+pub fn create_hello_router() -> OpenApiRouter<AppState> {
+    let hello_router: OpenApiRouter<AppState> = OpenApiRouter::new()
         .routes(utoipa_axum::routes!(hello))
         .routes(utoipa_axum::routes!(greet))
         .routes(utoipa_axum::routes!(hello_remote));
     hello_router
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use axum::response::IntoResponse;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::auth::{API_KEY_HEADER, ApiKeyConfig, AuthConfig, Authenticator};
+
+    /// 呼び出し先の代わりに決まった結果を返す
+    struct FakeHelloRepository(Result<&'static str, StatusCode>);
+
+    #[async_trait::async_trait]
+    impl HelloRepository for FakeHelloRepository {
+        async fn fetch_remote_hello(&self, _deadline: Deadline) -> Result<String, OutboundError> {
+            self.0.map(str::to_string).map_err(OutboundError::Status)
+        }
+    }
+
+    async fn get_remote(
+        repository: FakeHelloRepository,
+        api_key: Option<&str>,
+    ) -> (StatusCode, String) {
+        let authenticator: Authenticator = Authenticator::load(&AuthConfig {
+            api_keys: vec![ApiKeyConfig {
+                id: "tester".to_string(),
+                key: "secret".to_string(),
+            }],
+            ..AuthConfig::default()
+        })
+        .await
+        .unwrap();
+        let state: AppState = AppState::builder()
+            .authenticator(authenticator)
+            .hello_repository(Arc::new(repository))
+            .build()
+            .unwrap();
+        let (router, _) = create_hello_router().split_for_parts();
+        let mut request = Request::get("/hello/remote");
+        if let Some(api_key) = api_key {
+            request = request.header(API_KEY_HEADER, api_key);
+        }
+        let response = router
+            .with_state(state)
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status: StatusCode = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn hello_remote_returns_the_repository_response() {
+        let (status, body) =
+            get_remote(FakeHelloRepository(Ok("Hello, Remote!")), Some("secret")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "Hello, Remote!");
+    }
+
+    #[tokio::test]
+    async fn hello_remote_maps_repository_errors_to_bad_gateway() {
+        let (status, body) = get_remote(
+            FakeHelloRepository(Err(StatusCode::SERVICE_UNAVAILABLE)),
+            Some("secret"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(body.contains("remote-unavailable"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn hello_remote_is_unavailable_without_a_configured_remote() {
+        let err: OutboundError = RemoteHelloRepository::new(None)
+            .fetch_remote_hello(Deadline(None))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, OutboundError::NotConfigured("remote")));
+        let response = ApiError::from(err).into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn hello_remote_requires_credentials() {
+        let (status, _) = get_remote(FakeHelloRepository(Ok("Hello, Remote!")), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn greet_content_schema_matches_its_validation() {
+        let schema = serde_json::to_value(<GreetContent as utoipa::PartialSchema>::schema())
+            .unwrap();
+        assert_eq!(schema["properties"]["person"]["minLength"], 1);
+        assert_eq!(schema["properties"]["person"]["maxLength"], 20);
+        let content: GreetContent = GreetContent {
+            person: String::new(),
+            message: "あ".repeat(33),
+        };
+        assert_eq!(
+            content.validate().unwrap_err(),
+            vec![
+                crate::error::Violation::new("person", "person length must be between 1 and 20"),
+                crate::error::Violation::new("message", "message length must be between 3 and 32"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,29 @@
+//! API サーバーと Lambda extension で共有するモジュール
+
+pub mod admin;
+pub mod auth;
+pub mod config;
+pub mod cors;
+pub mod downstream;
+pub mod emf;
+pub mod error;
+pub mod export_queue;
+pub mod extension;
+pub mod flush;
+pub mod hello;
+pub mod logging;
+pub mod otel;
+pub mod otlp;
+pub mod otlp_aws;
+pub mod otlp_retry;
+pub mod outbound;
+pub mod ratelimit;
+pub mod receiver;
+pub mod request_id;
+pub mod sigv4;
+pub mod state;
+pub mod tail_sampling;
+pub mod telemetry_api;
+#[cfg(test)]
+mod testing;
+pub mod validation;
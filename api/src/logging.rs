@@ -0,0 +1,786 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use anyhow::Context as _;
+use opentelemetry::trace::{SpanContext, TraceContextExt};
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use tracing::{
+    Event, Metadata, Subscriber,
+    field::{Field, Visit},
+    span,
+    subscriber::Interest,
+};
+use tracing_subscriber::{
+    EnvFilter, Layer,
+    filter::{LevelFilter, ParseError},
+    fmt::{
+        FmtContext, FormatEvent, FormatFields, MakeWriter,
+        format::{FmtSpan, JsonFields, Writer},
+        time::{FormatTime, OffsetTime},
+    },
+    layer::{self, Filter},
+    registry::LookupSpan,
+};
+
+use crate::config::Env;
+
+/// 内側の JSON 形式の行に、処理中の span の `trace_id`、`span_id`、`trace_flags` を加える
+///
+/// X-Ray の propagator を使うときは `xray_trace_id` (`1-xxxxxxxx-xxxxxxxxxxxxxxxxxxxxxxxx`) も加え、
+/// CloudWatch Logs Insights から X-Ray のトレースを引けるようにする
+pub struct TraceContextJson<F> {
+    inner: F,
+    xray: bool,
+}
+
+impl<F> TraceContextJson<F> {
+    pub fn new(inner: F, xray: bool) -> Self {
+        Self { inner, xray }
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for TraceContextJson<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let mut line: String = String::new();
+        self.inner
+            .format_event(ctx, Writer::new(&mut line), event)?;
+
+        // tracing-opentelemetry が span に入るときに context を切り替えている
+        let context = opentelemetry::Context::current();
+        let span_context: SpanContext = context.span().span_context().clone();
+        let object: Option<&str> = line.trim_end().strip_suffix('}');
+        let (Some(object), true) = (object, span_context.is_valid()) else {
+            return writer.write_str(&line);
+        };
+
+        let trace_id: String = span_context.trace_id().to_string();
+        write!(
+            writer,
+            r#"{},"trace_id":"{}","span_id":"{}","trace_flags":"{:02x}""#,
+            object,
+            trace_id,
+            span_context.span_id(),
+            span_context.trace_flags().to_u8()
+        )?;
+        if self.xray {
+            write!(
+                writer,
+                r#","xray_trace_id":"1-{}-{}""#,
+                &trace_id[..8],
+                &trace_id[8..]
+            )?;
+        }
+        writeln!(writer, "}}")
+    }
+}
+
+/// 標準出力のログの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Pretty,
+    Compact,
+    Logfmt,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(LogFormat::Json),
+            "pretty" => Ok(LogFormat::Pretty),
+            "compact" => Ok(LogFormat::Compact),
+            "logfmt" => Ok(LogFormat::Logfmt),
+            _ => anyhow::bail!(
+                "unsupported log format `{}` (json, pretty, compact or logfmt)",
+                s
+            ),
+        }
+    }
+}
+
+// exporter の crate と、export の失敗や再送を記録する自身の module
+const DEFAULT_OTLP_FILTER: &str = concat!(
+    "info,opentelemetry=off,tonic=off,hyper=off,h2=off,reqwest=off,",
+    "api::otlp=off,api::otlp_retry=off,api::otlp_aws=off,api::export_queue=off",
+);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// 省略時は、標準出力が端末なら `pretty`、それ以外 (Lambda など) は `json`
+    pub format: Option<LogFormat>,
+    /// 標準出力に書くログの `EnvFilter` の directive (例: `info,api=debug`)
+    pub filter: String,
+    /// OTLP で送るログの directive。exporter 自身のログが送信のたびに戻ってこないよう、既定で exporter が使う crate と export を担う module を外す
+    pub otlp_filter: String,
+    /// OTLP で送る span の directive
+    pub span_filter: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: None,
+            filter: LevelFilter::INFO.to_string(),
+            otlp_filter: DEFAULT_OTLP_FILTER.to_string(),
+            span_filter: LevelFilter::INFO.to_string(),
+        }
+    }
+}
+
+impl LogConfig {
+    /// `LOG_FORMAT`、`RUST_LOG`、`LOG_OTLP_FILTER`、`LOG_SPAN_FILTER` で上書きする
+    pub fn apply_env_overrides(&mut self, env: &Env) -> anyhow::Result<()> {
+        if let Some(format) = env.get("LOG_FORMAT")
+            && !format.is_empty()
+        {
+            self.format = Some(format.parse().context("invalid value in `LOG_FORMAT`")?);
+        }
+        for (name, filter) in [
+            (EnvFilter::DEFAULT_ENV, &mut self.filter),
+            ("LOG_OTLP_FILTER", &mut self.otlp_filter),
+            ("LOG_SPAN_FILTER", &mut self.span_filter),
+        ] {
+            if let Some(value) = env.get(name)
+                && !value.is_empty()
+            {
+                *filter = value.to_string();
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, filter) in [
+            ("filter", &self.filter),
+            ("otlp_filter", &self.otlp_filter),
+            ("span_filter", &self.span_filter),
+        ] {
+            parse_filter(filter).with_context(|| format!("invalid log.{} `{}`", name, filter))?;
+        }
+        Ok(())
+    }
+
+    pub fn format(&self) -> LogFormat {
+        self.format.unwrap_or_else(|| {
+            if std::io::stdout().is_terminal() {
+                LogFormat::Pretty
+            } else {
+                LogFormat::Json
+            }
+        })
+    }
+}
+
+/// directive に target の指定しかなければ、それ以外は `info` にする
+fn parse_filter(directives: &str) -> Result<EnvFilter, ParseError> {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .parse(directives)
+}
+
+struct FilterState {
+    filter: EnvFilter,
+    /// [`with_debug`] の中で使う filter。`filter` の level を `debug` まで上げ、`=off` の directive は残す
+    debug_filter: EnvFilter,
+    directives: String,
+}
+
+impl FilterState {
+    fn parse(directives: &str) -> Result<Self, ParseError> {
+        Ok(Self {
+            filter: parse_filter(directives)?,
+            debug_filter: parse_filter(&debug_directives(directives))?,
+            directives: directives.to_string(),
+        })
+    }
+}
+
+/// level を `debug` 未満に絞る directive を `debug` に上げる
+///
+/// `opentelemetry=off` のように出力を止める directive はそのまま残し、
+/// OTLP の exporter 自身の debug のログが送信のたびに戻ってこないようにする
+fn debug_directives(directives: &str) -> String {
+    let mut has_default: bool = false;
+    let mut debug: Vec<String> = directives
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| {
+            let (target, level) = match directive.rsplit_once('=') {
+                Some((target, level)) => (Some(target), level),
+                None => (None, directive),
+            };
+            let Ok(level) = level.parse::<LevelFilter>() else {
+                // level のない `target` はすべての level を通す
+                return directive.to_string();
+            };
+            let level: LevelFilter = if level == LevelFilter::OFF {
+                level
+            } else {
+                level.max(LevelFilter::DEBUG)
+            };
+            match target {
+                Some(target) => format!("{}={}", target, level),
+                None => {
+                    has_default = true;
+                    level.to_string()
+                }
+            }
+        })
+        .collect();
+    if !has_default {
+        debug.push(LevelFilter::DEBUG.to_string());
+    }
+    debug.join(",")
+}
+
+/// 実行中に差し替えられる `EnvFilter`
+///
+/// [`LogFilterHandle::filter`] を layer に付け、admin endpoint や SIGHUP から [`LogFilterHandle::reload`] する
+#[derive(Clone)]
+pub struct LogFilterHandle(Arc<RwLock<FilterState>>);
+
+impl Default for LogFilterHandle {
+    fn default() -> Self {
+        Self::new(&LogConfig::default().filter).expect("default log filter is valid")
+    }
+}
+
+impl LogFilterHandle {
+    pub fn new(directives: &str) -> Result<Self, ParseError> {
+        Ok(Self(Arc::new(RwLock::new(FilterState::parse(directives)?))))
+    }
+
+    pub fn directives(&self) -> String {
+        self.0.read().unwrap().directives.clone()
+    }
+
+    pub fn reload(&self, directives: &str) -> Result<(), ParseError> {
+        let state: FilterState = FilterState::parse(directives)?;
+        *self.0.write().unwrap() = state;
+        // 以前の filter で無効にした callsite も評価し直す
+        tracing::callsite::rebuild_interest_cache();
+        tracing::info!(log.filter = directives, "Reloaded log filter");
+        Ok(())
+    }
+
+    pub fn filter(&self) -> ReloadableFilter {
+        ReloadableFilter(self.0.clone())
+    }
+}
+
+/// 出力先ごとの filter
+#[derive(Clone, Default)]
+pub struct LogFilters {
+    /// 標準出力のログ
+    pub stdout: LogFilterHandle,
+    /// OTLP で送るログ
+    pub otlp: LogFilterHandle,
+    /// OTLP で送る span
+    pub spans: LogFilterHandle,
+}
+
+impl LogFilters {
+    pub fn new(config: &LogConfig) -> Result<Self, ParseError> {
+        Ok(Self {
+            stdout: LogFilterHandle::new(&config.filter)?,
+            otlp: LogFilterHandle::new(&config.otlp_filter)?,
+            spans: LogFilterHandle::new(&config.span_filter)?,
+        })
+    }
+
+    /// 変わった filter だけ差し替える
+    pub fn reload(&self, config: &LogConfig) -> Result<(), ParseError> {
+        for (handle, directives) in [
+            (&self.stdout, &config.filter),
+            (&self.otlp, &config.otlp_filter),
+            (&self.spans, &config.span_filter),
+        ] {
+            if handle.directives() != *directives {
+                handle.reload(directives)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+tokio::task_local! {
+    static DEBUG_OVERRIDE: ();
+}
+
+// 実行中の [`with_debug`] の数。0 の間は filter で無効にした callsite を評価し直さない
+static ACTIVE_DEBUG_OVERRIDES: AtomicUsize = AtomicUsize::new(0);
+
+/// `future` の中では、filter によらず `debug` のログと span を出力する
+pub async fn with_debug<F: Future>(future: F) -> F::Output {
+    let _active: ActiveDebugOverride = ActiveDebugOverride::new();
+    DEBUG_OVERRIDE.scope((), future).await
+}
+
+fn debug_override_active() -> bool {
+    ACTIVE_DEBUG_OVERRIDES.load(Ordering::Acquire) > 0
+}
+
+/// [`ACTIVE_DEBUG_OVERRIDES`] を増やし、drop で減らす
+///
+/// 最初の 1 つが始まったときと最後の 1 つが終わったときに、callsite の interest を作り直す
+struct ActiveDebugOverride;
+
+impl ActiveDebugOverride {
+    fn new() -> Self {
+        if ACTIVE_DEBUG_OVERRIDES.fetch_add(1, Ordering::AcqRel) == 0 {
+            tracing::callsite::rebuild_interest_cache();
+        }
+        Self
+    }
+}
+
+impl Drop for ActiveDebugOverride {
+    fn drop(&mut self) {
+        if ACTIVE_DEBUG_OVERRIDES.fetch_sub(1, Ordering::AcqRel) == 1 {
+            tracing::callsite::rebuild_interest_cache();
+        }
+    }
+}
+
+/// [`LogFilterHandle`] の `EnvFilter` に委譲する per-layer filter
+///
+/// [`with_debug`] の中では、`=off` の directive を残したまま `debug` のイベントも通す
+pub struct ReloadableFilter(Arc<RwLock<FilterState>>);
+
+impl<S> Filter<S> for ReloadableFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: &layer::Context<'_, S>) -> bool {
+        let state = self.0.read().unwrap();
+        if DEBUG_OVERRIDE.try_with(|_| ()).is_ok() {
+            Filter::<S>::enabled(&state.debug_filter, metadata, ctx)
+        } else {
+            Filter::<S>::enabled(&state.filter, metadata, ctx)
+        }
+    }
+
+    fn callsite_enabled(&self, metadata: &'static Metadata<'static>) -> Interest {
+        let state = self.0.read().unwrap();
+        let interest: Interest = Filter::<S>::callsite_enabled(&state.filter, metadata);
+        let debug_interest: Interest = Filter::<S>::callsite_enabled(&state.debug_filter, metadata);
+        // debug の上書き中だけ、debug の filter で通る callsite を呼び出しのたびに評価させる
+        if !debug_override_active() || interest.is_always() || debug_interest.is_never() {
+            interest
+        } else {
+            Interest::sometimes()
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        let state = self.0.read().unwrap();
+        if debug_override_active() {
+            Filter::<S>::max_level_hint(&state.debug_filter)
+        } else {
+            Filter::<S>::max_level_hint(&state.filter)
+        }
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: layer::Context<'_, S>) {
+        let state = self.0.read().unwrap();
+        Filter::<S>::on_new_span(&state.filter, attrs, id, ctx.clone());
+        Filter::<S>::on_new_span(&state.debug_filter, attrs, id, ctx);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: layer::Context<'_, S>) {
+        let state = self.0.read().unwrap();
+        Filter::<S>::on_record(&state.filter, id, values, ctx.clone());
+        Filter::<S>::on_record(&state.debug_filter, id, values, ctx);
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: layer::Context<'_, S>) {
+        let state = self.0.read().unwrap();
+        Filter::<S>::on_enter(&state.filter, id, ctx.clone());
+        Filter::<S>::on_enter(&state.debug_filter, id, ctx);
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: layer::Context<'_, S>) {
+        let state = self.0.read().unwrap();
+        Filter::<S>::on_exit(&state.filter, id, ctx.clone());
+        Filter::<S>::on_exit(&state.debug_filter, id, ctx);
+    }
+
+    fn on_close(&self, id: span::Id, ctx: layer::Context<'_, S>) {
+        let state = self.0.read().unwrap();
+        Filter::<S>::on_close(&state.filter, id.clone(), ctx.clone());
+        Filter::<S>::on_close(&state.debug_filter, id, ctx);
+    }
+}
+
+/// SIGHUP を受けたら設定を読み直し、`log` の filter を反映する
+#[cfg(not(feature = "lambda"))]
+pub async fn reload_on_sighup(filters: LogFilters) {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            tracing::warn!("Failed to listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let result: anyhow::Result<()> = crate::config::Config::load().and_then(|config| {
+            filters
+                .reload(&config.log)
+                .context("invalid log filter")
+        });
+        if let Err(err) = result {
+            tracing::error!("Failed to reload log filter on SIGHUP: {:#}", err);
+        }
+    }
+}
+
+fn timer() -> OffsetTime<Rfc3339> {
+    OffsetTime::local_rfc_3339().expect("Failed to create tracing subscriber timer")
+}
+
+/// 設定された形式で標準出力に書く fmt layer
+///
+/// `json` の 1 行は次のフィールドを持つ (`span`、`spans` は span の中でのみ、`trace_id` 以降は有効な span context があるときのみ)
+///
+/// | フィールド | 内容 |
+/// | --- | --- |
+/// | `timestamp` | RFC 3339 のローカル時刻 |
+/// | `level` | `TRACE` から `ERROR` |
+/// | `fields` | `message` とイベントのフィールド |
+/// | `target` | モジュールパス |
+/// | `filename`, `line_number` | 出力した場所 |
+/// | `threadId` | `ThreadId(N)` |
+/// | `span` | 現在の span のフィールドと `name` |
+/// | `spans` | root から現在までの span |
+/// | `trace_id`, `span_id` | 16 進数の OpenTelemetry の ID |
+/// | `trace_flags` | 2 桁の 16 進数 (`01` ならサンプリング対象) |
+/// | `xray_trace_id` | `OTEL_PROPAGATORS` に `xray` を含むときのみ |
+pub fn fmt_layer<S>(config: &LogConfig, xray: bool) -> Box<dyn Layer<S> + Send + Sync + 'static>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    format_layer(
+        config.format(),
+        xray,
+        std::io::stdout().is_terminal(),
+        std::io::stdout,
+        timer(),
+    )
+}
+
+/// `format` の fmt layer を `writer` に書く。時刻は `timer` で書く
+pub fn format_layer<S, W, T>(
+    format: LogFormat,
+    xray: bool,
+    ansi: bool,
+    writer: W,
+    timer: T,
+) -> Box<dyn Layer<S> + Send + Sync + 'static>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    T: FormatTime + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Json => layer
+            .with_span_events(FmtSpan::ACTIVE)
+            .with_ansi(false)
+            .fmt_fields(JsonFields::new())
+            .event_format(TraceContextJson::new(
+                tracing_subscriber::fmt::format()
+                    .json()
+                    .with_level(true)
+                    .with_timer(timer)
+                    .with_file(true)
+                    .with_line_number(true)
+                    .with_thread_ids(true)
+                    .with_current_span(true)
+                    .with_target(true),
+                xray,
+            ))
+            .boxed(),
+        LogFormat::Pretty => layer.pretty().with_ansi(ansi).with_timer(timer).boxed(),
+        LogFormat::Compact => layer.compact().with_ansi(ansi).with_timer(timer).boxed(),
+        LogFormat::Logfmt => layer
+            .with_span_events(FmtSpan::ACTIVE)
+            .with_ansi(false)
+            .event_format(Logfmt { timer })
+            .boxed(),
+    }
+}
+
+/// `ts=... level=info target=... msg="..." key=value ... span=... trace_id=... span_id=...`
+struct Logfmt<T> {
+    timer: T,
+}
+
+impl<S, N, T> FormatEvent<S, N> for Logfmt<T>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    T: FormatTime,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let mut timestamp: String = String::new();
+        self.timer.format_time(&mut Writer::new(&mut timestamp))?;
+        let metadata = event.metadata();
+        write!(
+            writer,
+            "ts={} level={} target={}",
+            timestamp,
+            metadata.level().as_str().to_lowercase(),
+            metadata.target()
+        )?;
+
+        let mut visitor: LogfmtVisitor = LogfmtVisitor {
+            writer: writer.by_ref(),
+            result: Ok(()),
+        };
+        event.record(&mut visitor);
+        visitor.result?;
+
+        if let Some(span) = ctx.lookup_current() {
+            write_logfmt_pair(&mut writer, "span", span.name())?;
+        }
+        let context = opentelemetry::Context::current();
+        let span_context: SpanContext = context.span().span_context().clone();
+        if span_context.is_valid() {
+            write!(
+                writer,
+                " trace_id={} span_id={}",
+                span_context.trace_id(),
+                span_context.span_id()
+            )?;
+        }
+        writeln!(writer)
+    }
+}
+
+struct LogfmtVisitor<'a> {
+    writer: Writer<'a>,
+    result: std::fmt::Result,
+}
+
+impl Visit for LogfmtVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.write(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.write(field, &format!("{:?}", value));
+    }
+}
+
+impl LogfmtVisitor<'_> {
+    fn write(&mut self, field: &Field, value: &str) {
+        if self.result.is_err() {
+            return;
+        }
+        let key: &str = match field.name() {
+            "message" => "msg",
+            name => name,
+        };
+        self.result = write_logfmt_pair(&mut self.writer, key, value);
+    }
+}
+
+// 空白や `=`、`"` を含む値と空の値は引用符で囲む
+fn write_logfmt_pair(writer: &mut Writer<'_>, key: &str, value: &str) -> std::fmt::Result {
+    let quote: bool = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || c == '=' || c == '"' || c.is_control());
+    if quote {
+        write!(writer, " {}={:?}", key, value)
+    } else {
+        write!(writer, " {}={}", key, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use opentelemetry::trace::{SpanId, TraceId, TracerProvider as _};
+    use opentelemetry_sdk::trace::{IdGenerator, SdkTracerProvider};
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    use super::*;
+    use crate::testing::assert_golden;
+
+    struct FixedTime;
+
+    impl FormatTime for FixedTime {
+        fn format_time(&self, writer: &mut Writer<'_>) -> std::fmt::Result {
+            writer.write_str("2026-01-02T03:04:05.678+09:00")
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct SequentialIds(AtomicU64);
+
+    impl IdGenerator for SequentialIds {
+        fn new_trace_id(&self) -> TraceId {
+            TraceId::from(0x4bf92f3577b34da6a3ce929d0e0e4736)
+        }
+
+        fn new_span_id(&self) -> SpanId {
+            SpanId::from(0x00f067aa0ba902b7 + self.0.fetch_add(1, Ordering::Relaxed))
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn render(format: LogFormat) -> String {
+        let buffer: Buffer = Buffer::default();
+        let tracer_provider: SdkTracerProvider = SdkTracerProvider::builder()
+            .with_id_generator(SequentialIds::default())
+            .build();
+        let writer: Buffer = buffer.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer("test")))
+            .with(format_layer(format, true, false, move || writer.clone(), FixedTime));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(target: "api::test", "Starting");
+            let span = tracing::info_span!(target: "api::test", "greet", person = "Alice");
+            let _entered = span.enter();
+            tracing::warn!(
+                target: "api::test",
+                attempt = 2,
+                reason = "remote unavailable",
+                "Retrying \"remote\""
+            );
+        });
+        let output: String = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        // 実行環境で変わる部分をそろえる
+        let output: String = regex::Regex::new(r"ThreadId\(\d+\)")
+            .unwrap()
+            .replace_all(&output, "ThreadId(1)")
+            .into_owned();
+        regex::Regex::new(r#"("line_number":|logging\.rs:)\d+"#)
+            .unwrap()
+            .replace_all(&output, "${1}1")
+            .into_owned()
+    }
+
+    #[derive(Clone, Default)]
+    struct CountEvents(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for CountEvents {
+        fn on_event(&self, _event: &Event<'_>, _ctx: layer::Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn debug_override_enables_debug_events_only_while_active() {
+        let handle: LogFilterHandle = LogFilterHandle::new("info").unwrap();
+        let events: CountEvents = CountEvents::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(events.clone().with_filter(handle.filter()));
+        let _default = tracing::subscriber::set_default(subscriber);
+        let emit = || tracing::debug!(target: "api::test", "debug");
+
+        emit();
+        assert_eq!(events.0.load(Ordering::Relaxed), 0);
+        with_debug(async { emit() }).await;
+        assert_eq!(events.0.load(Ordering::Relaxed), 1);
+        emit();
+        assert_eq!(events.0.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            Filter::<tracing_subscriber::Registry>::max_level_hint(&handle.filter()),
+            Some(LevelFilter::INFO)
+        );
+    }
+
+    #[test]
+    fn debug_directives_keep_disabled_targets() {
+        assert_eq!(debug_directives("info"), "debug");
+        assert_eq!(debug_directives("trace"), "trace");
+        assert_eq!(debug_directives("api=warn"), "api=debug,debug");
+        assert_eq!(
+            debug_directives(DEFAULT_OTLP_FILTER),
+            concat!(
+                "debug,opentelemetry=off,tonic=off,hyper=off,h2=off,reqwest=off,",
+                "api::otlp=off,api::otlp_retry=off,api::otlp_aws=off,api::export_queue=off",
+            )
+        );
+        assert_eq!(debug_directives("info,api::hello"), "debug,api::hello");
+    }
+
+    #[tokio::test]
+    async fn debug_override_keeps_off_directives() {
+        let handle: LogFilterHandle = LogFilterHandle::new(DEFAULT_OTLP_FILTER).unwrap();
+        let events: CountEvents = CountEvents::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(events.clone().with_filter(handle.filter()));
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        with_debug(async {
+            tracing::debug!(target: "opentelemetry_sdk", "exporter");
+            tracing::debug!(target: "h2::codec", "frame");
+            tracing::warn!(target: "api::otlp_retry", "retrying export");
+            tracing::warn!(target: "api::export_queue", "queue full");
+            tracing::debug!(target: "api::test", "debug");
+        })
+        .await;
+        assert_eq!(events.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn json_matches_golden_file() {
+        assert_golden("logging/json.log", &render(LogFormat::Json));
+    }
+
+    #[test]
+    fn pretty_matches_golden_file() {
+        assert_golden("logging/pretty.log", &render(LogFormat::Pretty));
+    }
+
+    #[test]
+    fn compact_matches_golden_file() {
+        assert_golden("logging/compact.log", &render(LogFormat::Compact));
+    }
+
+    #[test]
+    fn logfmt_matches_golden_file() {
+        assert_golden("logging/logfmt.log", &render(LogFormat::Logfmt));
+    }
+}
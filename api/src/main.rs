@@ -1,30 +1,83 @@
-mod hello;
-mod otel;
+use api::{
+    admin, auth, config, cors, downstream, export_queue, flush, hello, logging, otel, outbound,
+    ratelimit, receiver, request_id, state, tail_sampling,
+};
+#[cfg(not(feature = "lambda"))]
+use api::sigv4;
 
+use auth::SecurityAddon;
 use utoipa::OpenApi;
 #[derive(OpenApi)]
-#[openapi(info(
-    title = env!("PROJECT_NAME"),
-    version = env!("CARGO_PKG_VERSION"),
-    description = "sample api description",
-))]
+#[openapi(
+    info(
+        title = env!("PROJECT_NAME"),
+        version = env!("CARGO_PKG_VERSION"),
+        description = "sample api description",
+    ),
+    modifiers(&SecurityAddon),
+)]
 struct ApiDocs;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    opentelemetry::global::set_text_map_propagator(
-        opentelemetry_sdk::propagation::TraceContextPropagator::new(),
-    );
+    let config: config::Config = config::Config::load()?;
+    if std::env::args().any(|arg| arg == "--print-config") {
+        print!("{}", config.to_masked_toml()?);
+        return Ok(());
+    }
 
-    let resouce: opentelemetry_sdk::Resource = otel::init_resource();
-    let tracer_provider: opentelemetry_sdk::trace::SdkTracerProvider =
-        otel::init_tracer_provider(resouce.clone());
-    let logger_provider: opentelemetry_sdk::logs::SdkLoggerProvider = otel::init_logger_provider(resouce);
-    otel::init_tracing_subscriber(&tracer_provider, &logger_provider);
+    otel::init_propagator(&config.otel);
+
+    let resouce: opentelemetry_sdk::Resource = otel::init_resource(&config);
+    let pending_telemetry: flush::PendingTelemetry = flush::PendingTelemetry::default();
+    let span_queue: export_queue::ExportQueue =
+        export_queue::ExportQueue::spans(&config.otel.queues.spans);
+    let log_queue: export_queue::ExportQueue =
+        export_queue::ExportQueue::logs(&config.otel.queues.logs);
+    let tail_sampler: tail_sampling::TailSampler =
+        tail_sampling::TailSampler::new(&config.otel.tail_sampling);
+    let tracer_provider: opentelemetry_sdk::trace::SdkTracerProvider = otel::init_tracer_provider(
+        resouce.clone(),
+        &config.otel,
+        &pending_telemetry,
+        &span_queue,
+        &tail_sampler,
+    );
+    let meter_provider: opentelemetry_sdk::metrics::SdkMeterProvider =
+        otel::init_meter_provider(resouce.clone(), &config.otel);
+    let logger_provider: opentelemetry_sdk::logs::SdkLoggerProvider =
+        otel::init_logger_provider(resouce, &config.otel, &pending_telemetry, &log_queue);
+    let self_meter: opentelemetry::metrics::Meter =
+        opentelemetry::global::meter_with_scope(otel::init_scope());
+    span_queue.register_metrics(&self_meter);
+    log_queue.register_metrics(&self_meter);
+    if tail_sampler.enabled() {
+        tail_sampler.register_metrics(&self_meter);
+    }
+    let log_filters: logging::LogFilters =
+        otel::init_tracing_subscriber(&tracer_provider, &logger_provider, &config);
+    #[cfg(not(feature = "lambda"))]
+    tokio::spawn(logging::reload_on_sighup(log_filters.clone()));
 
-    //let state: StateContainer = StateContainer::new(tracer_provider, logger_provider);
+    let outbound_client: outbound::OutboundClient = outbound::OutboundClient::new();
+    let downstream_services: downstream::DownstreamServices =
+        downstream::DownstreamServices::load(&config.downstream, &outbound_client)?;
+    // remote を呼ばない関数 (remote 自身など) も起動できるよう、設定がなければ呼び出し時に 503 を返す
+    let remote: Option<downstream::DownstreamService> = downstream_services
+        .require("remote")
+        .inspect_err(|err| tracing::warn!("{:#}; /hello/remote responds with 503", err))
+        .ok();
+    let hello_repository = std::sync::Arc::new(hello::RemoteHelloRepository::new(remote));
+    let authenticator: auth::Authenticator = auth::Authenticator::load(&config.auth).await?;
+    let state: state::AppState = state::AppState::builder()
+        .config(std::sync::Arc::new(config.clone()))
+        .authenticator(authenticator)
+        .hello_repository(hello_repository)
+        .log_filters(log_filters)
+        .telemetry_forwarder(receiver::TelemetryForwarder::new(&config)?)
+        .build()?;
 
-    const API_BASE_PATH: &str = env!("API_BASE_PATH");
+    let api_base_path: &str = config.server.api_base_path.as_str();
     // クレートバージョンが 0.1.2 ならば、メジャーバージョンは 0
     let api_major_version: usize = env!("CARGO_PKG_VERSION")
         .split('.')
@@ -34,32 +87,105 @@ async fn main() -> anyhow::Result<()> {
         .unwrap();
 
     use utoipa_axum::router::OpenApiRouter;
-    let api_base_path = format!("{}/v{}",API_BASE_PATH, api_major_version);
-    let (api_router, api_docs) = OpenApiRouter::with_openapi(ApiDocs::openapi())
-        .nest(api_base_path.as_str(), hello::create_hello_router())
-        .split_for_parts();
+    let api_versioned_base_path = format!("{}/v{}", api_base_path, api_major_version);
+    let mut openapi: utoipa::openapi::OpenApi = ApiDocs::openapi();
+    openapi.info.title = config.project_name.clone();
+    let api_router = OpenApiRouter::with_openapi(openapi).nest(
+        api_versioned_base_path.as_str(),
+        hello::create_hello_router().merge(admin::create_admin_router()),
+    );
+    // OTLP の exporter が付けるパスに合わせ、API のバージョンを付けない
+    let api_router = if config.receiver.enabled {
+        api_router.nest(
+            api_base_path,
+            receiver::create_receiver_router(&config.receiver),
+        )
+    } else {
+        api_router
+    };
+    let (api_router, mut api_docs) = api_router.split_for_parts();
+    let api_router: axum::Router = api_router.with_state(state.clone());
+
+    // SigV4 で検証した呼び出し元ごとに制限できるよう、SigV4 の middleware の内側に置く
+    let api_router: axum::Router = if config.rate_limit.enabled {
+        ratelimit::document_responses(&mut api_docs);
+        api_router.layer(axum::middleware::from_fn_with_state(
+            ratelimit::RateLimiter::new(&config.rate_limit, state.authenticator.clone()),
+            ratelimit::enforce_rate_limit,
+        ))
+    } else {
+        api_router
+    };
+
+    // function URL の AWS_IAM 認証の代わりに、プロキシの後ろで SigV4 署名を検証する
+    #[cfg(not(feature = "lambda"))]
+    let api_router: axum::Router = if config.auth.sigv4.enabled {
+        if config.auth.sigv4.credentials.is_empty() {
+            tracing::warn!("SigV4 is enabled without credentials; every API request will be rejected");
+        }
+        let verifier: sigv4::SigV4Verifier = sigv4::SigV4Verifier::new(
+            &config.auth.sigv4,
+            std::sync::Arc::new(sigv4::StaticCredentialsProvider::from_config(
+                &config.auth.sigv4.credentials,
+            )),
+        );
+        api_router.layer(axum::middleware::from_fn_with_state(
+            verifier,
+            sigv4::require_sigv4,
+        ))
+    } else {
+        api_router
+    };
+
+    let api_router: axum::Router = cors::CorsPolicy::new("api", &config.cors.api)?.apply(api_router);
 
     use utoipa_scalar::{Scalar, Servable};
+    let docs_router: axum::Router = cors::CorsPolicy::new("docs", &config.cors.docs)?.apply(
+        axum::Router::new().merge(Scalar::with_url(format!("{}/docs", api_base_path), api_docs)),
+    );
     let app_router = axum::Router::new()
         .merge(api_router)
-        .merge(Scalar::with_url(format!("{}/docs", API_BASE_PATH), api_docs))
-        .layer(tower_http::cors::CorsLayer::permissive())
+        .merge(docs_router)
+        .layer(axum::middleware::from_fn(request_id::propagate_request_id))
         .layer(
             tower_http::trace::TraceLayer::new_for_http()
                 .make_span_with(otel::make_span_with_impl)
                 .on_request(otel::on_request_impl)
                 .on_response(otel::on_response_impl)
-        );
+        )
+        // server span も debug で作れるよう、TraceLayer の外側に置く
+        .layer(axum::middleware::from_fn_with_state(
+            state,
+            admin::debug_log_override,
+        ));
 
     #[cfg(not(feature = "lambda"))]
     {
         use tokio::net::TcpListener;
-        let listener: TcpListener = TcpListener::bind("localhost:3030").await.unwrap();
-        axum::serve(listener, app_router).await.unwrap();
+        let listener: TcpListener = TcpListener::bind(config.server.bind_address.as_str()).await?;
+        // 流量制限で送信元 IP を使う
+        axum::serve(
+            listener,
+            app_router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+        )
+        .await
+        .unwrap();
+        tracer_provider.shutdown()?;
+        meter_provider.shutdown()?;
+        logger_provider.shutdown()?;
     }
 
     #[cfg(feature = "lambda")]
     {
+        let flusher: flush::Flusher = flush::Flusher::new(
+            &config.otel.flush,
+            &tracer_provider,
+            &meter_provider,
+            &logger_provider,
+            pending_telemetry,
+            &opentelemetry::global::meter_with_scope(otel::init_scope()),
+        );
+        tokio::spawn(flush::flush_on_sigterm(flusher.clone()));
         // lambda_http::run(app_router).await.unwrap();
         use lambda_http::lambda_runtime::layers::{
             OpenTelemetryFaasTrigger, OpenTelemetryLayer as OTelLayer,
@@ -67,11 +193,7 @@ async fn main() -> anyhow::Result<()> {
         let runtime =
             lambda_http::lambda_runtime::Runtime::new(lambda_http::Adapter::from(app_router))
                 .layer(
-                    OTelLayer::new(|| {
-                        tracing::info!("OpenTelemetry provider flush on lambda shutdown");
-                        tracer_provider.force_flush().unwrap();
-                        logger_provider.force_flush().unwrap();
-                    })
+                    OTelLayer::new(move || flusher.on_invocation_end())
                     .with_trigger(OpenTelemetryFaasTrigger::Http),
                 );
         runtime.run().await.unwrap();
@@ -0,0 +1,607 @@
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use opentelemetry_proto::tonic::collector::{
+    logs::v1::{ExportLogsServiceRequest, logs_service_client::LogsServiceClient},
+    trace::v1::{ExportTraceServiceRequest, trace_service_client::TraceServiceClient},
+};
+use opentelemetry_sdk::error::OTelSdkError;
+use serde::{Serialize, de::DeserializeOwned};
+use tonic::{Code, codec::CompressionEncoding, transport::Channel};
+
+use crate::config::{ExportTarget, OtelConfig};
+use crate::error::ApiError;
+use crate::otlp_aws::AwsOtlpEndpoint;
+use crate::sigv4::AwsCredentialsProvider;
+
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// OTLP/HTTP の payload の形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Protobuf,
+    Json,
+}
+
+impl Encoding {
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let content_type: &str = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        match content_type.split(';').next()?.trim() {
+            PROTOBUF_CONTENT_TYPE => Some(Self::Protobuf),
+            JSON_CONTENT_TYPE => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Protobuf => PROTOBUF_CONTENT_TYPE,
+            Self::Json => JSON_CONTENT_TYPE,
+        }
+    }
+}
+
+/// protobuf か JSON の、必要なら gzip された export request を読む
+///
+/// 展開後の大きさも `max_bytes` で制限する
+pub fn decode<M>(headers: &HeaderMap, body: &[u8], max_bytes: usize) -> Result<(M, Encoding), ApiError>
+where
+    M: prost::Message + DeserializeOwned + Default,
+{
+    let encoding: Encoding = Encoding::from_headers(headers).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            "Unsupported media type",
+            format!(
+                "Content-Type must be {} or {}",
+                PROTOBUF_CONTENT_TYPE, JSON_CONTENT_TYPE
+            ),
+        )
+    })?;
+    let mut decompressed: Vec<u8> = Vec::new();
+    let body: &[u8] = match headers
+        .get(header::CONTENT_ENCODING)
+        .map(HeaderValue::as_bytes)
+    {
+        None | Some(b"identity") => body,
+        Some(b"gzip") => {
+            flate2::read::GzDecoder::new(body)
+                .take(max_bytes as u64 + 1)
+                .read_to_end(&mut decompressed)
+                .map_err(|err| invalid(format!("invalid gzip body: {}", err)))?;
+            &decompressed
+        }
+        Some(_) => {
+            return Err(ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_content_encoding",
+                "Unsupported content encoding",
+                "Content-Encoding must be gzip or identity",
+            ));
+        }
+    };
+    if body.len() > max_bytes {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            "Payload too large",
+            format!("OTLP payload must not exceed {} bytes", max_bytes),
+        ));
+    }
+    let message: M = match encoding {
+        Encoding::Protobuf => M::decode(body).map_err(|err| invalid(err.to_string()))?,
+        Encoding::Json => serde_json::from_slice(body).map_err(|err| invalid(err.to_string()))?,
+    };
+    Ok((message, encoding))
+}
+
+fn invalid(detail: String) -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_otlp_payload",
+        "Invalid OTLP payload",
+        detail,
+    )
+}
+
+/// request と同じ形式で export response を返す
+pub fn encode_response<M>(response: &M, encoding: Encoding) -> Response
+where
+    M: prost::Message + Serialize,
+{
+    let body: Vec<u8> = match encoding {
+        Encoding::Protobuf => response.encode_to_vec(),
+        Encoding::Json => serde_json::to_vec(response).unwrap_or_default(),
+    };
+    ([(header::CONTENT_TYPE, encoding.content_type())], body).into_response()
+}
+
+/// OTLP の export の失敗
+#[derive(Debug)]
+pub enum ExportError {
+    /// request を作れなかった
+    Request(anyhow::Error),
+    /// 接続できないなど、応答を受け取れなかった
+    Transport(anyhow::Error),
+    Grpc(tonic::Status),
+    Http {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        body: String,
+    },
+    /// SDK の exporter の、原因の分からない失敗
+    Sdk(String),
+}
+
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportError::Request(err) => write!(f, "invalid export request: {:#}", err),
+            ExportError::Transport(err) => write!(f, "transport error: {:#}", err),
+            ExportError::Grpc(status) => {
+                write!(
+                    f,
+                    "collector responded with {:?}: {}",
+                    status.code(),
+                    status.message()
+                )
+            }
+            ExportError::Http { status, body, .. } => {
+                write!(f, "endpoint responded with {}: {}", status, body)
+            }
+            ExportError::Sdk(message) => write!(f, "exporter failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl ExportError {
+    /// OTLP の仕様で再送してよいとされている失敗か
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ExportError::Request(_) | ExportError::Sdk(_) => false,
+            ExportError::Transport(_) => true,
+            ExportError::Grpc(status) => match status.code() {
+                Code::Cancelled
+                | Code::DeadlineExceeded
+                | Code::Aborted
+                | Code::OutOfRange
+                | Code::Unavailable
+                | Code::DataLoss => true,
+                // 回復できるときだけ server が RetryInfo を付ける
+                Code::ResourceExhausted => self.retry_after().is_some(),
+                _ => false,
+            },
+            ExportError::Http { status, .. } => matches!(
+                *status,
+                StatusCode::TOO_MANY_REQUESTS
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+        }
+    }
+
+    /// server が指定した再送までの時間 (gRPC の `RetryInfo` か HTTP の `Retry-After`)
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ExportError::Grpc(status) => retry_info(status.details()),
+            ExportError::Http { retry_after, .. } => *retry_after,
+            ExportError::Request(_) | ExportError::Transport(_) | ExportError::Sdk(_) => None,
+        }
+    }
+
+    /// SDK の exporter の失敗。tonic の `Status` は `code: '<code の説明>'` で始まる文字列になるので、そこから code を読む
+    ///
+    /// `RetryInfo` は文字列に残らないので、`ResourceExhausted` は再送しない
+    pub fn from_sdk(err: OTelSdkError) -> Self {
+        match err {
+            OTelSdkError::InternalFailure(message) => match grpc_code(&message) {
+                Some(code) => ExportError::Grpc(tonic::Status::new(code, message)),
+                None => ExportError::Sdk(message),
+            },
+            OTelSdkError::Timeout(timeout) => {
+                ExportError::Transport(anyhow::anyhow!("export timed out after {:?}", timeout))
+            }
+            err => ExportError::Sdk(err.to_string()),
+        }
+    }
+
+    pub fn from_response(status: StatusCode, headers: &HeaderMap, body: &[u8]) -> Self {
+        // HTTP-date の形式は使われないので秒数だけ読む
+        let retry_after: Option<Duration> = headers
+            .get(header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+        ExportError::Http {
+            status,
+            retry_after,
+            body: String::from_utf8_lossy(body).into_owned(),
+        }
+    }
+}
+
+fn grpc_code(message: &str) -> Option<Code> {
+    let description: &str = message.strip_prefix("code: '")?;
+    (0..=16).map(Code::from_i32).find(|code| {
+        description
+            .strip_prefix(code.description())
+            .is_some_and(|rest| rest.starts_with('\''))
+    })
+}
+
+/// SDK の exporter は失敗を文字列にするので、HTTP client が受け取った応答をここに残して再送の判断に使う
+#[derive(Debug, Clone, Default)]
+pub struct ExportFailures(Arc<Mutex<Option<ExportError>>>);
+
+impl ExportFailures {
+    pub fn record(&self, err: ExportError) {
+        *self.0.lock().unwrap() = Some(err);
+    }
+
+    pub fn take(&self) -> Option<ExportError> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// `grpc-status-details-bin` の `google.rpc.Status`
+#[derive(Clone, PartialEq, prost::Message)]
+struct RpcStatus {
+    #[prost(message, repeated, tag = "3")]
+    details: Vec<RpcAny>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct RpcAny {
+    #[prost(string, tag = "1")]
+    type_url: String,
+    #[prost(bytes = "vec", tag = "2")]
+    value: Vec<u8>,
+}
+
+/// `google.rpc.RetryInfo`
+#[derive(Clone, PartialEq, prost::Message)]
+struct RetryInfo {
+    #[prost(message, optional, tag = "1")]
+    retry_delay: Option<RpcDuration>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct RpcDuration {
+    #[prost(int64, tag = "1")]
+    seconds: i64,
+    #[prost(int32, tag = "2")]
+    nanos: i32,
+}
+
+fn retry_info(details: &[u8]) -> Option<Duration> {
+    use prost::Message;
+    RpcStatus::decode(details)
+        .ok()?
+        .details
+        .iter()
+        .find(|detail| detail.type_url.ends_with("/google.rpc.RetryInfo"))
+        .and_then(|detail| RetryInfo::decode(detail.value.as_slice()).ok())
+        .and_then(|info| info.retry_delay)
+        .map(|delay| Duration::new(delay.seconds.max(0) as u64, delay.nanos.max(0) as u32))
+}
+
+/// timeout を付け、最初に送るときに接続する collector の channel
+pub fn collector_channel(otel_config: &OtelConfig) -> anyhow::Result<Channel> {
+    Ok(Channel::from_shared(otel_config.endpoint.clone())?
+        .timeout(otel_config.timeout())
+        .connect_lazy())
+}
+
+/// span とログの送り先
+#[derive(Debug)]
+pub enum Destination {
+    Collector(Channel),
+    Aws(AwsOtlpEndpoint),
+}
+
+impl Destination {
+    /// `otel.export_target` の span の送り先
+    pub fn traces(otel_config: &OtelConfig) -> anyhow::Result<Self> {
+        Ok(match otel_config.export_target {
+            ExportTarget::Collector => Destination::Collector(collector_channel(otel_config)?),
+            ExportTarget::Aws => Destination::Aws(AwsOtlpEndpoint::traces(
+                otel_config,
+                AwsCredentialsProvider::from_env()?,
+            )?),
+        })
+    }
+
+    /// `otel.export_target` のログの送り先
+    pub fn logs(otel_config: &OtelConfig) -> anyhow::Result<Self> {
+        Ok(match otel_config.export_target {
+            ExportTarget::Collector => Destination::Collector(collector_channel(otel_config)?),
+            ExportTarget::Aws => Destination::Aws(AwsOtlpEndpoint::logs(
+                otel_config,
+                AwsCredentialsProvider::from_env()?,
+            )?),
+        })
+    }
+
+    pub async fn export_traces(
+        &self,
+        request: ExportTraceServiceRequest,
+    ) -> Result<(), ExportError> {
+        match self {
+            Destination::Collector(channel) => {
+                TraceServiceClient::new(channel.clone())
+                    .send_compressed(CompressionEncoding::Gzip)
+                    .export(request)
+                    .await
+                    .map_err(ExportError::Grpc)?;
+            }
+            Destination::Aws(endpoint) => endpoint.export(&request).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn export_logs(&self, request: ExportLogsServiceRequest) -> Result<(), ExportError> {
+        match self {
+            Destination::Collector(channel) => {
+                LogsServiceClient::new(channel.clone())
+                    .send_compressed(CompressionEncoding::Gzip)
+                    .export(request)
+                    .await
+                    .map_err(ExportError::Grpc)?;
+            }
+            Destination::Aws(endpoint) => endpoint.export(&request).await?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+
+    fn http_error(status: StatusCode, retry_after: &str) -> ExportError {
+        let mut headers: HeaderMap = HeaderMap::new();
+        headers.insert(
+            header::RETRY_AFTER,
+            HeaderValue::from_str(retry_after).unwrap(),
+        );
+        ExportError::from_response(status, &headers, b"")
+    }
+
+    fn retry_info_status(code: Code, delay: RpcDuration) -> tonic::Status {
+        let details: RpcStatus = RpcStatus {
+            details: vec![RpcAny {
+                type_url: "type.googleapis.com/google.rpc.RetryInfo".to_string(),
+                value: RetryInfo {
+                    retry_delay: Some(delay),
+                }
+                .encode_to_vec(),
+            }],
+        };
+        tonic::Status::with_details(code, "slow down", details.encode_to_vec().into())
+    }
+
+    #[test]
+    fn retry_after_is_read_in_seconds() {
+        let err: ExportError = http_error(StatusCode::TOO_MANY_REQUESTS, " 3 ");
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(3)));
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn retry_after_as_http_date_is_ignored() {
+        let err: ExportError = http_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Wed, 21 Oct 2026 07:28:00 GMT",
+        );
+        assert_eq!(err.retry_after(), None);
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn only_throttling_and_unavailable_http_statuses_are_retryable() {
+        for status in [
+            StatusCode::BAD_REQUEST,
+            StatusCode::FORBIDDEN,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ] {
+            assert!(!http_error(status, "1").is_retryable(), "{}", status);
+        }
+    }
+
+    #[test]
+    fn retry_info_gives_the_delay_and_makes_resource_exhausted_retryable() {
+        let status: tonic::Status = retry_info_status(
+            Code::ResourceExhausted,
+            RpcDuration {
+                seconds: 1,
+                nanos: 500_000_000,
+            },
+        );
+        let err: ExportError = ExportError::Grpc(status);
+        assert_eq!(err.retry_after(), Some(Duration::from_millis(1500)));
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn resource_exhausted_without_retry_info_is_not_retryable() {
+        let err: ExportError = ExportError::Grpc(tonic::Status::resource_exhausted("full"));
+        assert_eq!(err.retry_after(), None);
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn negative_retry_delays_are_clamped() {
+        let status: tonic::Status = retry_info_status(
+            Code::Unavailable,
+            RpcDuration {
+                seconds: -1,
+                nanos: -1,
+            },
+        );
+        assert_eq!(
+            ExportError::Grpc(status).retry_after(),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn sdk_failures_keep_the_grpc_code() {
+        let err: ExportError = ExportError::from_sdk(OTelSdkError::InternalFailure(
+            tonic::Status::unavailable("connection refused").to_string(),
+        ));
+        assert!(matches!(&err, ExportError::Grpc(status) if status.code() == Code::Unavailable));
+        assert!(err.is_retryable());
+
+        let err: ExportError = ExportError::from_sdk(OTelSdkError::InternalFailure(
+            tonic::Status::invalid_argument("bad span").to_string(),
+        ));
+        assert!(
+            matches!(&err, ExportError::Grpc(status) if status.code() == Code::InvalidArgument)
+        );
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn unknown_sdk_failures_are_not_retried() {
+        let err: ExportError = ExportError::from_sdk(OTelSdkError::InternalFailure(
+            "Status Code: 503".to_string(),
+        ));
+        assert!(matches!(err, ExportError::Sdk(_)));
+        assert!(!err.is_retryable());
+        assert!(
+            ExportError::from_sdk(OTelSdkError::Timeout(Duration::from_secs(1))).is_retryable()
+        );
+    }
+
+    fn decode_traces(
+        content_type: &str,
+        content_encoding: Option<&str>,
+        body: &[u8],
+        max_bytes: usize,
+    ) -> Result<(ExportTraceServiceRequest, Encoding), ApiError> {
+        let mut headers: HeaderMap = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(content_type).unwrap(),
+        );
+        if let Some(content_encoding) = content_encoding {
+            headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_str(content_encoding).unwrap(),
+            );
+        }
+        decode(&headers, body, max_bytes)
+    }
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decode_reads_protobuf_and_json_with_or_without_gzip() {
+        let request: ExportTraceServiceRequest = ExportTraceServiceRequest {
+            resource_spans: vec![Default::default()],
+        };
+        let protobuf: Vec<u8> = request.encode_to_vec();
+        let json: Vec<u8> = serde_json::to_vec(&request).unwrap();
+        for (content_type, content_encoding, body, encoding) in [
+            (
+                PROTOBUF_CONTENT_TYPE,
+                None,
+                protobuf.clone(),
+                Encoding::Protobuf,
+            ),
+            (
+                PROTOBUF_CONTENT_TYPE,
+                Some("identity"),
+                protobuf.clone(),
+                Encoding::Protobuf,
+            ),
+            (
+                PROTOBUF_CONTENT_TYPE,
+                Some("gzip"),
+                gzip(&protobuf),
+                Encoding::Protobuf,
+            ),
+            (
+                "application/json; charset=utf-8",
+                None,
+                json.clone(),
+                Encoding::Json,
+            ),
+            (JSON_CONTENT_TYPE, Some("gzip"), gzip(&json), Encoding::Json),
+        ] {
+            let decoded = decode_traces(content_type, content_encoding, &body, 1024).unwrap();
+            assert_eq!(
+                decoded,
+                (request.clone(), encoding),
+                "{} {:?}",
+                content_type,
+                content_encoding
+            );
+        }
+    }
+
+    #[test]
+    fn decode_limits_the_decompressed_size() {
+        let body: Vec<u8> = vec![0; 64];
+        for (content_encoding, body) in [(None, body.clone()), (Some("gzip"), gzip(&body))] {
+            // 0 の並びは protobuf として読めないので、大きさの検査を通ると 400 になる
+            let err: ApiError =
+                decode_traces(PROTOBUF_CONTENT_TYPE, content_encoding, &body, 64).unwrap_err();
+            assert!(
+                err.to_string().starts_with("invalid_otlp_payload:"),
+                "{}",
+                err
+            );
+            let err: ApiError =
+                decode_traces(PROTOBUF_CONTENT_TYPE, content_encoding, &body, 63).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "payload_too_large: OTLP payload must not exceed 63 bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn decode_rejects_unsupported_media_types_and_broken_bodies() {
+        for (content_type, content_encoding, body, error_type) in [
+            ("text/plain", None, &b""[..], "unsupported_media_type"),
+            (
+                PROTOBUF_CONTENT_TYPE,
+                Some("br"),
+                b"",
+                "unsupported_content_encoding",
+            ),
+            (
+                PROTOBUF_CONTENT_TYPE,
+                Some("gzip"),
+                b"not gzip",
+                "invalid_otlp_payload",
+            ),
+            (JSON_CONTENT_TYPE, None, b"{", "invalid_otlp_payload"),
+        ] {
+            let err: ApiError =
+                decode_traces(content_type, content_encoding, body, 1024).unwrap_err();
+            assert!(
+                err.to_string().starts_with(&format!("{}:", error_type)),
+                "{}",
+                err
+            );
+        }
+    }
+}
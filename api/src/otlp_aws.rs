@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+
+use flate2::write::GzEncoder;
+use axum::http::{HeaderValue, header};
+use opentelemetry_http::{Bytes, HttpClient, HttpError, Request, Response};
+use opentelemetry_otlp::{
+    Compression, LogExporter, Protocol, SpanExporter, WithExportConfig, WithHttpConfig,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Env, OtelConfig};
+use crate::otlp::{ExportError, ExportFailures, PROTOBUF_CONTENT_TYPE};
+use crate::sigv4::{AwsCredentialsProvider, SigV4Signer};
+
+pub const LOG_GROUP_HEADER: &str = "x-aws-log-group";
+pub const LOG_STREAM_HEADER: &str = "x-aws-log-stream";
+
+/// collector を使わず、X-Ray と CloudWatch Logs の OTLP endpoint に直接送るときの設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AwsExportConfig {
+    /// 空なら `AWS_REGION`
+    pub region: String,
+    /// 省略時は `https://xray.<region>.amazonaws.com/v1/traces`
+    pub traces_endpoint: Option<String>,
+    /// 省略時は `https://logs.<region>.amazonaws.com/v1/logs`
+    pub logs_endpoint: Option<String>,
+    /// 空なら `AWS_LAMBDA_LOG_GROUP_NAME`
+    pub log_group: String,
+    pub log_stream: String,
+}
+
+impl Default for AwsExportConfig {
+    fn default() -> Self {
+        Self {
+            region: String::new(),
+            traces_endpoint: None,
+            logs_endpoint: None,
+            log_group: String::new(),
+            log_stream: "default".to_string(),
+        }
+    }
+}
+
+impl AwsExportConfig {
+    /// 空の `region` と `log_group` を Lambda の環境変数で埋める
+    pub fn apply_env_overrides(&mut self, env: &Env) {
+        for (name, value) in [
+            ("AWS_REGION", &mut self.region),
+            ("AWS_LAMBDA_LOG_GROUP_NAME", &mut self.log_group),
+        ] {
+            if value.is_empty() {
+                *value = env.get(name).unwrap_or_default().to_string();
+            }
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.region.is_empty() || self.log_group.is_empty() || self.log_stream.is_empty() {
+            anyhow::bail!(
+                "otel.aws.region, log_group and log_stream are required when otel.export_target is `aws`"
+            );
+        }
+        Ok(())
+    }
+
+    pub fn traces_endpoint(&self) -> String {
+        self.traces_endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://xray.{}.amazonaws.com/v1/traces", self.region))
+    }
+
+    pub fn logs_endpoint(&self) -> String {
+        self.logs_endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://logs.{}.amazonaws.com/v1/logs", self.region))
+    }
+}
+
+/// 送る前に SigV4 で署名する OTLP/HTTP の client
+///
+/// batch processor は専用のスレッドから export するので、credentials の取得と reqwest は作ったときの tokio runtime で動かす
+#[derive(Debug)]
+pub struct SigV4HttpClient {
+    client: reqwest::Client,
+    signer: SigV4Signer,
+    runtime: tokio::runtime::Handle,
+    /// SDK の exporter に渡すときに、失敗した応答を残す先
+    failures: Option<ExportFailures>,
+}
+
+impl SigV4HttpClient {
+    pub fn new(
+        otel_config: &OtelConfig,
+        service: &str,
+        credentials: AwsCredentialsProvider,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(otel_config.timeout())
+                .build()?,
+            signer: SigV4Signer::new(credentials, &otel_config.aws.region, service),
+            runtime: tokio::runtime::Handle::current(),
+            failures: None,
+        })
+    }
+
+    fn record_failures(mut self, failures: &ExportFailures) -> Self {
+        self.failures = Some(failures.clone());
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl HttpClient for SigV4HttpClient {
+    async fn send_bytes(&self, request: Request<Bytes>) -> Result<Response<Bytes>, HttpError> {
+        let client: reqwest::Client = self.client.clone();
+        let signer: SigV4Signer = self.signer.clone();
+        let result: anyhow::Result<Response<Bytes>> = self
+            .runtime
+            .spawn(async move {
+                let (mut parts, body) = request.into_parts();
+                signer.sign(&mut parts, &body).await?;
+                let request: reqwest::Request = Request::from_parts(parts, body).try_into()?;
+                let response: reqwest::Response = client.execute(request).await?;
+                let mut builder = Response::builder().status(response.status());
+                if let Some(headers) = builder.headers_mut() {
+                    *headers = response.headers().clone();
+                }
+                let body: Bytes = response.bytes().await?;
+                anyhow::Ok(builder.body(body)?)
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result);
+        if let Some(failures) = &self.failures {
+            match &result {
+                Ok(response) if !response.status().is_success() => {
+                    failures.record(ExportError::from_response(
+                        response.status(),
+                        response.headers(),
+                        response.body(),
+                    ));
+                }
+                Ok(_) => {}
+                Err(err) => failures.record(ExportError::Transport(anyhow::anyhow!("{:#}", err))),
+            }
+        }
+        Ok(result?)
+    }
+}
+
+/// `xray` に SigV4 で署名して送る span exporter
+pub fn span_exporter(
+    otel_config: &OtelConfig,
+    credentials: AwsCredentialsProvider,
+    failures: &ExportFailures,
+) -> anyhow::Result<SpanExporter> {
+    let client: SigV4HttpClient =
+        SigV4HttpClient::new(otel_config, "xray", credentials)?.record_failures(failures);
+    Ok(SpanExporter::builder()
+        .with_http()
+        .with_http_client(client)
+        .with_endpoint(otel_config.aws.traces_endpoint())
+        .with_protocol(Protocol::HttpBinary)
+        .with_compression(Compression::Gzip)
+        .with_timeout(otel_config.timeout())
+        .build()?)
+}
+
+/// `logs` に SigV4 で署名し、送り先の log group と log stream を付けて送る log exporter
+pub fn log_exporter(
+    otel_config: &OtelConfig,
+    credentials: AwsCredentialsProvider,
+    failures: &ExportFailures,
+) -> anyhow::Result<LogExporter> {
+    let client: SigV4HttpClient =
+        SigV4HttpClient::new(otel_config, "logs", credentials)?.record_failures(failures);
+    Ok(LogExporter::builder()
+        .with_http()
+        .with_http_client(client)
+        .with_endpoint(otel_config.aws.logs_endpoint())
+        .with_protocol(Protocol::HttpBinary)
+        .with_compression(Compression::Gzip)
+        .with_timeout(otel_config.timeout())
+        .with_headers(HashMap::from([
+            (
+                LOG_GROUP_HEADER.to_string(),
+                otel_config.aws.log_group.clone(),
+            ),
+            (
+                LOG_STREAM_HEADER.to_string(),
+                otel_config.aws.log_stream.clone(),
+            ),
+        ]))
+        .build()?)
+}
+
+/// SDK の exporter を通さず、受け取った OTLP の request をそのまま送る先
+#[derive(Debug)]
+pub struct AwsOtlpEndpoint {
+    client: SigV4HttpClient,
+    endpoint: String,
+    headers: Vec<(&'static str, String)>,
+    limits: RequestLimits,
+}
+
+/// endpoint が 1 回の request で受け付ける上限
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    /// 展開後の大きさ
+    pub max_bytes: usize,
+    /// span かログの件数
+    pub max_items: usize,
+}
+
+impl RequestLimits {
+    pub const UNLIMITED: Self = Self {
+        max_bytes: usize::MAX,
+        max_items: usize::MAX,
+    };
+
+    /// 両方の上限を満たす上限
+    pub fn min(self, other: Self) -> Self {
+        Self {
+            max_bytes: self.max_bytes.min(other.max_bytes),
+            max_items: self.max_items.min(other.max_items),
+        }
+    }
+}
+
+impl AwsOtlpEndpoint {
+    /// X-Ray の `/v1/traces`
+    pub fn traces(
+        otel_config: &OtelConfig,
+        credentials: AwsCredentialsProvider,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: SigV4HttpClient::new(otel_config, "xray", credentials)?,
+            endpoint: otel_config.aws.traces_endpoint(),
+            headers: vec![],
+            limits: RequestLimits::UNLIMITED,
+        })
+    }
+
+    /// CloudWatch Logs の `/v1/logs`
+    pub fn logs(
+        otel_config: &OtelConfig,
+        credentials: AwsCredentialsProvider,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: SigV4HttpClient::new(otel_config, "logs", credentials)?,
+            endpoint: otel_config.aws.logs_endpoint(),
+            headers: vec![
+                (LOG_GROUP_HEADER, otel_config.aws.log_group.clone()),
+                (LOG_STREAM_HEADER, otel_config.aws.log_stream.clone()),
+            ],
+            // PutLogEvents と同じ上限
+            limits: RequestLimits {
+                max_bytes: 1024 * 1024,
+                max_items: 10_000,
+            },
+        })
+    }
+
+    pub fn limits(&self) -> RequestLimits {
+        self.limits
+    }
+
+    /// gzip した protobuf で送る
+    pub async fn export(&self, message: &impl prost::Message) -> Result<(), ExportError> {
+        let request: Request<Bytes> = self.request(message).map_err(ExportError::Request)?;
+        let response = self
+            .client
+            .send_bytes(request)
+            .await
+            .map_err(|err| ExportError::Transport(anyhow::anyhow!(err)))?;
+        if !response.status().is_success() {
+            return Err(ExportError::from_response(
+                response.status(),
+                response.headers(),
+                response.body(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn request(&self, message: &impl prost::Message) -> anyhow::Result<Request<Bytes>> {
+        let mut body = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut body, &message.encode_to_vec())?;
+        let mut request = Request::post(&self.endpoint)
+            .header(header::CONTENT_TYPE, PROTOBUF_CONTENT_TYPE)
+            .header(header::CONTENT_ENCODING, "gzip");
+        for (name, value) in &self.headers {
+            request = request.header(*name, HeaderValue::from_str(value)?);
+        }
+        Ok(request.body(Bytes::from(body.finish()?))?)
+    }
+}
+
+#[cfg(all(test, not(feature = "lambda")))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{Router, routing::post};
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+
+    use super::*;
+    use crate::auth::SigV4Config;
+    use crate::sigv4::{
+        AwsCredentials, Credentials, SigV4Verifier, StaticCredentialsProvider, require_sigv4,
+    };
+
+    const ACCESS_KEY_ID: &str = "AKIDEXAMPLE";
+    const SECRET_ACCESS_KEY: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+
+    /// SigV4 署名を検証し、受け取った request の `content-encoding` を記録する X-Ray の代わり
+    async fn stub_xray() -> (String, Arc<Mutex<Vec<String>>>) {
+        let received: Arc<Mutex<Vec<String>>> = Arc::default();
+        let provider: StaticCredentialsProvider = [(
+            ACCESS_KEY_ID.to_string(),
+            Credentials {
+                secret_access_key: SECRET_ACCESS_KEY.to_string(),
+                principal: "exporter".to_string(),
+            },
+        )]
+        .into_iter()
+        .collect();
+        let verifier: SigV4Verifier = SigV4Verifier::new(
+            &SigV4Config {
+                region: "us-east-1".to_string(),
+                service: "xray".to_string(),
+                ..SigV4Config::default()
+            },
+            Arc::new(provider),
+        );
+        let record = received.clone();
+        let router: Router = Router::new()
+            .route(
+                "/v1/traces",
+                post(move |headers: axum::http::HeaderMap| async move {
+                    let encoding = headers.get(header::CONTENT_ENCODING).cloned();
+                    record.lock().unwrap().push(
+                        encoding
+                            .map(|value| value.to_str().unwrap().to_string())
+                            .unwrap_or_default(),
+                    );
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(verifier, require_sigv4));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint: String = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        (endpoint, received)
+    }
+
+    fn otel_config(url: &str) -> OtelConfig {
+        let mut otel_config: OtelConfig = OtelConfig::default();
+        otel_config.aws.region = "us-east-1".to_string();
+        otel_config.aws.traces_endpoint = Some(url.to_string());
+        otel_config
+    }
+
+    fn credentials(secret_access_key: &str) -> AwsCredentialsProvider {
+        AwsCredentialsProvider::Static(AwsCredentials {
+            access_key_id: ACCESS_KEY_ID.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+            session_token: Some("session".to_string()),
+            expiration: None,
+        })
+    }
+
+    fn endpoint(url: &str, secret_access_key: &str) -> AwsOtlpEndpoint {
+        AwsOtlpEndpoint::traces(&otel_config(url), credentials(secret_access_key)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn signed_exports_are_accepted_by_the_verifier() {
+        let (url, received) = stub_xray().await;
+        endpoint(&url, SECRET_ACCESS_KEY)
+            .export(&ExportTraceServiceRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(*received.lock().unwrap(), ["gzip"]);
+    }
+
+    #[tokio::test]
+    async fn exports_signed_with_the_wrong_secret_are_rejected() {
+        let (url, received) = stub_xray().await;
+        let err: ExportError = endpoint(&url, "wrong")
+            .export(&ExportTraceServiceRequest::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ExportError::Http {
+                status: axum::http::StatusCode::FORBIDDEN,
+                ..
+            }
+        ));
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sdk_span_exporter_signs_requests() {
+        use opentelemetry_sdk::trace::SpanExporter as _;
+        let (url, received) = stub_xray().await;
+        let failures: ExportFailures = ExportFailures::default();
+        let exporter: SpanExporter =
+            span_exporter(&otel_config(&url), credentials(SECRET_ACCESS_KEY), &failures).unwrap();
+        exporter.export(vec![]).await.unwrap();
+        assert_eq!(*received.lock().unwrap(), ["gzip"]);
+        assert!(failures.take().is_none());
+    }
+
+    #[tokio::test]
+    async fn sdk_span_exporter_records_rejected_responses() {
+        use opentelemetry_sdk::trace::SpanExporter as _;
+        let (url, received) = stub_xray().await;
+        let failures: ExportFailures = ExportFailures::default();
+        let exporter: SpanExporter =
+            span_exporter(&otel_config(&url), credentials("wrong"), &failures).unwrap();
+        exporter.export(vec![]).await.unwrap_err();
+        assert!(matches!(
+            failures.take(),
+            Some(ExportError::Http {
+                status: axum::http::StatusCode::FORBIDDEN,
+                ..
+            })
+        ));
+        assert!(received.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,835 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use opentelemetry::InstrumentationScope;
+use opentelemetry_proto::{
+    tonic::collector::{logs::v1::ExportLogsServiceRequest, trace::v1::ExportTraceServiceRequest},
+    transform::{
+        common::tonic::ResourceAttributesWithSchema, logs::tonic::group_logs_by_resource_and_scope,
+        trace::tonic::group_spans_by_resource_and_scope,
+    },
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::{OTelSdkError, OTelSdkResult},
+    logs::{LogBatch, LogExporter, SdkLogRecord},
+    trace::{SpanData, SpanExporter},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::OtelConfig;
+use crate::otlp::{Destination, ExportError, ExportFailures};
+use crate::outbound::RetryPolicy;
+
+// SDK の batch processor が `force_flush` で export を待つ時間。flush がこれより長く止まらないようにする
+const FORCE_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// span とログの export を再送する設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// 最初の 1 回を含む。1 なら再送しない
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    /// server が `RetryInfo` や `Retry-After` でこれより長く待たせるときは再送しない
+    pub max_backoff_ms: u64,
+    /// 再送と送り直しを含めて 1 回の export にかける時間の上限。超えたら書き出して諦める
+    pub max_elapsed_ms: u64,
+    pub spill: SpillConfig,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 5000,
+            max_elapsed_ms: 2000,
+            spill: SpillConfig::default(),
+        }
+    }
+}
+
+/// 再送しても送れなかった batch を書き出しておく場所
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpillConfig {
+    pub enabled: bool,
+    /// Lambda で書き込めるのは `/tmp` の下だけ
+    pub directory: PathBuf,
+    /// span とログのそれぞれで、これを超えたら古いものから消す
+    pub max_bytes: u64,
+    /// export に成功したときに送り直す batch の数
+    pub replay_batches: usize,
+}
+
+impl Default for SpillConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: PathBuf::from("/tmp/otel-spill"),
+            max_bytes: 16 * 1024 * 1024,
+            replay_batches: 16,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.max_attempts == 0 || self.initial_backoff_ms == 0 {
+            anyhow::bail!("otel.retry.max_attempts and initial_backoff_ms must be positive");
+        }
+        if self.initial_backoff_ms > self.max_backoff_ms {
+            anyhow::bail!("otel.retry.initial_backoff_ms must not exceed max_backoff_ms");
+        }
+        if self.max_elapsed_ms == 0 || self.max_elapsed() > FORCE_FLUSH_TIMEOUT {
+            anyhow::bail!(
+                "otel.retry.max_elapsed_ms must be positive and at most {}",
+                FORCE_FLUSH_TIMEOUT.as_millis()
+            );
+        }
+        if self.spill.enabled && (self.spill.max_bytes == 0 || self.spill.replay_batches == 0) {
+            anyhow::bail!("otel.retry.spill.max_bytes and replay_batches must be positive");
+        }
+        Ok(())
+    }
+
+    fn max_elapsed(&self) -> Duration {
+        Duration::from_millis(self.max_elapsed_ms)
+    }
+
+    fn policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts,
+            base_delay: Duration::from_millis(self.initial_backoff_ms),
+            max_delay: Duration::from_millis(self.max_backoff_ms),
+        }
+    }
+}
+
+/// 再送と書き出しの単位になる export request
+pub trait ExportRequest: prost::Message + Default + Clone {
+    /// 書き出したファイルの名前の先頭
+    const SIGNAL: &'static str;
+
+    fn send(
+        self,
+        destination: &Destination,
+    ) -> impl Future<Output = Result<(), ExportError>> + Send;
+}
+
+impl ExportRequest for ExportTraceServiceRequest {
+    const SIGNAL: &'static str = "traces";
+
+    fn send(
+        self,
+        destination: &Destination,
+    ) -> impl Future<Output = Result<(), ExportError>> + Send {
+        destination.export_traces(self)
+    }
+}
+
+impl ExportRequest for ExportLogsServiceRequest {
+    const SIGNAL: &'static str = "logs";
+
+    fn send(
+        self,
+        destination: &Destination,
+    ) -> impl Future<Output = Result<(), ExportError>> + Send {
+        destination.export_logs(self)
+    }
+}
+
+/// 送れなかった export request を protobuf のままファイルに書き出す queue
+///
+/// ファイル名は書き出した時刻から始まるので、名前の順に送り直す
+///
+/// SDK の exporter は `SpanData` とログの record しか送れないので、書き出した request は `destination` に送り直す
+#[derive(Debug)]
+struct SpillQueue {
+    directory: PathBuf,
+    prefix: &'static str,
+    max_bytes: u64,
+    replay_batches: usize,
+    sequence: AtomicU64,
+    destination: Destination,
+}
+
+impl SpillQueue {
+    fn new(config: &SpillConfig, prefix: &'static str, destination: Destination) -> Self {
+        Self {
+            directory: config.directory.clone(),
+            prefix,
+            max_bytes: config.max_bytes,
+            replay_batches: config.replay_batches,
+            sequence: AtomicU64::new(0),
+            destination,
+        }
+    }
+
+    /// 古い順のファイルと大きさ
+    fn files(&self) -> std::io::Result<Vec<(PathBuf, u64)>> {
+        let entries = match std::fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut files: Vec<(PathBuf, u64)> = vec![];
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(self.prefix) && name.ends_with(".pb") {
+                files.push((entry.path(), entry.metadata()?.len()));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn push(&self, bytes: &[u8]) -> std::io::Result<()> {
+        let size: u64 = bytes.len() as u64;
+        if size > self.max_bytes {
+            tracing::warn!(
+                "Discarded an OTLP {} batch of {} bytes larger than the spill limit",
+                self.prefix,
+                size
+            );
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.directory)?;
+        let files: Vec<(PathBuf, u64)> = self.files()?;
+        let mut total: u64 = files.iter().map(|(_, size)| size).sum::<u64>() + size;
+        let mut discarded: usize = 0;
+        for (path, file_size) in files {
+            if total <= self.max_bytes {
+                break;
+            }
+            std::fs::remove_file(path)?;
+            total -= file_size;
+            discarded += 1;
+        }
+        if discarded > 0 {
+            tracing::warn!(
+                "Discarded {} spilled OTLP {} batches to stay under {} bytes",
+                discarded,
+                self.prefix,
+                self.max_bytes
+            );
+        }
+
+        let timestamp: u128 = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let sequence: u64 = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let name: String = format!("{}-{:020}-{:06}.pb", self.prefix, timestamp, sequence);
+        // 書きかけのファイルを送り直さないよう、書き終えてから名前を付ける
+        let partial: PathBuf = self.directory.join(format!(".{}.partial", name));
+        std::fs::write(&partial, bytes)?;
+        std::fs::rename(&partial, self.directory.join(name))
+    }
+
+    fn remove(&self, path: &Path) {
+        if let Err(err) = std::fs::remove_file(path) {
+            tracing::warn!(
+                "Failed to remove spilled OTLP batch {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// SDK の exporter を包み、再送できる失敗は backoff を挟んで送り直し、それでも送れなければファイルに書き出す
+///
+/// 書き出した batch は、次に export できたときに古いものから送り直す。
+/// 再送と送り直しは `max_elapsed_ms` のうちに終え、送れない間も flush を長く止めない
+#[derive(Debug)]
+pub struct OtlpExporter<E> {
+    exporter: E,
+    failures: ExportFailures,
+    policy: RetryPolicy,
+    max_elapsed: Duration,
+    spill: Option<SpillQueue>,
+    resource: ResourceAttributesWithSchema,
+    /// batch processor は tokio の外のスレッドから export するので、timer はここで動かす
+    runtime: tokio::runtime::Handle,
+}
+
+impl<E> OtlpExporter<E> {
+    /// `failures` は `exporter` の HTTP client が失敗した応答を残す先
+    fn new(
+        exporter: E,
+        failures: ExportFailures,
+        otel_config: &OtelConfig,
+        signal: &'static str,
+        destination: impl FnOnce(&OtelConfig) -> anyhow::Result<Destination>,
+    ) -> anyhow::Result<Self> {
+        let retry: &RetryConfig = &otel_config.retry;
+        let spill: Option<SpillQueue> = if retry.spill.enabled {
+            Some(SpillQueue::new(
+                &retry.spill,
+                signal,
+                destination(otel_config)?,
+            ))
+        } else {
+            None
+        };
+        Ok(Self {
+            exporter,
+            failures,
+            policy: retry.policy(),
+            max_elapsed: retry.max_elapsed(),
+            spill,
+            resource: ResourceAttributesWithSchema::default(),
+            runtime: tokio::runtime::Handle::current(),
+        })
+    }
+
+    /// `send` で送り、`request` は送れなかったときに書き出す request を作る
+    async fn export_batch<R, F>(
+        &self,
+        send: impl Fn() -> F,
+        request: impl FnOnce() -> R,
+    ) -> OTelSdkResult
+    where
+        R: ExportRequest,
+        F: Future<Output = OTelSdkResult>,
+    {
+        let deadline: Instant = Instant::now() + self.max_elapsed;
+        let err: ExportError = match self.send_with_retry(R::SIGNAL, send, deadline).await {
+            Ok(()) => {
+                self.replay::<R>(deadline).await;
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+        if let Some(spill) = &self.spill
+            && err.is_retryable()
+        {
+            if let Err(spill_err) = spill.push(&request().encode_to_vec()) {
+                tracing::warn!("Failed to spill an OTLP {} batch: {}", R::SIGNAL, spill_err);
+            }
+            return Err(OTelSdkError::InternalFailure(format!(
+                "{} (spilled to {})",
+                err,
+                spill.directory.display()
+            )));
+        }
+        Err(OTelSdkError::InternalFailure(err.to_string()))
+    }
+
+    /// `deadline` を過ぎる backoff は待たずに、最後の失敗を返す
+    async fn send_with_retry<F>(
+        &self,
+        signal: &str,
+        send: impl Fn() -> F,
+        deadline: Instant,
+    ) -> Result<(), ExportError>
+    where
+        F: Future<Output = OTelSdkResult>,
+    {
+        let mut attempt: u32 = 0;
+        loop {
+            self.failures.take();
+            let result: OTelSdkResult = match self.within(deadline, send()).await {
+                Some(result) => result,
+                None => return Err(self.deadline_exceeded()),
+            };
+            attempt += 1;
+            let err: ExportError = match result {
+                Ok(()) => return Ok(()),
+                Err(err) => self
+                    .failures
+                    .take()
+                    .unwrap_or_else(|| ExportError::from_sdk(err)),
+            };
+            if !err.is_retryable() || attempt >= self.policy.max_attempts {
+                return Err(err);
+            }
+            let backoff: Duration = match err.retry_after() {
+                Some(retry_after) if retry_after > self.policy.max_delay => return Err(err),
+                Some(retry_after) => retry_after,
+                None => self.policy.backoff(attempt),
+            };
+            if Instant::now() + backoff >= deadline {
+                return Err(err);
+            }
+            tracing::warn!(
+                attempt,
+                ?backoff,
+                "Retrying OTLP {} export: {}",
+                signal,
+                err
+            );
+            let sleep = {
+                let _runtime = self.runtime.enter();
+                tokio::time::sleep(backoff)
+            };
+            sleep.await;
+        }
+    }
+
+    /// `deadline` までに終わらなければ `None`
+    async fn within<T>(&self, deadline: Instant, future: impl Future<Output = T>) -> Option<T> {
+        let timeout = {
+            let _runtime = self.runtime.enter();
+            tokio::time::timeout_at(deadline.into(), future)
+        };
+        timeout.await.ok()
+    }
+
+    fn deadline_exceeded(&self) -> ExportError {
+        ExportError::Transport(anyhow::anyhow!(
+            "export did not finish within {:?}",
+            self.max_elapsed
+        ))
+    }
+
+    /// 書き出した batch を古いものから 1 回ずつ送り直す。再送できる失敗が起きたか `deadline` を過ぎたら次の機会に回す
+    async fn replay<R: ExportRequest>(&self, deadline: Instant) {
+        let Some(spill) = &self.spill else {
+            return;
+        };
+        let files: Vec<(PathBuf, u64)> = match spill.files() {
+            Ok(files) => files,
+            Err(err) => {
+                tracing::warn!("Failed to list spilled OTLP {} batches: {}", R::SIGNAL, err);
+                return;
+            }
+        };
+        for (path, _) in files.into_iter().take(spill.replay_batches) {
+            let request: R = match std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(R::decode(bytes.as_slice())?))
+            {
+                Ok(request) => request,
+                Err(err) => {
+                    tracing::warn!(
+                        "Discarded unreadable spilled OTLP batch {}: {}",
+                        path.display(),
+                        err
+                    );
+                    spill.remove(&path);
+                    continue;
+                }
+            };
+            let result: Result<(), ExportError> = match self
+                .within(deadline, request.send(&spill.destination))
+                .await
+            {
+                Some(result) => result,
+                None => Err(self.deadline_exceeded()),
+            };
+            match result {
+                Ok(()) => spill.remove(&path),
+                Err(err) if err.is_retryable() => {
+                    tracing::warn!("Failed to replay spilled OTLP {} batch: {}", R::SIGNAL, err);
+                    return;
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "Discarded spilled OTLP batch {} rejected by the endpoint: {}",
+                        path.display(),
+                        err
+                    );
+                    spill.remove(&path);
+                }
+            }
+        }
+    }
+}
+
+impl<E: SpanExporter> OtlpExporter<E> {
+    /// `exporter` が送れなかった span を `otel.export_target` に送り直す
+    pub fn traces(
+        exporter: E,
+        failures: ExportFailures,
+        otel_config: &OtelConfig,
+    ) -> anyhow::Result<Self> {
+        Self::new(
+            exporter,
+            failures,
+            otel_config,
+            ExportTraceServiceRequest::SIGNAL,
+            Destination::traces,
+        )
+    }
+}
+
+impl<E: LogExporter> OtlpExporter<E> {
+    /// `exporter` が送れなかったログを `otel.export_target` に送り直す
+    pub fn logs(
+        exporter: E,
+        failures: ExportFailures,
+        otel_config: &OtelConfig,
+    ) -> anyhow::Result<Self> {
+        Self::new(
+            exporter,
+            failures,
+            otel_config,
+            ExportLogsServiceRequest::SIGNAL,
+            Destination::logs,
+        )
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for OtlpExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        self.export_batch(
+            || self.exporter.export(batch.clone()),
+            || ExportTraceServiceRequest {
+                resource_spans: group_spans_by_resource_and_scope(batch.clone(), &self.resource),
+            },
+        )
+        .await
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.exporter.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.exporter.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.exporter.set_resource(resource);
+        self.resource = ResourceAttributesWithSchema::from(resource);
+    }
+}
+
+impl<E: LogExporter> LogExporter for OtlpExporter<E> {
+    async fn export(&self, batch: LogBatch<'_>) -> OTelSdkResult {
+        let records: Vec<(&SdkLogRecord, &InstrumentationScope)> = batch.iter().collect();
+        self.export_batch(
+            || self.exporter.export(LogBatch::new(&records)),
+            || ExportLogsServiceRequest {
+                resource_logs: group_logs_by_resource_and_scope(
+                    LogBatch::new(&records),
+                    &self.resource,
+                ),
+            },
+        )
+        .await
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.exporter.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.exporter.set_resource(resource);
+        self.resource = ResourceAttributesWithSchema::from(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::collections::VecDeque;
+    use std::sync::atomic::AtomicU32;
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+    use opentelemetry::trace::{
+        SpanContext, SpanId, SpanKind, Status, TraceFlags, TraceId, TraceState,
+    };
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        ExportTraceServiceResponse,
+        trace_service_server::{TraceService, TraceServiceServer},
+    };
+    use opentelemetry_sdk::trace::{SpanEvents, SpanLinks};
+
+    use super::*;
+
+    /// 1 回の export の結果。最後の 1 つはその後も繰り返す
+    #[derive(Debug, Clone)]
+    enum Step {
+        Ok,
+        Unavailable,
+        InvalidArgument,
+        /// HTTP client が応答を残し、SDK は文字列にした失敗を返す
+        Http(StatusCode, &'static str),
+        Hang,
+    }
+
+    #[derive(Debug)]
+    struct ScriptedExporter {
+        steps: Mutex<VecDeque<Step>>,
+        calls: Arc<AtomicU32>,
+        failures: ExportFailures,
+    }
+
+    impl ScriptedExporter {
+        fn new(steps: &[Step], failures: &ExportFailures) -> (Self, Arc<AtomicU32>) {
+            let calls: Arc<AtomicU32> = Arc::default();
+            let exporter: ScriptedExporter = ScriptedExporter {
+                steps: Mutex::new(steps.iter().cloned().collect()),
+                calls: calls.clone(),
+                failures: failures.clone(),
+            };
+            (exporter, calls)
+        }
+
+        fn next(&self) -> Step {
+            let mut steps = self.steps.lock().unwrap();
+            if steps.len() > 1 {
+                steps.pop_front().unwrap()
+            } else {
+                steps.front().cloned().unwrap()
+            }
+        }
+    }
+
+    impl SpanExporter for ScriptedExporter {
+        async fn export(&self, _batch: Vec<SpanData>) -> OTelSdkResult {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            let status: tonic::Status = match self.next() {
+                Step::Ok => return Ok(()),
+                Step::Unavailable => tonic::Status::unavailable("collector is down"),
+                Step::InvalidArgument => tonic::Status::invalid_argument("bad span"),
+                Step::Http(status, retry_after) => {
+                    let mut headers: HeaderMap = HeaderMap::new();
+                    headers.insert(header::RETRY_AFTER, HeaderValue::from_static(retry_after));
+                    self.failures
+                        .record(ExportError::from_response(status, &headers, b""));
+                    return Err(OTelSdkError::InternalFailure(format!(
+                        "Status Code: {}",
+                        status.as_u16()
+                    )));
+                }
+                Step::Hang => std::future::pending().await,
+            };
+            Err(OTelSdkError::InternalFailure(status.to_string()))
+        }
+    }
+
+    /// 受け取った request を記録する collector
+    #[derive(Debug, Clone, Default)]
+    struct RecordingCollector(Arc<Mutex<Vec<ExportTraceServiceRequest>>>);
+
+    #[tonic::async_trait]
+    impl TraceService for RecordingCollector {
+        async fn export(
+            &self,
+            request: tonic::Request<ExportTraceServiceRequest>,
+        ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+            self.0.lock().unwrap().push(request.into_inner());
+            Ok(tonic::Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    async fn serve_collector() -> (String, RecordingCollector) {
+        let collector: RecordingCollector = RecordingCollector::default();
+        let router: axum::Router = tonic::service::Routes::new(
+            TraceServiceServer::new(collector.clone())
+                .accept_compressed(tonic::codec::CompressionEncoding::Gzip),
+        )
+        .into_axum_router();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint: String = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        (endpoint, collector)
+    }
+
+    fn spill_directory(name: &str) -> PathBuf {
+        let directory: PathBuf =
+            std::env::temp_dir().join(format!("otlp-retry-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn otel_config(endpoint: &str, spill: Option<&Path>) -> OtelConfig {
+        OtelConfig {
+            endpoint: endpoint.to_string(),
+            retry: RetryConfig {
+                max_attempts: 5,
+                initial_backoff_ms: 1,
+                max_backoff_ms: 10,
+                max_elapsed_ms: 300,
+                spill: SpillConfig {
+                    enabled: spill.is_some(),
+                    directory: spill.map(Path::to_path_buf).unwrap_or_default(),
+                    ..SpillConfig::default()
+                },
+            },
+            ..OtelConfig::default()
+        }
+    }
+
+    fn scripted(
+        steps: &[Step],
+        otel_config: &OtelConfig,
+    ) -> (OtlpExporter<ScriptedExporter>, Arc<AtomicU32>) {
+        let failures: ExportFailures = ExportFailures::default();
+        let (scripted, calls) = ScriptedExporter::new(steps, &failures);
+        let exporter = OtlpExporter::traces(scripted, failures, otel_config).unwrap();
+        (exporter, calls)
+    }
+
+    fn span(name: &'static str) -> SpanData {
+        SpanData {
+            span_context: SpanContext::new(
+                TraceId::from(1),
+                SpanId::from(1),
+                TraceFlags::SAMPLED,
+                false,
+                TraceState::default(),
+            ),
+            parent_span_id: SpanId::INVALID,
+            parent_span_is_remote: false,
+            span_kind: SpanKind::Server,
+            name: Cow::Borrowed(name),
+            start_time: SystemTime::UNIX_EPOCH,
+            end_time: SystemTime::UNIX_EPOCH,
+            attributes: vec![],
+            dropped_attributes_count: 0,
+            events: SpanEvents::default(),
+            links: SpanLinks::default(),
+            status: Status::Unset,
+            instrumentation_scope: InstrumentationScope::builder("test").build(),
+        }
+    }
+
+    /// batch processor のスレッドと同じく、tokio の外で future を動かす
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct Unpark(std::thread::Thread);
+        impl std::task::Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = std::task::Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut context = std::task::Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+            std::thread::park();
+        }
+    }
+
+    fn spilled(directory: &Path) -> Vec<PathBuf> {
+        match std::fs::read_dir(directory) {
+            Ok(entries) => entries.map(|entry| entry.unwrap().path()).collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    #[test]
+    fn max_elapsed_must_fit_in_the_flush_timeout() {
+        let retry: RetryConfig = RetryConfig {
+            max_elapsed_ms: 5001,
+            ..RetryConfig::default()
+        };
+        assert!(retry.validate().is_err());
+        assert!(RetryConfig::default().validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn retryable_failures_are_retried_until_they_succeed() {
+        let steps: [Step; 3] = [Step::Unavailable, Step::Unavailable, Step::Ok];
+        let (exporter, calls) = scripted(&steps, &otel_config("http://127.0.0.1:1", None));
+        exporter.export(vec![span("retried")]).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn retries_wait_outside_the_runtime() {
+        let steps: [Step; 3] = [Step::Unavailable, Step::Hang, Step::Ok];
+        let mut otel_config: OtelConfig = otel_config("http://127.0.0.1:1", None);
+        otel_config.retry.max_elapsed_ms = 1000;
+        let (exporter, calls) = scripted(&steps, &otel_config);
+        let thread = std::thread::spawn(move || block_on(exporter.export(vec![span("thread")])));
+        let result: OTelSdkResult = tokio::task::spawn_blocking(move || thread.join().unwrap())
+            .await
+            .unwrap();
+        // 止まった 2 回目は deadline で打ち切られる
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn non_retryable_failures_are_not_retried_or_spilled() {
+        let directory: PathBuf = spill_directory("rejected");
+        let otel_config: OtelConfig = otel_config("http://127.0.0.1:1", Some(&directory));
+        let (exporter, calls) = scripted(&[Step::InvalidArgument], &otel_config);
+        exporter.export(vec![span("rejected")]).await.unwrap_err();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(spilled(&directory).is_empty());
+    }
+
+    #[tokio::test]
+    async fn recorded_http_responses_decide_the_retry() {
+        let steps: [Step; 2] = [Step::Http(StatusCode::SERVICE_UNAVAILABLE, "0"), Step::Ok];
+        let (exporter, calls) = scripted(&steps, &otel_config("http://127.0.0.1:1", None));
+        exporter.export(vec![span("throttled")]).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        // max_backoff_ms より長く待たせる Retry-After には従わない
+        let steps: [Step; 2] = [Step::Http(StatusCode::TOO_MANY_REQUESTS, "60"), Step::Ok];
+        let (exporter, calls) = scripted(&steps, &otel_config("http://127.0.0.1:1", None));
+        exporter.export(vec![span("throttled")]).await.unwrap_err();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn exports_give_up_and_spill_at_the_deadline() {
+        for step in [Step::Unavailable, Step::Hang] {
+            let directory: PathBuf = spill_directory("deadline");
+            let mut otel_config: OtelConfig = otel_config("http://127.0.0.1:1", Some(&directory));
+            otel_config.retry.max_attempts = u32::MAX;
+            let (exporter, _) = scripted(std::slice::from_ref(&step), &otel_config);
+            let started: Instant = Instant::now();
+            exporter.export(vec![span("deadline")]).await.unwrap_err();
+            let elapsed: Duration = started.elapsed();
+            assert!(
+                elapsed < Duration::from_secs(1),
+                "{:?} took {:?}",
+                step,
+                elapsed
+            );
+            assert_eq!(spilled(&directory).len(), 1, "{:?}", step);
+            std::fs::remove_dir_all(&directory).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn spilled_batches_are_replayed_after_a_successful_export() {
+        let directory: PathBuf = spill_directory("replay");
+        let (endpoint, collector) = serve_collector().await;
+        let otel_config: OtelConfig = otel_config(&endpoint, Some(&directory));
+
+        let (failing, _) = scripted(&[Step::Unavailable], &otel_config);
+        failing.export(vec![span("spilled")]).await.unwrap_err();
+        assert_eq!(spilled(&directory).len(), 1);
+        assert!(collector.0.lock().unwrap().is_empty());
+
+        let (recovered, _) = scripted(&[Step::Ok], &otel_config);
+        recovered.export(vec![span("live")]).await.unwrap();
+        assert!(spilled(&directory).is_empty());
+        let received = collector.0.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let spans = &received[0].resource_spans[0].scope_spans[0].spans;
+        assert_eq!(spans[0].name, "spilled");
+        drop(received);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unreachable_destinations_keep_spilled_batches() {
+        let directory: PathBuf = spill_directory("unreachable");
+        let otel_config: OtelConfig = otel_config("http://127.0.0.1:1", Some(&directory));
+        let (failing, _) = scripted(&[Step::Unavailable], &otel_config);
+        failing.export(vec![span("spilled")]).await.unwrap_err();
+
+        let (recovered, _) = scripted(&[Step::Ok], &otel_config);
+        recovered.export(vec![span("live")]).await.unwrap();
+        assert_eq!(spilled(&directory).len(), 1);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
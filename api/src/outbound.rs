@@ -0,0 +1,596 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{extract::FromRequestParts, http::StatusCode, http::request::Parts};
+use reqwest::{Method, header::HeaderMap};
+use tokio::time::Instant;
+
+use crate::error::ApiError;
+use crate::request_id::{REQUEST_ID_HEADER, RequestId};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const POOL_MAX_IDLE_PER_HOST: usize = 8;
+// Lambda のタイムアウトまでにエラーレスポンスを返すための余白
+#[cfg(feature = "lambda")]
+const DEADLINE_SAFETY_MARGIN: Duration = Duration::from_millis(200);
+
+/// リクエスト全体の締め切り
+///
+/// `lambda` feature では Lambda の残り実行時間から求め、それ以外では締め切りなし
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(pub Option<Instant>);
+
+impl Deadline {
+    fn remaining(&self) -> Option<Duration> {
+        self.0
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+impl<S> FromRequestParts<S> for Deadline
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(_parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        #[cfg(feature = "lambda")]
+        {
+            use lambda_http::RequestExt;
+            if let Some(context) = _parts.lambda_context_ref() {
+                let remaining: Duration = context
+                    .deadline()
+                    .duration_since(std::time::SystemTime::now())
+                    .unwrap_or_default()
+                    .saturating_sub(DEADLINE_SAFETY_MARGIN);
+                return Ok(Self(Some(Instant::now() + remaining)));
+            }
+        }
+        Ok(Self(None))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // full jitter: [0, min(max_delay, base_delay * 2^attempt)]
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let cap: Duration = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        Duration::from_millis(rand::random_range(0..=cap.as_millis() as u64))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CircuitState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen,
+}
+
+/// 連続失敗で open になり、`open_duration` 経過後に 1 リクエストだけ試す circuit breaker
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: Mutex<CircuitState>,
+    failure_threshold: u32,
+    open_duration: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(30))
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            state: Mutex::new(CircuitState::Closed { failures: 0 }),
+            failure_threshold,
+            open_duration,
+        }
+    }
+
+    fn try_acquire(&self) -> Option<CircuitPermit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        let probe: bool = match *state {
+            CircuitState::Closed { .. } => false,
+            CircuitState::Open { until } if Instant::now() >= until => {
+                *state = CircuitState::HalfOpen;
+                true
+            }
+            CircuitState::Open { .. } | CircuitState::HalfOpen => return None,
+        };
+        Some(CircuitPermit {
+            circuit_breaker: self,
+            probe,
+            finished: false,
+        })
+    }
+
+    fn on_success(&self) {
+        *self.state.lock().unwrap() = CircuitState::Closed { failures: 0 };
+    }
+
+    fn on_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = match *state {
+            CircuitState::Closed { failures } if failures + 1 < self.failure_threshold => {
+                CircuitState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            _ => {
+                tracing::warn!("Circuit breaker opened for {:?}", self.open_duration);
+                CircuitState::Open {
+                    until: Instant::now() + self.open_duration,
+                }
+            }
+        };
+    }
+}
+
+/// circuit breaker を通ったリクエストの結果を記録する
+///
+/// 結果を記録せずに drop されたとき (リクエストの future の cancel など) は、試したリクエストが
+/// half-open のまま残らないよう open に戻し、次のリクエストに試させる
+#[must_use]
+struct CircuitPermit<'a> {
+    circuit_breaker: &'a CircuitBreaker,
+    probe: bool,
+    finished: bool,
+}
+
+impl CircuitPermit<'_> {
+    fn success(mut self) {
+        self.finished = true;
+        self.circuit_breaker.on_success();
+    }
+
+    fn failure(mut self) {
+        self.finished = true;
+        self.circuit_breaker.on_failure();
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        if self.finished || !self.probe {
+            return;
+        }
+        let mut state = self.circuit_breaker.state.lock().unwrap();
+        if *state == CircuitState::HalfOpen {
+            *state = CircuitState::Open {
+                until: Instant::now(),
+            };
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OutboundError {
+    /// 呼び出し先が設定されていない
+    NotConfigured(&'static str),
+    CircuitOpen,
+    Timeout,
+    Transport(reqwest::Error),
+    Status(StatusCode),
+}
+
+impl std::fmt::Display for OutboundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutboundError::NotConfigured(name) => {
+                write!(f, "downstream service `{}` is not configured", name)
+            }
+            OutboundError::CircuitOpen => write!(f, "circuit breaker is open"),
+            OutboundError::Timeout => write!(f, "deadline exceeded"),
+            OutboundError::Transport(err) => write!(f, "transport error: {}", err),
+            OutboundError::Status(status) => write!(f, "upstream responded with {}", status),
+        }
+    }
+}
+
+impl std::error::Error for OutboundError {}
+
+impl OutboundError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            OutboundError::NotConfigured(_)
+            | OutboundError::CircuitOpen
+            | OutboundError::Timeout => false,
+            OutboundError::Transport(err) => err.is_connect() || err.is_timeout(),
+            OutboundError::Status(status) => matches!(
+                *status,
+                StatusCode::TOO_MANY_REQUESTS
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+        }
+    }
+
+    /// 呼び出し先の不調として circuit breaker に数える失敗か
+    ///
+    /// 429 以外の 4xx はリクエストの問題なので、呼び出し先は正常とみなす
+    fn is_upstream_failure(&self) -> bool {
+        match self {
+            OutboundError::Status(status) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => true,
+        }
+    }
+}
+
+impl From<OutboundError> for ApiError {
+    fn from(err: OutboundError) -> Self {
+        match err {
+            OutboundError::NotConfigured(_) => ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "remote_not_configured",
+                "Remote endpoint not configured",
+                err.to_string(),
+            ),
+            OutboundError::Timeout => ApiError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "remote_timeout",
+                "Remote endpoint timed out",
+                err.to_string(),
+            ),
+            OutboundError::Transport(ref transport) if transport.is_timeout() => ApiError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "remote_timeout",
+                "Remote endpoint timed out",
+                err.to_string(),
+            ),
+            OutboundError::CircuitOpen => ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                "remote_circuit_open",
+                "Remote endpoint unavailable",
+                err.to_string(),
+            ),
+            OutboundError::Transport(_) | OutboundError::Status(_) => ApiError::new(
+                StatusCode::BAD_GATEWAY,
+                "remote_unavailable",
+                "Remote endpoint unavailable",
+                err.to_string(),
+            ),
+        }
+    }
+}
+
+/// connection pool を共有する外部呼び出し用の HTTP クライアント
+#[derive(Debug, Clone)]
+pub struct OutboundClient {
+    client: reqwest::Client,
+    retry_policy: RetryPolicy,
+    circuit_breaker: Arc<CircuitBreaker>,
+    timeout: Duration,
+}
+
+impl Default for OutboundClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutboundClient {
+    pub fn new() -> Self {
+        let client: reqwest::Client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+            .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+            .build()
+            .expect("Failed to create outbound HTTP client");
+        Self {
+            client,
+            retry_policy: RetryPolicy::default(),
+            circuit_breaker: Arc::new(CircuitBreaker::default()),
+            timeout: REQUEST_TIMEOUT,
+        }
+    }
+
+    /// connection pool は共有したまま、timeout と circuit breaker を呼び出し先ごとに分ける
+    pub fn for_service(&self, timeout: Duration) -> Self {
+        Self {
+            client: self.client.clone(),
+            retry_policy: self.retry_policy,
+            circuit_breaker: Arc::new(CircuitBreaker::default()),
+            timeout,
+        }
+    }
+
+    pub async fn get(
+        &self,
+        url: &str,
+        headers: HeaderMap,
+        deadline: Deadline,
+    ) -> Result<reqwest::Response, OutboundError> {
+        self.send(Method::GET, url, headers, deadline).await
+    }
+
+    /// 処理中のリクエストの `X-Request-Id` を引き継いで送る
+    pub async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        mut headers: HeaderMap,
+        deadline: Deadline,
+    ) -> Result<reqwest::Response, OutboundError> {
+        if let Some(request_id) = RequestId::current() {
+            headers.insert(REQUEST_ID_HEADER, request_id.header_value());
+        }
+        // 冪等なメソッドのみ再試行する
+        let max_attempts: u32 = if method.is_idempotent() {
+            self.retry_policy.max_attempts.max(1)
+        } else {
+            1
+        };
+        let mut attempt: u32 = 0;
+        loop {
+            let result: Result<reqwest::Response, OutboundError> = self
+                .send_once(method.clone(), url, headers.clone(), deadline, attempt)
+                .await;
+            attempt += 1;
+            let err: OutboundError = match result {
+                Ok(response) => return Ok(response),
+                Err(err) => err,
+            };
+            if !err.is_retryable() || attempt >= max_attempts {
+                return Err(err);
+            }
+            let backoff: Duration = self.retry_policy.backoff(attempt);
+            if deadline.remaining().is_some_and(|remaining| remaining <= backoff) {
+                return Err(err);
+            }
+            tracing::warn!(attempt, ?backoff, "Retrying outbound request: {}", err);
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    async fn send_once(
+        &self,
+        method: Method,
+        url: &str,
+        headers: HeaderMap,
+        deadline: Deadline,
+        resend_count: u32,
+    ) -> Result<reqwest::Response, OutboundError> {
+        let timeout: Duration = match deadline.remaining() {
+            Some(remaining) if remaining.is_zero() => return Err(OutboundError::Timeout),
+            Some(remaining) => remaining.min(self.timeout),
+            None => self.timeout,
+        };
+        // circuit breaker を通る前に作り、組み立ての失敗で half-open のまま返らないようにする
+        let request: reqwest::Request = self
+            .client
+            .request(method, url)
+            .headers(headers)
+            .timeout(timeout)
+            .build()
+            .map_err(OutboundError::Transport)?;
+        let Some(permit) = self.circuit_breaker.try_acquire() else {
+            return Err(OutboundError::CircuitOpen);
+        };
+        let result: Result<reqwest::Response, OutboundError> =
+            match crate::otel::send_instrumented(&self.client, request, resend_count).await {
+            // 4xx のエラー本文を成功として返さない
+            Ok(response) if !response.status().is_success() => {
+                Err(OutboundError::Status(response.status()))
+            }
+            Ok(response) => Ok(response),
+            Err(err) => Err(OutboundError::Transport(err)),
+        };
+        match &result {
+            Err(err) if err.is_upstream_failure() => permit.failure(),
+            _ => permit.success(),
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use axum::{Router, extract::State, response::IntoResponse, routing::get};
+
+    use super::*;
+
+    type Script = (Arc<Mutex<VecDeque<StatusCode>>>, Arc<Mutex<u32>>);
+
+    async fn scripted(State((statuses, calls)): State<Script>) -> (StatusCode, String) {
+        *calls.lock().unwrap() += 1;
+        let mut statuses = statuses.lock().unwrap();
+        let status: StatusCode = if statuses.len() > 1 {
+            statuses.pop_front().unwrap()
+        } else {
+            statuses[0]
+        };
+        (status, status.as_str().to_string())
+    }
+
+    /// 順に `statuses` を返し、最後の 1 つはその後も繰り返す呼び出し先
+    async fn serve(statuses: &[StatusCode]) -> (String, Arc<Mutex<u32>>) {
+        let calls: Arc<Mutex<u32>> = Arc::default();
+        let statuses: Arc<Mutex<VecDeque<StatusCode>>> =
+            Arc::new(Mutex::new(statuses.iter().copied().collect()));
+        let router: Router = Router::new()
+            .route("/hello", get(scripted).post(scripted))
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "late"
+                }),
+            )
+            .with_state((statuses, calls.clone()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url: String = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        (base_url, calls)
+    }
+
+    fn client() -> OutboundClient {
+        OutboundClient {
+            retry_policy: RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(5),
+            },
+            ..OutboundClient::new()
+        }
+    }
+
+    fn status_of(err: OutboundError) -> StatusCode {
+        ApiError::from(err).into_response().status()
+    }
+
+    #[tokio::test]
+    async fn retryable_statuses_are_retried_with_backoff() {
+        let (base_url, calls) = serve(&[
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::OK,
+        ])
+        .await;
+        let response: reqwest::Response = client()
+            .get(
+                &format!("{}/hello", base_url),
+                HeaderMap::new(),
+                Deadline(None),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "200");
+        assert_eq!(*calls.lock().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn non_idempotent_requests_are_not_retried() {
+        let (base_url, calls) = serve(&[StatusCode::SERVICE_UNAVAILABLE, StatusCode::OK]).await;
+        let err: OutboundError = client()
+            .send(
+                Method::POST,
+                &format!("{}/hello", base_url),
+                HeaderMap::new(),
+                Deadline(None),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            OutboundError::Status(StatusCode::SERVICE_UNAVAILABLE)
+        ));
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn error_statuses_map_to_bad_gateway() {
+        for status in [
+            StatusCode::UNAUTHORIZED,
+            StatusCode::NOT_FOUND,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::SERVICE_UNAVAILABLE,
+        ] {
+            let (base_url, _) = serve(&[status]).await;
+            let err: OutboundError = client()
+                .get(
+                    &format!("{}/hello", base_url),
+                    HeaderMap::new(),
+                    Deadline(None),
+                )
+                .await
+                .unwrap_err();
+            assert!(matches!(err, OutboundError::Status(actual) if actual == status));
+            assert_eq!(status_of(err), StatusCode::BAD_GATEWAY, "{}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried_and_keep_the_circuit_closed() {
+        let (base_url, calls) = serve(&[StatusCode::NOT_FOUND]).await;
+        let client: OutboundClient = client();
+        for _ in 0..CircuitBreaker::default().failure_threshold + 1 {
+            client
+                .get(
+                    &format!("{}/hello", base_url),
+                    HeaderMap::new(),
+                    Deadline(None),
+                )
+                .await
+                .unwrap_err();
+        }
+        assert_eq!(*calls.lock().unwrap(), 6);
+        assert_eq!(
+            *client.circuit_breaker.state.lock().unwrap(),
+            CircuitState::Closed { failures: 0 }
+        );
+    }
+
+    #[tokio::test]
+    async fn exhausted_deadlines_give_up_with_gateway_timeout() {
+        let (base_url, calls) = serve(&[StatusCode::OK]).await;
+        let client: OutboundClient = client();
+
+        let err: OutboundError = client
+            .get(
+                &format!("{}/hello", base_url),
+                HeaderMap::new(),
+                Deadline(Some(Instant::now())),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status_of(err), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(*calls.lock().unwrap(), 0);
+
+        let started: Instant = Instant::now();
+        let err: OutboundError = client
+            .get(
+                &format!("{}/slow", base_url),
+                HeaderMap::new(),
+                Deadline(Some(Instant::now() + Duration::from_millis(100))),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status_of(err), StatusCode::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn cancelled_probe_reopens_the_circuit() {
+        let circuit_breaker = CircuitBreaker::new(1, Duration::ZERO);
+        circuit_breaker.try_acquire().unwrap().failure();
+
+        let probe = circuit_breaker.try_acquire().unwrap();
+        assert!(circuit_breaker.try_acquire().is_none());
+        drop(probe);
+
+        circuit_breaker.try_acquire().unwrap().success();
+        assert_eq!(
+            *circuit_breaker.state.lock().unwrap(),
+            CircuitState::Closed { failures: 0 }
+        );
+    }
+}
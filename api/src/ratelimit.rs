@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode, header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{
+    KeyValue,
+    metrics::{Counter, Meter, UpDownCounter},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use utoipa::openapi::{
+    ContentBuilder, Object, OpenApi, Ref, ResponseBuilder, Type, header::HeaderBuilder,
+};
+
+use crate::auth::{Authenticator, Principal};
+use crate::error::{ApiError, PROBLEM_JSON_CONTENT_TYPE};
+
+// これを超えたら、満タンに戻った bucket を捨てる
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// API のルートに対する利用者ごとの流量制限と、全体の同時実行数の制限
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// token bucket の補充速度
+    pub requests_per_second: f64,
+    /// token bucket の容量
+    pub burst: u32,
+    pub max_concurrent_requests: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            requests_per_second: 5.0,
+            burst: 10,
+            max_concurrent_requests: 32,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.enabled
+            && (!self.requests_per_second.is_finite()
+                || self.requests_per_second <= 0.0
+                || self.burst == 0
+                || self.max_concurrent_requests == 0)
+        {
+            anyhow::bail!(
+                "rate_limit.requests_per_second, burst and max_concurrent_requests must be positive"
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed: f64 = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+/// 認証した利用者 (できなければ送信元 IP) ごとの token bucket と、同時実行数の semaphore
+#[derive(Clone)]
+pub struct RateLimiter {
+    authenticator: Authenticator,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    rate: f64,
+    burst: f64,
+    concurrency: Arc<Semaphore>,
+    throttled_requests: Counter<u64>,
+    active_requests: UpDownCounter<i64>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, authenticator: Authenticator) -> Self {
+        Self::with_meter(
+            config,
+            authenticator,
+            &opentelemetry::global::meter_with_scope(crate::otel::init_scope()),
+        )
+    }
+
+    /// メトリクスを `meter` で記録する。テストでは global の meter provider を使わない
+    fn with_meter(config: &RateLimitConfig, authenticator: Authenticator, meter: &Meter) -> Self {
+        Self {
+            authenticator,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            rate: config.requests_per_second,
+            burst: f64::from(config.burst),
+            concurrency: Arc::new(Semaphore::new(config.max_concurrent_requests)),
+            throttled_requests: meter
+                .u64_counter("http.server.throttled_requests")
+                .with_unit("{request}")
+                .with_description(
+                    "Number of requests rejected with 429 by the rate or concurrency limit.",
+                )
+                .build(),
+            active_requests: meter
+                .i64_up_down_counter(
+                    opentelemetry_semantic_conventions::metric::HTTP_SERVER_ACTIVE_REQUESTS,
+                )
+                .with_unit("{request}")
+                .with_description("Number of active HTTP server requests.")
+                .build(),
+        }
+    }
+
+    /// token を 1 つ消費する。足りなければ次の token までの待ち時間を返す
+    fn try_consume(&self, key: String) -> Result<(), Duration> {
+        let now: Instant = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&key) {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.refill(now, rate, burst);
+                bucket.tokens < burst
+            });
+        }
+        let bucket: &mut Bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.refill(now, self.rate, self.burst);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    fn throttled(&self, reason: &'static str, retry_after: Duration, detail: &str) -> ApiError {
+        self.throttled_requests
+            .add(1, &[KeyValue::new("throttle.reason", reason)]);
+        // Retry-After は秒単位なので切り上げる
+        let retry_after: u64 = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            reason,
+            "Too many requests",
+            detail,
+        )
+        .with_header(header::RETRY_AFTER, HeaderValue::from(retry_after.max(1)))
+    }
+}
+
+/// 流量制限の単位。認証できた利用者の識別子を使い、できなければ送信元 IP を使う
+///
+/// 認証前のヘッダーの値を使うと、毎回違う偽のキーを送るだけで制限を回避できる。
+/// 認証できたら [`Principal`] を extensions に入れ、handler の [`crate::auth::Authenticated`] で使い回す
+fn client_key(limiter: &RateLimiter, parts: &mut Parts) -> String {
+    if let Some(principal) = parts.extensions.get::<Principal>() {
+        return format!("principal:{}", principal.id);
+    }
+    if let Ok(principal) = limiter.authenticator.authenticate(&parts.headers) {
+        tracing::Span::current().record(
+            opentelemetry_semantic_conventions::attribute::ENDUSER_ID,
+            principal.id.as_str(),
+        );
+        let key: String = format!("principal:{}", principal.id);
+        parts.extensions.insert(principal);
+        return key;
+    }
+    match client_ip(parts) {
+        Some(ip) => format!("ip:{}", ip),
+        None => "unknown".to_string(),
+    }
+}
+
+#[cfg(not(feature = "lambda"))]
+fn client_ip(parts: &Parts) -> Option<IpAddr> {
+    parts
+        .extensions
+        .get::<axum::extract::ConnectInfo<std::net::SocketAddr>>()
+        .map(|connect_info| connect_info.0.ip())
+}
+
+#[cfg(feature = "lambda")]
+fn client_ip(parts: &Parts) -> Option<IpAddr> {
+    use lambda_http::{RequestExt, request::RequestContext};
+    let RequestContext::ApiGatewayV2(context) = parts.request_context_ref()?;
+    context.http.source_ip.as_deref()?.parse().ok()
+}
+
+/// 同時実行数と利用者ごとの流量を制限し、超えたら `429 Too Many Requests` と `Retry-After` を返す middleware
+pub async fn enforce_rate_limit(
+    State(limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let (mut parts, body) = request.into_parts();
+    let key: String = client_key(&limiter, &mut parts);
+    if let Err(retry_after) = limiter.try_consume(key) {
+        return Err(limiter.throttled(
+            "rate_limited",
+            retry_after,
+            "rate limit exceeded for this client",
+        ));
+    }
+    let _permit: OwnedSemaphorePermit =
+        limiter
+            .concurrency
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| {
+                limiter.throttled(
+                    "concurrency_limited",
+                    Duration::from_secs(1),
+                    "too many requests in flight",
+                )
+            })?;
+    let _active_request: ActiveRequest = ActiveRequest::new(&limiter.active_requests);
+    Ok(next.run(Request::from_parts(parts, body)).await)
+}
+
+/// `http.server.active_requests` を増やし、drop で減らす
+///
+/// client が切断してリクエストの future が drop されても減らせるようにする
+struct ActiveRequest<'a>(&'a UpDownCounter<i64>);
+
+impl<'a> ActiveRequest<'a> {
+    fn new(active_requests: &'a UpDownCounter<i64>) -> Self {
+        active_requests.add(1, &[]);
+        Self(active_requests)
+    }
+}
+
+impl Drop for ActiveRequest<'_> {
+    fn drop(&mut self) {
+        self.0.add(-1, &[]);
+    }
+}
+
+/// 制限を掛けた API のすべての operation に `429` のレスポンスを追加する
+pub fn document_responses(openapi: &mut OpenApi) {
+    let response = ResponseBuilder::new()
+        .description("rate limit or concurrency limit exceeded")
+        .header(
+            "Retry-After",
+            HeaderBuilder::new()
+                .schema(Object::with_type(Type::Integer))
+                .description(Some("seconds to wait before retrying"))
+                .build(),
+        )
+        .content(
+            PROBLEM_JSON_CONTENT_TYPE,
+            ContentBuilder::new()
+                .schema(Some(Ref::from_schema_name("ProblemDetails")))
+                .build(),
+        )
+        .build();
+    for path_item in openapi.paths.paths.values_mut() {
+        for operation in [
+            &mut path_item.get,
+            &mut path_item.put,
+            &mut path_item.post,
+            &mut path_item.delete,
+            &mut path_item.patch,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            operation.responses.responses.insert(
+                StatusCode::TOO_MANY_REQUESTS.as_u16().to_string(),
+                response.clone().into(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, body::Body, response::IntoResponse, routing::get};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::auth::{API_KEY_HEADER, ApiKeyConfig, AuthConfig};
+    use crate::testing::TestMeter;
+
+    /// 1000 秒に 1 つしか補充しない bucket
+    fn slow_refill() -> RateLimitConfig {
+        RateLimitConfig {
+            requests_per_second: 0.001,
+            burst: 2,
+            ..RateLimitConfig::default()
+        }
+    }
+
+    async fn limiter(config: &RateLimitConfig) -> (RateLimiter, TestMeter) {
+        let authenticator: Authenticator = Authenticator::load(&AuthConfig {
+            api_keys: vec![ApiKeyConfig {
+                id: "tester".to_string(),
+                key: "secret".to_string(),
+            }],
+            ..AuthConfig::default()
+        })
+        .await
+        .unwrap();
+        let meter: TestMeter = TestMeter::new();
+        let limiter: RateLimiter = RateLimiter::with_meter(config, authenticator, &meter.meter());
+        (limiter, meter)
+    }
+
+    fn router(limiter: RateLimiter) -> Router {
+        Router::new()
+            .route("/hello", get(|| async { "hello" }))
+            .layer(axum::middleware::from_fn_with_state(
+                limiter,
+                enforce_rate_limit,
+            ))
+    }
+
+    async fn call(router: &Router, api_key: &str) -> Response {
+        let mut request = axum::http::Request::get("/hello")
+            .header(API_KEY_HEADER, api_key)
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(axum::extract::ConnectInfo(std::net::SocketAddr::from((
+                [192, 0, 2, 1],
+                443,
+            ))));
+        router.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn status(router: &Router, api_key: &str) -> StatusCode {
+        call(router, api_key).await.status()
+    }
+
+    fn retry_after(response: &Response) -> u64 {
+        response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn made_up_api_keys_share_the_client_ip_bucket() {
+        let router: Router = router(limiter(&slow_refill()).await.0);
+        assert_eq!(status(&router, "fake-1").await, StatusCode::OK);
+        assert_eq!(status(&router, "fake-2").await, StatusCode::OK);
+        assert_eq!(
+            status(&router, "fake-3").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // 認証できた利用者は送信元 IP とは別の bucket を使う
+        assert_eq!(status(&router, "secret").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn authenticated_clients_are_limited_by_principal() {
+        let (limiter, meter) = limiter(&slow_refill()).await;
+        let router: Router = router(limiter);
+        assert_eq!(status(&router, "secret").await, StatusCode::OK);
+        assert_eq!(status(&router, "secret").await, StatusCode::OK);
+
+        let response: Response = call(&router, "secret").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // 次の token まで 1000 秒弱なので、切り上げて 1000 秒
+        assert_eq!(retry_after(&response), 1000);
+        assert_eq!(
+            meter.values("http.server.throttled_requests"),
+            [("throttle.reason=rate_limited".to_string(), 1.0)].into()
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_after_is_rounded_up_to_whole_seconds() {
+        let (limiter, _) = limiter(&RateLimitConfig::default()).await;
+        for (wait, expected) in [
+            (Duration::ZERO, 1),
+            (Duration::from_millis(1), 1),
+            (Duration::from_secs(1), 1),
+            (Duration::from_millis(1001), 2),
+            (Duration::from_millis(2500), 3),
+        ] {
+            let response: Response = limiter
+                .throttled("rate_limited", wait, "rate limit exceeded")
+                .into_response();
+            assert_eq!(retry_after(&response), expected, "{:?}", wait);
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_concurrency_limit_are_rejected() {
+        let config: RateLimitConfig = RateLimitConfig {
+            max_concurrent_requests: 2,
+            ..RateLimitConfig::default()
+        };
+        let (limiter, meter) = limiter(&config).await;
+        let held: OwnedSemaphorePermit = limiter
+            .concurrency
+            .clone()
+            .try_acquire_many_owned(2)
+            .unwrap();
+        let router: Router = router(limiter);
+
+        let response: Response = call(&router, "secret").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(retry_after(&response), 1);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["type"], "urn:problem-type:concurrency-limited");
+        assert_eq!(
+            meter.values("http.server.throttled_requests"),
+            [("throttle.reason=concurrency_limited".to_string(), 1.0)].into()
+        );
+
+        drop(held);
+        assert_eq!(status(&router, "secret").await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,879 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, State},
+    http::{HeaderMap, StatusCode, header},
+    response::Response,
+};
+use opentelemetry_proto::tonic::{
+    collector::{
+        logs::v1::{ExportLogsServiceRequest, ExportLogsServiceResponse},
+        metrics::v1::{
+            ExportMetricsPartialSuccess, ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+            metrics_service_client::MetricsServiceClient,
+        },
+        trace::v1::{ExportTraceServiceRequest, ExportTraceServiceResponse},
+    },
+    common::v1::{AnyValue, KeyValue, any_value},
+    metrics::v1::{Metric, metric},
+    resource::v1::Resource,
+};
+use serde::{Deserialize, Serialize};
+use tonic::{codec::CompressionEncoding, transport::Channel};
+use utoipa_axum::router::OpenApiRouter;
+
+use crate::auth::{Authenticated, Principal};
+use crate::config::{Config, MetricsExporter};
+use crate::emf::{EmfConfig, EmfExporter};
+use crate::error::{ApiError, PROBLEM_JSON_CONTENT_TYPE, ProblemDetails};
+use crate::otlp::{self, Destination, JSON_CONTENT_TYPE, PROTOBUF_CONTENT_TYPE};
+use crate::state::AppState;
+
+const OTLP_TAG: &str = "otlp";
+// API 自身の resource の `service.name`
+const API_SERVICE_NAME: &str = env!("CARGO_PKG_NAME");
+// API 自身の telemetry と区別できなくなるので、client に名乗らせない resource の属性
+const SERVER_RESOURCE_KEYS: [&str; 3] = [
+    opentelemetry_semantic_conventions::attribute::ENDUSER_ID,
+    opentelemetry_semantic_conventions::resource::DEPLOYMENT_ENVIRONMENT_NAME,
+    opentelemetry_semantic_conventions::resource::SERVICE_NAMESPACE,
+];
+// API 自身の metrics の名前。client からは受け付けない
+const RESERVED_METRIC_PREFIXES: [&str; 4] =
+    ["http.server.", "telemetry.", "otel.sdk.", "aws.lambda."];
+const RESERVED_METRICS_MESSAGE: &str = "metric names reserved for the API were rejected";
+
+/// Web フロントエンドなどから OTLP/HTTP で telemetry を受け取る receiver の設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReceiverConfig {
+    /// `{api_base_path}/v1/traces`、`/v1/logs`、`/v1/metrics` を公開する
+    pub enabled: bool,
+    /// 1 回の export request の上限 (展開後)
+    pub max_request_bytes: usize,
+    /// client が `service.name` を名乗らなかったとき、または API の名前を名乗ったときの `service.name`
+    pub service_name: String,
+    /// client の metrics を EMF で書くときの CloudWatch の namespace。client の resource では変えられない
+    pub metrics_namespace: String,
+}
+
+impl Default for ReceiverConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_request_bytes: 1024 * 1024,
+            service_name: "client".to_string(),
+            metrics_namespace: format!("{}/client", API_SERVICE_NAME),
+        }
+    }
+}
+
+impl ReceiverConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.service_name.is_empty() || self.service_name == API_SERVICE_NAME {
+            anyhow::bail!(
+                "receiver.service_name must be non-empty and differ from `{}`",
+                API_SERVICE_NAME
+            );
+        }
+        if self.metrics_namespace.is_empty() {
+            anyhow::bail!("receiver.metrics_namespace must not be empty");
+        }
+        Ok(())
+    }
+}
+
+enum MetricsDestination {
+    Collector(Channel),
+    Emf(EmfExporter),
+}
+
+struct Destinations {
+    traces: Destination,
+    logs: Destination,
+    metrics: MetricsDestination,
+    /// client の値を捨てて付ける属性
+    resource_attributes: Vec<KeyValue>,
+    service_name: String,
+}
+
+/// 受け取った OTLP を、API 自身の telemetry と同じ送り先にそのまま転送する
+///
+/// 既定値は受け取ったものを捨てる
+#[derive(Clone, Default)]
+pub struct TelemetryForwarder(Option<Arc<Destinations>>);
+
+impl TelemetryForwarder {
+    /// `otel.export_target` と `otel.metrics_exporter` に従って送り先を決める
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let otel_config = &config.otel;
+        let traces: Destination = Destination::traces(otel_config)?;
+        let logs: Destination = Destination::logs(otel_config)?;
+        let metrics: MetricsDestination = match otel_config.metrics_exporter {
+            MetricsExporter::Otlp => {
+                MetricsDestination::Collector(otlp::collector_channel(otel_config)?)
+            }
+            MetricsExporter::Emf => {
+                MetricsDestination::Emf(EmfExporter::new(&client_emf_config(config)))
+            }
+        };
+        Ok(Self(Some(Arc::new(Destinations {
+            traces,
+            logs,
+            metrics,
+            resource_attributes: [
+                (
+                    opentelemetry_semantic_conventions::resource::DEPLOYMENT_ENVIRONMENT_NAME,
+                    &config.stack,
+                ),
+                (
+                    opentelemetry_semantic_conventions::resource::SERVICE_NAMESPACE,
+                    &config.project_name,
+                ),
+            ]
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(key, value)| string_attribute(key, value))
+            .collect(),
+            service_name: config.receiver.service_name.clone(),
+        }))))
+    }
+
+    async fn export_traces(&self, request: ExportTraceServiceRequest) -> anyhow::Result<()> {
+        let Some(destinations) = &self.0 else {
+            return Ok(());
+        };
+        destinations.traces.export_traces(request).await?;
+        Ok(())
+    }
+
+    async fn export_logs(&self, request: ExportLogsServiceRequest) -> anyhow::Result<()> {
+        let Some(destinations) = &self.0 else {
+            return Ok(());
+        };
+        destinations.logs.export_logs(request).await?;
+        Ok(())
+    }
+
+    async fn export_metrics(&self, request: ExportMetricsServiceRequest) -> anyhow::Result<()> {
+        let Some(destinations) = &self.0 else {
+            return Ok(());
+        };
+        match &destinations.metrics {
+            MetricsDestination::Collector(channel) => {
+                MetricsServiceClient::new(channel.clone())
+                    .send_compressed(CompressionEncoding::Gzip)
+                    .export(request)
+                    .await?;
+            }
+            MetricsDestination::Emf(exporter) => {
+                EmfExporter::write(&exporter.proto_documents(&request, SystemTime::now()))?
+            }
+        }
+        Ok(())
+    }
+
+    /// 環境と認証した利用者の属性は client の値を捨てて付け直す
+    ///
+    /// API 自身の `service.name` を名乗る resource は `receiver.service_name` にする
+    fn enrich<'a>(
+        &self,
+        resources: impl Iterator<Item = &'a mut Option<Resource>>,
+        principal: &Principal,
+        headers: &HeaderMap,
+    ) {
+        let user_agent: Option<&str> = headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok());
+        let Some(destinations) = &self.0 else {
+            return;
+        };
+        for resource in resources {
+            let attributes: &mut Vec<KeyValue> =
+                &mut resource.get_or_insert_with(Resource::default).attributes;
+            attributes.retain(|attribute| !SERVER_RESOURCE_KEYS.contains(&attribute.key.as_str()));
+            attributes.push(string_attribute(
+                opentelemetry_semantic_conventions::attribute::ENDUSER_ID,
+                &principal.id,
+            ));
+            attributes.extend(destinations.resource_attributes.iter().cloned());
+
+            let service_name: Option<&str> = attributes
+                .iter()
+                .find(|attribute| {
+                    attribute.key == opentelemetry_semantic_conventions::resource::SERVICE_NAME
+                })
+                .and_then(|attribute| match attribute.value.as_ref()?.value.as_ref()? {
+                    any_value::Value::StringValue(value) => Some(value.as_str()),
+                    _ => None,
+                });
+            if service_name.is_none_or(|name| name.is_empty() || name == API_SERVICE_NAME) {
+                attributes.retain(|attribute| {
+                    attribute.key != opentelemetry_semantic_conventions::resource::SERVICE_NAME
+                });
+                attributes.push(string_attribute(
+                    opentelemetry_semantic_conventions::resource::SERVICE_NAME,
+                    &destinations.service_name,
+                ));
+            }
+
+            let has_user_agent: bool = attributes.iter().any(|attribute| {
+                attribute.key == opentelemetry_semantic_conventions::attribute::USER_AGENT_ORIGINAL
+            });
+            if let Some(user_agent) = user_agent.filter(|_| !has_user_agent) {
+                attributes.push(string_attribute(
+                    opentelemetry_semantic_conventions::attribute::USER_AGENT_ORIGINAL,
+                    user_agent,
+                ));
+            }
+        }
+    }
+}
+
+/// client の metrics の EMF の設定。namespace を固定し、dimension は API が値を付ける resource の属性に限る
+fn client_emf_config(config: &Config) -> EmfConfig {
+    let emf_config: &EmfConfig = &config.otel.emf;
+    EmfConfig {
+        namespace: config.receiver.metrics_namespace.clone(),
+        dimensions: emf_config
+            .dimensions
+            .iter()
+            .filter(|name| SERVER_RESOURCE_KEYS.contains(&name.as_str()))
+            .cloned()
+            .collect(),
+        attribute_dimensions: emf_config.attribute_dimensions.clone(),
+    }
+}
+
+/// API 自身の metrics の名前と、EMF の document のキーになる名前の metric を取り除き、その data point の数を返す
+fn remove_reserved_metrics(config: &Config, request: &mut ExportMetricsServiceRequest) -> i64 {
+    let is_reserved = |metric: &Metric| {
+        RESERVED_METRIC_PREFIXES
+            .iter()
+            .any(|prefix| metric.name.starts_with(prefix))
+            || config.otel.emf.is_reserved_name(&metric.name)
+    };
+    let mut rejected: usize = 0;
+    let scopes = request
+        .resource_metrics
+        .iter_mut()
+        .flat_map(|resource| &mut resource.scope_metrics);
+    for scope in scopes {
+        scope.metrics.retain(|metric| {
+            if !is_reserved(metric) {
+                return true;
+            }
+            rejected += data_point_count(metric);
+            false
+        });
+    }
+    i64::try_from(rejected).unwrap_or(i64::MAX)
+}
+
+fn data_point_count(metric: &Metric) -> usize {
+    match &metric.data {
+        Some(metric::Data::Gauge(gauge)) => gauge.data_points.len(),
+        Some(metric::Data::Sum(sum)) => sum.data_points.len(),
+        Some(metric::Data::Histogram(histogram)) => histogram.data_points.len(),
+        Some(metric::Data::ExponentialHistogram(histogram)) => histogram.data_points.len(),
+        Some(metric::Data::Summary(summary)) => summary.data_points.len(),
+        None => 0,
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value.to_string())),
+        }),
+    }
+}
+
+fn forward_failed(signal: &str, err: anyhow::Error) -> ApiError {
+    tracing::error!("Failed to forward client {}: {:#}", signal, err);
+    ApiError::new(
+        StatusCode::BAD_GATEWAY,
+        "telemetry_forward_failed",
+        "Telemetry forwarding failed",
+        format!("failed to forward {}", signal),
+    )
+}
+
+/// export の endpoint が共通で返す response
+#[derive(utoipa::IntoResponses)]
+// OpenAPI の文書にだけ使い、値は作らない
+#[allow(dead_code)]
+enum ExportResponses {
+    #[response(
+        status = StatusCode::OK,
+        description = "`Export*ServiceResponse` in the request format"
+    )]
+    Ok,
+    #[response(
+        status = StatusCode::BAD_REQUEST,
+        description = "invalid OTLP payload",
+        content_type = PROBLEM_JSON_CONTENT_TYPE
+    )]
+    BadRequest(ProblemDetails),
+    #[response(
+        status = StatusCode::UNAUTHORIZED,
+        description = "missing or invalid credentials",
+        content_type = PROBLEM_JSON_CONTENT_TYPE
+    )]
+    Unauthorized(ProblemDetails),
+    #[response(
+        status = StatusCode::PAYLOAD_TOO_LARGE,
+        description = "payload exceeds receiver.max_request_bytes",
+        content_type = PROBLEM_JSON_CONTENT_TYPE
+    )]
+    PayloadTooLarge(ProblemDetails),
+    #[response(
+        status = StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        description = "unsupported Content-Type or Content-Encoding",
+        content_type = PROBLEM_JSON_CONTENT_TYPE
+    )]
+    UnsupportedMediaType(ProblemDetails),
+    #[response(
+        status = StatusCode::BAD_GATEWAY,
+        description = "forwarding to the exporter failed",
+        content_type = PROBLEM_JSON_CONTENT_TYPE
+    )]
+    BadGateway(ProblemDetails),
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/traces",
+    request_body(
+        description = "OTLP `ExportTraceServiceRequest` (gzip 可)",
+        content((Vec<u8> = PROTOBUF_CONTENT_TYPE), (Object = JSON_CONTENT_TYPE)),
+    ),
+    responses(ExportResponses),
+    security(("api_key" = []), ("bearer" = [])),
+    tags = [ OTLP_TAG ]
+)]
+async fn export_traces(
+    Authenticated(principal): Authenticated,
+    State(config): State<Arc<Config>>,
+    State(forwarder): State<TelemetryForwarder>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let (mut request, encoding): (ExportTraceServiceRequest, _) =
+        otlp::decode(&headers, &body, config.receiver.max_request_bytes)?;
+    forwarder.enrich(
+        request.resource_spans.iter_mut().map(|spans| &mut spans.resource),
+        &principal,
+        &headers,
+    );
+    forwarder
+        .export_traces(request)
+        .await
+        .map_err(|err| forward_failed("spans", err))?;
+    Ok(otlp::encode_response(
+        &ExportTraceServiceResponse::default(),
+        encoding,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/logs",
+    request_body(
+        description = "OTLP `ExportLogsServiceRequest` (gzip 可)",
+        content((Vec<u8> = PROTOBUF_CONTENT_TYPE), (Object = JSON_CONTENT_TYPE)),
+    ),
+    responses(ExportResponses),
+    security(("api_key" = []), ("bearer" = [])),
+    tags = [ OTLP_TAG ]
+)]
+async fn export_logs(
+    Authenticated(principal): Authenticated,
+    State(config): State<Arc<Config>>,
+    State(forwarder): State<TelemetryForwarder>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let (mut request, encoding): (ExportLogsServiceRequest, _) =
+        otlp::decode(&headers, &body, config.receiver.max_request_bytes)?;
+    forwarder.enrich(
+        request.resource_logs.iter_mut().map(|logs| &mut logs.resource),
+        &principal,
+        &headers,
+    );
+    forwarder
+        .export_logs(request)
+        .await
+        .map_err(|err| forward_failed("logs", err))?;
+    Ok(otlp::encode_response(
+        &ExportLogsServiceResponse::default(),
+        encoding,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/metrics",
+    request_body(
+        description = "OTLP `ExportMetricsServiceRequest` (gzip 可)",
+        content((Vec<u8> = PROTOBUF_CONTENT_TYPE), (Object = JSON_CONTENT_TYPE)),
+    ),
+    responses(ExportResponses),
+    security(("api_key" = []), ("bearer" = [])),
+    tags = [ OTLP_TAG ]
+)]
+async fn export_metrics(
+    Authenticated(principal): Authenticated,
+    State(config): State<Arc<Config>>,
+    State(forwarder): State<TelemetryForwarder>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let (mut request, encoding): (ExportMetricsServiceRequest, _) =
+        otlp::decode(&headers, &body, config.receiver.max_request_bytes)?;
+    let rejected_data_points: i64 = remove_reserved_metrics(&config, &mut request);
+    forwarder.enrich(
+        request.resource_metrics.iter_mut().map(|metrics| &mut metrics.resource),
+        &principal,
+        &headers,
+    );
+    forwarder
+        .export_metrics(request)
+        .await
+        .map_err(|err| forward_failed("metrics", err))?;
+    let response: ExportMetricsServiceResponse = ExportMetricsServiceResponse {
+        partial_success: (rejected_data_points > 0).then(|| ExportMetricsPartialSuccess {
+            rejected_data_points,
+            error_message: RESERVED_METRICS_MESSAGE.to_string(),
+        }),
+    };
+    Ok(otlp::encode_response(&response, encoding))
+}
+
+/// [`ExportResponses`] は schema を登録しないので、router の文書に加える
+#[derive(utoipa::OpenApi)]
+#[openapi(components(schemas(ProblemDetails)))]
+struct ReceiverDocs;
+
+/// バージョンの付かない OTLP/HTTP の標準のパスで公開する
+pub fn create_receiver_router(config: &ReceiverConfig) -> OpenApiRouter<AppState> {
+    // gzip の展開後の大きさは decode で制限する
+    OpenApiRouter::with_openapi(<ReceiverDocs as utoipa::OpenApi>::openapi())
+        .routes(utoipa_axum::routes!(export_traces))
+        .routes(utoipa_axum::routes!(export_logs))
+        .routes(utoipa_axum::routes!(export_metrics))
+        .layer(DefaultBodyLimit::max(config.max_request_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use axum::{body::Body, http::Request};
+    use opentelemetry_proto::tonic::{
+        metrics::v1::{Gauge, NumberDataPoint, ResourceMetrics, ScopeMetrics},
+        trace::v1::ResourceSpans,
+    };
+    use prost::Message;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::auth::{API_KEY_HEADER, ApiKeyConfig, AuthConfig, AuthMethod, Authenticator};
+    use crate::hello::RemoteHelloRepository;
+
+    const MAX_REQUEST_BYTES: usize = 1024;
+
+    fn config() -> Config {
+        Config {
+            stack: "prod".to_string(),
+            project_name: "sample".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn attributes(resource: &Option<Resource>) -> Vec<(&str, &str)> {
+        let mut attributes: Vec<(&str, &str)> = resource
+            .iter()
+            .flat_map(|resource| &resource.attributes)
+            .filter_map(
+                |attribute| match attribute.value.as_ref()?.value.as_ref()? {
+                    any_value::Value::StringValue(value) => {
+                        Some((attribute.key.as_str(), value.as_str()))
+                    }
+                    _ => None,
+                },
+            )
+            .collect();
+        attributes.sort();
+        attributes
+    }
+
+    fn enrich(resource: Vec<KeyValue>) -> Option<Resource> {
+        let forwarder: TelemetryForwarder = TelemetryForwarder::new(&config()).unwrap();
+        let principal: Principal = Principal {
+            id: "user-1".to_string(),
+            method: AuthMethod::Jwt,
+        };
+        let mut headers: HeaderMap = HeaderMap::new();
+        headers.insert(header::USER_AGENT, "browser".parse().unwrap());
+        let mut resource: Option<Resource> = Some(Resource {
+            attributes: resource,
+            ..Default::default()
+        });
+        forwarder.enrich(std::iter::once(&mut resource), &principal, &headers);
+        resource
+    }
+
+    #[tokio::test]
+    async fn enrich_overrides_server_attributes() {
+        let resource: Option<Resource> = enrich(vec![
+            string_attribute("service.name", "web"),
+            string_attribute("service.namespace", "other"),
+            string_attribute("deployment.environment.name", "dev"),
+            string_attribute("enduser.id", "admin"),
+            string_attribute("user_agent.original", "custom"),
+        ]);
+        assert_eq!(
+            attributes(&resource),
+            [
+                ("deployment.environment.name", "prod"),
+                ("enduser.id", "user-1"),
+                ("service.name", "web"),
+                ("service.namespace", "sample"),
+                ("user_agent.original", "custom"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn enrich_does_not_let_clients_pose_as_the_api() {
+        for resource in [
+            vec![],
+            vec![string_attribute("service.name", API_SERVICE_NAME)],
+        ] {
+            let resource: Option<Resource> = enrich(resource);
+            assert_eq!(
+                attributes(&resource),
+                [
+                    ("deployment.environment.name", "prod"),
+                    ("enduser.id", "user-1"),
+                    ("service.name", "client"),
+                    ("service.namespace", "sample"),
+                    ("user_agent.original", "browser"),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn removes_reserved_metrics() {
+        let gauge = |name: &str, points: usize| Metric {
+            name: name.to_string(),
+            data: Some(metric::Data::Gauge(Gauge {
+                data_points: vec![NumberDataPoint::default(); points],
+            })),
+            ..Default::default()
+        };
+        let mut request: ExportMetricsServiceRequest = ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                scope_metrics: vec![ScopeMetrics {
+                    metrics: vec![
+                        gauge("web.vitals.lcp", 1),
+                        gauge("http.server.request.duration", 2),
+                        gauge("telemetry.flush.duration", 1),
+                        gauge("_aws", 1),
+                        gauge("http.route", 1),
+                        gauge("http.client.request.duration", 1),
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        assert_eq!(remove_reserved_metrics(&config(), &mut request), 5);
+        let names: Vec<&str> = request.resource_metrics[0].scope_metrics[0]
+            .metrics
+            .iter()
+            .map(|metric| metric.name.as_str())
+            .collect();
+        assert_eq!(names, ["web.vitals.lcp", "http.client.request.duration"]);
+    }
+
+    #[test]
+    fn client_emf_config_pins_namespace_and_dimensions() {
+        let emf_config: EmfConfig = client_emf_config(&config());
+        assert_eq!(emf_config.namespace, "api/client");
+        // `faas.name` は client が値を決めるので dimension にしない
+        assert_eq!(emf_config.dimensions, ["deployment.environment.name"]);
+    }
+
+    #[test]
+    fn config_rejects_the_api_service_name() {
+        assert!(!ReceiverConfig::default().enabled);
+        let config: ReceiverConfig = ReceiverConfig {
+            service_name: API_SERVICE_NAME.to_string(),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn traces() -> ExportTraceServiceRequest {
+        ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans::default()],
+        }
+    }
+
+    /// API キー `secret` で認証する receiver に POST する
+    async fn post(path: &str, headers: &[(header::HeaderName, &str)], body: Vec<u8>) -> Response {
+        let receiver_config: ReceiverConfig = ReceiverConfig {
+            enabled: true,
+            max_request_bytes: MAX_REQUEST_BYTES,
+            ..Default::default()
+        };
+        let authenticator: Authenticator = Authenticator::load(&AuthConfig {
+            api_keys: vec![ApiKeyConfig {
+                id: "web".to_string(),
+                key: "secret".to_string(),
+            }],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let state: AppState = AppState::builder()
+            .config(Arc::new(Config {
+                receiver: receiver_config.clone(),
+                ..config()
+            }))
+            .authenticator(authenticator)
+            .hello_repository(Arc::new(RemoteHelloRepository::new(None)))
+            .build()
+            .unwrap();
+        let (router, _) = create_receiver_router(&receiver_config).split_for_parts();
+        let mut request = Request::post(path);
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        router
+            .with_state(state)
+            .oneshot(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn body(response: Response) -> Bytes {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+    }
+
+    async fn problem_type(response: Response) -> String {
+        let body: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        body["type"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn accepts_protobuf_and_responds_in_protobuf() {
+        let response: Response = post(
+            "/v1/traces",
+            &[
+                (header::CONTENT_TYPE, PROTOBUF_CONTENT_TYPE),
+                (API_KEY_HEADER.parse().unwrap(), "secret"),
+            ],
+            traces().encode_to_vec(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            PROTOBUF_CONTENT_TYPE
+        );
+        let response = ExportTraceServiceResponse::decode(body(response).await).unwrap();
+        assert_eq!(response, ExportTraceServiceResponse::default());
+    }
+
+    #[tokio::test]
+    async fn accepts_json_and_responds_in_json() {
+        let response: Response = post(
+            "/v1/logs",
+            &[
+                (header::CONTENT_TYPE, JSON_CONTENT_TYPE),
+                (API_KEY_HEADER.parse().unwrap(), "secret"),
+            ],
+            br#"{"resourceLogs":[{"resource":{"attributes":[]}}]}"#.to_vec(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], JSON_CONTENT_TYPE);
+        let response: ExportLogsServiceResponse =
+            serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(response, ExportLogsServiceResponse::default());
+    }
+
+    #[tokio::test]
+    async fn accepts_gzip_bodies() {
+        let response: Response = post(
+            "/v1/traces",
+            &[
+                (header::CONTENT_TYPE, PROTOBUF_CONTENT_TYPE),
+                (header::CONTENT_ENCODING, "gzip"),
+                (API_KEY_HEADER.parse().unwrap(), "secret"),
+            ],
+            gzip(&traces().encode_to_vec()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn limits_the_size_after_decompression() {
+        let body: Vec<u8> = gzip(&vec![0; MAX_REQUEST_BYTES + 1]);
+        assert!(body.len() < MAX_REQUEST_BYTES);
+        let response: Response = post(
+            "/v1/traces",
+            &[
+                (header::CONTENT_TYPE, PROTOBUF_CONTENT_TYPE),
+                (header::CONTENT_ENCODING, "gzip"),
+                (API_KEY_HEADER.parse().unwrap(), "secret"),
+            ],
+            body,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            problem_type(response).await,
+            "urn:problem-type:payload-too-large"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_content_types_and_encodings() {
+        for (content_type, content_encoding, expected) in [
+            (
+                "text/plain",
+                "identity",
+                "urn:problem-type:unsupported-media-type",
+            ),
+            (
+                PROTOBUF_CONTENT_TYPE,
+                "br",
+                "urn:problem-type:unsupported-content-encoding",
+            ),
+        ] {
+            let response: Response = post(
+                "/v1/metrics",
+                &[
+                    (header::CONTENT_TYPE, content_type),
+                    (header::CONTENT_ENCODING, content_encoding),
+                    (API_KEY_HEADER.parse().unwrap(), "secret"),
+                ],
+                Vec::new(),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            assert_eq!(problem_type(response).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_payloads() {
+        let response: Response = post(
+            "/v1/traces",
+            &[
+                (header::CONTENT_TYPE, JSON_CONTENT_TYPE),
+                (API_KEY_HEADER.parse().unwrap(), "secret"),
+            ],
+            b"[1, 2".to_vec(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            problem_type(response).await,
+            "urn:problem-type:invalid-otlp-payload"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_without_credentials() {
+        for path in ["/v1/traces", "/v1/logs", "/v1/metrics"] {
+            let response: Response = post(
+                path,
+                &[(header::CONTENT_TYPE, PROTOBUF_CONTENT_TYPE)],
+                Vec::new(),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_reserved_metrics_as_partial_success() {
+        let gauge = |name: &str, points: usize| Metric {
+            name: name.to_string(),
+            data: Some(metric::Data::Gauge(Gauge {
+                data_points: vec![NumberDataPoint::default(); points],
+            })),
+            ..Default::default()
+        };
+        let request: ExportMetricsServiceRequest = ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                scope_metrics: vec![ScopeMetrics {
+                    metrics: vec![
+                        gauge("web.vitals.lcp", 1),
+                        gauge("http.server.request.duration", 2),
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        let response: Response = post(
+            "/v1/metrics",
+            &[
+                (header::CONTENT_TYPE, PROTOBUF_CONTENT_TYPE),
+                (API_KEY_HEADER.parse().unwrap(), "secret"),
+            ],
+            request.encode_to_vec(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = ExportMetricsServiceResponse::decode(body(response).await).unwrap();
+        assert_eq!(
+            response.partial_success,
+            Some(ExportMetricsPartialSuccess {
+                rejected_data_points: 2,
+                error_message: RESERVED_METRICS_MESSAGE.to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn export_operations_share_their_responses() {
+        let (_, openapi) = create_receiver_router(&ReceiverConfig::default()).split_for_parts();
+        for path in ["/v1/traces", "/v1/logs", "/v1/metrics"] {
+            let operation = openapi.paths.paths[path].post.as_ref().unwrap();
+            let statuses: Vec<&str> = operation
+                .responses
+                .responses
+                .keys()
+                .map(String::as_str)
+                .collect();
+            assert_eq!(
+                statuses,
+                ["200", "400", "401", "413", "415", "502"],
+                "{}",
+                path
+            );
+        }
+        let components = openapi.components.unwrap();
+        assert!(components.schemas.contains_key("ProblemDetails"));
+    }
+}
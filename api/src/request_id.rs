@@ -0,0 +1,196 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// server span に記録する属性名
+pub const REQUEST_ID_FIELD: &str = "request.id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// リクエストごとの識別子
+///
+/// 受け取った `X-Request-Id` を使い、なければ Lambda の `aws_request_id`、それもなければ UUIDv7 を生成する
+#[derive(Debug, Clone)]
+pub struct RequestId(HeaderValue);
+
+impl RequestId {
+    fn from_request(request: &Request) -> Self {
+        if let Some(value) = request.headers().get(REQUEST_ID_HEADER)
+            && is_valid(value)
+        {
+            return Self(value.clone());
+        }
+        #[cfg(feature = "lambda")]
+        {
+            use lambda_http::RequestExt;
+            if let Some(context) = request.lambda_context_ref()
+                && let Ok(value) = HeaderValue::from_str(&context.request_id)
+            {
+                return Self(value);
+            }
+        }
+        Self(
+            HeaderValue::from_str(&uuid::Uuid::now_v7().to_string())
+                .expect("UUID is a valid header value"),
+        )
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().unwrap_or_default()
+    }
+
+    /// 処理中のリクエストの識別子。外部呼び出しのヘッダーに付ける
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    pub fn header_value(&self) -> HeaderValue {
+        self.0.clone()
+    }
+}
+
+// ログやヘッダーを汚さないよう、長すぎる値や表示できない文字を含む値は使わない
+fn is_valid(value: &HeaderValue) -> bool {
+    let value: &[u8] = value.as_bytes();
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LEN
+        && value.iter().all(u8::is_ascii_graphic)
+}
+
+/// `X-Request-Id` を決めて server span に記録し、レスポンスに返す middleware
+///
+/// handler の中では [`RequestId::current`] で参照できる
+pub async fn propagate_request_id(request: Request, next: Next) -> Response {
+    let request_id: RequestId = RequestId::from_request(&request);
+    tracing::Span::current().record(REQUEST_ID_FIELD, request_id.as_str());
+    let mut response: Response = CURRENT.scope(request_id.clone(), next.run(request)).await;
+    response
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, request_id.header_value());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, body::Body, http::HeaderMap, routing::get};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::outbound::{Deadline, OutboundClient};
+
+    /// handler から見える `RequestId::current` を body で返す
+    fn router() -> Router {
+        Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    RequestId::current()
+                        .map(|id| id.as_str().to_string())
+                        .unwrap_or_default()
+                }),
+            )
+            .layer(axum::middleware::from_fn(propagate_request_id))
+    }
+
+    /// `X-Request-Id` を付けて送り、(レスポンスのヘッダー, handler から見えた値) を返す
+    async fn call(router: Router, request_id: Option<&str>) -> (String, String) {
+        let mut request = Request::builder().uri("/");
+        if let Some(request_id) = request_id {
+            request = request.header(REQUEST_ID_HEADER, request_id);
+        }
+        let response: Response = router
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let header: String = response.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (header, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn assert_generated(request_id: &str) {
+        let uuid: uuid::Uuid = request_id.parse().unwrap();
+        assert_eq!(uuid.get_version(), Some(uuid::Version::SortRand));
+    }
+
+    #[tokio::test]
+    async fn incoming_request_ids_are_echoed() {
+        let (header, current) = call(router(), Some("req-123")).await;
+        assert_eq!(header, "req-123");
+        assert_eq!(current, "req-123");
+    }
+
+    #[tokio::test]
+    async fn missing_request_ids_are_generated() {
+        let (header, current) = call(router(), None).await;
+        assert_generated(&header);
+        assert_eq!(current, header);
+
+        let (other, _) = call(router(), None).await;
+        assert_ne!(other, header);
+    }
+
+    #[tokio::test]
+    async fn invalid_request_ids_are_replaced() {
+        let oversized: String = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        for request_id in ["", "has space", "tab\there", oversized.as_str()] {
+            let (header, current) = call(router(), Some(request_id)).await;
+            assert_generated(&header);
+            assert_eq!(current, header);
+        }
+        let longest: String = "a".repeat(MAX_REQUEST_ID_LEN);
+        assert_eq!(call(router(), Some(&longest)).await.0, longest);
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_forwarded_to_outbound_calls() {
+        let downstream: Router = Router::new().route(
+            "/echo",
+            get(|headers: HeaderMap| async move {
+                headers
+                    .get(REQUEST_ID_HEADER)
+                    .map(|value| value.to_str().unwrap().to_string())
+                    .unwrap_or_default()
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: String = format!("http://{}/echo", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, downstream).await });
+
+        let router: Router = Router::new()
+            .route(
+                "/",
+                get(move || async move {
+                    OutboundClient::new()
+                        .get(&url, HeaderMap::new(), Deadline(None))
+                        .await
+                        .unwrap()
+                        .text()
+                        .await
+                        .unwrap()
+                }),
+            )
+            .layer(axum::middleware::from_fn(propagate_request_id));
+        assert_eq!(
+            call(router.clone(), Some("req-456")).await,
+            ("req-456".to_string(), "req-456".to_string())
+        );
+
+        let (header, forwarded) = call(router, None).await;
+        assert_generated(&header);
+        assert_eq!(forwarded, header);
+        // middleware の外では付けない
+        assert!(RequestId::current().is_none());
+    }
+}
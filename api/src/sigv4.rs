@@ -0,0 +1,456 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, request::Parts};
+use hmac::{Hmac, Mac};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use time::{OffsetDateTime, format_description::FormatItem, macros::format_description};
+
+#[cfg(not(feature = "lambda"))]
+mod verify;
+#[cfg(not(feature = "lambda"))]
+pub use verify::{
+    Credentials, CredentialsProvider, SigV4Verifier, StaticCredentialsProvider, require_sigv4,
+};
+
+pub const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const AMZ_DATE_HEADER: &str = "x-amz-date";
+const SECURITY_TOKEN_HEADER: &str = "x-amz-security-token";
+const SCOPE_TERMINATOR: &str = "aws4_request";
+const CONTAINER_CREDENTIALS_HOST: &str = "http://169.254.170.2";
+const CONTAINER_CREDENTIALS_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const CONTAINER_CREDENTIALS_TIMEOUT: Duration = Duration::from_secs(5);
+// 期限のこれだけ前に一時的な credentials を取り直す
+const REFRESH_BEFORE_EXPIRATION: time::Duration = time::Duration::minutes(5);
+const AMZ_DATE_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year][month][day]T[hour][minute][second]Z");
+// RFC 3986 の unreserved 以外をすべてエンコードする
+const URI_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key: Vec<u8> = hmac_sha256(
+        format!("AWS4{}", secret_access_key).as_bytes(),
+        date.as_bytes(),
+    );
+    let key: Vec<u8> = hmac_sha256(&key, region.as_bytes());
+    let key: Vec<u8> = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, SCOPE_TERMINATOR.as_bytes())
+}
+
+fn uri_encode(value: &str) -> String {
+    utf8_percent_encode(value, URI_ENCODE_SET).to_string()
+}
+
+/// S3 以外のサービスと同じく、エンコード済みのパスをセグメントごとにもう一度エンコードする
+fn canonical_uri(path: &str) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    path.split('/')
+        .map(uri_encode)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn canonical_query(query: Option<&str>) -> String {
+    let mut pairs: Vec<(String, String)> = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+            (uri_encode(&decode(key)), uri_encode(&decode(value)))
+        })
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// 署名対象のヘッダーを `name:value\n` の形に並べる。署名対象のヘッダーがなければ `None`
+fn canonical_headers(headers: &HeaderMap, signed_headers: &[&str]) -> Option<String> {
+    signed_headers
+        .iter()
+        .map(|name| {
+            let values: Vec<String> = headers
+                .get_all(*name)
+                .iter()
+                .map(|value| {
+                    String::from_utf8_lossy(value.as_bytes())
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect();
+            (!values.is_empty()).then(|| format!("{}:{}\n", name, values.join(",")))
+        })
+        .collect()
+}
+
+fn canonical_request(
+    parts: &Parts,
+    canonical_headers: &str,
+    signed_headers: &[&str],
+    payload_hash: &str,
+) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        parts.method,
+        canonical_uri(parts.uri.path()),
+        canonical_query(parts.uri.query()),
+        canonical_headers,
+        signed_headers.join(";"),
+        payload_hash
+    )
+}
+
+/// 送信するリクエストに署名する鍵。Lambda では実行ロールの一時的な credentials が環境変数に入っている
+#[derive(Clone)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+    /// 一時的な credentials の期限。環境変数から読んだものは持たない
+    pub expiration: Option<OffsetDateTime>,
+}
+
+impl std::fmt::Debug for AwsCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AwsCredentials")
+            .field("access_key_id", &self.access_key_id)
+            .field("expiration", &self.expiration)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AwsCredentials {
+    /// `AWS_ACCESS_KEY_ID`、`AWS_SECRET_ACCESS_KEY`、`AWS_SESSION_TOKEN` から読む
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            access_key_id: env_var("AWS_ACCESS_KEY_ID").context("`AWS_ACCESS_KEY_ID` is not set")?,
+            secret_access_key: env_var("AWS_SECRET_ACCESS_KEY")
+                .context("`AWS_SECRET_ACCESS_KEY` is not set")?,
+            session_token: env_var("AWS_SESSION_TOKEN"),
+            expiration: None,
+        })
+    }
+
+    fn expires_soon(&self, now: OffsetDateTime) -> bool {
+        self.expiration
+            .is_some_and(|expiration| expiration - now < REFRESH_BEFORE_EXPIRATION)
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// [`SigV4Signer`] が署名のたびに使う credentials の取得元
+#[derive(Debug, Clone)]
+pub enum AwsCredentialsProvider {
+    /// 期限のない credentials。Lambda の環境変数の credentials は実行環境が続く間使える
+    Static(AwsCredentials),
+    /// ECS や Lambda SnapStart の container credentials endpoint。期限の前に取り直す
+    Container(Arc<ContainerCredentials>),
+}
+
+impl AwsCredentialsProvider {
+    /// container credentials endpoint の環境変数があればそこから取り、なければ環境変数の credentials を使う
+    pub fn from_env() -> anyhow::Result<Self> {
+        let container: Option<ContainerCredentials> = ContainerCredentials::from_env()?;
+        Ok(match container {
+            Some(container) => Self::Container(Arc::new(container)),
+            None => Self::Static(AwsCredentials::from_env()?),
+        })
+    }
+
+    pub async fn credentials(&self) -> anyhow::Result<AwsCredentials> {
+        match self {
+            Self::Static(credentials) => Ok(credentials.clone()),
+            Self::Container(container) => container.credentials().await,
+        }
+    }
+}
+
+/// `AWS_CONTAINER_CREDENTIALS_FULL_URI` か `AWS_CONTAINER_CREDENTIALS_RELATIVE_URI` から取る一時的な credentials
+#[derive(Debug)]
+pub struct ContainerCredentials {
+    client: reqwest::Client,
+    uri: String,
+    authorization: Option<ContainerAuthorization>,
+    cached: tokio::sync::Mutex<Option<AwsCredentials>>,
+}
+
+/// endpoint に送る `Authorization` ヘッダーの値
+#[derive(Debug)]
+enum ContainerAuthorization {
+    Token(String),
+    /// EKS Pod Identity のように中身が差し替わるので、取り直すたびに読む
+    TokenFile(std::path::PathBuf),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerCredentialsResponse {
+    access_key_id: String,
+    secret_access_key: String,
+    token: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    expiration: OffsetDateTime,
+}
+
+impl ContainerCredentials {
+    fn from_env() -> anyhow::Result<Option<Self>> {
+        let uri: String = match (
+            env_var("AWS_CONTAINER_CREDENTIALS_FULL_URI"),
+            env_var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"),
+        ) {
+            (Some(uri), _) => uri,
+            (None, Some(path)) => format!("{}{}", CONTAINER_CREDENTIALS_HOST, path),
+            (None, None) => return Ok(None),
+        };
+        let authorization: Option<ContainerAuthorization> =
+            match env_var("AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE") {
+                Some(path) => Some(ContainerAuthorization::TokenFile(path.into())),
+                None => env_var("AWS_CONTAINER_AUTHORIZATION_TOKEN")
+                    .map(ContainerAuthorization::Token),
+            };
+        Ok(Some(Self::new(uri, authorization)?))
+    }
+
+    fn new(uri: String, authorization: Option<ContainerAuthorization>) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .connect_timeout(CONTAINER_CREDENTIALS_CONNECT_TIMEOUT)
+                .timeout(CONTAINER_CREDENTIALS_TIMEOUT)
+                .build()?,
+            uri,
+            authorization,
+            cached: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// 期限まで 5 分を切っていたら取り直す。取り直している間の署名は待たせる
+    pub async fn credentials(&self) -> anyhow::Result<AwsCredentials> {
+        let mut cached = self.cached.lock().await;
+        if let Some(credentials) = cached.as_ref()
+            && !credentials.expires_soon(OffsetDateTime::now_utc())
+        {
+            return Ok(credentials.clone());
+        }
+        let credentials: AwsCredentials = self
+            .fetch()
+            .await
+            .with_context(|| format!("failed to fetch container credentials from {}", self.uri))?;
+        *cached = Some(credentials.clone());
+        Ok(credentials)
+    }
+
+    async fn fetch(&self) -> anyhow::Result<AwsCredentials> {
+        let mut request = self.client.get(&self.uri);
+        match &self.authorization {
+            Some(ContainerAuthorization::Token(token)) => {
+                request = request.header(axum::http::header::AUTHORIZATION, token);
+            }
+            Some(ContainerAuthorization::TokenFile(path)) => {
+                let token: String = tokio::fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                request = request.header(axum::http::header::AUTHORIZATION, token.trim());
+            }
+            None => {}
+        }
+        let response: ContainerCredentialsResponse =
+            request.send().await?.error_for_status()?.json().await?;
+        Ok(AwsCredentials {
+            access_key_id: response.access_key_id,
+            secret_access_key: response.secret_access_key,
+            session_token: response.token,
+            expiration: Some(response.expiration),
+        })
+    }
+}
+
+/// AWS のサービスに送るリクエストに SigV4 署名を付ける
+#[derive(Debug, Clone)]
+pub struct SigV4Signer {
+    credentials: AwsCredentialsProvider,
+    region: String,
+    service: String,
+}
+
+impl SigV4Signer {
+    pub fn new(credentials: AwsCredentialsProvider, region: &str, service: &str) -> Self {
+        Self {
+            credentials,
+            region: region.to_string(),
+            service: service.to_string(),
+        }
+    }
+
+    /// `host`、`x-amz-date` (と session token) を加え、付いているヘッダーすべてに署名する
+    pub async fn sign(&self, parts: &mut Parts, body: &[u8]) -> anyhow::Result<()> {
+        let credentials: AwsCredentials = self.credentials.credentials().await?;
+        self.sign_at(&credentials, parts, body, OffsetDateTime::now_utc())
+    }
+
+    pub fn sign_at(
+        &self,
+        credentials: &AwsCredentials,
+        parts: &mut Parts,
+        body: &[u8],
+        now: OffsetDateTime,
+    ) -> anyhow::Result<()> {
+        let amz_date: String = now
+            .format(AMZ_DATE_FORMAT)
+            .context("failed to format x-amz-date")?;
+        let date: &str = &amz_date[..8];
+        if !parts.headers.contains_key(axum::http::header::HOST) {
+            let host: &str = parts
+                .uri
+                .authority()
+                .context("request URI has no host")?
+                .as_str();
+            parts
+                .headers
+                .insert(axum::http::header::HOST, HeaderValue::from_str(host)?);
+        }
+        parts.headers.insert(
+            HeaderName::from_static(AMZ_DATE_HEADER),
+            HeaderValue::from_str(&amz_date)?,
+        );
+        if let Some(session_token) = &credentials.session_token {
+            parts.headers.insert(
+                HeaderName::from_static(SECURITY_TOKEN_HEADER),
+                HeaderValue::from_str(session_token)?,
+            );
+        }
+        parts.headers.remove(axum::http::header::AUTHORIZATION);
+
+        let mut signed_headers: Vec<&str> = parts.headers.keys().map(HeaderName::as_str).collect();
+        signed_headers.sort_unstable();
+        signed_headers.dedup();
+        let canonical_headers: String = canonical_headers(&parts.headers, &signed_headers)
+            .context("request has no headers to sign")?;
+        let canonical_request: String = canonical_request(
+            parts,
+            &canonical_headers,
+            &signed_headers,
+            &sha256_hex(body),
+        );
+        let scope: String = format!(
+            "{}/{}/{}/{}",
+            date, self.region, self.service, SCOPE_TERMINATOR
+        );
+        let string_to_sign: String = format!(
+            "{}\n{}\n{}\n{}",
+            ALGORITHM,
+            amz_date,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let key: Vec<u8> = signing_key(
+            &credentials.secret_access_key,
+            date,
+            &self.region,
+            &self.service,
+        );
+        let signature: String = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+        let authorization: String = format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM,
+            credentials.access_key_id,
+            scope,
+            signed_headers.join(";"),
+            signature
+        );
+        parts.headers.insert(
+            axum::http::header::AUTHORIZATION,
+            HeaderValue::from_str(&authorization)?,
+        );
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+
+    use axum::{Router, extract::State, http::HeaderMap, routing::get};
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::validation::ApiJson;
+
+    #[derive(Clone, Default)]
+    struct Endpoint {
+        requests: Arc<AtomicUsize>,
+        expires_in_minutes: Arc<AtomicI64>,
+    }
+
+    async fn container_credentials(
+        State(endpoint): State<Endpoint>,
+        headers: HeaderMap,
+    ) -> ApiJson<Value> {
+        assert_eq!(headers[axum::http::header::AUTHORIZATION], "token");
+        let count: usize = endpoint.requests.fetch_add(1, Ordering::SeqCst) + 1;
+        let expiration: OffsetDateTime = OffsetDateTime::now_utc()
+            + time::Duration::minutes(endpoint.expires_in_minutes.load(Ordering::SeqCst));
+        ApiJson(json!({
+            "AccessKeyId": format!("ASIA{}", count),
+            "SecretAccessKey": "secret",
+            "Token": "session",
+            "Expiration": expiration
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap(),
+        }))
+    }
+
+    #[tokio::test]
+    async fn container_credentials_are_refreshed_before_they_expire() {
+        let endpoint: Endpoint = Endpoint::default();
+        endpoint.expires_in_minutes.store(1, Ordering::SeqCst);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri: String = format!("http://{}/creds", listener.local_addr().unwrap());
+        let router: Router = Router::new()
+            .route("/creds", get(container_credentials))
+            .with_state(endpoint.clone());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let provider: AwsCredentialsProvider = AwsCredentialsProvider::Container(Arc::new(
+            ContainerCredentials::new(uri, Some(ContainerAuthorization::Token("token".to_string())))
+                .unwrap(),
+        ));
+        let first: AwsCredentials = provider.credentials().await.unwrap();
+        assert_eq!(first.access_key_id, "ASIA1");
+        assert_eq!(first.session_token.as_deref(), Some("session"));
+        // 期限まで 5 分を切っているので取り直す
+        endpoint.expires_in_minutes.store(60, Ordering::SeqCst);
+        assert_eq!(provider.credentials().await.unwrap().access_key_id, "ASIA2");
+        // 期限まで余裕があれば取り直さない
+        assert_eq!(provider.credentials().await.unwrap().access_key_id, "ASIA2");
+        assert_eq!(endpoint.requests.load(Ordering::SeqCst), 2);
+    }
+}
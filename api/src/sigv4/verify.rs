@@ -0,0 +1,490 @@
+//! `lambda` feature なしで起動したときの SigV4 署名の検証
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode, header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+use super::{
+    ALGORITHM, AMZ_DATE_FORMAT, AMZ_DATE_HEADER, SCOPE_TERMINATOR, canonical_headers,
+    canonical_request, hmac_sha256, sha256_hex, signing_key,
+};
+use crate::auth::{AuthMethod, Principal, SigV4Config, SigV4CredentialConfig};
+use crate::error::ApiError;
+
+const CONTENT_SHA256_HEADER: &str = "x-amz-content-sha256";
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+// Lambda function URL と同じ payload の上限
+const MAX_BODY_BYTES: usize = 6 * 1024 * 1024;
+
+/// access key ID に対応する秘密鍵と呼び出し元
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub secret_access_key: String,
+    pub principal: String,
+}
+
+/// access key ID から [`Credentials`] を引く
+pub trait CredentialsProvider: Send + Sync {
+    fn credentials(&self, access_key_id: &str) -> Option<Credentials>;
+}
+
+/// 設定やテストで使う、access key ID をキーにした固定の credentials
+#[derive(Debug, Default)]
+pub struct StaticCredentialsProvider(HashMap<String, Credentials>);
+
+impl StaticCredentialsProvider {
+    pub fn from_config(credentials: &[SigV4CredentialConfig]) -> Self {
+        credentials
+            .iter()
+            .map(|config| {
+                (
+                    config.access_key_id.clone(),
+                    Credentials {
+                        secret_access_key: config.secret_access_key.clone(),
+                        principal: config.principal.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+impl FromIterator<(String, Credentials)> for StaticCredentialsProvider {
+    fn from_iter<I: IntoIterator<Item = (String, Credentials)>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl CredentialsProvider for StaticCredentialsProvider {
+    fn credentials(&self, access_key_id: &str) -> Option<Credentials> {
+        self.0.get(access_key_id).cloned()
+    }
+}
+
+/// `Authorization: AWS4-HMAC-SHA256 Credential=..., SignedHeaders=..., Signature=...`
+struct Authorization<'a> {
+    access_key_id: &'a str,
+    date: &'a str,
+    region: &'a str,
+    service: &'a str,
+    signed_headers: Vec<&'a str>,
+    signature: &'a str,
+}
+
+impl<'a> Authorization<'a> {
+    fn parse(value: &'a str) -> Option<Self> {
+        let params: &str = value.strip_prefix(ALGORITHM)?.strip_prefix(' ')?;
+        let (mut credential, mut signed_headers, mut signature) = (None, None, None);
+        for param in params.split(',') {
+            match param.trim().split_once('=')? {
+                ("Credential", value) => credential = Some(value),
+                ("SignedHeaders", value) => signed_headers = Some(value),
+                ("Signature", value) => signature = Some(value),
+                _ => return None,
+            }
+        }
+        let mut scope = credential?.splitn(5, '/');
+        let authorization = Self {
+            access_key_id: scope.next()?,
+            date: scope.next()?,
+            region: scope.next()?,
+            service: scope.next()?,
+            signed_headers: signed_headers?.split(';').collect(),
+            signature: signature?,
+        };
+        (scope.next()? == SCOPE_TERMINATOR).then_some(authorization)
+    }
+}
+
+/// `lambda` feature なしで起動したときに、`AWS_IAM` 認証の function URL と同じく SigV4 署名を検証する
+#[derive(Clone)]
+pub struct SigV4Verifier {
+    provider: Arc<dyn CredentialsProvider>,
+    region: String,
+    service: String,
+    max_clock_skew: Duration,
+}
+
+impl SigV4Verifier {
+    pub fn new(config: &SigV4Config, provider: Arc<dyn CredentialsProvider>) -> Self {
+        Self {
+            provider,
+            region: config.region.clone(),
+            service: config.service.clone(),
+            max_clock_skew: Duration::from_secs(config.max_clock_skew_secs),
+        }
+    }
+
+    pub fn verify(&self, parts: &Parts, body: &[u8]) -> Result<Principal, ApiError> {
+        self.verify_at(parts, body, OffsetDateTime::now_utc())
+    }
+
+    fn verify_at(
+        &self,
+        parts: &Parts,
+        body: &[u8],
+        now: OffsetDateTime,
+    ) -> Result<Principal, ApiError> {
+        let authorization: Authorization = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .ok_or_else(|| unauthorized("missing SigV4 signature"))?
+            .to_str()
+            .ok()
+            .and_then(Authorization::parse)
+            .ok_or_else(|| unauthorized("malformed SigV4 authorization header"))?;
+        if authorization.region != self.region || authorization.service != self.service {
+            return Err(unauthorized(
+                "credential scope does not match this endpoint",
+            ));
+        }
+        if !authorization.signed_headers.contains(&"host")
+            || !authorization.signed_headers.contains(&AMZ_DATE_HEADER)
+            || !authorization.signed_headers.is_sorted()
+        {
+            return Err(unauthorized(
+                "SignedHeaders must be sorted and include host and x-amz-date",
+            ));
+        }
+
+        let amz_date: &str = parts
+            .headers
+            .get(AMZ_DATE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| unauthorized("missing x-amz-date header"))?;
+        let signed_at: OffsetDateTime = PrimitiveDateTime::parse(amz_date, AMZ_DATE_FORMAT)
+            .map_err(|_| unauthorized("malformed x-amz-date header"))?
+            .assume_utc();
+        if !amz_date.starts_with(authorization.date) || authorization.date.len() != 8 {
+            return Err(unauthorized(
+                "credential scope date does not match x-amz-date",
+            ));
+        }
+        if (now - signed_at).unsigned_abs() > self.max_clock_skew {
+            return Err(forbidden(
+                "request_expired",
+                "signature expired or signed in the future",
+            ));
+        }
+
+        let credentials: Credentials = self
+            .provider
+            .credentials(authorization.access_key_id)
+            // AWS と同じく、署名の形式が正しければ未知のキーは 403 にする
+            .ok_or_else(|| {
+                forbidden(
+                    "invalid_client_token_id",
+                    "the access key ID does not exist",
+                )
+            })?;
+
+        let body_hash: String = sha256_hex(body);
+        let payload_hash: &str = match parts
+            .headers
+            .get(CONTENT_SHA256_HEADER)
+            .map(HeaderValue::to_str)
+        {
+            None => &body_hash,
+            Some(Ok(UNSIGNED_PAYLOAD)) => UNSIGNED_PAYLOAD,
+            Some(Ok(hash)) if hash == body_hash => &body_hash,
+            Some(_) => return Err(signature_mismatch()),
+        };
+        let canonical_headers: String =
+            canonical_headers(&parts.headers, &authorization.signed_headers)
+                .ok_or_else(signature_mismatch)?;
+        let canonical_request: String = canonical_request(
+            parts,
+            &canonical_headers,
+            &authorization.signed_headers,
+            payload_hash,
+        );
+        let scope: String = format!(
+            "{}/{}/{}/{}",
+            authorization.date, authorization.region, authorization.service, SCOPE_TERMINATOR
+        );
+        let string_to_sign: String = format!(
+            "{}\n{}\n{}\n{}",
+            ALGORITHM,
+            amz_date,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let key: Vec<u8> = signing_key(
+            &credentials.secret_access_key,
+            authorization.date,
+            authorization.region,
+            authorization.service,
+        );
+        let signature: String = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+        if !crate::auth::constant_time_eq(signature.as_bytes(), authorization.signature.as_bytes())
+        {
+            return Err(signature_mismatch());
+        }
+
+        Ok(Principal {
+            id: credentials.principal,
+            method: AuthMethod::SigV4,
+        })
+    }
+}
+
+fn unauthorized(detail: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::UNAUTHORIZED,
+        "unauthorized",
+        "Unauthorized",
+        detail,
+    )
+    .with_header(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static(ALGORITHM),
+    )
+}
+
+fn forbidden(error_type: &'static str, detail: &str) -> ApiError {
+    ApiError::new(StatusCode::FORBIDDEN, error_type, "Forbidden", detail)
+}
+
+fn signature_mismatch() -> ApiError {
+    forbidden(
+        "signature_mismatch",
+        "the request signature does not match the computed signature",
+    )
+}
+
+/// SigV4 署名を検証する middleware
+///
+/// 検証済みの [`Principal`] を request extensions に入れ、server span の `enduser.id` に記録する
+pub async fn require_sigv4(
+    State(verifier): State<SigV4Verifier>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let (mut parts, body) = request.into_parts();
+    let body: Bytes = axum::body::to_bytes(body, MAX_BODY_BYTES)
+        .await
+        .map_err(|err| {
+            ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                "Payload too large",
+                err.to_string(),
+            )
+        })?;
+    let principal: Principal = verifier.verify(&parts, &body)?;
+    tracing::Span::current().record(
+        opentelemetry_semantic_conventions::attribute::ENDUSER_ID,
+        principal.id.as_str(),
+    );
+    parts.extensions.insert(principal);
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::Request, response::IntoResponse};
+    use time::macros::datetime;
+
+    use super::*;
+
+    // AWS の SigV4 test suite と IAM のドキュメントの例で使われる credentials と時刻
+    const ACCESS_KEY_ID: &str = "AKIDEXAMPLE";
+    const SECRET_ACCESS_KEY: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+    const SIGNED_AT: OffsetDateTime = datetime!(2015-08-30 12:36:00 UTC);
+
+    fn verifier(service: &str) -> SigV4Verifier {
+        let provider: StaticCredentialsProvider = [(
+            ACCESS_KEY_ID.to_string(),
+            Credentials {
+                secret_access_key: SECRET_ACCESS_KEY.to_string(),
+                principal: "arn:aws:iam::123456789012:user/example".to_string(),
+            },
+        )]
+        .into_iter()
+        .collect();
+        SigV4Verifier::new(
+            &SigV4Config {
+                region: "us-east-1".to_string(),
+                service: service.to_string(),
+                ..SigV4Config::default()
+            },
+            Arc::new(provider),
+        )
+    }
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Parts {
+        let mut builder = Request::get(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    fn get_vanilla(signature: &str) -> Parts {
+        let authorization: String = format!(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, Signature={}",
+            signature
+        );
+        request(
+            "/",
+            &[
+                ("host", "example.amazonaws.com"),
+                ("x-amz-date", "20150830T123600Z"),
+                ("authorization", &authorization),
+            ],
+        )
+    }
+
+    const GET_VANILLA_SIGNATURE: &str =
+        "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31";
+
+    fn status(result: Result<Principal, ApiError>) -> StatusCode {
+        result.unwrap_err().into_response().status()
+    }
+
+    #[test]
+    fn verifies_get_vanilla() {
+        let principal: Principal = verifier("service")
+            .verify_at(&get_vanilla(GET_VANILLA_SIGNATURE), b"", SIGNED_AT)
+            .unwrap();
+        assert_eq!(principal.id, "arn:aws:iam::123456789012:user/example");
+        assert_eq!(principal.method, AuthMethod::SigV4);
+    }
+
+    #[test]
+    fn verifies_get_vanilla_query_order_key_case() {
+        let parts: Parts = request(
+            "/?Param2=value2&Param1=value1",
+            &[
+                ("host", "example.amazonaws.com"),
+                ("x-amz-date", "20150830T123600Z"),
+                (
+                    "authorization",
+                    "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+                     SignedHeaders=host;x-amz-date, \
+                     Signature=b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500",
+                ),
+            ],
+        );
+        assert!(
+            verifier("service")
+                .verify_at(&parts, b"", SIGNED_AT)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn verifies_iam_list_users() {
+        let parts: Parts = request(
+            "https://iam.amazonaws.com/?Action=ListUsers&Version=2010-05-08",
+            &[
+                ("host", "iam.amazonaws.com"),
+                (
+                    "content-type",
+                    "application/x-www-form-urlencoded; charset=utf-8",
+                ),
+                ("x-amz-date", "20150830T123600Z"),
+                (
+                    "authorization",
+                    "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+                     SignedHeaders=content-type;host;x-amz-date, \
+                     Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7",
+                ),
+            ],
+        );
+        assert!(verifier("iam").verify_at(&parts, b"", SIGNED_AT).is_ok());
+    }
+
+    #[test]
+    fn rejects_expired_and_future_signatures() {
+        let parts: Parts = get_vanilla(GET_VANILLA_SIGNATURE);
+        let skew: time::Duration = time::Duration::minutes(6);
+        for now in [SIGNED_AT + skew, SIGNED_AT - skew] {
+            let err: ApiError = verifier("service").verify_at(&parts, b"", now).unwrap_err();
+            assert_eq!(err.into_response().status(), StatusCode::FORBIDDEN);
+        }
+        assert!(
+            verifier("service")
+                .verify_at(&parts, b"", SIGNED_AT + time::Duration::minutes(4))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_requests() {
+        let verifier: SigV4Verifier = verifier("service");
+        let mut parts: Parts = get_vanilla(GET_VANILLA_SIGNATURE);
+        parts.uri = "/?admin=true".parse().unwrap();
+        assert_eq!(
+            status(verifier.verify_at(&parts, b"", SIGNED_AT)),
+            StatusCode::FORBIDDEN
+        );
+
+        let mut parts: Parts = get_vanilla(GET_VANILLA_SIGNATURE);
+        parts
+            .headers
+            .insert(header::HOST, HeaderValue::from_static("evil.example.com"));
+        assert_eq!(
+            status(verifier.verify_at(&parts, b"", SIGNED_AT)),
+            StatusCode::FORBIDDEN
+        );
+
+        let parts: Parts = get_vanilla(GET_VANILLA_SIGNATURE);
+        assert_eq!(
+            status(verifier.verify_at(&parts, b"tampered", SIGNED_AT)),
+            StatusCode::FORBIDDEN
+        );
+
+        let parts: Parts = get_vanilla(&GET_VANILLA_SIGNATURE.replace('5', "6"));
+        assert_eq!(
+            status(verifier.verify_at(&parts, b"", SIGNED_AT)),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_access_keys_with_forbidden() {
+        let mut parts: Parts = get_vanilla(GET_VANILLA_SIGNATURE);
+        let authorization: String = parts.headers[header::AUTHORIZATION]
+            .to_str()
+            .unwrap()
+            .replace(ACCESS_KEY_ID, "AKIDUNKNOWN");
+        parts.headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&authorization).unwrap(),
+        );
+        assert_eq!(
+            status(verifier("service").verify_at(&parts, b"", SIGNED_AT)),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[test]
+    fn rejects_missing_or_malformed_signatures_with_unauthorized() {
+        let verifier: SigV4Verifier = verifier("service");
+        let mut parts: Parts = get_vanilla(GET_VANILLA_SIGNATURE);
+        parts.headers.remove(header::AUTHORIZATION);
+        assert_eq!(
+            status(verifier.verify_at(&parts, b"", SIGNED_AT)),
+            StatusCode::UNAUTHORIZED
+        );
+        parts.headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE"),
+        );
+        assert_eq!(
+            status(verifier.verify_at(&parts, b"", SIGNED_AT)),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
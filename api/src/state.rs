@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use axum::extract::FromRef;
+
+use crate::auth::Authenticator;
+use crate::config::Config;
+use crate::hello::HelloRepository;
+use crate::logging::LogFilters;
+use crate::receiver::TelemetryForwarder;
+
+/// handler に `Router::with_state` で渡すアプリケーションの状態
+///
+/// 各フィールドは `State<T>` で個別に取り出せる
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub authenticator: Authenticator,
+    pub hello_repository: Arc<dyn HelloRepository>,
+    pub log_filters: LogFilters,
+    pub telemetry_forwarder: TelemetryForwarder,
+}
+
+impl AppState {
+    pub fn builder() -> AppStateBuilder {
+        AppStateBuilder::default()
+    }
+}
+
+/// [`AppState`] の builder
+///
+/// repository 以外は省略でき、テストでは repository に fake を、authenticator に API キーを設定したものを渡す
+#[derive(Default)]
+pub struct AppStateBuilder {
+    config: Option<Arc<Config>>,
+    authenticator: Option<Authenticator>,
+    hello_repository: Option<Arc<dyn HelloRepository>>,
+    log_filters: Option<LogFilters>,
+    telemetry_forwarder: Option<TelemetryForwarder>,
+}
+
+impl AppStateBuilder {
+    pub fn config(mut self, config: Arc<Config>) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    pub fn hello_repository(mut self, hello_repository: Arc<dyn HelloRepository>) -> Self {
+        self.hello_repository = Some(hello_repository);
+        self
+    }
+
+    pub fn log_filters(mut self, log_filters: LogFilters) -> Self {
+        self.log_filters = Some(log_filters);
+        self
+    }
+
+    pub fn telemetry_forwarder(mut self, telemetry_forwarder: TelemetryForwarder) -> Self {
+        self.telemetry_forwarder = Some(telemetry_forwarder);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<AppState> {
+        Ok(AppState {
+            config: self.config.unwrap_or_default(),
+            // 既定では認証が必要なルートをすべて拒否する
+            authenticator: self.authenticator.unwrap_or_default(),
+            hello_repository: self
+                .hello_repository
+                .ok_or_else(|| anyhow!("hello_repository is required"))?,
+            // subscriber に登録していない filter なので、変更してもログには影響しない
+            log_filters: self.log_filters.unwrap_or_default(),
+            // 受け取った telemetry を捨てる
+            telemetry_forwarder: self.telemetry_forwarder.unwrap_or_default(),
+        })
+    }
+}
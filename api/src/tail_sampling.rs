@@ -0,0 +1,787 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use opentelemetry::{
+    Context, KeyValue, Value,
+    metrics::{Counter, Meter},
+    trace::{SpanId, Status, TraceId},
+};
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    trace::{Span, SpanData, SpanProcessor},
+};
+use opentelemetry_semantic_conventions::attribute;
+use serde::{Deserialize, Serialize};
+
+const DECISION: &str = "telemetry.tail_sampling.decision";
+const REASON: &str = "telemetry.tail_sampling.reason";
+
+/// 残す trace を決める属性
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttributeMatch {
+    pub key: String,
+    /// 空なら属性があれば残す
+    #[serde(default)]
+    pub values: Vec<String>,
+}
+
+/// trace の root span が終わってから、残すかどうかを決める tail sampling の設定
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TailSamplingConfig {
+    pub enabled: bool,
+    /// エラー、遅い request、`keep_attributes` のどれでもない trace を残す割合
+    pub sample_ratio: f64,
+    /// `http.route` ごとの、これより遅ければ残す時間。route の末尾と比べるので `/greet` のように書ける
+    pub latency_thresholds_ms: BTreeMap<String, u64>,
+    /// `latency_thresholds_ms` にない route の閾値
+    pub default_latency_threshold_ms: Option<u64>,
+    /// どれかの span に属性があれば残す
+    pub keep_attributes: Vec<AttributeMatch>,
+    /// 溜めておく trace の数。超えたら古いものから root を待たずに決める
+    pub max_traces: usize,
+    /// 1 つの trace で溜める span の数。超えた分は捨てる
+    pub max_spans_per_trace: usize,
+    /// root span がこれだけ終わらなければ、root を待たずに決める
+    pub decision_wait_ms: u64,
+}
+
+impl Default for TailSamplingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sample_ratio: 0.1,
+            latency_thresholds_ms: BTreeMap::new(),
+            default_latency_threshold_ms: None,
+            keep_attributes: vec![],
+            max_traces: 1000,
+            max_spans_per_trace: 256,
+            decision_wait_ms: 30_000,
+        }
+    }
+}
+
+impl TailSamplingConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.sample_ratio) {
+            anyhow::bail!(
+                "otel.tail_sampling.sample_ratio must be between 0 and 1, got {}",
+                self.sample_ratio
+            );
+        }
+        if self.max_traces == 0 || self.max_spans_per_trace == 0 || self.decision_wait_ms == 0 {
+            anyhow::bail!(
+                "otel.tail_sampling.max_traces, max_spans_per_trace and decision_wait_ms must be positive"
+            );
+        }
+        Ok(())
+    }
+
+    fn latency_threshold(&self, route: &str) -> Option<Duration> {
+        self.latency_thresholds_ms
+            .iter()
+            .filter(|(suffix, _)| route.ends_with(suffix.as_str()))
+            .max_by_key(|(suffix, _)| suffix.len())
+            .map(|(_, threshold)| *threshold)
+            .or(self.default_latency_threshold_ms)
+            .map(Duration::from_millis)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Decision {
+    Keep(&'static str),
+    Drop,
+}
+
+impl Decision {
+    fn attributes(self) -> [KeyValue; 2] {
+        match self {
+            Decision::Keep(reason) => [
+                KeyValue::new(DECISION, "keep"),
+                KeyValue::new(REASON, reason),
+            ],
+            Decision::Drop => [
+                KeyValue::new(DECISION, "drop"),
+                KeyValue::new(REASON, "sampled_out"),
+            ],
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PendingTrace {
+    spans: Vec<SpanData>,
+    first_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Buffer {
+    traces: HashMap<TraceId, PendingTrace>,
+    /// 溜め始めた順。決めた後の trace も残るので `first_seen` で見分ける
+    order: VecDeque<(Instant, TraceId)>,
+    /// root の後に終わった span のための、決めた trace の結果
+    decided: HashMap<TraceId, bool>,
+    decided_order: VecDeque<TraceId>,
+}
+
+#[derive(Debug)]
+struct SamplerCounters {
+    traces: Counter<u64>,
+    evicted_traces: Counter<u64>,
+    dropped_spans: Counter<u64>,
+}
+
+#[derive(Debug)]
+struct SamplerState {
+    config: TailSamplingConfig,
+    buffer: Mutex<Buffer>,
+    buffered_spans: AtomicUsize,
+    /// meter provider を作った後に `register_metrics` で設定する
+    counters: OnceLock<SamplerCounters>,
+}
+
+/// trace ごとに span を溜め、tail sampling で残すと決めた trace だけを次の processor に渡す
+#[derive(Debug, Clone)]
+pub struct TailSampler(Arc<SamplerState>);
+
+impl TailSampler {
+    pub fn new(config: &TailSamplingConfig) -> Self {
+        Self(Arc::new(SamplerState {
+            config: config.clone(),
+            buffer: Mutex::default(),
+            buffered_spans: AtomicUsize::new(0),
+            counters: OnceLock::new(),
+        }))
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0.config.enabled
+    }
+
+    /// `inner` の前で tail sampling する processor
+    pub fn processor<P: SpanProcessor>(&self, inner: P) -> TailSamplingProcessor<P> {
+        TailSamplingProcessor {
+            inner,
+            sampler: self.clone(),
+        }
+    }
+
+    /// 決めた trace の数、捨てた span の数、溜めている span の数を記録する
+    pub fn register_metrics(&self, meter: &Meter) {
+        let state: TailSampler = self.clone();
+        meter
+            .i64_observable_up_down_counter("telemetry.tail_sampling.buffered_spans")
+            .with_description("Spans buffered until their trace is decided")
+            .with_callback(move |observer| {
+                observer.observe(state.0.buffered_spans.load(Ordering::Relaxed) as i64, &[]);
+            })
+            .build();
+        // 一度しか呼ばない
+        let _ = self.0.counters.set(SamplerCounters {
+            traces: meter
+                .u64_counter("telemetry.tail_sampling.traces")
+                .with_description("Traces decided by tail sampling")
+                .build(),
+            evicted_traces: meter
+                .u64_counter("telemetry.tail_sampling.evicted_traces")
+                .with_description("Traces decided before their root span ended")
+                .build(),
+            dropped_spans: meter
+                .u64_counter("telemetry.tail_sampling.dropped_spans")
+                .with_description("Spans not exported because of tail sampling")
+                .build(),
+        });
+    }
+
+    fn count_dropped_spans(&self, count: usize, reason: &'static str) {
+        if let Some(counters) = self.0.counters.get() {
+            counters
+                .dropped_spans
+                .add(count as u64, &[KeyValue::new(REASON, reason)]);
+        }
+    }
+
+    /// span を溜め、決まった trace の残す span を返す
+    fn on_end(&self, span: SpanData) -> Vec<SpanData> {
+        let config: &TailSamplingConfig = &self.0.config;
+        let trace_id: TraceId = span.span_context.trace_id();
+        let is_local_root: bool =
+            span.parent_span_id == SpanId::INVALID || span.parent_span_is_remote;
+        let now: Instant = Instant::now();
+        let mut released: Vec<SpanData> = vec![];
+        let mut buffer = self.0.buffer.lock().unwrap();
+        self.expire(&mut buffer, now, &mut released);
+
+        if let Some(keep) = buffer.decided.get(&trace_id) {
+            if *keep {
+                released.push(span);
+            } else {
+                self.count_dropped_spans(1, "sampled_out");
+            }
+            return released;
+        }
+        if !buffer.traces.contains_key(&trace_id) {
+            if buffer.traces.len() >= config.max_traces {
+                self.evict_oldest(&mut buffer, "buffer_full", &mut released);
+            }
+            buffer.traces.insert(
+                trace_id,
+                PendingTrace {
+                    spans: vec![],
+                    first_seen: now,
+                },
+            );
+            buffer.order.push_back((now, trace_id));
+        }
+        let trace: &mut PendingTrace = buffer.traces.get_mut(&trace_id).unwrap();
+        // root がないと決められないので、root は上限を超えても溜める
+        if trace.spans.len() >= config.max_spans_per_trace && !is_local_root {
+            self.count_dropped_spans(1, "trace_too_large");
+        } else {
+            trace.spans.push(span);
+            self.0.buffered_spans.fetch_add(1, Ordering::Relaxed);
+        }
+        if is_local_root {
+            self.decide(&mut buffer, trace_id, true, &mut released);
+        }
+        released
+    }
+
+    /// `decision_wait_ms` を過ぎた trace を root を待たずに決める
+    fn expire(&self, buffer: &mut Buffer, now: Instant, released: &mut Vec<SpanData>) {
+        let wait: Duration = Duration::from_millis(self.0.config.decision_wait_ms);
+        while let Some(&(first_seen, trace_id)) = buffer.order.front() {
+            if !Self::is_pending(buffer, first_seen, trace_id) {
+                buffer.order.pop_front();
+                continue;
+            }
+            if now.duration_since(first_seen) < wait {
+                break;
+            }
+            buffer.order.pop_front();
+            self.count_evicted("timeout");
+            self.decide(buffer, trace_id, false, released);
+        }
+    }
+
+    fn evict_oldest(
+        &self,
+        buffer: &mut Buffer,
+        reason: &'static str,
+        released: &mut Vec<SpanData>,
+    ) {
+        while let Some((first_seen, trace_id)) = buffer.order.pop_front() {
+            if Self::is_pending(buffer, first_seen, trace_id) {
+                self.count_evicted(reason);
+                self.decide(buffer, trace_id, false, released);
+                return;
+            }
+        }
+    }
+
+    fn is_pending(buffer: &Buffer, first_seen: Instant, trace_id: TraceId) -> bool {
+        buffer
+            .traces
+            .get(&trace_id)
+            .is_some_and(|trace| trace.first_seen == first_seen)
+    }
+
+    fn count_evicted(&self, reason: &'static str) {
+        if let Some(counters) = self.0.counters.get() {
+            counters
+                .evicted_traces
+                .add(1, &[KeyValue::new(REASON, reason)]);
+        }
+    }
+
+    fn decide(
+        &self,
+        buffer: &mut Buffer,
+        trace_id: TraceId,
+        root_ended: bool,
+        released: &mut Vec<SpanData>,
+    ) {
+        let Some(trace) = buffer.traces.remove(&trace_id) else {
+            return;
+        };
+        self.0
+            .buffered_spans
+            .fetch_sub(trace.spans.len(), Ordering::Relaxed);
+        let decision: Decision = self.evaluate(trace_id, &trace.spans, root_ended);
+        if let Some(counters) = self.0.counters.get() {
+            counters.traces.add(1, &decision.attributes());
+        }
+        let keep: bool = matches!(decision, Decision::Keep(_));
+        if keep {
+            released.extend(trace.spans);
+        } else {
+            self.count_dropped_spans(trace.spans.len(), "sampled_out");
+        }
+
+        buffer.decided.insert(trace_id, keep);
+        buffer.decided_order.push_back(trace_id);
+        while buffer.decided_order.len() > self.0.config.max_traces {
+            if let Some(oldest) = buffer.decided_order.pop_front() {
+                buffer.decided.remove(&oldest);
+            }
+        }
+    }
+
+    fn evaluate(&self, trace_id: TraceId, spans: &[SpanData], root_ended: bool) -> Decision {
+        let config: &TailSamplingConfig = &self.0.config;
+        if spans.iter().any(is_error) {
+            return Decision::Keep("error");
+        }
+        if spans.iter().any(|span| {
+            span.attributes.iter().any(|kv| {
+                config.keep_attributes.iter().any(|rule| {
+                    kv.key.as_str() == rule.key
+                        && (rule.values.is_empty()
+                            || rule.values.iter().any(|value| *value == kv.value.as_str()))
+                })
+            })
+        }) {
+            return Decision::Keep("attribute");
+        }
+        // root が終わっていなければ trace の長さは分からない
+        if root_ended && let Some(root) = spans.last() {
+            let route: String = string_attribute(root, attribute::HTTP_ROUTE).unwrap_or_default();
+            let duration: Duration = root
+                .end_time
+                .duration_since(root.start_time)
+                .unwrap_or_default();
+            if config
+                .latency_threshold(&route)
+                .is_some_and(|threshold| duration > threshold)
+            {
+                return Decision::Keep("latency");
+            }
+        }
+        // 同じ trace を他の service でも同じように残せるよう、乱数でなく trace ID で決める
+        let bytes: [u8; 16] = trace_id.to_bytes();
+        let id: u64 = u64::from_be_bytes(bytes[8..].try_into().unwrap()) >> 1;
+        if (id as f64) < config.sample_ratio * (1u64 << 63) as f64 {
+            Decision::Keep("probability")
+        } else {
+            Decision::Drop
+        }
+    }
+
+    /// 溜めているすべての trace を root を待たずに決める
+    fn drain(&self) -> Vec<SpanData> {
+        let mut released: Vec<SpanData> = vec![];
+        let mut buffer = self.0.buffer.lock().unwrap();
+        let trace_ids: Vec<TraceId> = buffer.traces.keys().copied().collect();
+        for trace_id in trace_ids {
+            self.count_evicted("shutdown");
+            self.decide(&mut buffer, trace_id, false, &mut released);
+        }
+        buffer.order.clear();
+        released
+    }
+}
+
+fn string_attribute(span: &SpanData, key: &str) -> Option<String> {
+    span.attributes
+        .iter()
+        .find(|kv| kv.key.as_str() == key)
+        .map(|kv| kv.value.as_str().into_owned())
+}
+
+/// span の status か、5xx の `http.response.status_code`
+fn is_error(span: &SpanData) -> bool {
+    if matches!(span.status, Status::Error { .. }) {
+        return true;
+    }
+    span.attributes
+        .iter()
+        .find(|kv| kv.key.as_str() == attribute::HTTP_RESPONSE_STATUS_CODE)
+        .and_then(|kv| match &kv.value {
+            Value::I64(code) => Some(*code),
+            // tracing の field は `500 Internal Server Error` のように記録される
+            value => value.as_str().split_whitespace().next()?.parse().ok(),
+        })
+        .is_some_and(|code| code >= 500)
+}
+
+/// `TailSampler` で残すと決めた trace の span だけを `inner` に渡す processor
+#[derive(Debug)]
+pub struct TailSamplingProcessor<P> {
+    inner: P,
+    sampler: TailSampler,
+}
+
+impl<P: SpanProcessor> SpanProcessor for TailSamplingProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        // 次の processor は queue が空くまで待つことがあるので、lock の外で渡す
+        for span in self.sampler.on_end(span) {
+            self.inner.on_end(span);
+        }
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        // root の終わっていない trace は後の invocation で終わるかもしれないので溜めたままにする
+        let mut released: Vec<SpanData> = vec![];
+        {
+            let mut buffer = self.sampler.0.buffer.lock().unwrap();
+            self.sampler
+                .expire(&mut buffer, Instant::now(), &mut released);
+        }
+        for span in released {
+            self.inner.on_end(span);
+        }
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        for span in self.sampler.drain() {
+            self.inner.on_end(span);
+        }
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::time::SystemTime;
+
+    use opentelemetry::InstrumentationScope;
+    use opentelemetry::trace::{SpanContext, SpanKind, TraceFlags, TraceState};
+    use opentelemetry_sdk::trace::{
+        InMemorySpanExporter, SimpleSpanProcessor, SpanEvents, SpanLinks,
+    };
+
+    use super::*;
+    use crate::testing::TestMeter;
+
+    const GREET_ROUTE: &str = "/api/greet";
+
+    struct Harness {
+        processor: TailSamplingProcessor<SimpleSpanProcessor<InMemorySpanExporter>>,
+        exporter: InMemorySpanExporter,
+        meter: TestMeter,
+    }
+
+    impl Harness {
+        fn new(config: TailSamplingConfig) -> Self {
+            let exporter: InMemorySpanExporter = InMemorySpanExporter::default();
+            let sampler: TailSampler = TailSampler::new(&TailSamplingConfig {
+                enabled: true,
+                ..config
+            });
+            let meter: TestMeter = TestMeter::new();
+            sampler.register_metrics(&meter.meter());
+            Self {
+                processor: sampler.processor(SimpleSpanProcessor::new(exporter.clone())),
+                exporter,
+                meter,
+            }
+        }
+
+        fn end(&self, span: SpanData) {
+            self.processor.on_end(span);
+        }
+
+        /// 書き出した span の名前
+        fn exported(&self) -> Vec<String> {
+            self.exporter
+                .get_finished_spans()
+                .unwrap()
+                .into_iter()
+                .map(|span| span.name.into_owned())
+                .collect()
+        }
+
+        fn count(&self, name: &str, attributes: &str) -> f64 {
+            self.meter
+                .values(name)
+                .get(attributes)
+                .copied()
+                .unwrap_or_default()
+        }
+    }
+
+    /// 他の理由で残さない限り、すべての trace を捨てる設定
+    fn drop_all() -> TailSamplingConfig {
+        TailSamplingConfig {
+            sample_ratio: 0.0,
+            ..TailSamplingConfig::default()
+        }
+    }
+
+    /// `parent` が 0 なら local root の span
+    fn span(trace: u128, id: u64, parent: u64) -> SpanData {
+        SpanData {
+            span_context: SpanContext::new(
+                TraceId::from(trace),
+                SpanId::from(id),
+                TraceFlags::SAMPLED,
+                false,
+                TraceState::default(),
+            ),
+            parent_span_id: if parent == 0 {
+                SpanId::INVALID
+            } else {
+                SpanId::from(parent)
+            },
+            parent_span_is_remote: false,
+            span_kind: SpanKind::Internal,
+            name: Cow::Owned(format!("{}-{}", trace, id)),
+            start_time: SystemTime::UNIX_EPOCH,
+            end_time: SystemTime::UNIX_EPOCH + Duration::from_millis(10),
+            attributes: vec![],
+            dropped_attributes_count: 0,
+            events: SpanEvents::default(),
+            links: SpanLinks::default(),
+            status: Status::Unset,
+            instrumentation_scope: InstrumentationScope::builder("test").build(),
+        }
+    }
+
+    fn request(trace: u128, route: &str, duration: Duration) -> SpanData {
+        SpanData {
+            span_kind: SpanKind::Server,
+            attributes: vec![KeyValue::new(attribute::HTTP_ROUTE, route.to_string())],
+            end_time: SystemTime::UNIX_EPOCH + duration,
+            ..span(trace, 1, 0)
+        }
+    }
+
+    #[test]
+    fn error_traces_are_kept() {
+        let harness: Harness = Harness::new(drop_all());
+        harness.end(SpanData {
+            status: Status::error("boom"),
+            ..span(1, 2, 1)
+        });
+        harness.end(span(1, 1, 0));
+        harness.end(SpanData {
+            attributes: vec![KeyValue::new(
+                attribute::HTTP_RESPONSE_STATUS_CODE,
+                "503 Service Unavailable",
+            )],
+            ..span(2, 1, 0)
+        });
+        harness.end(span(3, 1, 0));
+        assert_eq!(harness.exported(), ["1-2", "1-1", "2-1"]);
+        assert_eq!(
+            harness.count(
+                "telemetry.tail_sampling.traces",
+                "telemetry.tail_sampling.decision=keep,telemetry.tail_sampling.reason=error"
+            ),
+            2.0
+        );
+    }
+
+    #[test]
+    fn slow_greet_traces_are_kept() {
+        let harness: Harness = Harness::new(TailSamplingConfig {
+            latency_thresholds_ms: BTreeMap::from([("/greet".to_string(), 1500)]),
+            ..drop_all()
+        });
+        harness.end(request(1, GREET_ROUTE, Duration::from_millis(2000)));
+        harness.end(request(2, GREET_ROUTE, Duration::from_millis(1000)));
+        harness.end(request(3, "/api/hello", Duration::from_millis(2000)));
+        assert_eq!(harness.exported(), ["1-1"]);
+        assert_eq!(
+            harness.count(
+                "telemetry.tail_sampling.traces",
+                "telemetry.tail_sampling.decision=keep,telemetry.tail_sampling.reason=latency"
+            ),
+            1.0
+        );
+    }
+
+    #[test]
+    fn traces_with_a_matching_attribute_are_kept() {
+        let harness: Harness = Harness::new(TailSamplingConfig {
+            keep_attributes: vec![AttributeMatch {
+                key: "enduser.id".to_string(),
+                values: vec!["alice".to_string()],
+            }],
+            ..drop_all()
+        });
+        for (trace, user) in [(1, "alice"), (2, "bob")] {
+            harness.end(SpanData {
+                attributes: vec![KeyValue::new("enduser.id", user)],
+                ..span(trace, 2, 1)
+            });
+            harness.end(span(trace, 1, 0));
+        }
+        assert_eq!(harness.exported(), ["1-2", "1-1"]);
+    }
+
+    #[test]
+    fn probabilistic_decisions_depend_only_on_the_trace_id() {
+        let config: TailSamplingConfig = TailSamplingConfig {
+            sample_ratio: 0.5,
+            ..TailSamplingConfig::default()
+        };
+        // trace ID の下位 64 bit が小さいほど残る
+        let low: u128 = 1;
+        let high: u128 = u128::MAX;
+        for _ in 0..2 {
+            let harness: Harness = Harness::new(config.clone());
+            harness.end(span(low, 1, 0));
+            harness.end(span(high, 1, 0));
+            assert_eq!(harness.exported(), [format!("{}-1", low)]);
+            assert_eq!(
+                harness.count(
+                    "telemetry.tail_sampling.dropped_spans",
+                    "telemetry.tail_sampling.reason=sampled_out"
+                ),
+                1.0
+            );
+        }
+    }
+
+    #[test]
+    fn the_oldest_trace_is_evicted_when_the_buffer_is_full() {
+        let harness: Harness = Harness::new(TailSamplingConfig {
+            max_traces: 2,
+            ..drop_all()
+        });
+        for trace in 1..=3 {
+            harness.end(span(trace, 2, 1));
+        }
+        assert_eq!(
+            harness.count(
+                "telemetry.tail_sampling.evicted_traces",
+                "telemetry.tail_sampling.reason=buffer_full"
+            ),
+            1.0
+        );
+        assert_eq!(
+            harness.count("telemetry.tail_sampling.buffered_spans", ""),
+            2.0
+        );
+
+        // 決めた後に終わった root は、決めた結果に従う
+        harness.end(SpanData {
+            status: Status::error("too late"),
+            ..span(1, 1, 0)
+        });
+        assert!(harness.exported().is_empty());
+        assert_eq!(
+            harness.count(
+                "telemetry.tail_sampling.dropped_spans",
+                "telemetry.tail_sampling.reason=sampled_out"
+            ),
+            2.0
+        );
+    }
+
+    #[test]
+    fn spans_beyond_the_per_trace_limit_are_dropped() {
+        let harness: Harness = Harness::new(TailSamplingConfig {
+            sample_ratio: 1.0,
+            max_spans_per_trace: 2,
+            ..TailSamplingConfig::default()
+        });
+        for id in 2..=4 {
+            harness.end(span(1, id, 1));
+        }
+        // root は上限を超えても溜める
+        harness.end(span(1, 1, 0));
+        assert_eq!(harness.exported(), ["1-2", "1-3", "1-1"]);
+        assert_eq!(
+            harness.count(
+                "telemetry.tail_sampling.dropped_spans",
+                "telemetry.tail_sampling.reason=trace_too_large"
+            ),
+            1.0
+        );
+    }
+
+    #[test]
+    fn traces_whose_root_never_ends_are_decided_after_the_wait() {
+        let harness: Harness = Harness::new(TailSamplingConfig {
+            decision_wait_ms: 10,
+            ..drop_all()
+        });
+        harness.end(SpanData {
+            status: Status::error("orphan"),
+            ..span(1, 2, 1)
+        });
+        harness.processor.force_flush().unwrap();
+        assert!(harness.exported().is_empty());
+
+        std::thread::sleep(Duration::from_millis(30));
+        harness.processor.force_flush().unwrap();
+        assert_eq!(harness.exported(), ["1-2"]);
+        assert_eq!(
+            harness.count(
+                "telemetry.tail_sampling.evicted_traces",
+                "telemetry.tail_sampling.reason=timeout"
+            ),
+            1.0
+        );
+    }
+
+    #[test]
+    fn late_spans_follow_the_decision() {
+        let harness: Harness = Harness::new(drop_all());
+        harness.end(SpanData {
+            status: Status::error("kept"),
+            ..span(1, 1, 0)
+        });
+        harness.end(span(2, 1, 0));
+        harness.end(span(1, 2, 1));
+        harness.end(span(2, 2, 1));
+        assert_eq!(harness.exported(), ["1-1", "1-2"]);
+        assert_eq!(
+            harness.count(
+                "telemetry.tail_sampling.dropped_spans",
+                "telemetry.tail_sampling.reason=sampled_out"
+            ),
+            2.0
+        );
+        assert_eq!(
+            harness.count("telemetry.tail_sampling.buffered_spans", ""),
+            0.0
+        );
+    }
+
+    #[test]
+    fn shutdown_decides_buffered_traces() {
+        let harness: Harness = Harness::new(TailSamplingConfig {
+            sample_ratio: 1.0,
+            ..TailSamplingConfig::default()
+        });
+        harness.end(span(1, 2, 1));
+        harness
+            .processor
+            .shutdown_with_timeout(Duration::from_secs(1))
+            .unwrap();
+        // in-memory の exporter は shutdown で記録を消すので、決めた結果を metric で確かめる
+        assert_eq!(
+            harness.count(
+                "telemetry.tail_sampling.traces",
+                "telemetry.tail_sampling.decision=keep,telemetry.tail_sampling.reason=probability"
+            ),
+            1.0
+        );
+        assert_eq!(harness.count("telemetry.tail_sampling.buffered_spans", ""), 0.0);
+        assert_eq!(
+            harness.count(
+                "telemetry.tail_sampling.evicted_traces",
+                "telemetry.tail_sampling.reason=shutdown"
+            ),
+            1.0
+        );
+    }
+}
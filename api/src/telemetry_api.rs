@@ -0,0 +1,554 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use opentelemetry::{
+    propagation::TextMapPropagator,
+    trace::{SpanContext, TraceContextExt},
+};
+use opentelemetry_aws::trace::XrayPropagator;
+use opentelemetry_proto::tonic::{
+    common::v1::{AnyValue, InstrumentationScope, KeyValue, any_value},
+    metrics::v1::{Gauge, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics, metric, number_data_point},
+    resource::v1::Resource,
+    trace::v1::{ResourceSpans, ScopeSpans, Span, Status, span::SpanKind, status::StatusCode},
+};
+use opentelemetry_sdk::trace::{IdGenerator, RandomIdGenerator};
+use serde::Deserialize;
+use serde_json::Value;
+use time::{Duration, OffsetDateTime};
+
+/// Telemetry API の schema
+pub const SCHEMA_VERSION: &str = "2022-12-13";
+const SUCCESS: &str = "success";
+// Lambda の timeout の上限。これより前に始まって `platform.report` の届かない invocation は捨てる
+const MAX_INVOCATION_DURATION: Duration = Duration::minutes(15);
+
+/// Telemetry API から届く event
+///
+/// `record` は `type` ごとに形が違うので、使う event だけを読む
+#[derive(Debug, Deserialize)]
+pub struct TelemetryEvent {
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub record: Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Tracing {
+    #[serde(rename = "type")]
+    kind: String,
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StartRecord {
+    request_id: String,
+    tracing: Option<Tracing>,
+}
+
+/// `platform.runtimeDone` と `platform.initRuntimeDone` の区間
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatformSpan {
+    name: String,
+    #[serde(with = "time::serde::rfc3339")]
+    start: OffsetDateTime,
+    duration_ms: f64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RuntimeDoneRecord {
+    request_id: String,
+    #[serde(default)]
+    spans: Vec<PlatformSpan>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReportMetrics {
+    duration_ms: f64,
+    billed_duration_ms: f64,
+    #[serde(rename = "memorySizeMB")]
+    memory_size_mb: u64,
+    #[serde(rename = "maxMemoryUsedMB")]
+    max_memory_used_mb: u64,
+    /// cold start のときだけある
+    init_duration_ms: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReportRecord {
+    request_id: String,
+    status: String,
+    metrics: ReportMetrics,
+    tracing: Option<Tracing>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InitRuntimeDoneRecord {
+    #[serde(default)]
+    spans: Vec<PlatformSpan>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InitReportMetrics {
+    duration_ms: f64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InitReportRecord {
+    initialization_type: String,
+    status: String,
+    metrics: InitReportMetrics,
+}
+
+/// `platform.start` から `platform.report` までに集めた invocation の情報
+struct Invocation {
+    start: OffsetDateTime,
+    trace: Option<SpanContext>,
+    spans: Vec<PlatformSpan>,
+}
+
+/// trace ID が分かるまで待っている初期化の区間
+#[derive(Default)]
+struct Init {
+    start: Option<OffsetDateTime>,
+    spans: Vec<PlatformSpan>,
+    report: Option<(OffsetDateTime, InitReportRecord)>,
+}
+
+#[derive(Default)]
+struct Invocations {
+    running: HashMap<String, Invocation>,
+    init: Init,
+}
+
+/// Lambda の platform event を、invocation の trace に入る span と metrics にする
+///
+/// invocation の span は Lambda が `X-Amzn-Trace-Id` で渡す segment の子にする。
+/// 初期化は trace ID を持たないので、その後の最初の invocation の trace に入れる
+pub struct PlatformTelemetry {
+    resource: Resource,
+    scope: InstrumentationScope,
+    invocations: Mutex<Invocations>,
+    id_generator: Box<dyn IdGenerator>,
+}
+
+impl PlatformTelemetry {
+    pub fn new(resource: &opentelemetry_sdk::Resource) -> Self {
+        let resource_attributes = opentelemetry_proto::transform::common::tonic::ResourceAttributesWithSchema::from(resource);
+        Self {
+            resource: Resource {
+                attributes: resource_attributes.attributes.0,
+                ..Resource::default()
+            },
+            scope: InstrumentationScope {
+                name: format!("{}/telemetry-api", env!("CARGO_PKG_NAME")),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                ..InstrumentationScope::default()
+            },
+            invocations: Mutex::default(),
+            id_generator: Box::new(RandomIdGenerator::default()),
+        }
+    }
+
+    /// event を読んで、終わった区間の span と metrics を返す
+    ///
+    /// 読めない event と使わない種類の event は無視する
+    pub fn record(&self, events: Vec<TelemetryEvent>) -> (Vec<ResourceSpans>, Vec<ResourceMetrics>) {
+        let mut spans: Vec<Span> = Vec::new();
+        let mut metrics: Vec<Metric> = Vec::new();
+        let mut invocations = self.invocations.lock().unwrap();
+        for event in events {
+            let time: OffsetDateTime = event.time;
+            let parsed: serde_json::Result<()> = match event.kind.as_str() {
+                "platform.initStart" => {
+                    invocations.init = Init {
+                        start: Some(time),
+                        ..Init::default()
+                    };
+                    Ok(())
+                }
+                "platform.initRuntimeDone" => {
+                    serde_json::from_value(event.record).map(|record: InitRuntimeDoneRecord| {
+                        invocations.init.spans = record.spans;
+                    })
+                }
+                "platform.initReport" => {
+                    serde_json::from_value(event.record).map(|record: InitReportRecord| {
+                        metrics.push(gauge(
+                            "aws.lambda.init_duration",
+                            "ms",
+                            time,
+                            record.metrics.duration_ms,
+                        ));
+                        invocations.init.report = Some((time, record));
+                    })
+                }
+                "platform.start" => serde_json::from_value(event.record).map(|record: StartRecord| {
+                    let trace: Option<SpanContext> = record.tracing.as_ref().and_then(span_context);
+                    if let Some(trace) = &trace
+                        && let Some(init_span) = self.init_span(&mut invocations.init, trace)
+                    {
+                        spans.extend(init_span);
+                    }
+                    // event の取りこぼしや timeout で report の届かなかった invocation
+                    invocations
+                        .running
+                        .retain(|_, invocation| time - invocation.start <= MAX_INVOCATION_DURATION);
+                    invocations.running.insert(
+                        record.request_id,
+                        Invocation {
+                            start: time,
+                            trace,
+                            spans: vec![],
+                        },
+                    );
+                }),
+                "platform.runtimeDone" => {
+                    serde_json::from_value(event.record).map(|record: RuntimeDoneRecord| {
+                        if let Some(invocation) = invocations.running.get_mut(&record.request_id) {
+                            invocation.spans = record.spans;
+                        }
+                    })
+                }
+                "platform.report" => serde_json::from_value(event.record).map(|record: ReportRecord| {
+                    let duration: Duration = milliseconds(record.metrics.duration_ms);
+                    // subscribe する前に始まった invocation は report から開始時刻を求める
+                    let invocation: Invocation = invocations
+                        .running
+                        .remove(&record.request_id)
+                        .unwrap_or_else(|| Invocation {
+                            start: time - duration,
+                            trace: record.tracing.as_ref().and_then(span_context),
+                            spans: vec![],
+                        });
+                    metrics.extend([
+                        gauge("aws.lambda.duration", "ms", time, record.metrics.duration_ms),
+                        gauge(
+                            "aws.lambda.billed_duration",
+                            "ms",
+                            time,
+                            record.metrics.billed_duration_ms,
+                        ),
+                        gauge(
+                            "aws.lambda.max_memory_used",
+                            "MiBy",
+                            time,
+                            record.metrics.max_memory_used_mb as f64,
+                        ),
+                    ]);
+                    spans.extend(self.invocation_spans(&invocation, &record, duration));
+                }),
+                _ => Ok(()),
+            };
+            if let Err(err) = parsed {
+                tracing::warn!("Ignoring malformed `{}` event: {}", event.kind, err);
+            }
+        }
+        drop(invocations);
+
+        let resource_spans: Vec<ResourceSpans> = if spans.is_empty() {
+            vec![]
+        } else {
+            vec![ResourceSpans {
+                resource: Some(self.resource.clone()),
+                scope_spans: vec![ScopeSpans {
+                    scope: Some(self.scope.clone()),
+                    spans,
+                    ..ScopeSpans::default()
+                }],
+                ..ResourceSpans::default()
+            }]
+        };
+        let resource_metrics: Vec<ResourceMetrics> = if metrics.is_empty() {
+            vec![]
+        } else {
+            vec![ResourceMetrics {
+                resource: Some(self.resource.clone()),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(self.scope.clone()),
+                    metrics,
+                    ..ScopeMetrics::default()
+                }],
+                ..ResourceMetrics::default()
+            }]
+        };
+        (resource_spans, resource_metrics)
+    }
+
+    /// 初期化が終わっていれば、その span を `trace` の segment の子として取り出す
+    fn init_span(&self, init: &mut Init, trace: &SpanContext) -> Option<Vec<Span>> {
+        if !trace.is_sampled() {
+            return None;
+        }
+        let (time, report) = init.report.take()?;
+        let init: Init = std::mem::take(init);
+        let duration: Duration = milliseconds(report.metrics.duration_ms);
+        let start: OffsetDateTime = init.start.unwrap_or(time - duration);
+        let span: Span = self.span(
+            trace,
+            trace.span_id().to_bytes().to_vec(),
+            "Init",
+            start,
+            start + duration,
+            &report.status,
+            vec![string_attribute(
+                "aws.lambda.initialization_type",
+                &report.initialization_type,
+            )],
+        );
+        let children = self.child_spans(trace, &span, &init.spans);
+        Some(std::iter::once(span).chain(children).collect())
+    }
+
+    fn invocation_spans(
+        &self,
+        invocation: &Invocation,
+        report: &ReportRecord,
+        duration: Duration,
+    ) -> Vec<Span> {
+        let Some(trace) = invocation.trace.as_ref().filter(|trace| trace.is_sampled()) else {
+            return vec![];
+        };
+        let metrics: &ReportMetrics = &report.metrics;
+        let span: Span = self.span(
+            trace,
+            trace.span_id().to_bytes().to_vec(),
+            "Invoke",
+            invocation.start,
+            invocation.start + duration,
+            &report.status,
+            vec![
+                string_attribute(
+                    opentelemetry_semantic_conventions::attribute::FAAS_INVOCATION_ID,
+                    &report.request_id,
+                ),
+                bool_attribute(
+                    opentelemetry_semantic_conventions::attribute::FAAS_COLDSTART,
+                    metrics.init_duration_ms.is_some(),
+                ),
+                double_attribute("aws.lambda.billed_duration_ms", metrics.billed_duration_ms),
+                int_attribute("aws.lambda.memory_size_mb", metrics.memory_size_mb as i64),
+                int_attribute("aws.lambda.max_memory_used_mb", metrics.max_memory_used_mb as i64),
+            ],
+        );
+        let children = self.child_spans(trace, &span, &invocation.spans);
+        std::iter::once(span).chain(children).collect()
+    }
+
+    /// `responseLatency` のような platform の区間を子の span にする
+    fn child_spans(&self, trace: &SpanContext, parent: &Span, spans: &[PlatformSpan]) -> Vec<Span> {
+        spans
+            .iter()
+            .map(|span| {
+                self.span(
+                    trace,
+                    parent.span_id.clone(),
+                    &span.name,
+                    span.start,
+                    span.start + milliseconds(span.duration_ms),
+                    SUCCESS,
+                    vec![],
+                )
+            })
+            .collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn span(
+        &self,
+        trace: &SpanContext,
+        parent_span_id: Vec<u8>,
+        name: &str,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        status: &str,
+        attributes: Vec<KeyValue>,
+    ) -> Span {
+        Span {
+            trace_id: trace.trace_id().to_bytes().to_vec(),
+            span_id: self.id_generator.new_span_id().to_bytes().to_vec(),
+            parent_span_id,
+            flags: u32::from(trace.trace_flags().to_u8()),
+            name: name.to_string(),
+            kind: SpanKind::Internal as i32,
+            start_time_unix_nano: unix_nanos(start),
+            end_time_unix_nano: unix_nanos(end),
+            attributes,
+            status: (status != SUCCESS).then(|| Status {
+                code: StatusCode::Error as i32,
+                message: status.to_string(),
+            }),
+            ..Span::default()
+        }
+    }
+}
+
+/// `X-Amzn-Trace-Id` の形式の tracing から、Lambda の segment を親にする span context を読む
+fn span_context(tracing: &Tracing) -> Option<SpanContext> {
+    if tracing.kind != "X-Amzn-Trace-Id" {
+        return None;
+    }
+    let carrier: HashMap<String, String> =
+        HashMap::from([("x-amzn-trace-id".to_string(), tracing.value.clone())]);
+    let context = XrayPropagator::default().extract(&carrier);
+    let span_context: SpanContext = context.span().span_context().clone();
+    span_context.is_valid().then_some(span_context)
+}
+
+fn gauge(name: &str, unit: &str, time: OffsetDateTime, value: f64) -> Metric {
+    Metric {
+        name: name.to_string(),
+        unit: unit.to_string(),
+        data: Some(metric::Data::Gauge(Gauge {
+            data_points: vec![NumberDataPoint {
+                time_unix_nano: unix_nanos(time),
+                value: Some(number_data_point::Value::AsDouble(value)),
+                ..NumberDataPoint::default()
+            }],
+        })),
+        ..Metric::default()
+    }
+}
+
+fn milliseconds(ms: f64) -> Duration {
+    Duration::seconds_f64(ms / 1000.0)
+}
+
+fn unix_nanos(time: OffsetDateTime) -> u64 {
+    u64::try_from(time.unix_timestamp_nanos()).unwrap_or_default()
+}
+
+fn attribute(key: &str, value: any_value::Value) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue { value: Some(value) }),
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> KeyValue {
+    attribute(key, any_value::Value::StringValue(value.to_string()))
+}
+
+fn bool_attribute(key: &str, value: bool) -> KeyValue {
+    attribute(key, any_value::Value::BoolValue(value))
+}
+
+fn int_attribute(key: &str, value: i64) -> KeyValue {
+    attribute(key, any_value::Value::IntValue(value))
+}
+
+fn double_attribute(key: &str, value: f64) -> KeyValue {
+    attribute(key, any_value::Value::DoubleValue(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use opentelemetry::trace::{SpanId, TraceId};
+
+    use super::*;
+    use crate::testing::assert_golden;
+
+    #[derive(Debug, Default)]
+    struct SequentialIds(AtomicU64);
+
+    impl IdGenerator for SequentialIds {
+        fn new_trace_id(&self) -> TraceId {
+            TraceId::INVALID
+        }
+
+        fn new_span_id(&self) -> SpanId {
+            SpanId::from(0x00f067aa0ba902b7 + self.0.fetch_add(1, Ordering::Relaxed))
+        }
+    }
+
+    fn telemetry() -> PlatformTelemetry {
+        let resource: opentelemetry_sdk::Resource = opentelemetry_sdk::Resource::builder_empty()
+            .with_attribute(opentelemetry::KeyValue::new(
+                opentelemetry_semantic_conventions::resource::FAAS_NAME,
+                "api",
+            ))
+            .build();
+        PlatformTelemetry {
+            id_generator: Box::new(SequentialIds::default()),
+            ..PlatformTelemetry::new(&resource)
+        }
+    }
+
+    fn events(json: &str) -> Vec<TelemetryEvent> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn start(time: &str, request_id: &str) -> String {
+        format!(
+            r#"{{"time": "{}", "type": "platform.start", "record": {{"requestId": "{}"}}}}"#,
+            time, request_id
+        )
+    }
+
+    #[test]
+    fn records_platform_events() {
+        let events: Vec<TelemetryEvent> =
+            events(include_str!("../testdata/telemetry_api/events.json"));
+        let (spans, metrics) = telemetry().record(events);
+        let actual: Value = serde_json::json!({ "spans": spans, "metrics": metrics });
+        assert_golden(
+            "telemetry_api/record.json",
+            &format!("{}\n", serde_json::to_string_pretty(&actual).unwrap()),
+        );
+    }
+
+    #[test]
+    fn keeps_init_until_a_sampled_invocation() {
+        let telemetry: PlatformTelemetry = telemetry();
+        let events: Vec<TelemetryEvent> =
+            events(include_str!("../testdata/telemetry_api/events.json"));
+        let (init, invocations): (Vec<TelemetryEvent>, Vec<TelemetryEvent>) = events
+            .into_iter()
+            .partition(|event| event.kind.starts_with("platform.init"));
+
+        let (spans, _) = telemetry.record(init);
+        assert!(spans.is_empty());
+        let (spans, _) = telemetry.record(invocations);
+        let names: Vec<&str> = spans[0].scope_spans[0]
+            .spans
+            .iter()
+            .map(|span| span.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            ["Init", "Invoke", "responseLatency", "responseDuration", "Invoke"]
+        );
+    }
+
+    #[test]
+    fn prunes_invocations_without_a_report() {
+        let telemetry: PlatformTelemetry = telemetry();
+        telemetry.record(events(&format!(
+            "[{}, {}]",
+            start("2026-01-02T03:00:00Z", "lost"),
+            start("2026-01-02T03:10:00Z", "running"),
+        )));
+        telemetry.record(events(&format!(
+            "[{}]",
+            start("2026-01-02T03:16:00Z", "next")
+        )));
+        let invocations = telemetry.invocations.lock().unwrap();
+        let mut running: Vec<&str> = invocations.running.keys().map(String::as_str).collect();
+        running.sort();
+        assert_eq!(running, ["next", "running"]);
+    }
+}
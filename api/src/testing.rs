@@ -0,0 +1,87 @@
+//! テストで共有する補助関数
+
+use std::path::PathBuf;
+
+/// `actual` が `testdata/<name>` の内容と一致することを確かめる
+///
+/// `UPDATE_GOLDEN=1 cargo test` で golden file を書き直す
+pub fn assert_golden(name: &str, actual: &str) {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testdata", name]
+        .iter()
+        .collect();
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected: String = std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("failed to read {}: {}", path.display(), err));
+    assert_eq!(actual, expected, "output differs from {}", path.display());
+}
+
+/// 記録した metric を in-memory の exporter に書き出して読む meter provider
+#[derive(Debug)]
+pub struct TestMeter {
+    provider: opentelemetry_sdk::metrics::SdkMeterProvider,
+    exporter: opentelemetry_sdk::metrics::InMemoryMetricExporter,
+}
+
+impl TestMeter {
+    pub fn new() -> Self {
+        let exporter = opentelemetry_sdk::metrics::InMemoryMetricExporter::default();
+        let provider = opentelemetry_sdk::metrics::SdkMeterProvider::builder()
+            .with_periodic_exporter(exporter.clone())
+            .build();
+        Self { provider, exporter }
+    }
+
+    pub fn meter(&self) -> opentelemetry::metrics::Meter {
+        use opentelemetry::metrics::MeterProvider;
+        self.provider.meter("test")
+    }
+
+    /// `name` の counter の、`key=value` を `,` でつないだ属性ごとの値
+    ///
+    /// histogram は記録した回数を返す
+    pub fn values(&self, name: &str) -> std::collections::BTreeMap<String, f64> {
+        use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+        fn points<T: Copy>(data: &MetricData<T>, to_f64: fn(T) -> f64) -> Vec<(String, f64)> {
+            fn key<'a>(attributes: impl Iterator<Item = &'a opentelemetry::KeyValue>) -> String {
+                let mut pairs: Vec<String> = attributes
+                    .map(|kv| format!("{}={}", kv.key, kv.value))
+                    .collect();
+                pairs.sort();
+                pairs.join(",")
+            }
+            match data {
+                MetricData::Sum(sum) => sum
+                    .data_points()
+                    .map(|point| (key(point.attributes()), to_f64(point.value())))
+                    .collect(),
+                MetricData::Gauge(gauge) => gauge
+                    .data_points()
+                    .map(|point| (key(point.attributes()), to_f64(point.value())))
+                    .collect(),
+                MetricData::Histogram(histogram) => histogram
+                    .data_points()
+                    .map(|point| (key(point.attributes()), point.count() as f64))
+                    .collect(),
+                MetricData::ExponentialHistogram(_) => vec![],
+            }
+        }
+        self.provider.force_flush().unwrap();
+        let metrics = self.exporter.get_finished_metrics().unwrap();
+        let Some(last) = metrics.last() else {
+            return Default::default();
+        };
+        last.scope_metrics()
+            .flat_map(|scope| scope.metrics())
+            .filter(|metric| metric.name() == name)
+            .flat_map(|metric| match metric.data() {
+                AggregatedMetrics::F64(data) => points(data, |value| value),
+                AggregatedMetrics::U64(data) => points(data, |value| value as f64),
+                AggregatedMetrics::I64(data) => points(data, |value| value as f64),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,228 @@
+// `ApiJson` は `axum::Json` を包む
+#![allow(clippy::disallowed_types)]
+
+use axum::{
+    Json,
+    extract::{FromRequest, Request},
+    response::{IntoResponse, Response},
+};
+use regex::Regex;
+use serde::{Serialize, de::DeserializeOwned};
+use utoipa::ToSchema;
+
+use crate::error::{ApiError, Violation};
+
+pub use api_derive::Validate;
+
+/// request body の検証
+///
+/// 通常は `#[derive(Validate)]` で実装する
+pub trait Validate {
+    fn validate(&self) -> Result<(), Vec<Violation>>;
+}
+
+/// 文字列フィールドの制約
+///
+/// `#[derive(Validate)]` がフィールドの `#[schema(min_length, max_length, pattern)]` から組み立てる
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StringConstraint {
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    pattern: Option<&'static str>,
+}
+
+impl StringConstraint {
+    pub const fn new() -> Self {
+        Self {
+            min_length: None,
+            max_length: None,
+            pattern: None,
+        }
+    }
+
+    pub const fn min_length(mut self, min_length: usize) -> Self {
+        self.min_length = Some(min_length);
+        self
+    }
+
+    pub const fn max_length(mut self, max_length: usize) -> Self {
+        self.max_length = Some(max_length);
+        self
+    }
+
+    pub const fn pattern(mut self, pattern: &'static str) -> Self {
+        self.pattern = Some(pattern);
+        self
+    }
+
+    /// 実行時の検証に使う正規表現をコンパイルする
+    pub fn compile(self) -> CompiledConstraint {
+        CompiledConstraint {
+            constraint: self,
+            pattern: self
+                .pattern
+                .map(|pattern| Regex::new(pattern).expect("Failed to compile validation pattern")),
+        }
+    }
+}
+
+/// 正規表現をコンパイル済みの [`StringConstraint`]
+///
+/// `#[derive(Validate)]` がフィールドごとに static に持つ
+#[derive(Debug)]
+pub struct CompiledConstraint {
+    constraint: StringConstraint,
+    pattern: Option<Regex>,
+}
+
+impl CompiledConstraint {
+    // JSON Schema の minLength / maxLength はバイト数ではなく文字数
+    pub fn check(&self, field: &str, value: &str, violations: &mut Vec<Violation>) {
+        let StringConstraint {
+            min_length,
+            max_length,
+            pattern,
+        } = self.constraint;
+        let length: usize = value.chars().count();
+        let too_short: bool = min_length.is_some_and(|min| length < min);
+        let too_long: bool = max_length.is_some_and(|max| length > max);
+        if too_short || too_long {
+            let message: String = match (min_length, max_length) {
+                (Some(min), Some(max)) => {
+                    format!("{} length must be between {} and {}", field, min, max)
+                }
+                (Some(min), None) => format!("{} length must be at least {}", field, min),
+                (None, Some(max)) => format!("{} length must be at most {}", field, max),
+                (None, None) => unreachable!(),
+            };
+            violations.push(Violation::new(field, message));
+        }
+        if let (Some(pattern), Some(regex)) = (pattern, &self.pattern)
+            && !regex.is_match(value)
+        {
+            violations.push(Violation::new(
+                field,
+                format!("{} must match pattern {}", field, pattern),
+            ));
+        }
+    }
+}
+
+/// `#[derive(Validate)]` で検証できるフィールドの型
+pub trait ValidateField {
+    fn check_field(
+        &self,
+        field: &str,
+        constraint: &CompiledConstraint,
+        violations: &mut Vec<Violation>,
+    );
+}
+
+impl ValidateField for String {
+    fn check_field(
+        &self,
+        field: &str,
+        constraint: &CompiledConstraint,
+        violations: &mut Vec<Violation>,
+    ) {
+        constraint.check(field, self, violations);
+    }
+}
+
+/// 省略されたフィールドは検証しない
+impl<T: ValidateField> ValidateField for Option<T> {
+    fn check_field(
+        &self,
+        field: &str,
+        constraint: &CompiledConstraint,
+        violations: &mut Vec<Violation>,
+    ) {
+        if let Some(value) = self {
+            value.check_field(field, constraint, violations);
+        }
+    }
+}
+
+/// JSON の request body を deserialize して検証する extractor と、JSON の response
+///
+/// extractor としては `T: Validate + ToSchema` を要求する。`axum::Json` は `clippy.toml` の `disallowed_types` で禁止している。
+/// rustc ではなく `cargo clippy -- -D warnings` での検査なので、clippy を通さないビルドでは `axum::Json` で検証を迂回できる
+#[derive(Debug)]
+pub struct ApiJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ApiJson<T>
+where
+    T: DeserializeOwned + Validate + ToSchema,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value): Json<T> = Json::from_request(req, state).await?;
+        value.validate().map_err(ApiError::validation)?;
+        Ok(Self(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for ApiJson<T> {
+    fn into_response(self) -> Response {
+        Json(self.0).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use utoipa::PartialSchema;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, ToSchema, Validate)]
+    #[serde(rename_all = "camelCase")]
+    struct Body {
+        #[schema(min_length = 2, max_length = 4, example = "abc")]
+        display_name: String,
+        #[schema(pattern = "^[a-z]+$", value_type = Option<String>)]
+        #[serde(rename = "tag")]
+        label: Option<String>,
+        #[validate(skip)]
+        #[allow(dead_code)]
+        note: String,
+    }
+
+    #[test]
+    fn schema_carries_the_validated_constraints() {
+        let schema = serde_json::to_value(Body::schema()).unwrap();
+        let properties = &schema["properties"];
+        assert_eq!(properties["displayName"]["minLength"], 2);
+        assert_eq!(properties["displayName"]["maxLength"], 4);
+        assert_eq!(properties["tag"]["pattern"], "^[a-z]+$");
+        assert_eq!(properties["note"].get("minLength"), None);
+    }
+
+    #[test]
+    fn violations_use_serde_field_names() {
+        let body = Body {
+            display_name: "a".to_string(),
+            label: Some("A1".to_string()),
+            note: String::new(),
+        };
+        assert_eq!(
+            body.validate().unwrap_err(),
+            vec![
+                Violation::new("displayName", "displayName length must be between 2 and 4"),
+                Violation::new("tag", "tag must match pattern ^[a-z]+$"),
+            ]
+        );
+    }
+
+    #[test]
+    fn omitted_optional_fields_are_not_checked() {
+        let body = Body {
+            display_name: "abc".to_string(),
+            label: None,
+            note: String::new(),
+        };
+        assert!(body.validate().is_ok());
+    }
+}